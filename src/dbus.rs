@@ -0,0 +1,578 @@
+//! Just enough of the D-Bus wire protocol for `serve --dbus`: EXTERNAL
+//! authentication on the session bus, little-endian marshalling of the types
+//! the service sends, and decoding of the header fields it reads.
+
+use std::env;
+use std::io::{Read, Write};
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::net::UnixStream;
+use std::sync::mpsc;
+use std::thread;
+
+use crate::CliError;
+
+/// The specification's limit on a whole message; anything claiming more is
+/// refused before its buffer is allocated.
+const MAX_MESSAGE_LEN: usize = 128 << 20;
+
+#[derive(Clone, Copy, PartialEq)]
+pub(crate) enum MessageType {
+    MethodCall = 1,
+    MethodReturn = 2,
+    Error = 3,
+    Signal = 4,
+}
+
+/// A decoded message: the header fields the service uses and the raw body.
+#[derive(Default)]
+pub(crate) struct Message {
+    pub(crate) kind: u8,
+    pub(crate) flags: u8,
+    pub(crate) serial: u32,
+    pub(crate) big_endian: bool,
+    pub(crate) path: Option<String>,
+    pub(crate) interface: Option<String>,
+    pub(crate) member: Option<String>,
+    pub(crate) error_name: Option<String>,
+    pub(crate) reply_serial: Option<u32>,
+    pub(crate) sender: Option<String>,
+    pub(crate) signature: String,
+    pub(crate) body: Vec<u8>,
+}
+
+/// Little-endian marshalling for the handful of types the service sends.
+#[derive(Default)]
+pub(crate) struct Writer {
+    pub(crate) buf: Vec<u8>,
+}
+
+impl Writer {
+    fn align(&mut self, alignment: usize) {
+        while !self.buf.len().is_multiple_of(alignment) {
+            self.buf.push(0);
+        }
+    }
+
+    pub(crate) fn u32(&mut self, value: u32) {
+        self.align(4);
+        self.buf.extend(value.to_le_bytes());
+    }
+
+    pub(crate) fn string(&mut self, value: &str) {
+        self.u32(value.len() as u32);
+        self.buf.extend(value.as_bytes());
+        self.buf.push(0);
+    }
+
+    fn signature(&mut self, value: &str) {
+        self.buf.push(value.len() as u8);
+        self.buf.extend(value.as_bytes());
+        self.buf.push(0);
+    }
+
+    pub(crate) fn string_array(&mut self, values: &[String]) {
+        self.u32(0);
+        let length_at = self.buf.len() - 4;
+        let start = self.buf.len();
+        for value in values {
+            self.string(value);
+        }
+        let length = (self.buf.len() - start) as u32;
+        self.buf[length_at..start].copy_from_slice(&length.to_le_bytes());
+    }
+
+    fn header_field(&mut self, code: u8, signature: &str, write: impl FnOnce(&mut Self)) {
+        self.align(8);
+        self.buf.push(code);
+        self.signature(signature);
+        write(self);
+    }
+}
+
+/// Reads values from a message body, honouring the sender's byte order.
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    big_endian: bool,
+}
+
+impl Reader<'_> {
+    fn align(&mut self, alignment: usize) {
+        self.pos = self.pos.next_multiple_of(alignment);
+    }
+
+    fn take(&mut self, len: usize) -> Option<&[u8]> {
+        let bytes = self.data.get(self.pos..self.pos.checked_add(len)?)?;
+        self.pos += len;
+        Some(bytes)
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        self.align(4);
+        let bytes: [u8; 4] = self.take(4)?.try_into().ok()?;
+        Some(if self.big_endian {
+            u32::from_be_bytes(bytes)
+        } else {
+            u32::from_le_bytes(bytes)
+        })
+    }
+
+    fn string(&mut self) -> Option<String> {
+        let len = self.u32()? as usize;
+        let text = String::from_utf8(self.take(len)?.to_vec()).ok()?;
+        self.take(1)?;
+        Some(text)
+    }
+
+    fn signature(&mut self) -> Option<String> {
+        let len = usize::from(*self.take(1)?.first()?);
+        let text = String::from_utf8(self.take(len)?.to_vec()).ok()?;
+        self.take(1)?;
+        Some(text)
+    }
+}
+
+impl Message {
+    pub(crate) fn parse(data: &[u8]) -> Option<Message> {
+        let big_endian = match data.first()? {
+            b'l' => false,
+            b'B' => true,
+            _ => return None,
+        };
+        let mut reader = Reader {
+            data,
+            pos: 4,
+            big_endian,
+        };
+        let body_len = reader.u32()? as usize;
+        let mut message = Message {
+            kind: data[1],
+            flags: data[2],
+            serial: reader.u32()?,
+            big_endian,
+            ..Message::default()
+        };
+        let fields_end = reader.u32()? as usize + 16;
+        while reader.pos < fields_end {
+            reader.align(8);
+            let code = *reader.take(1)?.first()?;
+            let signature = reader.signature()?;
+            match (code, signature.as_str()) {
+                (1, "o") => message.path = Some(reader.string()?),
+                (2, "s") => message.interface = Some(reader.string()?),
+                (3, "s") => message.member = Some(reader.string()?),
+                (4, "s") => message.error_name = Some(reader.string()?),
+                (5, "u") => message.reply_serial = Some(reader.u32()?),
+                (7, "s") => message.sender = Some(reader.string()?),
+                (8, "g") => message.signature = reader.signature()?,
+                (_, "s" | "o") => {
+                    reader.string()?;
+                }
+                (_, "g") => {
+                    reader.signature()?;
+                }
+                (_, "u") => {
+                    reader.u32()?;
+                }
+                _ => return None,
+            }
+        }
+        let body_start = fields_end.next_multiple_of(8);
+        message.body = data.get(body_start..body_start + body_len)?.to_vec();
+        Some(message)
+    }
+
+    /// Reads one whole message off the stream; `None` once the bus goes away,
+    /// announces more than `MAX_MESSAGE_LEN` bytes, or sends something that
+    /// does not parse.
+    fn read(stream: &mut impl Read) -> Option<Message> {
+        let mut fixed = [0u8; 16];
+        stream.read_exact(&mut fixed).ok()?;
+        let word = |offset: usize| {
+            let bytes: [u8; 4] = fixed[offset..offset + 4].try_into().unwrap_or_default();
+            if fixed[0] == b'B' {
+                u32::from_be_bytes(bytes)
+            } else {
+                u32::from_le_bytes(bytes)
+            }
+        };
+        let fields_len = word(12) as usize;
+        let body_len = word(4) as usize;
+        if fields_len > MAX_MESSAGE_LEN || body_len > MAX_MESSAGE_LEN {
+            return None;
+        }
+        let total = (16 + fields_len).next_multiple_of(8) + body_len;
+        if total > MAX_MESSAGE_LEN {
+            return None;
+        }
+        let mut data = fixed.to_vec();
+        data.resize(total, 0);
+        stream.read_exact(&mut data[16..]).ok()?;
+        Message::parse(&data)
+    }
+
+    /// The body's single string argument, if that is what the call carries.
+    pub(crate) fn string_arg(&self) -> Option<String> {
+        if self.signature != "s" {
+            return None;
+        }
+        Reader {
+            data: &self.body,
+            pos: 0,
+            big_endian: self.big_endian,
+        }
+        .string()
+    }
+
+    pub(crate) fn is(&self, interface: &str, member: &str) -> bool {
+        self.interface
+            .as_deref()
+            .is_none_or(|name| name == interface)
+            && self.member.as_deref() == Some(member)
+    }
+}
+
+/// Marshals a complete message; `fields` writes the header fields other than
+/// the body signature.
+fn encode(
+    serial: u32,
+    kind: MessageType,
+    fields: impl FnOnce(&mut Writer),
+    signature: &str,
+    body: &[u8],
+) -> Vec<u8> {
+    let mut message = Writer::default();
+    message.buf.extend([b'l', kind as u8, 0, 1]);
+    message.u32(body.len() as u32);
+    message.u32(serial);
+    message.u32(0);
+    let start = message.buf.len();
+    fields(&mut message);
+    if !signature.is_empty() {
+        message.header_field(8, "g", |writer| writer.signature(signature));
+    }
+    let length = (message.buf.len() - start) as u32;
+    message.buf[12..16].copy_from_slice(&length.to_le_bytes());
+    message.align(8);
+    message.buf.extend(body);
+    message.buf
+}
+
+/// A session bus connection: messages are written on the caller's thread and
+/// read on a background thread into `incoming`.
+pub(crate) struct Connection {
+    stream: UnixStream,
+    serial: u32,
+    pub(crate) incoming: mpsc::Receiver<Message>,
+}
+
+impl Connection {
+    /// Connects to the session bus and claims `name`, failing if another
+    /// process already owns it.
+    pub(crate) fn session(name: &str) -> Result<Connection, CliError> {
+        let mut stream = session_stream()?;
+        let uid = std::fs::metadata("/proc/self")
+            .map_err(|err| format!("failed to determine uid: {err}"))?
+            .uid();
+        let hex_uid: String = uid
+            .to_string()
+            .bytes()
+            .map(|b| format!("{b:02x}"))
+            .collect();
+        stream
+            .write_all(format!("\0AUTH EXTERNAL {hex_uid}\r\n").as_bytes())
+            .map_err(|err| format!("failed to authenticate with the session bus: {err}"))?;
+        let mut line = Vec::new();
+        let mut byte = [0u8; 1];
+        while !line.ends_with(b"\r\n") {
+            match stream.read(&mut byte) {
+                Ok(1) => line.push(byte[0]),
+                _ => {
+                    return Err("session bus closed the connection during authentication"
+                        .to_string()
+                        .into())
+                }
+            }
+        }
+        if !line.starts_with(b"OK ") {
+            return Err(format!(
+                "session bus rejected authentication: {}",
+                String::from_utf8_lossy(&line).trim()
+            )
+            .into());
+        }
+        stream
+            .write_all(b"BEGIN\r\n")
+            .map_err(|err| format!("failed to authenticate with the session bus: {err}"))?;
+
+        let mut reader = stream
+            .try_clone()
+            .map_err(|err| format!("failed to clone bus socket: {err}"))?;
+        let (sender, incoming) = mpsc::channel();
+        thread::spawn(move || {
+            while let Some(message) = Message::read(&mut reader) {
+                if sender.send(message).is_err() {
+                    break;
+                }
+            }
+        });
+        let mut connection = Connection {
+            stream,
+            serial: 0,
+            incoming,
+        };
+        connection.call_bus("Hello", None)?;
+        let reply = connection.call_bus("RequestName", Some(name))?;
+        let result = Reader {
+            data: &reply.body,
+            pos: 0,
+            big_endian: reply.big_endian,
+        }
+        .u32();
+        if result != Some(1) {
+            return Err(format!("{name} is already owned on the session bus").into());
+        }
+        Ok(connection)
+    }
+
+    fn send(
+        &mut self,
+        kind: MessageType,
+        fields: impl FnOnce(&mut Writer),
+        signature: &str,
+        body: &[u8],
+    ) -> Result<u32, CliError> {
+        self.serial += 1;
+        let message = encode(self.serial, kind, fields, signature, body);
+        self.stream
+            .write_all(&message)
+            .map_err(|err| format!("failed to write to the session bus: {err}"))?;
+        Ok(self.serial)
+    }
+
+    /// Calls a method on the bus daemon itself and waits for its reply.
+    fn call_bus(&mut self, member: &str, arg: Option<&str>) -> Result<Message, CliError> {
+        let mut body = Writer::default();
+        if let Some(arg) = arg {
+            body.string(arg);
+            // DBUS_NAME_FLAG_DO_NOT_QUEUE: fail instead of waiting for the name.
+            body.u32(4);
+        }
+        let serial = self.send(
+            MessageType::MethodCall,
+            |header| {
+                header.header_field(1, "o", |writer| writer.string("/org/freedesktop/DBus"));
+                header.header_field(2, "s", |writer| writer.string("org.freedesktop.DBus"));
+                header.header_field(3, "s", |writer| writer.string(member));
+                header.header_field(6, "s", |writer| writer.string("org.freedesktop.DBus"));
+            },
+            if arg.is_some() { "su" } else { "" },
+            &body.buf,
+        )?;
+        loop {
+            let message = self
+                .incoming
+                .recv()
+                .map_err(|_| "session bus closed the connection".to_string())?;
+            if message.reply_serial != Some(serial) {
+                continue;
+            }
+            if message.kind == MessageType::Error as u8 {
+                return Err(format!(
+                    "{member} failed: {}",
+                    message.error_name.as_deref().unwrap_or("unknown error")
+                )
+                .into());
+            }
+            return Ok(message);
+        }
+    }
+
+    pub(crate) fn reply(
+        &mut self,
+        call: &Message,
+        signature: &str,
+        body: &[u8],
+    ) -> Result<(), CliError> {
+        // NO_REPLY_EXPECTED
+        if call.flags & 0x1 != 0 {
+            return Ok(());
+        }
+        self.send(
+            MessageType::MethodReturn,
+            |header| {
+                header.header_field(5, "u", |writer| writer.u32(call.serial));
+                if let Some(sender) = &call.sender {
+                    header.header_field(6, "s", |writer| writer.string(sender));
+                }
+            },
+            signature,
+            body,
+        )?;
+        Ok(())
+    }
+
+    pub(crate) fn reply_error(
+        &mut self,
+        call: &Message,
+        name: &str,
+        text: &str,
+    ) -> Result<(), CliError> {
+        if call.flags & 0x1 != 0 {
+            return Ok(());
+        }
+        let mut body = Writer::default();
+        body.string(text);
+        self.send(
+            MessageType::Error,
+            |header| {
+                header.header_field(4, "s", |writer| writer.string(name));
+                header.header_field(5, "u", |writer| writer.u32(call.serial));
+                if let Some(sender) = &call.sender {
+                    header.header_field(6, "s", |writer| writer.string(sender));
+                }
+            },
+            "s",
+            &body.buf,
+        )?;
+        Ok(())
+    }
+
+    pub(crate) fn emit(
+        &mut self,
+        path: &str,
+        interface: &str,
+        member: &str,
+    ) -> Result<(), CliError> {
+        self.send(
+            MessageType::Signal,
+            |header| {
+                header.header_field(1, "o", |writer| writer.string(path));
+                header.header_field(2, "s", |writer| writer.string(interface));
+                header.header_field(3, "s", |writer| writer.string(member));
+            },
+            "",
+            &[],
+        )?;
+        Ok(())
+    }
+}
+
+fn session_stream() -> Result<UnixStream, CliError> {
+    let address = env::var("DBUS_SESSION_BUS_ADDRESS").ok().or_else(|| {
+        env::var("XDG_RUNTIME_DIR")
+            .ok()
+            .map(|dir| format!("unix:path={dir}/bus"))
+    });
+    let address = address
+        .ok_or_else(|| "DBUS_SESSION_BUS_ADDRESS and XDG_RUNTIME_DIR are not set".to_string())?;
+    for entry in address.split(';') {
+        let Some(params) = entry.strip_prefix("unix:") else {
+            continue;
+        };
+        for param in params.split(',') {
+            let stream = match param.split_once('=') {
+                Some(("path", path)) => UnixStream::connect(path),
+                Some(("abstract", name)) => {
+                    use std::os::linux::net::SocketAddrExt;
+                    std::os::unix::net::SocketAddr::from_abstract_name(name)
+                        .and_then(|addr| UnixStream::connect_addr(&addr))
+                }
+                _ => continue,
+            };
+            return stream
+                .map_err(|err| format!("failed to connect to the session bus: {err}").into());
+        }
+    }
+    Err(format!("unsupported session bus address: {address}").into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn method_call(serial: u32, member: &str, arg: Option<&str>) -> Vec<u8> {
+        let mut body = Writer::default();
+        if let Some(arg) = arg {
+            body.string(arg);
+        }
+        encode(
+            serial,
+            MessageType::MethodCall,
+            |header| {
+                header.header_field(1, "o", |writer| writer.string("/org/xrandrutils/Displays"));
+                header.header_field(2, "s", |writer| writer.string("org.xrandrutils.Displays"));
+                header.header_field(3, "s", |writer| writer.string(member));
+                header.header_field(7, "s", |writer| writer.string(":1.42"));
+            },
+            if arg.is_some() { "s" } else { "" },
+            &body.buf,
+        )
+    }
+
+    #[test]
+    fn encoded_calls_parse_back() {
+        let data = method_call(7, "GetGeometry", Some("DP-1"));
+        let message = Message::parse(&data).unwrap();
+        assert!(message.kind == MessageType::MethodCall as u8);
+        assert_eq!(message.serial, 7);
+        assert_eq!(message.path.as_deref(), Some("/org/xrandrutils/Displays"));
+        assert_eq!(message.sender.as_deref(), Some(":1.42"));
+        assert_eq!(message.signature, "s");
+        assert!(message.is("org.xrandrutils.Displays", "GetGeometry"));
+        assert!(!message.is("org.freedesktop.DBus.Peer", "GetGeometry"));
+        assert_eq!(message.string_arg().as_deref(), Some("DP-1"));
+
+        let message = Message::parse(&method_call(8, "ListDisplays", None)).unwrap();
+        assert_eq!(message.signature, "");
+        assert_eq!(message.string_arg(), None);
+        assert!(message.body.is_empty());
+    }
+
+    #[test]
+    fn string_arrays_carry_their_byte_length() {
+        let mut writer = Writer::default();
+        writer.string_array(&["eDP-1".to_string(), "DP-1".to_string()]);
+        let mut reader = Reader {
+            data: &writer.buf,
+            pos: 0,
+            big_endian: false,
+        };
+        let length = reader.u32().unwrap() as usize;
+        assert_eq!(length, writer.buf.len() - 4);
+        assert_eq!(reader.string().as_deref(), Some("eDP-1"));
+        assert_eq!(reader.string().as_deref(), Some("DP-1"));
+        assert_eq!(reader.pos, writer.buf.len());
+    }
+
+    #[test]
+    fn big_endian_messages_parse() {
+        let data = [
+            b'B', 1, 0, 1, 0, 0, 0, 0, 0, 0, 0, 9, 0, 0, 0, 13, //
+            3, 1, b's', 0, 0, 0, 0, 4, b'P', b'i', b'n', b'g', 0, 0, 0, 0,
+        ];
+        let message = Message::parse(&data).unwrap();
+        assert!(message.big_endian);
+        assert_eq!(message.serial, 9);
+        assert_eq!(message.member.as_deref(), Some("Ping"));
+    }
+
+    #[test]
+    fn read_frames_messages_from_a_stream() {
+        let mut data = method_call(1, "Query", Some("display_names"));
+        data.extend(method_call(2, "Ping", None));
+        let mut stream = &data[..];
+        let first = Message::read(&mut stream).unwrap();
+        assert_eq!(first.string_arg().as_deref(), Some("display_names"));
+        let second = Message::read(&mut stream).unwrap();
+        assert_eq!(second.serial, 2);
+        assert!(Message::read(&mut stream).is_none());
+
+        let truncated = method_call(3, "Query", Some("display_names"));
+        assert!(Message::read(&mut &truncated[..truncated.len() - 1]).is_none());
+
+        let mut oversized = method_call(4, "Ping", None);
+        oversized[4..8].copy_from_slice(&(MAX_MESSAGE_LEN as u32).to_le_bytes());
+        assert!(Message::read(&mut &oversized[..]).is_none());
+    }
+}
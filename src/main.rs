@@ -1,116 +1,928 @@
+mod dbus;
+
+use std::cell::RefCell;
 use std::collections::{HashMap, HashSet};
 use std::env;
-use std::io::{self, IsTerminal, Read, Write};
+use std::fmt;
+use std::io::{self, BufRead, IsTerminal, Read, Write};
+use std::iter::Peekable;
+use std::os::unix::fs::{FileTypeExt, MetadataExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
 use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, OnceLock, RwLock};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+// Local stand-ins for the std printing macros: output goes to stdout unless
+// `capture_output` is collecting it for batch, serve or the D-Bus service.
+macro_rules! print {
+    ($($arg:tt)*) => {
+        write_output(format_args!($($arg)*))
+    };
+}
+
+macro_rules! println {
+    () => {
+        write_output(format_args!("\n"))
+    };
+    ($($arg:tt)*) => {
+        write_output(format_args!("{}\n", format_args!($($arg)*)))
+    };
+}
+
+thread_local! {
+    static CAPTURED_OUTPUT: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+fn write_output(args: fmt::Arguments) {
+    let captured = CAPTURED_OUTPUT.with(|captured| match captured.borrow_mut().as_mut() {
+        Some(buffer) => {
+            let _ = fmt::Write::write_fmt(buffer, args);
+            true
+        }
+        None => false,
+    });
+    if !captured {
+        let _ = io::stdout().write_fmt(args);
+    }
+}
+
+fn capture_output<T>(run: impl FnOnce() -> T) -> (T, String) {
+    let outer = CAPTURED_OUTPUT.with(|captured| captured.borrow_mut().replace(String::new()));
+    let result = run();
+    let output = CAPTURED_OUTPUT
+        .with(|captured| captured.replace(outer))
+        .unwrap_or_default();
+    (result, output)
+}
 
 fn main() {
-    if let Err(err) = run() {
-        eprintln!("{err}");
-        std::process::exit(1);
+    match run() {
+        Ok(Outcome::Done) => {}
+        Ok(outcome) => std::process::exit(outcome.exit_code()),
+        Err(err) => {
+            if JSON_ERRORS.load(Ordering::Relaxed) {
+                eprintln!("{}", err.json());
+            } else {
+                eprintln!("{err}");
+            }
+            std::process::exit(err.exit_code());
+        }
+    }
+}
+
+/// Result of a command that ran to completion, mapped to its exit status.
+#[derive(Clone, Copy, PartialEq)]
+enum Outcome {
+    Done,
+    Changed,
+    False,
+    Failed,
+    /// A command run on the user's behalf failed; its exit code is passed through.
+    Exited(i32),
+}
+
+impl Outcome {
+    fn exit_code(self) -> i32 {
+        match self {
+            Outcome::Done => 0,
+            Outcome::False | Outcome::Failed => 1,
+            Outcome::Changed => 6,
+            Outcome::Exited(code) => code,
+        }
+    }
+}
+
+#[derive(Clone)]
+enum CliError {
+    NotFound(String),
+    Usage(String),
+    Backend(String),
+    EdidMissing(String),
+    General(String),
+}
+
+impl CliError {
+    fn exit_code(&self) -> i32 {
+        match self {
+            CliError::General(_) => 1,
+            CliError::NotFound(_) => 2,
+            CliError::Backend(_) => 3,
+            CliError::EdidMissing(_) => 4,
+            CliError::Usage(_) => 5,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            CliError::General(_) => "error",
+            CliError::NotFound(_) => "display_not_found",
+            CliError::Backend(_) => "backend_failed",
+            CliError::EdidMissing(_) => "edid_missing",
+            CliError::Usage(_) => "usage",
+        }
+    }
+
+    fn message(&self) -> &str {
+        match self {
+            CliError::NotFound(message)
+            | CliError::Usage(message)
+            | CliError::Backend(message)
+            | CliError::EdidMissing(message)
+            | CliError::General(message) => message,
+        }
+    }
+
+    fn json(&self) -> String {
+        format!(
+            "{{\"error\": {}, \"message\": {}, \"exit_code\": {}}}",
+            json_string(self.name()),
+            json_string(self.message()),
+            self.exit_code()
+        )
+    }
+}
+
+impl fmt::Display for CliError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.message())
+    }
+}
+
+impl From<String> for CliError {
+    fn from(message: String) -> CliError {
+        CliError::General(message)
     }
 }
 
-fn run() -> Result<(), String> {
-    let mut args = env::args();
-    let _binary = args.next();
+#[derive(Default)]
+struct GlobalOptions {
+    debug_parse: bool,
+    dry_run: bool,
+    fuzzy: bool,
+    allow_remote: bool,
+    ignore: Vec<String>,
+}
+
+static DRY_RUN: AtomicBool = AtomicBool::new(false);
+static ALLOW_REMOTE: AtomicBool = AtomicBool::new(false);
+static FUZZY_NAMES: AtomicBool = AtomicBool::new(false);
+static JSON_ERRORS: AtomicBool = AtomicBool::new(false);
+static CURRENT_SEAT_ONLY: AtomicBool = AtomicBool::new(false);
+/// Set when the layout text is a replay (stdin, --input or a snapshot) rather than a
+/// live query, so nothing from this machine's sysfs or logind is mixed into it.
+static REPLAYED_INPUT: AtomicBool = AtomicBool::new(false);
+static SEAT_CARDS: OnceLock<Result<HashMap<String, String>, CliError>> = OnceLock::new();
+static CURRENT_SEAT: OnceLock<Result<String, CliError>> = OnceLock::new();
+static INPUT_PATH: OnceLock<String> = OnceLock::new();
+static IGNORED_DISPLAYS: OnceLock<Vec<NamePattern>> = OnceLock::new();
+
+fn run() -> Result<Outcome, CliError> {
+    let mut args = env::args().skip(1).peekable();
+    let mut options = GlobalOptions::default();
+    while let Some(arg) = args.peek() {
+        match arg.as_str() {
+            "--debug-parse" => options.debug_parse = true,
+            "--dry-run" => options.dry_run = true,
+            "--fuzzy" => options.fuzzy = true,
+            "--allow-remote" => options.allow_remote = true,
+            "--current-seat" => CURRENT_SEAT_ONLY.store(true, Ordering::Relaxed),
+            "--input" => {
+                args.next();
+                let path = expect_arg(&mut args, "input file")?;
+                INPUT_PATH.get_or_init(|| path);
+                continue;
+            }
+            "--error-format" => {
+                args.next();
+                let format = expect_arg(&mut args, "error format")?;
+                match format.as_str() {
+                    "json" => JSON_ERRORS.store(true, Ordering::Relaxed),
+                    "text" => JSON_ERRORS.store(false, Ordering::Relaxed),
+                    _ => {
+                        return Err(format!(
+                            "invalid error format: {format} (expected text or json)"
+                        )
+                        .into())
+                    }
+                }
+                continue;
+            }
+            "--ignore" => {
+                args.next();
+                options
+                    .ignore
+                    .push(expect_arg(&mut args, "ignore selector")?);
+                continue;
+            }
+            _ => break,
+        }
+        args.next();
+    }
+    DRY_RUN.store(options.dry_run, Ordering::Relaxed);
+    ALLOW_REMOTE.store(options.allow_remote, Ordering::Relaxed);
+    let fuzzy_env = env::var("XRANDR_UTILS_FUZZY").is_ok_and(|value| value == "1");
+    FUZZY_NAMES.store(options.fuzzy || fuzzy_env, Ordering::Relaxed);
+    if let Ok(value) = env::var("XRANDR_UTILS_IGNORE") {
+        options.ignore.extend(
+            value
+                .split(',')
+                .map(str::trim)
+                .filter(|selector| !selector.is_empty())
+                .map(str::to_string),
+        );
+    }
+    let ignored = options
+        .ignore
+        .iter()
+        .map(|selector| display_name_matcher(selector))
+        .collect::<Result<Vec<_>, _>>()?;
+    IGNORED_DISPLAYS.get_or_init(|| ignored);
+
     let command = match args.next() {
         Some(value) => value,
+        None if options.debug_parse => String::new(),
         None => {
             print_usage();
-            return Ok(());
+            return Ok(Outcome::Done);
         }
     };
 
     if matches!(command.as_str(), "--help" | "-h" | "help") {
         print_usage();
-        return Ok(());
+        return Ok(Outcome::Done);
+    }
+
+    if command == "bench" {
+        return run_bench(&mut args).map(|()| Outcome::Done);
+    }
+    let backend = Backend::detect()?;
+    if command == "simulate" {
+        return run_simulate_command(&mut args, backend);
+    }
+    if command == "serve" {
+        return run_serve(&mut args, backend).map(|()| Outcome::Done);
+    }
+    let verbose = get_verbose_text(backend)?;
+    check_current_seat()?;
+    if options.debug_parse {
+        print_parse_debug(backend, &verbose);
+        if command.is_empty() {
+            return Ok(Outcome::Done);
+        }
+    }
+    if command == "batch" {
+        return run_batch(args, backend, &verbose).map(|()| Outcome::Done);
+    }
+    let sections = parse_backend_sections(backend, &verbose);
+    run_command(&command, args, sections, backend, &verbose)
+}
+
+/// Commands that only read the parsed layout, and so may run inside batch, serve
+/// and the D-Bus service.
+const QUERY_COMMANDS: &[&str] = &[
+    "assert",
+    "capabilities",
+    "connector_alias_map",
+    "display_bandwidth",
+    "display_brightness",
+    "display_connected",
+    "display_connected_map",
+    "display_connector",
+    "display_connector_map",
+    "display_crtc",
+    "display_crtc_map",
+    "display_current_mode",
+    "display_current_mode_map",
+    "display_dpi",
+    "display_dpi_map",
+    "display_edid",
+    "display_edid_decoded",
+    "display_edid_info",
+    "display_edid_validate",
+    "display_gamma",
+    "display_gamma_map",
+    "display_geometry",
+    "display_geometry_map",
+    "display_identity_map",
+    "display_is_primary",
+    "display_label_line",
+    "display_manufacturer",
+    "display_manufacturer_map",
+    "display_model",
+    "display_model_map",
+    "display_modes",
+    "display_modes_map",
+    "display_name_for",
+    "display_names",
+    "display_native_check",
+    "display_physical_size",
+    "display_physical_size_map",
+    "display_preferred_map",
+    "display_preferred_mode",
+    "display_preferred_mode_map",
+    "display_primary",
+    "display_properties",
+    "display_property",
+    "display_rate",
+    "display_rates_map",
+    "display_rotation",
+    "display_rotation_map",
+    "display_section",
+    "display_section_map",
+    "display_serial",
+    "display_serial_map",
+    "display_state",
+    "display_state_map",
+    "display_transform",
+    "display_uptime_map",
+    "display_uptime_metrics",
+    "get",
+    "layout_check",
+    "monitor_serial_get_displays",
+    "monitor_serial_has_displays",
+    "seat_map",
+];
+
+fn check_query_command(command: &str, context: &str) -> Result<(), CliError> {
+    if QUERY_COMMANDS.contains(&command) {
+        Ok(())
+    } else {
+        Err(CliError::Usage(format!(
+            "{command} cannot be used inside {context} (only query commands are allowed)"
+        )))
     }
+}
 
-    let verbose = get_verbose_text()?;
-    let sections = parse_sections(&verbose);
+fn run_batch<I: Iterator<Item = String>>(
+    args: I,
+    backend: Backend,
+    verbose: &str,
+) -> Result<(), CliError> {
     let mut args = args.peekable();
+    let json = args.next_if(|arg| arg == "--json").is_some();
+    let queries = args
+        .map(|query| split_command_words(&query).map(|words| (query, words)))
+        .collect::<Result<Vec<_>, _>>()?;
+    if queries.is_empty() {
+        return Err(CliError::Usage(
+            "batch requires at least one command".to_string(),
+        ));
+    }
+    for (_, words) in &queries {
+        let command = words
+            .first()
+            .ok_or_else(|| CliError::Usage("batch command must not be empty".to_string()))?;
+        check_query_command(command, "batch")?;
+    }
+
+    let mut results = Vec::new();
+    for (index, (query, words)) in queries.into_iter().enumerate() {
+        let mut words = words.into_iter();
+        let command = words.next().unwrap_or_default();
+        let sections = parse_backend_sections(backend, verbose);
+        let (result, output) =
+            capture_output(|| run_command(&command, words.peekable(), sections, backend, verbose));
+        if json {
+            let (exit_code, error) = match &result {
+                Ok(outcome) => (outcome.exit_code(), "null".to_string()),
+                Err(err) => (err.exit_code(), json_string(err.message())),
+            };
+            results.push(format!(
+                "{{\"command\": {}, \"exit_code\": {exit_code}, \"output\": {}, \"error\": {error}}}",
+                json_string(&query),
+                json_string(&output)
+            ));
+            continue;
+        }
+        if index > 0 {
+            println!();
+        }
+        println!("==> {query} <==");
+        print!("{output}");
+        result?;
+    }
+    if json {
+        println!("[{}]", results.join(", "));
+    }
+    Ok(())
+}
 
-    match command.as_str() {
+fn run_command<I: Iterator<Item = String>>(
+    command: &str,
+    mut args: Peekable<I>,
+    sections: Vec<DisplaySection>,
+    backend: Backend,
+    verbose: &str,
+) -> Result<Outcome, CliError> {
+    match command {
         "display_connected" => {
-            let display = expect_arg(&mut args, "display")?;
+            let display = expect_display(&mut args, "display", &sections)?;
             let section = find_section(&sections, &display)
-                .ok_or_else(|| format!("display not found: {display}"))?;
+                .ok_or_else(|| display_not_found(&sections, &display))?;
             println!("{}", section.state.as_str());
         }
+        "display_state" => {
+            let display = expect_display(&mut args, "display", &sections)?;
+            let section = find_section(&sections, &display)
+                .ok_or_else(|| display_not_found(&sections, &display))?;
+            println!("{}", section_activity(section));
+        }
+        "display_state_map" => {
+            let mut map = MapOutput::new(parse_map_flags(&mut args, false)?);
+            for section in &sections {
+                map.entry(&section.name, section_activity(section));
+            }
+            map.finish();
+        }
+        "display_uptime_map" => {
+            let mut map = MapOutput::new(parse_map_flags(&mut args, false)?);
+            let now = unix_timestamp();
+            for record in read_uptime_records()? {
+                map.entry(
+                    &record.name,
+                    &format!(
+                        "{} uptime={} changes={}",
+                        record.state,
+                        now.saturating_sub(record.since),
+                        record.changes
+                    ),
+                );
+            }
+            map.finish();
+        }
+        "display_uptime_metrics" => {
+            if let Some(other) = args.next() {
+                return Err(CliError::Usage(format!("unknown option: {other}")));
+            }
+            print!("{}", uptime_metrics(&read_uptime_records()?));
+        }
         "single_display_output" => {
-            let keep = expect_arg(&mut args, "display")?;
-            run_single_display_output(&keep, &sections)?;
+            let keep = expect_display(&mut args, "display", &sections)?;
+            let flags = parse_apply_flags(&mut args)?;
+            return run_single_display_output(
+                &keep,
+                &sections,
+                screen_maximum_size(verbose),
+                backend,
+                &flags,
+            );
         }
         "dual_display_output" => {
-            let left = expect_arg(&mut args, "left display")?;
-            let right = expect_arg(&mut args, "right display")?;
-            run_dual_display_output(&left, &right, &sections)?;
+            let left = expect_display(&mut args, "left display", &sections)?;
+            let right = expect_display(&mut args, "right display", &sections)?;
+            let (primary, align, mut flags) = parse_dual_args(&mut args)?;
+            align_dual_scales(
+                &mut flags,
+                [&left, &right],
+                primary,
+                align,
+                &sections,
+                backend,
+            )?;
+            return run_dual_display_output(
+                &left,
+                &right,
+                primary,
+                &sections,
+                screen_maximum_size(verbose),
+                backend,
+                &flags,
+            );
+        }
+        "triple_display_output" => {
+            let left = expect_display(&mut args, "left display", &sections)?;
+            let middle = expect_display(&mut args, "middle display", &sections)?;
+            let right = expect_display(&mut args, "right display", &sections)?;
+            let flags = parse_apply_flags(&mut args)?;
+            return run_row_display_output(
+                &[left, middle, right],
+                0,
+                &sections,
+                screen_maximum_size(verbose),
+                backend,
+                &flags,
+            );
+        }
+        "extend_all" => {
+            let mut sort = "position".to_string();
+            let mut flags = ApplyFlags::default();
+            while let Some(arg) = args.next() {
+                match arg.as_str() {
+                    "--sort" => sort = expect_arg(&mut args, "sort order")?,
+                    _ if flags.accept(&arg, &mut args)? => {}
+                    other => return Err(CliError::Usage(format!("unknown option: {other}"))),
+                }
+            }
+            let mut displays = connected_in_order(&sections, &sort)?;
+            let mut primary = displays
+                .iter()
+                .position(|name| {
+                    find_section(&sections, name).is_some_and(|section| section.primary)
+                })
+                .unwrap_or(0);
+            let overrides = match read_optional_user_config()? {
+                Some(text) => output_overrides(&parse_config(&text)?)?,
+                None => Vec::new(),
+            };
+            apply_output_overrides(
+                &overrides,
+                &sections,
+                &mut displays,
+                &mut primary,
+                &mut flags,
+            )?;
+            return run_row_display_output(
+                &displays,
+                primary,
+                &sections,
+                screen_maximum_size(verbose),
+                backend,
+                &flags,
+            );
+        }
+        "row_display_output" | "apply_layout" => {
+            let (displays, primary, flags) = parse_row_args(&mut args)?;
+            let displays = expand_display_patterns(&sections, &displays)?;
+            return run_row_display_output(
+                &displays,
+                primary,
+                &sections,
+                screen_maximum_size(verbose),
+                backend,
+                &flags,
+            );
+        }
+        "layout" | "profile" => {
+            let action = expect_arg(&mut args, "layout action")?;
+            match action.as_str() {
+                "apply" => {
+                    let path = expect_arg(&mut args, "layout file")?;
+                    let mut flags = ApplyFlags::default();
+                    let mut substitution = Substitution::Never;
+                    while let Some(arg) = args.next() {
+                        match arg.as_str() {
+                            "--substitute" => substitution = Substitution::Offer,
+                            "--auto-substitute" => substitution = Substitution::Auto,
+                            _ if flags.accept(&arg, &mut args)? => {}
+                            _ => return Err(CliError::Usage(format!("unknown option: {arg}"))),
+                        }
+                    }
+                    let layout = load_layout(&path)?;
+                    let result = run_layout_apply(layout, &sections, backend, &flags, substitution);
+                    return guard_json_failure(result, &flags);
+                }
+                "import-autorandr" => {
+                    let dir = autorandr_profile_dir(&expect_arg(&mut args, "autorandr profile")?)?;
+                    if let Some(other) = args.next() {
+                        return Err(CliError::Usage(format!("unknown option: {other}")));
+                    }
+                    print!("{}", layout_toml(&import_autorandr(&dir)?));
+                }
+                "export-autorandr" => {
+                    let path = expect_arg(&mut args, "layout file")?;
+                    let dir = autorandr_profile_dir(&expect_arg(&mut args, "autorandr profile")?)?;
+                    let force = match args.next().as_deref() {
+                        Some("--force") => true,
+                        Some(other) => {
+                            return Err(CliError::Usage(format!("unknown option: {other}")))
+                        }
+                        None => false,
+                    };
+                    export_autorandr(load_layout(&path)?, &sections, backend, &dir, force)?;
+                }
+                _ => return Err(CliError::Usage(format!("unknown layout action: {action}"))),
+            }
+        }
+        "preset" => {
+            let name = expect_arg(&mut args, "preset name")?;
+            let entries = parse_config(&read_user_config()?)?;
+            if name == "--list" {
+                for preset in preset_names(&entries) {
+                    println!("{preset}");
+                }
+                return Ok(Outcome::Done);
+            }
+            match find_preset(entries, &name)? {
+                Preset::Command(words) => {
+                    let words: Vec<String> = words.into_iter().chain(args).collect();
+                    // toggle runs presets too, so either one could recurse forever.
+                    if matches!(words[0].as_str(), "preset" | "toggle") {
+                        return Err(format!(
+                            "preset {name} cannot invoke {} (presets must not run other presets)",
+                            words[0]
+                        )
+                        .into());
+                    }
+                    let mut words = words.into_iter().peekable();
+                    let preset_command = expect_arg(&mut words, "preset command")?;
+                    let sections = parse_backend_sections(backend, verbose);
+                    return run_command(&preset_command, words, sections, backend, verbose);
+                }
+                Preset::Layout(layout) => {
+                    let mut flags = ApplyFlags::default();
+                    while let Some(arg) = args.next() {
+                        if !flags.accept(&arg, &mut args)? {
+                            return Err(CliError::Usage(format!("unknown option: {arg}")));
+                        }
+                    }
+                    let result =
+                        run_layout_apply(layout, &sections, backend, &flags, Substitution::Never);
+                    return guard_json_failure(result, &flags);
+                }
+            }
+        }
+        "toggle" => {
+            let mut presets = Vec::new();
+            while let Some(name) = args.next_if(|arg| !arg.starts_with("--")) {
+                presets.push(name);
+            }
+            let rest: Vec<String> = args.collect();
+            let steps = toggle_steps(&sections, &presets)?;
+            let last = read_state("toggle")?.map(|saved| saved.trim().to_string());
+            let index = last
+                .and_then(|last| steps.iter().position(|(name, _)| *name == last))
+                .map_or(0, |index| (index + 1) % steps.len());
+            let (name, words) = steps.into_iter().nth(index).unwrap_or_default();
+            let check_only = rest.iter().any(|arg| arg == "--check");
+            let mut words = words
+                .into_iter()
+                .chain(rest)
+                .collect::<Vec<_>>()
+                .into_iter()
+                .peekable();
+            let step_command = expect_arg(&mut words, "toggle command")?;
+            let outcome = run_command(&step_command, words, sections, backend, verbose)?;
+            if !is_dry_run() && !check_only {
+                write_state("toggle", &format!("{name}\n"))?;
+            }
+            return Ok(outcome);
+        }
+        "layout_check" => {
+            let issues = layout_offset_issues(&current_output_states(&sections));
+            for issue in &issues {
+                println!("{issue}");
+            }
+            if !issues.is_empty() {
+                return Ok(Outcome::False);
+            }
+        }
+        "normalize_layout" => {
+            let flags = parse_apply_flags(&mut args)?;
+            let result = run_normalize_layout(&sections, backend, &flags);
+            return guard_json_failure(result, &flags);
+        }
+        "mirror_display_output" => {
+            let source = expect_display(&mut args, "source display", &sections)?;
+            let target = expect_display(&mut args, "target display", &sections)?;
+            run_mirror_display_output(&source, &target, &sections, backend)?;
+        }
+        "monitor_power" => {
+            let display = expect_display(&mut args, "display", &sections)?;
+            let state = expect_arg(&mut args, "power state")?;
+            let install_rule = match args.next().as_deref() {
+                Some("--install-udev-rule") => true,
+                Some(other) => return Err(CliError::Usage(format!("unknown option: {other}"))),
+                None => false,
+            };
+            run_monitor_power(&display, &state, &sections, install_rule)?;
         }
         "display_connected_map" => {
-            let flags = parse_map_flags(&mut args, false)?;
-            let mut seen_values = HashSet::new();
+            let mut map = MapOutput::new(parse_map_flags(&mut args, false)?);
             for section in &sections {
                 let value = section.state.as_str();
-                output_map_entry(&section.name, value, &flags, &mut seen_values);
+                map.entry(&section.name, value);
             }
+            map.finish();
         }
         "display_section" => {
-            let display = expect_arg(&mut args, "display")?;
+            let display = expect_display(&mut args, "display", &sections)?;
             let section = find_section(&sections, &display)
-                .ok_or_else(|| format!("display not found: {display}"))?;
+                .ok_or_else(|| display_not_found(&sections, &display))?;
             let text = section.lines.join("\n");
             if text.is_empty() {
-                return Err("section is empty".to_string());
+                return Err("section is empty".to_string().into());
             }
             println!("{text}");
         }
         "display_section_map" => {
-            let flags = parse_map_flags(&mut args, false)?;
-            let mut seen_values = HashSet::new();
+            let mut map = MapOutput::new(parse_map_flags(&mut args, false)?);
             for section in &sections {
                 let text = section.lines.join("\n");
                 let escaped = escape_multiline(&text);
-                output_map_entry(&section.name, &escaped, &flags, &mut seen_values);
+                map.entry(&section.name, &escaped);
             }
+            map.finish();
         }
         "display_edid" => {
-            let display = expect_arg(&mut args, "display")?;
+            require_properties(backend, verbose, command)?;
+            let display = expect_display(&mut args, "display", &sections)?;
+            let mut group = None;
+            let mut uppercase = false;
+            while let Some(arg) = args.next() {
+                match arg.as_str() {
+                    "--uppercase" => uppercase = true,
+                    "--group" => {
+                        let value = expect_arg(&mut args, "group size")?;
+                        group = match value.as_str() {
+                            "2" => Some(2),
+                            "16" => Some(16),
+                            "32" => Some(32),
+                            _ => {
+                                return Err(format!(
+                                    "invalid group size: {value} (expected 2, 16 or 32)"
+                                )
+                                .into())
+                            }
+                        };
+                    }
+                    other => return Err(CliError::Usage(format!("unknown option: {other}"))),
+                }
+            }
             let section = find_section(&sections, &display)
-                .ok_or_else(|| format!("display not found: {display}"))?;
-            let edid = extract_edid_hex(section)
-                .ok_or_else(|| format!("edid data not available for display: {display}"))?;
-            println!("{edid}");
+                .ok_or_else(|| display_not_found(&sections, &display))?;
+            let mut edid = extract_edid_hex(section).ok_or_else(|| {
+                CliError::EdidMissing(format!("edid data not available for display: {display}"))
+            })?;
+            if uppercase {
+                edid = edid.to_ascii_uppercase();
+            }
+            match group {
+                Some(size) => println!("{}", group_hex(&edid, size)),
+                None => println!("{edid}"),
+            }
         }
         "display_edid_decoded" => {
-            let display = expect_arg(&mut args, "display")?;
+            require_properties(backend, verbose, command)?;
+            let display = expect_display(&mut args, "display", &sections)?;
             let section = find_section(&sections, &display)
-                .ok_or_else(|| format!("display not found: {display}"))?;
-            let edid = extract_edid_hex(section)
-                .ok_or_else(|| format!("edid data not available for display: {display}"))?;
+                .ok_or_else(|| display_not_found(&sections, &display))?;
+            let edid = extract_edid_hex(section).ok_or_else(|| {
+                CliError::EdidMissing(format!("edid data not available for display: {display}"))
+            })?;
             let decoded = decode_edid(&edid)?;
             print!("{decoded}");
             if !decoded.ends_with('\n') {
                 println!();
             }
         }
+        "display_edid_validate" => {
+            require_properties(backend, verbose, command)?;
+            let display = expect_display(&mut args, "display", &sections)?;
+            let section = find_section(&sections, &display)
+                .ok_or_else(|| display_not_found(&sections, &display))?;
+            let edid = extract_edid_hex(section).ok_or_else(|| {
+                CliError::EdidMissing(format!("edid data not available for display: {display}"))
+            })?;
+            let issues = edid_validation_issues(&edid);
+            if issues.is_empty() {
+                println!("ok");
+            } else {
+                for issue in &issues {
+                    println!("{issue}");
+                }
+                return Ok(Outcome::False);
+            }
+        }
+        "display_edid_dump" => {
+            require_properties(backend, verbose, command)?;
+            let display = expect_display(&mut args, "display", &sections)?;
+            let path = expect_arg(&mut args, "output path")?;
+            let extensions_only = match args.next().as_deref() {
+                Some("--extensions-only") => true,
+                Some(other) => return Err(CliError::Usage(format!("unknown option: {other}"))),
+                None => false,
+            };
+            let section = find_section(&sections, &display)
+                .ok_or_else(|| display_not_found(&sections, &display))?;
+            let edid = extract_edid_hex(section).ok_or_else(|| {
+                CliError::EdidMissing(format!("edid data not available for display: {display}"))
+            })?;
+            let mut bytes = hex_to_bytes(&edid)?;
+            if extensions_only {
+                if bytes.len() <= 128 {
+                    return Err(format!("no edid extension blocks for display: {display}").into());
+                }
+                bytes.drain(..128);
+            }
+            if path == "-" {
+                io::stdout()
+                    .write_all(&bytes)
+                    .map_err(|err| format!("failed to write edid: {err}"))?;
+            } else {
+                std::fs::write(&path, &bytes)
+                    .map_err(|err| format!("failed to write {path}: {err}"))?;
+            }
+        }
+        "watch" => {
+            let flags = parse_watch_flags(&mut args)?;
+            run_watch(&sections, backend, &flags)?;
+        }
+        "install_service" => {
+            let mut print_only = false;
+            let mut enable = false;
+            let mut serve = false;
+            for arg in args.by_ref() {
+                match arg.as_str() {
+                    "--" => break,
+                    "--systemd-user" => enable = true,
+                    "--serve" => serve = true,
+                    "--print" => print_only = true,
+                    other => return Err(CliError::Usage(format!("unknown option: {other}"))),
+                }
+            }
+            if print_only && enable {
+                return Err(CliError::Usage(
+                    "--print cannot be combined with --systemd-user".to_string(),
+                ));
+            }
+            let watch_args: Vec<String> = args.collect();
+            if serve && !watch_args.is_empty() {
+                return Err(CliError::Usage(
+                    "--serve units take no extra options".to_string(),
+                ));
+            }
+            run_install_service(&watch_args, serve, print_only, enable)?;
+        }
+        "on_change" => {
+            let mut interval = Duration::from_secs(2);
+            let mut timeout = None;
+            while let Some(arg) = args.next() {
+                match arg.as_str() {
+                    "--" => break,
+                    "--interval" => {
+                        interval =
+                            parse_seconds(&expect_arg(&mut args, "interval seconds")?, "interval")?;
+                        if interval.is_zero() {
+                            return Err("invalid interval: must be greater than zero"
+                                .to_string()
+                                .into());
+                        }
+                    }
+                    "--timeout" => {
+                        timeout = Some(parse_seconds(
+                            &expect_arg(&mut args, "timeout seconds")?,
+                            "timeout",
+                        )?);
+                    }
+                    other => return Err(CliError::Usage(format!("unknown option: {other}"))),
+                }
+            }
+            let command: Vec<String> = args.collect();
+            if command.is_empty() {
+                return Err(CliError::Usage("missing command after --".to_string()));
+            }
+            return run_on_change(&sections, backend, interval, timeout, &command);
+        }
+        "display_edid_info" => {
+            require_properties(backend, verbose, command)?;
+            let display = expect_display(&mut args, "display", &sections)?;
+            let json = match args.next().as_deref() {
+                Some("--json") => true,
+                Some(other) => return Err(CliError::Usage(format!("unknown option: {other}"))),
+                None => false,
+            };
+            let section = find_section(&sections, &display)
+                .ok_or_else(|| display_not_found(&sections, &display))?;
+            let edid = extract_edid_hex(section).ok_or_else(|| {
+                CliError::EdidMissing(format!("edid data not available for display: {display}"))
+            })?;
+            let fields = edid_header_fields(&edid)?;
+            if json {
+                let body = fields
+                    .iter()
+                    .map(|(key, value)| format!("{}: {}", json_string(key), json_string(value)))
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                println!("{{{body}}}");
+            } else {
+                for (key, value) in &fields {
+                    println!("{key}={value}");
+                }
+            }
+        }
         "display_serial" => {
-            let display = expect_arg(&mut args, "display")?;
+            require_properties(backend, verbose, command)?;
+            let display = expect_display(&mut args, "display", &sections)?;
             let section = find_section(&sections, &display)
-                .ok_or_else(|| format!("display not found: {display}"))?;
-            let edid = extract_edid_hex(section)
-                .ok_or_else(|| format!("edid data not available for display: {display}"))?;
+                .ok_or_else(|| display_not_found(&sections, &display))?;
+            let edid = extract_edid_hex(section).ok_or_else(|| {
+                CliError::EdidMissing(format!("edid data not available for display: {display}"))
+            })?;
             let decoded = decode_edid(&edid)?;
             let serial = extract_serial(&decoded)
                 .ok_or_else(|| format!("serial not found in edid for: {display}"))?;
             println!("{serial}");
         }
         "display_serial_map" => {
-            let flags = parse_map_flags(&mut args, false)?;
-            let mut seen_values = HashSet::new();
+            require_properties(backend, verbose, command)?;
+            let mut map = MapOutput::new(parse_map_flags(&mut args, false)?);
             for section in &sections {
-                let serial = section_serial(section).unwrap_or_default();
-                output_map_entry(&section.name, serial.as_str(), &flags, &mut seen_values);
+                map.try_entry(&section.name, || section_serial(section))?;
             }
+            map.finish();
         }
         "monitor_serial_has_displays" => {
             let (serial, connected_only) = parse_monitor_serial_args(&mut args)?;
@@ -124,21 +936,31 @@ fn run() -> Result<(), String> {
                 println!("{display}");
             }
         }
+        "display_name_for" => {
+            let selector = expect_arg(&mut args, "identity selector")?;
+            println!("{}", display_name_for(&sections, &selector)?);
+        }
         "display_names" => {
-            let connected_only = parse_display_names_flags(&mut args)?;
-            for section in &sections {
-                if connected_only && section.state != DisplayState::Connected {
-                    continue;
+            let flags = parse_display_names_flags(&mut args)?;
+            let names: Vec<&str> = sections
+                .iter()
+                .filter(|section| flags.accepts(section))
+                .map(|section| section.name.as_str())
+                .collect();
+            if flags.count {
+                println!("{}", names.len());
+            } else {
+                for name in names {
+                    println!("{name}");
                 }
-                println!("{}", section.name);
             }
         }
         "display_geometry" => {
-            let display = expect_arg(&mut args, "display")?;
+            let display = expect_display(&mut args, "display", &sections)?;
             let section = find_section(&sections, &display)
-                .ok_or_else(|| format!("display not found: {display}"))?;
+                .ok_or_else(|| display_not_found(&sections, &display))?;
             if section.state != DisplayState::Connected {
-                return Err(format!("display not connected: {display}"));
+                return Err(format!("display not connected: {display}").into());
             }
             let geometry = section
                 .geometry
@@ -147,730 +969,10997 @@ fn run() -> Result<(), String> {
             println!("{geometry}");
         }
         "display_geometry_map" => {
-            let flags = parse_map_flags(&mut args, false)?;
-            let mut seen_values = HashSet::new();
+            let mut rest: Vec<String> = args.collect();
+            let orientation = rest.iter().any(|arg| arg == "--orientation");
+            rest.retain(|arg| arg != "--orientation");
+            let mut rest = rest.into_iter().peekable();
+            let mut map = MapOutput::new(parse_map_flags(&mut rest, false)?);
             for section in &sections {
                 if section.state != DisplayState::Connected {
                     continue;
                 }
                 if let Some(geometry) = &section.geometry {
-                    let value = if section.primary {
+                    let mut value = if section.primary {
                         format!("primary,{}", geometry)
                     } else {
                         geometry.clone()
                     };
-                    output_map_entry(&section.name, value.as_str(), &flags, &mut seen_values);
+                    if orientation {
+                        value.push(',');
+                        value.push_str(section.rotation.as_deref().unwrap_or("normal"));
+                        if let Some(reflection) = section_reflection(section) {
+                            value.push_str(",reflect-");
+                            value.push_str(reflection);
+                        }
+                    }
+                    map.entry(&section.name, value.as_str());
                 }
             }
+            map.finish();
         }
-        "display_connector" => {
-            let display = expect_arg(&mut args, "display")?;
+        "display_modes" => {
+            let display = expect_display(&mut args, "display", &sections)?;
             let section = find_section(&sections, &display)
-                .ok_or_else(|| format!("display not found: {display}"))?;
-            let connector = extract_connector_id(section)
-                .ok_or_else(|| format!("connector id not available for: {display}"))?;
-            println!("{connector}");
+                .ok_or_else(|| display_not_found(&sections, &display))?;
+            let modes = parse_modes(section);
+            if modes.is_empty() {
+                return Err(format!("no modes available for display: {display}").into());
+            }
+            for mode in &modes {
+                println!("{}", format_mode(mode));
+            }
         }
-        "display_connector_map" => {
-            let flags = parse_map_flags(&mut args, false)?;
-            let mut seen_values = HashSet::new();
+        "display_modes_map" => {
+            let mut map = MapOutput::new(parse_map_flags(&mut args, false)?);
             for section in &sections {
-                let connector = extract_connector_id(section).unwrap_or_default();
-                output_map_entry(&section.name, connector.as_str(), &flags, &mut seen_values);
+                map.try_entry(&section.name, || {
+                    Ok(Some(
+                        parse_modes(section)
+                            .iter()
+                            .map(format_mode)
+                            .collect::<Vec<_>>()
+                            .join(","),
+                    ))
+                })?;
             }
+            map.finish();
         }
-        "display_monitor" => {
-            let display = expect_arg(&mut args, "display")?;
-            let monitors_input = args.next();
-            let monitor_text = resolve_monitors_text(monitors_input)?;
-            let monitor_map = parse_monitor_map(&monitor_text);
-            let line = monitor_map
-                .get(&display)
-                .ok_or_else(|| format!("monitor entry not found for display: {display}"))?;
-            println!("{}", line);
+        "display_rate" => {
+            let display = expect_display(&mut args, "display", &sections)?;
+            let section = find_section(&sections, &display)
+                .ok_or_else(|| display_not_found(&sections, &display))?;
+            let mode = parse_modes(section)
+                .into_iter()
+                .find(|mode| mode.current)
+                .ok_or_else(|| format!("current mode not available for display: {display}"))?;
+            println!("{:.2}", mode.refresh);
         }
-        "display_monitor_map" => {
-            let flags = parse_map_flags(&mut args, false)?;
-            let monitors_input = args.next();
-            let monitor_text = resolve_monitors_text(monitors_input)?;
-            let monitor_map = parse_monitor_map(&monitor_text);
-            let mut seen_values = HashSet::new();
+        "display_current_mode" | "display_preferred_mode" => {
+            let display = expect_display(&mut args, "display", &sections)?;
+            let section = find_section(&sections, &display)
+                .ok_or_else(|| display_not_found(&sections, &display))?;
+            let current = command == "display_current_mode";
+            let kind = if current { "current" } else { "preferred" };
+            let mode = parse_modes(section)
+                .into_iter()
+                .find(|mode| {
+                    if current {
+                        mode.current
+                    } else {
+                        mode.preferred
+                    }
+                })
+                .ok_or_else(|| format!("{kind} mode not available for display: {display}"))?;
+            println!("{}", mode_label(&mode));
+        }
+        "display_current_mode_map" | "display_preferred_mode_map" => {
+            let mut map = MapOutput::new(parse_map_flags(&mut args, false)?);
+            let current = command == "display_current_mode_map";
             for section in &sections {
-                let value = monitor_map.get(&section.name).cloned().unwrap_or_default();
-                output_map_entry(&section.name, &value, &flags, &mut seen_values);
+                map.try_entry(&section.name, || {
+                    Ok(parse_modes(section)
+                        .iter()
+                        .find(|mode| {
+                            if current {
+                                mode.current
+                            } else {
+                                mode.preferred
+                            }
+                        })
+                        .map(mode_label))
+                })?;
             }
+            map.finish();
         }
-        "display_label_line" => {
-            let display = expect_arg(&mut args, "display")?;
+        "display_native_check" => {
+            let (display, exit_code) = parse_native_check_args(&mut args)?;
+            let mismatches = native_mismatches(&sections, display.as_deref())?;
+            for (name, current, preferred) in &mismatches {
+                println!("{name} {current} (preferred {preferred})");
+            }
+            if exit_code && !mismatches.is_empty() {
+                return Ok(Outcome::False);
+            }
+        }
+        "display_preferred_map" => {
+            let mut map = MapOutput::new(parse_map_flags(&mut args, false)?);
+            for section in &sections {
+                if section.state != DisplayState::Connected {
+                    continue;
+                }
+                map.try_entry(&section.name, || {
+                    Ok(preferred_mode_size(section)
+                        .map(|(width, height)| format!("{width}x{height}")))
+                })?;
+            }
+            map.finish();
+        }
+        "display_rates_map" => {
+            let mut map = MapOutput::new(parse_map_flags(&mut args, false)?);
+            for section in &sections {
+                if section.state != DisplayState::Connected {
+                    continue;
+                }
+                map.try_entry(&section.name, || {
+                    Ok(Some(
+                        current_resolution_rates(section)
+                            .iter()
+                            .map(|rate| rate.to_string())
+                            .collect::<Vec<_>>()
+                            .join(","),
+                    ))
+                })?;
+            }
+            map.finish();
+        }
+        "display_rotation" => {
+            let display = expect_display(&mut args, "display", &sections)?;
             let section = find_section(&sections, &display)
-                .ok_or_else(|| format!("display not found: {display}"))?;
-            if let Some(line) = section.lines.first() {
-                println!("{line}");
+                .ok_or_else(|| display_not_found(&sections, &display))?;
+            let rotation = section
+                .rotation
+                .as_deref()
+                .ok_or_else(|| format!("rotation not available for display: {display}"))?;
+            println!("{rotation}");
+        }
+        "display_rotation_map" => {
+            let mut map = MapOutput::new(parse_map_flags(&mut args, false)?);
+            for section in &sections {
+                let value = section.rotation.clone().unwrap_or_default();
+                map.entry(&section.name, &value);
+            }
+            map.finish();
+        }
+        "display_brightness" => {
+            require_verbose(backend, verbose, command)?;
+            let display = expect_display(&mut args, "display", &sections)?;
+            let section = find_section(&sections, &display)
+                .ok_or_else(|| display_not_found(&sections, &display))?;
+            let brightness = section_brightness(section)
+                .ok_or_else(|| format!("brightness not available for display: {display}"))?;
+            println!("{brightness:.2}");
+        }
+        "display_brightness_set" => {
+            let display = expect_display(&mut args, "display", &sections)?;
+            let value = expect_arg(&mut args, "brightness")?;
+            if value.starts_with(['+', '-']) {
+                require_verbose(backend, verbose, command)?;
+            }
+            run_display_brightness_set(&display, &value, &sections, backend)?;
+        }
+        "dpms" => {
+            let action = expect_arg(&mut args, "dpms action")?;
+            let output = match args.next().as_deref() {
+                Some("--output") => Some(expect_display(&mut args, "display", &sections)?),
+                Some(other) => return Err(CliError::Usage(format!("unknown option: {other}"))),
+                None => None,
+            };
+            run_dpms(&action, output.as_deref(), &sections, backend)?;
+        }
+        "undo" => {
+            let mut flags = parse_apply_flags(&mut args)?;
+            flags.undo = true;
+            let result = run_undo(&sections, backend, &flags);
+            return guard_json_failure(result, &flags);
+        }
+        "support_bundle" => {
+            let path = expect_arg(&mut args, "bundle file")?;
+            if let Some(other) = args.next() {
+                return Err(CliError::Usage(format!("unknown option: {other}")));
+            }
+            write_support_bundle(&path, &sections, backend, verbose)?;
+            println!("{path}");
+        }
+        "export" => {
+            let format = expect_arg(&mut args, "export format")?;
+            if format != "script" {
+                return Err(CliError::Usage(format!(
+                    "unknown export format: {format} (expected script)"
+                )));
+            }
+            let mut layout_only = false;
+            for arg in args {
+                match arg.as_str() {
+                    "--layout-only" => layout_only = true,
+                    other => return Err(CliError::Usage(format!("unknown option: {other}"))),
+                }
+            }
+            print!("{}", export_script(&sections, backend, !layout_only));
+        }
+        "state" => {
+            let action = expect_arg(&mut args, "state action")?;
+            return run_state(&action, &mut args, &sections, backend);
+        }
+        "history" => {
+            let action = expect_arg(&mut args, "history action")?;
+            return run_history(&action, &mut args, &sections, backend);
+        }
+        "reset" => {
+            if let Some(other) = args.next() {
+                return Err(CliError::Usage(format!("unknown option: {other}")));
+            }
+            run_reset(&sections, backend)?;
+        }
+        "display_off" => {
+            let display = expect_display(&mut args, "display", &sections)?;
+            let force = match args.next().as_deref() {
+                Some("--force") => true,
+                Some(other) => return Err(CliError::Usage(format!("unknown option: {other}"))),
+                None => false,
+            };
+            run_display_off(&display, &sections, backend, force)?;
+        }
+        "display_on" => {
+            let display = expect_display(&mut args, "display", &sections)?;
+            if let Some(other) = args.next() {
+                return Err(CliError::Usage(format!("unknown option: {other}")));
+            }
+            run_display_on(&display, &sections, backend)?;
+        }
+        "backlight" => {
+            let action = expect_arg(&mut args, "backlight action")?;
+            let display = expect_display(&mut args, "display", &sections)?;
+            let section = find_section(&sections, &display)
+                .ok_or_else(|| display_not_found(&sections, &display))?;
+            match action.as_str() {
+                "get" => {
+                    let level = find_backlight(section)?;
+                    println!("{}", level.percent());
+                }
+                "set" => {
+                    let value = expect_arg(&mut args, "backlight percent")?;
+                    let install_rule = match args.next().as_deref() {
+                        Some("--install-udev-rule") => true,
+                        Some(other) => {
+                            return Err(CliError::Usage(format!("unknown option: {other}")))
+                        }
+                        None => false,
+                    };
+                    let level = find_backlight(section)?;
+                    run_backlight_set(&display, &level, &value, backend, install_rule)?;
+                }
+                _ => {
+                    return Err(CliError::Usage(format!(
+                        "unknown backlight action: {action}"
+                    )))
+                }
+            }
+        }
+        "battery_mode" => match args.next().as_deref() {
+            None => {
+                let on = read_state("battery_mode")?.is_some();
+                println!("{}", if on { "on" } else { "off" });
+            }
+            Some("on") => {
+                let mut brightness = 50;
+                let mut disable = Vec::new();
+                let mut flags = ApplyFlags::default();
+                while let Some(arg) = args.next() {
+                    match arg.as_str() {
+                        "--brightness" => {
+                            let value = expect_arg(&mut args, "brightness percent")?;
+                            brightness = value
+                                .parse::<u32>()
+                                .ok()
+                                .filter(|percent| *percent <= 100)
+                                .ok_or_else(|| format!("invalid brightness percent: {value}"))?;
+                        }
+                        "--disable" => {
+                            disable.push(expect_display(&mut args, "display", &sections)?)
+                        }
+                        _ if flags.accept(&arg, &mut args)? => {}
+                        other => return Err(CliError::Usage(format!("unknown option: {other}"))),
+                    }
+                }
+                return run_battery_mode_on(&sections, backend, brightness, &disable, &flags);
+            }
+            Some("off") => restore_saved_state("battery_mode", &sections, backend)?,
+            Some(other) => {
+                return Err(format!("invalid battery mode: {other} (expected on or off)").into())
+            }
+        },
+        "game_mode" => {
+            let display = expect_display(&mut args, "display", &sections)?;
+            let mut flags = ApplyFlags::default();
+            let mut vrr = false;
+            let mut on = true;
+            while let Some(arg) = args.next() {
+                match arg.as_str() {
+                    "on" => on = true,
+                    "off" => on = false,
+                    "--vrr" => vrr = true,
+                    _ if flags.accept(&arg, &mut args)? => {}
+                    other => return Err(CliError::Usage(format!("unknown option: {other}"))),
+                }
+            }
+            if on {
+                return run_game_mode_on(&display, vrr, &sections, backend, &flags);
             } else {
-                return Err(format!("label line missing for display: {display}"));
+                restore_saved_state("game_mode", &sections, backend)?;
             }
         }
-        _ => return Err(format!("unknown command: {command}")),
-    }
-
-    Ok(())
-}
-
-fn run_single_display_output(keep: &str, sections: &[DisplaySection]) -> Result<(), String> {
-    if find_section(sections, keep).is_none() {
-        return Err(format!("display not found: {keep}"));
-    }
-
-    let mut exclude = HashSet::new();
-    exclude.insert(keep.to_string());
+        "connector_alias_map" => {
+            let mut rest: Vec<String> = args.collect();
+            let json = rest.iter().any(|arg| arg == "--json");
+            rest.retain(|arg| arg != "--json");
+            let mut rest = rest.into_iter().peekable();
+            let flags = parse_map_flags(&mut rest, false)?;
+            if json {
+                for section in &sections {
+                    let drm = section_drm_connector(section);
+                    if flags.filtered && drm.is_none() {
+                        continue;
+                    }
+                    let connector_id = extract_connector_id(section).unwrap_or_default();
+                    let drm_name = drm.as_ref().map(|drm| drm.name.as_str()).unwrap_or("");
+                    let ddc_bus = drm
+                        .as_ref()
+                        .and_then(DrmConnector::ddc_bus)
+                        .unwrap_or_default();
+                    println!(
+                        "{{\"name\": {}, \"drm\": {}, \"connector_id\": {}, \"ddc_bus\": {}}}",
+                        json_string(&section.name),
+                        json_string(drm_name),
+                        json_string(&connector_id),
+                        json_string(&ddc_bus)
+                    );
+                }
+            } else {
+                let mut map = MapOutput::new(flags);
+                for section in &sections {
+                    map.try_entry(&section.name, || {
+                        Ok(section_drm_connector(section).map(|drm| drm.name))
+                    })?;
+                }
+                map.finish();
+            }
+        }
+        "display_crtc" => {
+            require_verbose(backend, verbose, command)?;
+            let display = expect_display(&mut args, "display", &sections)?;
+            let section = find_section(&sections, &display)
+                .ok_or_else(|| display_not_found(&sections, &display))?;
+            let crtc = section_field(section, "CRTC")
+                .ok_or_else(|| format!("crtc not available for display: {display}"))?;
+            println!("{crtc}");
+        }
+        "display_crtc_map" => {
+            require_verbose(backend, verbose, command)?;
+            let mut map = MapOutput::new(parse_map_flags(&mut args, false)?);
+            for section in &sections {
+                map.entry(&section.name, section_field(section, "CRTC").unwrap_or(""));
+            }
+            map.finish();
+        }
+        "display_transform" => {
+            require_verbose(backend, verbose, command)?;
+            let display = expect_display(&mut args, "display", &sections)?;
+            let section = find_section(&sections, &display)
+                .ok_or_else(|| display_not_found(&sections, &display))?;
+            let matrix = section_transform(section)
+                .ok_or_else(|| format!("transform not available for display: {display}"))?;
+            for row in matrix.chunks(3) {
+                println!("{}", row.join(" "));
+            }
+        }
+        "display_scale" => {
+            let display = expect_display(&mut args, "display", &sections)?;
+            let factor = expect_arg(&mut args, "scale factor")?;
+            run_display_scale(&display, &factor, &sections, backend)?;
+        }
+        "display_properties" => {
+            require_properties(backend, verbose, command)?;
+            let display = expect_display(&mut args, "display", &sections)?;
+            let section = find_section(&sections, &display)
+                .ok_or_else(|| display_not_found(&sections, &display))?;
+            for property in section_properties(section) {
+                println!("{}={}", property.name, property.value);
+            }
+        }
+        "display_property" => {
+            require_properties(backend, verbose, command)?;
+            let display = expect_display(&mut args, "display", &sections)?;
+            let name = expect_arg(&mut args, "property name")?;
+            let section = find_section(&sections, &display)
+                .ok_or_else(|| display_not_found(&sections, &display))?;
+            let property = section_properties(section)
+                .into_iter()
+                .find(|property| property.name.eq_ignore_ascii_case(&name))
+                .ok_or_else(|| format!("property not found for display {display}: {name}"))?;
+            println!("{}", property.value);
+        }
+        "display_property_set" => {
+            let display = expect_display(&mut args, "display", &sections)?;
+            let name = expect_arg(&mut args, "property name")?;
+            let value = expect_arg(&mut args, "property value")?;
+            run_display_property_set(&display, &name, &value, &sections, backend)?;
+        }
+        "display_gamma" => {
+            require_verbose(backend, verbose, command)?;
+            let display = expect_display(&mut args, "display", &sections)?;
+            let section = find_section(&sections, &display)
+                .ok_or_else(|| display_not_found(&sections, &display))?;
+            let gamma = section_gamma(section)
+                .ok_or_else(|| format!("gamma not available for display: {display}"))?;
+            println!("{gamma}");
+        }
+        "display_gamma_map" => {
+            require_verbose(backend, verbose, command)?;
+            let mut map = MapOutput::new(parse_map_flags(&mut args, false)?);
+            for section in &sections {
+                map.entry(&section.name, &section_gamma(section).unwrap_or_default());
+            }
+            map.finish();
+        }
+        "display_gamma_set" => {
+            let display = expect_display(&mut args, "display", &sections)?;
+            let gamma = expect_arg(&mut args, "gamma")?;
+            run_display_gamma_set(&display, &gamma, &sections, backend)?;
+        }
+        "display_primary" => {
+            let section = sections
+                .iter()
+                .find(|section| section.primary)
+                .ok_or_else(|| "no primary display".to_string())?;
+            println!("{}", section.name);
+        }
+        "display_is_primary" => {
+            let display = expect_display(&mut args, "display", &sections)?;
+            let section = find_section(&sections, &display)
+                .ok_or_else(|| display_not_found(&sections, &display))?;
+            println!("{}", section.primary);
+        }
+        "set_primary" => {
+            let display = expect_display(&mut args, "display", &sections)?;
+            run_set_primary(&display, &sections, backend)?;
+        }
+        "display_rotate" => {
+            let display = expect_display(&mut args, "display", &sections)?;
+            let rotation = expect_arg(&mut args, "rotation")?;
+            run_display_rotate(&display, &rotation, &sections, backend)?;
+        }
+        "mode_add" => {
+            let display = expect_display(&mut args, "display", &sections)?;
+            let spec = expect_arg(&mut args, "mode")?;
+            let mut reduced = false;
+            let mut switch = false;
+            for arg in args.by_ref() {
+                match arg.as_str() {
+                    "--reduced" => reduced = true,
+                    "--switch" => switch = true,
+                    other => return Err(CliError::Usage(format!("unknown option: {other}"))),
+                }
+            }
+            run_mode_add(&display, &spec, reduced, switch, &sections, backend)?;
+        }
+        "virtual" => {
+            let action = expect_arg(&mut args, "virtual action")?;
+            if action != "enable" {
+                return Err(CliError::Usage(format!("unknown virtual action: {action}")));
+            }
+            let spec = expect_arg(&mut args, "mode")?;
+            let mut output = None;
+            while let Some(arg) = args.next() {
+                match arg.as_str() {
+                    "--output" => {
+                        output = Some(expect_display(&mut args, "display", &sections)?);
+                    }
+                    other => return Err(CliError::Usage(format!("unknown option: {other}"))),
+                }
+            }
+            run_virtual_enable(&spec, output.as_deref(), &sections, backend)?;
+        }
+        "mode_delete" => {
+            let display = expect_display(&mut args, "display", &sections)?;
+            let name = expect_arg(&mut args, "mode name")?;
+            run_mode_delete(&display, &name, &sections, backend)?;
+        }
+        "mode_gc" => {
+            run_mode_gc(backend)?;
+        }
+        "display_manufacturer" => {
+            require_properties(backend, verbose, command)?;
+            let display = expect_display(&mut args, "display", &sections)?;
+            let raw_id = match args.next().as_deref() {
+                Some("--id") => true,
+                Some(other) => return Err(CliError::Usage(format!("unknown option: {other}"))),
+                None => false,
+            };
+            let section = find_section(&sections, &display)
+                .ok_or_else(|| display_not_found(&sections, &display))?;
+            let edid = extract_edid_hex(section).ok_or_else(|| {
+                CliError::EdidMissing(format!("edid data not available for display: {display}"))
+            })?;
+            let id = edid_manufacturer_id(&edid)
+                .ok_or_else(|| format!("manufacturer id not found in edid for: {display}"))?;
+            if raw_id {
+                println!("{id}");
+            } else {
+                println!("{}", pnp_vendor_name(&id).unwrap_or(&id));
+            }
+        }
+        "display_manufacturer_map" => {
+            require_properties(backend, verbose, command)?;
+            let mut map = MapOutput::new(parse_map_flags(&mut args, false)?);
+            for section in &sections {
+                map.try_entry(&section.name, || {
+                    Ok(checked_edid(section)?
+                        .and_then(|edid| edid_manufacturer_id(&edid))
+                        .map(|id| pnp_vendor_name(&id).map(str::to_string).unwrap_or(id)))
+                })?;
+            }
+            map.finish();
+        }
+        "display_model" => {
+            require_properties(backend, verbose, command)?;
+            let display = expect_display(&mut args, "display", &sections)?;
+            let section = find_section(&sections, &display)
+                .ok_or_else(|| display_not_found(&sections, &display))?;
+            let edid = extract_edid_hex(section).ok_or_else(|| {
+                CliError::EdidMissing(format!("edid data not available for display: {display}"))
+            })?;
+            let model = edid_model_name(&edid)
+                .ok_or_else(|| format!("model name not found in edid for: {display}"))?;
+            println!("{model}");
+        }
+        "display_model_map" => {
+            require_properties(backend, verbose, command)?;
+            let mut map = MapOutput::new(parse_map_flags(&mut args, false)?);
+            for section in &sections {
+                map.try_entry(&section.name, || {
+                    Ok(checked_edid(section)?.and_then(|edid| edid_model_name(&edid)))
+                })?;
+            }
+            map.finish();
+        }
+        "display_identity_map" => {
+            require_properties(backend, verbose, command)?;
+            let mut rest: Vec<String> = args.collect();
+            let json = rest.iter().any(|arg| arg == "--json");
+            rest.retain(|arg| arg != "--json");
+            let mut rest = rest.into_iter().peekable();
+            let flags = parse_map_flags(&mut rest, false)?;
+            if json {
+                for section in &sections {
+                    let identity = section_identity(section);
+                    if flags.filtered && identity.is_none() {
+                        continue;
+                    }
+                    let identity = identity.unwrap_or_default();
+                    println!(
+                        "{{\"name\": {}, \"vendor\": {}, \"model\": {}, \"serial\": {}, \"edid_hash\": {}}}",
+                        json_string(&section.name),
+                        json_string(&identity.vendor),
+                        json_string(&identity.model),
+                        json_string(&identity.serial),
+                        json_string(&identity.edid_hash)
+                    );
+                }
+            } else {
+                let mut map = MapOutput::new(flags);
+                for section in &sections {
+                    map.try_entry(&section.name, || {
+                        checked_edid(section)?;
+                        Ok(section_identity(section).map(|identity| identity.describe()))
+                    })?;
+                }
+                map.finish();
+            }
+        }
+        "seat_map" => {
+            let mut map = MapOutput::new(parse_map_flags(&mut args, false)?);
+            for section in &sections {
+                map.try_entry(&section.name, || section_seat(section))?;
+            }
+            map.finish();
+        }
+        "display_physical_size" => {
+            let display = expect_display(&mut args, "display", &sections)?;
+            let json = match args.next().as_deref() {
+                Some("--json") => true,
+                Some(other) => return Err(CliError::Usage(format!("unknown option: {other}"))),
+                None => false,
+            };
+            let section = find_section(&sections, &display)
+                .ok_or_else(|| display_not_found(&sections, &display))?;
+            let (width, height) = section_physical_size(section)
+                .ok_or_else(|| format!("physical size not available for display: {display}"))?;
+            if json {
+                println!("{{\"width_mm\": {width}, \"height_mm\": {height}}}");
+            } else {
+                println!("{width}x{height}");
+            }
+        }
+        "display_physical_size_map" => {
+            let mut map = MapOutput::new(parse_map_flags(&mut args, false)?);
+            for section in &sections {
+                map.try_entry(&section.name, || {
+                    Ok(section_physical_size(section)
+                        .map(|(width, height)| format!("{width}x{height}")))
+                })?;
+            }
+            map.finish();
+        }
+        "display_bandwidth" => {
+            require_verbose(backend, verbose, command)?;
+            let display = expect_display(&mut args, "display", &sections)?;
+            let mut link = None;
+            while let Some(arg) = args.next() {
+                match arg.as_str() {
+                    "--link" => {
+                        let value = expect_arg(&mut args, "link capacity")?;
+                        link = Some(
+                            value
+                                .parse::<f64>()
+                                .ok()
+                                .filter(|gbps| *gbps > 0.0)
+                                .ok_or_else(|| format!("invalid link capacity: {value}"))?,
+                        );
+                    }
+                    other => return Err(CliError::Usage(format!("unknown option: {other}"))),
+                }
+            }
+            let section = find_section(&sections, &display)
+                .ok_or_else(|| display_not_found(&sections, &display))?;
+            run_display_bandwidth(section, link)?;
+        }
+        "display_dpi" => {
+            let display = expect_display(&mut args, "display", &sections)?;
+            let section = find_section(&sections, &display)
+                .ok_or_else(|| display_not_found(&sections, &display))?;
+            println!("{}", section_dpi(section)?);
+        }
+        "display_dpi_map" => {
+            let mut map = MapOutput::new(parse_map_flags(&mut args, false)?);
+            for section in &sections {
+                map.try_entry(&section.name, || {
+                    Ok(section_dpi(section).ok().map(|dpi| dpi.to_string()))
+                })?;
+            }
+            map.finish();
+        }
+        "display_connector" => {
+            let display = expect_display(&mut args, "display", &sections)?;
+            let section = find_section(&sections, &display)
+                .ok_or_else(|| display_not_found(&sections, &display))?;
+            let connector = extract_connector_id(section)
+                .ok_or_else(|| format!("connector id not available for: {display}"))?;
+            println!("{connector}");
+        }
+        "capabilities" => {
+            let json = match args.next().as_deref() {
+                Some("--json") => true,
+                Some(other) => return Err(CliError::Usage(format!("unknown option: {other}"))),
+                None => false,
+            };
+            run_capabilities(&sections, json);
+        }
+        "display_connector_map" => {
+            let mut map = MapOutput::new(parse_map_flags(&mut args, false)?);
+            for section in &sections {
+                let connector = extract_connector_id(section).unwrap_or_default();
+                map.entry(&section.name, connector.as_str());
+            }
+            map.finish();
+        }
+        "providers" => {
+            if backend == Backend::Wlr {
+                return Err("providers are not supported by the wlr backend"
+                    .to_string()
+                    .into());
+            }
+            let action = expect_arg(&mut args, "providers action")?;
+            match action.as_str() {
+                "list" => {
+                    let json = match args.next().as_deref() {
+                        Some("--json") => true,
+                        Some(other) => {
+                            return Err(CliError::Usage(format!("unknown option: {other}")))
+                        }
+                        None => false,
+                    };
+                    for provider in query_providers()? {
+                        if json {
+                            println!("{}", provider.json());
+                        } else {
+                            println!("{}", provider.line());
+                        }
+                    }
+                }
+                "offload" => {
+                    let source = expect_arg(&mut args, "source provider")?;
+                    let sink = expect_arg(&mut args, "sink provider")?;
+                    run_provider_link("--setprovideroffloadsink", &source, &sink)?;
+                }
+                "output-source" => {
+                    let sink = expect_arg(&mut args, "sink provider")?;
+                    let source = expect_arg(&mut args, "source provider")?;
+                    run_provider_link("--setprovideroutputsource", &sink, &source)?;
+                }
+                _ => {
+                    return Err(CliError::Usage(format!(
+                        "unknown providers action: {action}"
+                    )))
+                }
+            }
+        }
+        "display_monitor" => {
+            let display = expect_display(&mut args, "display", &sections)?;
+            let monitors_input = args.next();
+            let monitor_text = resolve_monitors_text(monitors_input)?;
+            let monitor_map = parse_monitor_map(&monitor_text);
+            let line = monitor_map
+                .get(&display)
+                .ok_or_else(|| format!("monitor entry not found for display: {display}"))?;
+            println!("{}", line);
+        }
+        "display_monitor_map" => {
+            let mut map = MapOutput::new(parse_map_flags(&mut args, false)?);
+            let monitors_input = args.next();
+            let monitor_text = resolve_monitors_text(monitors_input)?;
+            let monitor_map = parse_monitor_map(&monitor_text);
+            for section in &sections {
+                let value = monitor_map.get(&section.name).cloned().unwrap_or_default();
+                map.entry(&section.name, &value);
+            }
+            map.finish();
+        }
+        "get" => {
+            let path = expect_arg(&mut args, "path")?;
+            for (key, value) in resolve_get_path(&sections, &path)? {
+                match key {
+                    Some(key) => println!("{key}={value}"),
+                    None => println!("{value}"),
+                }
+            }
+        }
+        "set" => {
+            let path = expect_arg(&mut args, "path")?;
+            let value = expect_arg(&mut args, "value")?;
+            run_set_path(&path, &value, &sections, backend)?;
+        }
+        "assert" => {
+            let expr = args.collect::<Vec<_>>().join(" ");
+            if expr.trim().is_empty() {
+                return Err(CliError::Usage("missing argument: expression".to_string()));
+            }
+            if !evaluate_assertion(&expr, &sections)? {
+                return Ok(Outcome::False);
+            }
+        }
+        "display_label_line" => {
+            let display = expect_display(&mut args, "display", &sections)?;
+            let section = find_section(&sections, &display)
+                .ok_or_else(|| display_not_found(&sections, &display))?;
+            if let Some(line) = section.lines.first() {
+                println!("{line}");
+            } else {
+                return Err(format!("label line missing for display: {display}").into());
+            }
+        }
+        _ => return Err(CliError::Usage(format!("unknown command: {command}"))),
+    }
 
-    let off_targets = filtered_display_names(sections, &exclude);
-    let mut args = vec![
-        "--output".to_string(),
-        keep.to_string(),
-        "--primary".to_string(),
-        "--auto".to_string(),
-    ];
-    args.extend(build_off_args(&off_targets));
+    Ok(Outcome::Done)
+}
 
-    run_xrandr_with_args(args)
+fn current_pixel_clock(section: &DisplaySection) -> Option<f64> {
+    section
+        .lines
+        .iter()
+        .filter(|line| line.contains("*current"))
+        .find_map(|line| {
+            line.split_whitespace()
+                .find_map(|token| token.strip_suffix("MHz")?.parse::<f64>().ok())
+        })
+}
+
+fn section_bits_per_component(section: &DisplaySection) -> u32 {
+    let max_bpc = section_properties(section)
+        .into_iter()
+        .find(|property| property.name == "max bpc")
+        .and_then(|property| property.value.parse::<u32>().ok());
+    let edid_bpc = extract_edid_hex(section)
+        .and_then(|edid| hex_to_bytes(&edid).ok())
+        .and_then(|bytes| edid_color_depth(&bytes));
+    match (max_bpc, edid_bpc) {
+        (Some(max), Some(edid)) => max.min(edid),
+        (Some(bpc), None) | (None, Some(bpc)) => bpc,
+        (None, None) => 8,
+    }
+}
+
+fn edid_color_depth(bytes: &[u8]) -> Option<u32> {
+    let input = bytes.get(20).copied().filter(|input| input & 0x80 != 0)?;
+    match (input >> 4) & 0x07 {
+        depth @ 1..=6 => Some(4 + 2 * u32::from(depth)),
+        _ => None,
+    }
+}
+
+fn section_link_capacity(section: &DisplaySection) -> Option<(f64, String)> {
+    let properties = section_properties(section);
+    let property = |names: &[&str]| {
+        properties.iter().find_map(|property| {
+            let name = property.name.to_ascii_lowercase().replace(['-', '_'], " ");
+            names
+                .contains(&name.as_str())
+                .then_some(property.value.as_str())
+        })
+    };
+    let lane_rate = property(&["link rate", "max link rate"]).and_then(dp_lane_rate);
+    let lanes = property(&["lane count", "max lane count"])
+        .and_then(|value| value.parse::<u32>().ok())
+        .filter(|lanes| matches!(lanes, 1 | 2 | 4));
+    if let (Some(rate), Some(lanes)) = (lane_rate, lanes) {
+        // 8b/10b below UHBR, 128b/132b from UHBR10 up.
+        let efficiency = if rate >= 10.0 { 128.0 / 132.0 } else { 0.8 };
+        return Some((
+            rate * f64::from(lanes) * efficiency,
+            format!("DisplayPort {lanes} x {rate:.2} Gbit/s, from link properties"),
+        ));
+    }
+    let edid = extract_edid_hex(section)?;
+    let interface = hex_to_bytes(&edid)
+        .ok()
+        .and_then(|bytes| bytes.get(20).copied())
+        .filter(|input| input & 0x80 != 0)
+        .map(|input| input & 0x0f);
+    let features = edid_features(&edid)?;
+    if features.hdmi || matches!(interface, Some(2 | 3)) {
+        let mhz = features.max_tmds_mhz.unwrap_or(165);
+        return Some((
+            f64::from(mhz) * 24.0 / 1000.0,
+            format!("HDMI {mhz} MHz TMDS, from EDID"),
+        ));
+    }
+    match interface {
+        Some(1) => Some((3.96, "DVI single link, from EDID".to_string())),
+        _ => None,
+    }
+}
+
+fn dp_lane_rate(value: &str) -> Option<f64> {
+    let value = value.trim();
+    // DPCD link rate codes are multiples of 0.27 Gbit/s.
+    if let Some(hex) = value.strip_prefix("0x") {
+        return u32::from_str_radix(hex, 16)
+            .ok()
+            .filter(|code| *code > 0)
+            .map(|code| f64::from(code) * 0.27);
+    }
+    let number = value
+        .trim_end_matches("Gbps")
+        .trim_end_matches("Gbit/s")
+        .trim()
+        .parse::<f64>()
+        .ok()
+        .filter(|number| *number > 0.0)?;
+    // The kernel reports link rates in units of 10 kHz.
+    Some(if number >= 100_000.0 {
+        number / 100_000.0
+    } else {
+        number
+    })
+}
+
+fn run_display_bandwidth(section: &DisplaySection, link: Option<f64>) -> Result<(), CliError> {
+    let display = &section.name;
+    let clock = current_pixel_clock(section)
+        .ok_or_else(|| format!("no active mode for display: {display}"))?;
+    let bpp = section_bits_per_component(section) * 3;
+    let required = clock * f64::from(bpp) / 1000.0;
+    let (capacity, source) = match link {
+        Some(gbps) => (gbps, "given".to_string()),
+        None => section_link_capacity(section).ok_or_else(|| {
+            format!("link capacity unknown for display: {display} (pass --link <gbit/s>)")
+        })?,
+    };
+    let utilization = required / capacity * 100.0;
+    println!("pixel_clock={clock:.2} MHz");
+    println!("bpp={bpp}");
+    println!("required={required:.2} Gbit/s");
+    println!("capacity={capacity:.2} Gbit/s ({source})");
+    println!("utilization={utilization:.1}%");
+    if utilization >= 90.0 {
+        eprintln!("warning: {display} link is at {utilization:.1}% of its estimated capacity");
+    }
+    Ok(())
+}
+
+fn section_activity(section: &DisplaySection) -> &'static str {
+    let crtc = section_field(section, "CRTC").is_some_and(|crtc| !crtc.is_empty());
+    match section.state {
+        _ if section.geometry.is_some() || crtc => "active",
+        DisplayState::Connected => "connected",
+        _ => "disconnected",
+    }
+}
+
+fn run_single_display_output(
+    keep: &str,
+    sections: &[DisplaySection],
+    screen_maximum: Option<(i64, i64)>,
+    backend: Backend,
+    flags: &ApplyFlags,
+) -> Result<Outcome, CliError> {
+    run_row_display_output(
+        &[keep.to_string()],
+        0,
+        sections,
+        screen_maximum,
+        backend,
+        flags,
+    )
 }
 
 fn run_dual_display_output(
     left: &str,
     right: &str,
+    primary: usize,
     sections: &[DisplaySection],
-) -> Result<(), String> {
+    screen_maximum: Option<(i64, i64)>,
+    backend: Backend,
+    flags: &ApplyFlags,
+) -> Result<Outcome, CliError> {
     if left == right {
-        return Err("left and right displays must be different".to_string());
+        return Err("left and right displays must be different"
+            .to_string()
+            .into());
+    }
+
+    run_row_display_output(
+        &[left.to_string(), right.to_string()],
+        primary,
+        sections,
+        screen_maximum,
+        backend,
+        flags,
+    )
+}
+
+fn connected_in_order(sections: &[DisplaySection], sort: &str) -> Result<Vec<String>, CliError> {
+    let mut connected: Vec<&DisplaySection> = sections
+        .iter()
+        .filter(|section| section.state == DisplayState::Connected)
+        .collect();
+    match sort {
+        "name" => connected.sort_by(|a, b| a.name.cmp(&b.name)),
+        "edid" => connected.sort_by_cached_key(|section| {
+            section_identity(section)
+                .map(|identity| identity.describe())
+                .unwrap_or_default()
+        }),
+        "position" => connected.sort_by_key(|section| {
+            match section.geometry.as_deref().and_then(parse_geometry) {
+                Some(geometry) => (0, geometry.x, geometry.y),
+                None => (1, 0, 0),
+            }
+        }),
+        _ => {
+            return Err(
+                format!("invalid sort order: {sort} (expected name, edid or position)").into(),
+            )
+        }
+    }
+    if connected.is_empty() {
+        return Err("no connected displays".to_string().into());
     }
+    Ok(connected
+        .iter()
+        .map(|section| section.name.clone())
+        .collect())
+}
+
+fn run_row_display_output(
+    displays: &[String],
+    primary: usize,
+    sections: &[DisplaySection],
+    screen_maximum: Option<(i64, i64)>,
+    backend: Backend,
+    flags: &ApplyFlags,
+) -> Result<Outcome, CliError> {
+    let result = apply_row_layout(displays, primary, sections, screen_maximum, backend, flags);
+    guard_json_failure(result, flags)
+}
 
-    if find_section(sections, left).is_none() {
-        return Err(format!("display not found: {left}"));
+fn apply_row_layout(
+    displays: &[String],
+    primary: usize,
+    sections: &[DisplaySection],
+    screen_maximum: Option<(i64, i64)>,
+    backend: Backend,
+    flags: &ApplyFlags,
+) -> Result<Outcome, CliError> {
+    if displays.is_empty() {
+        return Err(CliError::Usage("missing argument: display".to_string()));
     }
-    if find_section(sections, right).is_none() {
-        return Err(format!("display not found: {right}"));
+    if primary >= displays.len() {
+        return Err(format!("primary index out of range: {}", primary + 1).into());
     }
 
     let mut exclude = HashSet::new();
-    exclude.insert(left.to_string());
-    exclude.insert(right.to_string());
+    for display in displays {
+        if find_section(sections, display).is_none() {
+            return Err(display_not_found(sections, display));
+        }
+        if !exclude.insert(display.clone()) {
+            return Err(format!("display listed more than once: {display}").into());
+        }
+    }
 
     let off_targets = filtered_display_names(sections, &exclude);
+    let before = current_output_states(sections);
+    let after = planned_row_states(displays, primary, sections, backend, flags)?;
+    if let (false, Some((max_width, max_height))) = (flags.bezel.is_empty(), screen_maximum) {
+        let (right, bottom) = after
+            .iter()
+            .filter_map(|state| state.geometry.as_deref().and_then(parse_geometry))
+            .fold((0, 0), |(right, bottom), geometry| {
+                (
+                    right.max(i64::from(geometry.x) + i64::from(geometry.width)),
+                    bottom.max(i64::from(geometry.y) + i64::from(geometry.height)),
+                )
+            });
+        if right > max_width || bottom > max_height {
+            return Err(format!(
+                "layout with bezel gaps needs {right}x{bottom}, exceeding screen maximum {max_width}x{max_height}"
+            ).into());
+        }
+    }
 
-    let mut args = vec![
-        "--output".to_string(),
-        left.to_string(),
-        "--primary".to_string(),
-        "--auto".to_string(),
-        "--output".to_string(),
-        right.to_string(),
-        "--auto".to_string(),
-        "--right-of".to_string(),
-        left.to_string(),
-    ];
+    let mut args = Vec::new();
+    for (index, display) in displays.iter().enumerate() {
+        args.push("--output".to_string());
+        args.push(display.clone());
+        let fixed_mode = match &flags.rate {
+            Some(choice) => {
+                let section = find_section(sections, display)
+                    .ok_or_else(|| display_not_found(sections, display))?;
+                let (width, height) = preferred_mode_size(section).ok_or_else(|| {
+                    format!("preferred mode not available for display: {display}")
+                })?;
+                let rate = select_mode_rate(section, width, height, Some(choice))
+                    .ok_or_else(|| format!("no refresh rates available for display: {display}"))?;
+                Some((format!("{width}x{height}"), format!("{rate:.2}")))
+            }
+            None => None,
+        };
+        match backend {
+            Backend::Xrandr => {
+                if index == primary {
+                    args.push("--primary".to_string());
+                }
+                match &fixed_mode {
+                    Some((mode, rate)) => {
+                        args.push("--mode".to_string());
+                        args.push(mode.clone());
+                        args.push("--rate".to_string());
+                        args.push(rate.clone());
+                    }
+                    None => args.push("--auto".to_string()),
+                }
+                if let Some(scale) = flags.scale_for(display) {
+                    args.push("--scale".to_string());
+                    args.push(format!("{scale}x{scale}"));
+                }
+                if let Some(rotation) = flags.rotation_for(display) {
+                    args.push("--rotate".to_string());
+                    args.push(rotation.to_string());
+                }
+                if flags.explicit_positions() {
+                    let geometry = after
+                        .iter()
+                        .find(|state| &state.name == display)
+                        .and_then(|state| state.geometry.as_deref())
+                        .and_then(parse_geometry)
+                        .ok_or_else(|| {
+                            format!("preferred mode not available for display: {display}")
+                        })?;
+                    args.push("--pos".to_string());
+                    args.push(format!("{}x{}", geometry.x, geometry.y));
+                } else if index > 0 {
+                    args.push(flags.position.unwrap_or_default().xrandr_flag().to_string());
+                    args.push(displays[index - 1].clone());
+                }
+            }
+            Backend::Wlr => {
+                let geometry = after
+                    .iter()
+                    .find(|state| &state.name == display)
+                    .and_then(|state| state.geometry.as_deref())
+                    .and_then(parse_geometry)
+                    .ok_or_else(|| {
+                        format!("preferred mode not available for display: {display}")
+                    })?;
+                args.push("--on".to_string());
+                match &fixed_mode {
+                    Some((mode, rate)) => {
+                        args.push("--mode".to_string());
+                        args.push(format!("{mode}@{rate}"));
+                    }
+                    None => args.push("--preferred".to_string()),
+                }
+                if let Some(scale) = flags.scale_for(display) {
+                    args.push("--scale".to_string());
+                    args.push(scale.to_string());
+                }
+                if let Some(rotation) = flags.rotation_for(display) {
+                    args.push("--transform".to_string());
+                    args.push(rotation_wlr_transform(rotation).to_string());
+                }
+                args.push("--pos".to_string());
+                args.push(format!("{},{}", geometry.x, geometry.y));
+            }
+        }
+    }
     args.extend(build_off_args(&off_targets));
 
-    run_xrandr_with_args(args)
+    finish_apply(sections, &before, &after, args, backend, flags)
 }
 
-fn filtered_display_names(sections: &[DisplaySection], exclude: &HashSet<String>) -> Vec<String> {
+#[derive(Clone, PartialEq)]
+struct OutputState {
+    name: String,
+    geometry: Option<String>,
+    rate: Option<String>,
+    primary: bool,
+    rotation: Option<String>,
+    scale: Option<String>,
+}
+
+impl OutputState {
+    fn describe(&self) -> String {
+        let geometry = match &self.geometry {
+            Some(geometry) => geometry,
+            None => return format!("{} off", self.name),
+        };
+        let mut text = format!("{} {geometry}", self.name);
+        if let Some(rate) = &self.rate {
+            text.push_str(&format!(" {rate}Hz"));
+        }
+        if self.primary {
+            text.push_str(" primary");
+        }
+        if let Some(rotation) = self
+            .rotation
+            .as_deref()
+            .filter(|rotation| *rotation != "normal")
+        {
+            text.push_str(&format!(" rotate {rotation}"));
+        }
+        if let Some(scale) = self
+            .scale
+            .as_deref()
+            .filter(|scale| *scale != format_scale(1.0))
+        {
+            text.push_str(&format!(" scale {scale}"));
+        }
+        text
+    }
+}
+
+fn current_output_states(sections: &[DisplaySection]) -> Vec<OutputState> {
     sections
         .iter()
-        .map(|section| section.name.as_str())
-        .filter(|name| !exclude.contains(*name))
-        .map(|name| name.to_string())
+        .map(|section| {
+            let active = section.geometry.is_some();
+            OutputState {
+                name: section.name.clone(),
+                geometry: section.geometry.clone(),
+                rate: section.geometry.as_ref().and_then(|_| {
+                    parse_modes(section)
+                        .iter()
+                        .find(|mode| mode.current)
+                        .map(|mode| format!("{:.2}", mode.refresh))
+                }),
+                primary: section.primary && active,
+                rotation: active
+                    .then(|| section.rotation.as_deref().unwrap_or("normal").to_string()),
+                scale: active.then(|| format_scale(section_scale(section).unwrap_or(1.0))),
+            }
+        })
         .collect()
 }
 
-fn build_off_args(displays: &[String]) -> Vec<String> {
-    let mut args = Vec::new();
-    for display in displays {
-        args.push("--output".to_string());
-        args.push(display.clone());
-        args.push("--off".to_string());
+fn format_scale(scale: f64) -> String {
+    format!("{scale:.2}")
+}
+
+/// Uniform output scale: wlr-randr reports it directly, xrandr as the diagonal of
+/// the output transform.
+fn section_scale(section: &DisplaySection) -> Option<f64> {
+    if let Some(scale) = wlr_field(section, "Scale:") {
+        return scale.parse().ok();
+    }
+    let matrix = section_transform(section)?;
+    let value = |index: usize| matrix[index].parse::<f64>().ok();
+    let (x, y) = (value(0)?, value(4)?);
+    (x == y).then_some(x)
+}
+
+#[derive(Clone, Copy, Default)]
+enum RowPosition {
+    #[default]
+    RightOf,
+    LeftOf,
+    Above,
+    Below,
+}
+
+impl RowPosition {
+    fn parse(value: &str) -> Result<Self, CliError> {
+        match value {
+            "right-of" => Ok(Self::RightOf),
+            "left-of" => Ok(Self::LeftOf),
+            "above" => Ok(Self::Above),
+            "below" => Ok(Self::Below),
+            _ => Err(format!(
+                "invalid position: {value} (expected above, below, left-of or right-of)"
+            )
+            .into()),
+        }
+    }
+
+    fn xrandr_flag(self) -> &'static str {
+        match self {
+            Self::RightOf => "--right-of",
+            Self::LeftOf => "--left-of",
+            Self::Above => "--above",
+            Self::Below => "--below",
+        }
+    }
+
+    fn gap(self, pixels: u32) -> (i32, i32) {
+        let pixels = pixels as i32;
+        match self {
+            Self::RightOf => (pixels, 0),
+            Self::LeftOf => (-pixels, 0),
+            Self::Above => (0, -pixels),
+            Self::Below => (0, pixels),
+        }
+    }
+
+    fn next_origin(self, previous: (i32, i32, u32, u32), width: u32, height: u32) -> (i32, i32) {
+        let (x, y, previous_width, previous_height) = previous;
+        match self {
+            Self::RightOf => (x + previous_width as i32, y),
+            Self::LeftOf => (x - width as i32, y),
+            Self::Above => (x, y - height as i32),
+            Self::Below => (x, y + previous_height as i32),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Default, PartialEq)]
+enum VAlign {
+    #[default]
+    Top,
+    Center,
+    Bottom,
+}
+
+impl VAlign {
+    fn parse(value: &str) -> Result<Self, CliError> {
+        match value {
+            "top" => Ok(Self::Top),
+            "center" => Ok(Self::Center),
+            "bottom" => Ok(Self::Bottom),
+            _ => Err(format!("invalid valign: {value} (expected top, center or bottom)").into()),
+        }
+    }
+
+    fn offset(self, previous_height: u32, height: u32) -> i32 {
+        let difference = previous_height as i32 - height as i32;
+        match self {
+            Self::Top => 0,
+            Self::Center => difference / 2,
+            Self::Bottom => difference,
+        }
+    }
+}
+
+#[derive(Clone)]
+enum RateChoice {
+    Fixed(f64),
+    Max,
+}
+
+fn select_mode_rate(
+    section: &DisplaySection,
+    width: u32,
+    height: u32,
+    choice: Option<&RateChoice>,
+) -> Option<f64> {
+    let modes = parse_modes(section);
+    let candidates: Vec<&Mode> = modes
+        .iter()
+        .filter(|mode| (mode.width, mode.height) == (width, height))
+        .collect();
+    match choice {
+        Some(RateChoice::Fixed(rate)) => candidates
+            .iter()
+            .min_by(|a, b| {
+                (a.refresh - rate)
+                    .abs()
+                    .total_cmp(&(b.refresh - rate).abs())
+            })
+            .map(|mode| mode.refresh),
+        Some(RateChoice::Max) => candidates
+            .iter()
+            .map(|mode| mode.refresh)
+            .max_by(|a, b| a.total_cmp(b)),
+        None => candidates
+            .iter()
+            .find(|mode| mode.preferred)
+            .or(candidates.first())
+            .map(|mode| mode.refresh),
+    }
+}
+
+fn row_mode_size(section: &DisplaySection, backend: Backend) -> Result<(u32, u32), CliError> {
+    let preferred = match backend {
+        Backend::Xrandr => preferred_mode_size(section),
+        Backend::Wlr => wlr_preferred_size(section),
+    };
+    preferred
+        .or_else(|| {
+            section
+                .geometry
+                .as_deref()
+                .and_then(parse_geometry)
+                .map(|geometry| (geometry.width, geometry.height))
+        })
+        .ok_or_else(|| format!("preferred mode not available for display: {}", section.name).into())
+}
+
+fn planned_row_states(
+    displays: &[String],
+    primary: usize,
+    sections: &[DisplaySection],
+    backend: Backend,
+    flags: &ApplyFlags,
+) -> Result<Vec<OutputState>, CliError> {
+    let mut placed = Vec::new();
+    let mut previous: Option<(i32, i32, u32, u32)> = None;
+    let position = flags.position.unwrap_or_default();
+    let valign = flags.valign.unwrap_or_default();
+    if valign != VAlign::Top && matches!(position, RowPosition::Above | RowPosition::Below) {
+        return Err("--valign only applies to left-of and right-of layouts"
+            .to_string()
+            .into());
+    }
+    if flags.bezel.len() > 1 && flags.bezel.len() + 1 != displays.len() {
+        return Err(format!(
+            "--bezel lists {} gaps but the layout has {}",
+            flags.bezel.len(),
+            displays.len().saturating_sub(1)
+        )
+        .into());
+    }
+
+    for (index, display) in displays.iter().enumerate() {
+        let section =
+            find_section(sections, display).ok_or_else(|| display_not_found(sections, display))?;
+        let current = section.geometry.as_deref().and_then(parse_geometry);
+        let (width, height) = row_mode_size(section, backend)?;
+        let (width, height) = match flags.rotation_for(display) {
+            Some("left" | "right") => (height, width),
+            _ => (width, height),
+        };
+        let (width, height) = match (flags.scale_for(display), backend) {
+            (Some(scale), Backend::Xrandr) => (
+                (f64::from(width) * scale).round() as u32,
+                (f64::from(height) * scale).round() as u32,
+            ),
+            (Some(scale), Backend::Wlr) => (
+                (f64::from(width) / scale).round() as u32,
+                (f64::from(height) / scale).round() as u32,
+            ),
+            (None, _) => (width, height),
+        };
+        let (x, y) = match previous {
+            Some(previous) => {
+                let (x, y) = position.next_origin(previous, width, height);
+                let gap = flags.bezel_gap(index - 1);
+                let (gap_x, gap_y) = position.gap(gap);
+                (x + gap_x, y + gap_y + valign.offset(previous.3, height))
+            }
+            None => match (backend, &current) {
+                (Backend::Xrandr, Some(geometry)) => (geometry.x, geometry.y),
+                _ => (0, 0),
+            },
+        };
+        let mode_rate = preferred_mode_size(section).and_then(|(width, height)| {
+            select_mode_rate(section, width, height, flags.rate.as_ref())
+                .map(|rate| format!("{rate:.2}"))
+        });
+        placed.push((display.as_str(), width, height, x, y, mode_rate));
+        previous = Some((x, y, width, height));
+    }
+
+    let min_x = placed.iter().map(|entry| entry.3).min().unwrap_or(0).min(0);
+    let min_y = placed.iter().map(|entry| entry.4).min().unwrap_or(0).min(0);
+    let placed: HashMap<&str, (String, Option<String>)> = placed
+        .into_iter()
+        .map(|(name, width, height, x, y, rate)| {
+            let (x, y) = (x - min_x, y - min_y);
+            (name, (format!("{width}x{height}{x:+}{y:+}"), rate))
+        })
+        .collect();
+
+    Ok(sections
+        .iter()
+        .map(|section| {
+            let active = placed.contains_key(section.name.as_str());
+            let rotation = flags
+                .rotation_for(&section.name)
+                .or(section.rotation.as_deref())
+                .unwrap_or("normal");
+            let scale = flags
+                .scale_for(&section.name)
+                .or_else(|| section_scale(section))
+                .unwrap_or(1.0);
+            OutputState {
+                name: section.name.clone(),
+                geometry: placed
+                    .get(section.name.as_str())
+                    .map(|(geometry, _)| geometry.clone()),
+                rate: placed
+                    .get(section.name.as_str())
+                    .and_then(|(_, rate)| rate.clone()),
+                primary: backend == Backend::Xrandr && displays.get(primary) == Some(&section.name),
+                rotation: active.then(|| rotation.to_string()),
+                scale: active.then(|| format_scale(scale)),
+            }
+        })
+        .collect())
+}
+
+fn finish_apply(
+    sections: &[DisplaySection],
+    before: &[OutputState],
+    after: &[OutputState],
+    args: Vec<String>,
+    backend: Backend,
+    flags: &ApplyFlags,
+) -> Result<Outcome, CliError> {
+    let changed = before != after;
+    finish_apply_change(sections, before, after, changed, args, backend, flags)
+}
+
+/// Like `finish_apply`, for callers that also detect changes `OutputState` does not
+/// capture, such as gamma or brightness in a saved state.
+fn finish_apply_change(
+    sections: &[DisplaySection],
+    before: &[OutputState],
+    after: &[OutputState],
+    changed: bool,
+    args: Vec<String>,
+    backend: Backend,
+    flags: &ApplyFlags,
+) -> Result<Outcome, CliError> {
+    let plan = build_plan(before, after);
+    if flags.plan && !flags.json {
+        for step in &plan {
+            println!("{}", step.line());
+        }
+    }
+    let any_active = |states: &[OutputState]| states.iter().any(|state| state.geometry.is_some());
+    if changed && !flags.force && any_active(before) && !any_active(after) {
+        return Err(LAST_ACTIVE_DISPLAY_ERROR.to_string().into());
+    }
+    if changed {
+        for issue in layout_offset_issues(after) {
+            eprintln!("warning: {issue}");
+        }
+    }
+    if changed && !flags.check {
+        check_remote_session(flags.force || flags.confirm_timeout.is_some())?;
+        if flags.undo {
+            run_backend_with_args(backend, args)?;
+        } else {
+            apply_layout_change(sections, backend, args)?;
+        }
+        if let Some(timeout) = flags.confirm_timeout {
+            if !is_dry_run() && !confirm_change(timeout) {
+                run_backend_with_args(backend, full_state_args(sections, backend))?;
+                return Err("layout change not confirmed; reverted".to_string().into());
+            }
+        }
+    }
+
+    if flags.json {
+        let mut json = format!("{{\"changed\": {changed}");
+        if flags.plan {
+            let steps = plan.iter().map(PlanStep::json).collect::<Vec<_>>();
+            json.push_str(&format!(", \"plan\": [{}]", steps.join(", ")));
+        }
+        if flags.diff {
+            json.push_str(&format!(
+                ", \"diff\": {{\"before\": {}, \"after\": {}}}",
+                json_string(&describe_states(before)),
+                json_string(&describe_states(after))
+            ));
+        }
+        json.push('}');
+        println!("{json}");
+        return Ok(Outcome::Done);
+    }
+
+    if flags.diff {
+        for (old, new) in before.iter().zip(after) {
+            if old != new {
+                println!("- {}", old.describe());
+                println!("+ {}", new.describe());
+            }
+        }
+    }
+    if flags.check {
+        return Ok(report_check(changed));
+    }
+    if changed && flags.exit_changed {
+        return Ok(Outcome::Changed);
+    }
+    Ok(Outcome::Done)
+}
+
+struct PlanStep {
+    output: String,
+    op: &'static str,
+    old: Option<String>,
+    new: Option<String>,
+}
+
+impl PlanStep {
+    fn line(&self) -> String {
+        format!(
+            "{} {} {} -> {}",
+            self.output,
+            self.op,
+            self.old.as_deref().unwrap_or("-"),
+            self.new.as_deref().unwrap_or("-")
+        )
+    }
+
+    fn json(&self) -> String {
+        let value = |value: &Option<String>| {
+            value
+                .as_deref()
+                .map(json_string)
+                .unwrap_or_else(|| "null".to_string())
+        };
+        format!(
+            "{{\"output\": {}, \"op\": {}, \"old\": {}, \"new\": {}}}",
+            json_string(&self.output),
+            json_string(self.op),
+            value(&self.old),
+            value(&self.new)
+        )
+    }
+}
+
+/// Summarises the change from `before` to `after` for `--plan`: disables first, then
+/// geometry, rate, rotation and scale changes, then primary moves. This only describes
+/// the change; each command still builds its own backend arguments.
+fn build_plan(before: &[OutputState], after: &[OutputState]) -> Vec<PlanStep> {
+    let mut disables = Vec::new();
+    let mut changes = Vec::new();
+    let mut primaries = Vec::new();
+    for (old, new) in before.iter().zip(after) {
+        let step = |op, from: &Option<String>, to: &Option<String>| PlanStep {
+            output: new.name.clone(),
+            op,
+            old: from.clone(),
+            new: to.clone(),
+        };
+        match (&old.geometry, &new.geometry) {
+            (Some(_), None) => disables.push(step("disable", &old.geometry, &None)),
+            (None, Some(_)) => changes.push(step("enable", &None, &new.geometry)),
+            (Some(from), Some(to)) if from != to => {
+                changes.push(step("geometry", &old.geometry, &new.geometry));
+            }
+            _ => {}
+        }
+        if new.geometry.is_some() && old.rate != new.rate {
+            changes.push(step("rate", &old.rate, &new.rate));
+        }
+        if old.geometry.is_some() && new.geometry.is_some() {
+            if old.rotation != new.rotation {
+                changes.push(step("rotation", &old.rotation, &new.rotation));
+            }
+            if old.scale != new.scale {
+                changes.push(step("scale", &old.scale, &new.scale));
+            }
+        }
+        if old.primary != new.primary {
+            let flag = |primary: bool| Some(primary.to_string());
+            primaries.push(step("primary", &flag(old.primary), &flag(new.primary)));
+        }
+    }
+    disables.extend(changes);
+    disables.extend(primaries);
+    disables
+}
+
+fn is_remote_session() -> bool {
+    env::var("SSH_CONNECTION").is_ok_and(|value| !value.is_empty())
+}
+
+/// Refuses to change the layout from an SSH session, where a bad layout can leave the
+/// local screen unusable with nobody there to fix it, unless the command's `--force` or
+/// the global `--allow-remote` is given, or the change will be reverted without a
+/// confirmation.
+fn check_remote_session(exempt: bool) -> Result<(), CliError> {
+    if exempt || is_dry_run() || ALLOW_REMOTE.load(Ordering::Relaxed) || !is_remote_session() {
+        return Ok(());
+    }
+    Err(
+        "remote session detected (SSH_CONNECTION); pass --force or --confirm-timeout <seconds> where supported, or --allow-remote"
+            .to_string()
+            .into(),
+    )
+}
+
+fn confirm_change(timeout: Duration) -> bool {
+    let mut tty = match std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/tty")
+    {
+        Ok(tty) => tty,
+        Err(_) => return false,
+    };
+    let _ = write!(
+        tty,
+        "keep this layout? [y/N] (reverting in {}s) ",
+        timeout.as_secs()
+    );
+    let _ = tty.flush();
+
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let mut line = String::new();
+        let _ = io::BufReader::new(tty).read_line(&mut line);
+        let _ = sender.send(line);
+    });
+    match receiver.recv_timeout(timeout) {
+        Ok(line) => matches!(line.trim().to_ascii_lowercase().as_str(), "y" | "yes"),
+        Err(_) => false,
+    }
+}
+
+fn restore_args(sections: &[DisplaySection], backend: Backend) -> Vec<String> {
+    let mut args = Vec::new();
+    for section in sections {
+        let current = parse_modes(section).into_iter().find(|mode| mode.current);
+        let geometry = section.geometry.as_deref().and_then(parse_geometry);
+        args.push("--output".to_string());
+        args.push(section.name.clone());
+        let (mode, geometry) = match (current, geometry) {
+            (Some(mode), Some(geometry)) => (mode, geometry),
+            _ => {
+                args.push("--off".to_string());
+                continue;
+            }
+        };
+        let rotation = section.rotation.as_deref().unwrap_or("normal");
+        match backend {
+            Backend::Xrandr => {
+                args.push("--mode".to_string());
+                args.push(format!("{}x{}", mode.width, mode.height));
+                args.push("--rate".to_string());
+                args.push(format!("{:.2}", mode.refresh));
+                args.push("--pos".to_string());
+                args.push(format!("{}x{}", geometry.x, geometry.y));
+                args.push("--rotate".to_string());
+                args.push(rotation.to_string());
+                if section.primary {
+                    args.push("--primary".to_string());
+                }
+            }
+            Backend::Wlr => {
+                args.push("--on".to_string());
+                args.push("--mode".to_string());
+                args.push(format!(
+                    "{}x{}@{:.3}",
+                    mode.width, mode.height, mode.refresh
+                ));
+                args.push("--pos".to_string());
+                args.push(format!("{},{}", geometry.x, geometry.y));
+                args.push("--transform".to_string());
+                args.push(rotation_wlr_transform(rotation).to_string());
+            }
+        }
+    }
+    args
+}
+
+const LAST_ACTIVE_DISPLAY_ERROR: &str =
+    "refusing to turn off every active display (use --force to override)";
+
+const MAX_SCREEN_COORDINATE: i64 = 32767;
+
+fn layout_offset_issues(states: &[OutputState]) -> Vec<String> {
+    let mut issues = Vec::new();
+    for state in states {
+        let geometry = match state.geometry.as_deref().and_then(parse_geometry) {
+            Some(geometry) => geometry,
+            None => continue,
+        };
+        if geometry.x < 0 || geometry.y < 0 {
+            issues.push(format!(
+                "{}: negative offset {},{}",
+                state.name, geometry.x, geometry.y
+            ));
+        }
+        let right = i64::from(geometry.x) + i64::from(geometry.width);
+        let bottom = i64::from(geometry.y) + i64::from(geometry.height);
+        if right > MAX_SCREEN_COORDINATE || bottom > MAX_SCREEN_COORDINATE {
+            issues.push(format!(
+                "{}: extends to {right},{bottom}, beyond {MAX_SCREEN_COORDINATE}",
+                state.name
+            ));
+        }
+    }
+    issues
+}
+
+fn run_normalize_layout(
+    sections: &[DisplaySection],
+    backend: Backend,
+    flags: &ApplyFlags,
+) -> Result<Outcome, CliError> {
+    let before = current_output_states(sections);
+    let active: Vec<(&OutputState, Geometry)> = before
+        .iter()
+        .filter_map(|state| {
+            let geometry = parse_geometry(state.geometry.as_deref()?)?;
+            Some((state, geometry))
+        })
+        .collect();
+    if active.is_empty() {
+        return Err("no active displays to normalize".to_string().into());
+    }
+
+    let min_x = active
+        .iter()
+        .map(|(_, geometry)| geometry.x)
+        .min()
+        .unwrap_or(0);
+    let min_y = active
+        .iter()
+        .map(|(_, geometry)| geometry.y)
+        .min()
+        .unwrap_or(0);
+
+    let mut args = Vec::new();
+    let mut moved = HashMap::new();
+    for (state, geometry) in &active {
+        let x = geometry.x - min_x;
+        let y = geometry.y - min_y;
+        moved.insert(
+            state.name.as_str(),
+            format!("{}x{}{x:+}{y:+}", geometry.width, geometry.height),
+        );
+        args.push("--output".to_string());
+        args.push(state.name.clone());
+        args.push("--pos".to_string());
+        match backend {
+            Backend::Xrandr => args.push(format!("{x}x{y}")),
+            Backend::Wlr => args.push(format!("{x},{y}")),
+        }
+    }
+
+    let after: Vec<OutputState> = before
+        .iter()
+        .map(|state| OutputState {
+            geometry: moved.get(state.name.as_str()).cloned(),
+            ..state.clone()
+        })
+        .collect();
+
+    let remaining = layout_offset_issues(&after);
+    if let Some(issue) = remaining.first() {
+        return Err(format!("cannot normalize layout: {issue}").into());
+    }
+
+    finish_apply(sections, &before, &after, args, backend, flags)
+}
+
+fn describe_states(states: &[OutputState]) -> String {
+    let mut text = String::new();
+    for state in states {
+        text.push_str(&state.describe());
+        text.push('\n');
+    }
+    text
+}
+
+fn guard_json_failure(
+    result: Result<Outcome, CliError>,
+    flags: &ApplyFlags,
+) -> Result<Outcome, CliError> {
+    match result {
+        Err(err) if flags.json => {
+            println!(
+                "{{\"failed\": true, \"msg\": {}}}",
+                json_string(err.message())
+            );
+            Ok(Outcome::Failed)
+        }
+        other => other,
+    }
+}
+
+fn report_check(changed: bool) -> Outcome {
+    if changed {
+        println!("changed");
+        return Outcome::Changed;
+    }
+    println!("unchanged");
+    Outcome::Done
+}
+
+#[derive(Default)]
+struct ApplyFlags {
+    check: bool,
+    diff: bool,
+    json: bool,
+    force: bool,
+    plan: bool,
+    exit_changed: bool,
+    undo: bool,
+    confirm_timeout: Option<Duration>,
+    scale: Option<f64>,
+    display_scales: Vec<(String, f64)>,
+    display_rotations: Vec<(String, String)>,
+    rate: Option<RateChoice>,
+    position: Option<RowPosition>,
+    valign: Option<VAlign>,
+    bezel: Vec<u32>,
+}
+
+impl ApplyFlags {
+    fn bezel_gap(&self, pair: usize) -> u32 {
+        match self.bezel[..] {
+            [gap] => gap,
+            _ => self.bezel.get(pair).copied().unwrap_or(0),
+        }
+    }
+
+    fn explicit_positions(&self) -> bool {
+        self.valign.unwrap_or_default() != VAlign::Top || self.bezel.iter().any(|gap| *gap > 0)
+    }
+
+    fn rotation_for(&self, display: &str) -> Option<&str> {
+        self.display_rotations
+            .iter()
+            .find(|(name, _)| name == display)
+            .map(|(_, rotation)| rotation.as_str())
+    }
+
+    fn scale_for(&self, display: &str) -> Option<f64> {
+        self.display_scales
+            .iter()
+            .find(|(name, _)| name == display)
+            .map(|(_, scale)| *scale)
+            .or(self.scale)
+    }
+
+    fn accept(
+        &mut self,
+        arg: &str,
+        args: &mut impl Iterator<Item = String>,
+    ) -> Result<bool, CliError> {
+        match arg {
+            "--check" => self.check = true,
+            "--diff" => self.diff = true,
+            "--json" => self.json = true,
+            "--force" => self.force = true,
+            "--plan" => self.plan = true,
+            "--exit-changed" => self.exit_changed = true,
+            "--scale" => {
+                let value = args
+                    .next()
+                    .ok_or_else(|| CliError::Usage("missing value for --scale".to_string()))?;
+                self.scale = Some(parse_scale_factor(&value)?.0);
+            }
+            "--confirm-timeout" => {
+                let value = args.next().ok_or_else(|| {
+                    CliError::Usage("missing value for --confirm-timeout".to_string())
+                })?;
+                self.confirm_timeout = Some(parse_seconds(&value, "confirm timeout")?);
+            }
+            "--position" => {
+                let value = args
+                    .next()
+                    .ok_or_else(|| CliError::Usage("missing value for --position".to_string()))?;
+                self.position = Some(RowPosition::parse(&value)?);
+            }
+            "--valign" => {
+                let value = args
+                    .next()
+                    .ok_or_else(|| CliError::Usage("missing value for --valign".to_string()))?;
+                self.valign = Some(VAlign::parse(&value)?);
+            }
+            "--bezel" => {
+                let value = args
+                    .next()
+                    .ok_or_else(|| CliError::Usage("missing value for --bezel".to_string()))?;
+                self.bezel = value
+                    .split(',')
+                    .map(|gap| gap.trim().parse::<u32>().ok())
+                    .collect::<Option<Vec<_>>>()
+                    .ok_or_else(|| format!("invalid bezel: {value} (expected <px>[,<px>...])"))?;
+            }
+            "--max-rate" => self.rate = Some(RateChoice::Max),
+            "--rate" => {
+                let value = args
+                    .next()
+                    .ok_or_else(|| CliError::Usage("missing value for --rate".to_string()))?;
+                let rate = value
+                    .parse::<f64>()
+                    .ok()
+                    .filter(|rate| *rate > 0.0)
+                    .ok_or_else(|| format!("invalid rate: {value}"))?;
+                self.rate = Some(RateChoice::Fixed(rate));
+            }
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+}
+
+fn parse_apply_flags(args: &mut impl Iterator<Item = String>) -> Result<ApplyFlags, CliError> {
+    let mut flags = ApplyFlags::default();
+    while let Some(arg) = args.next() {
+        if !flags.accept(&arg, args)? {
+            return Err(CliError::Usage(format!("unknown option: {arg}")));
+        }
+    }
+    Ok(flags)
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum DualAlign {
+    None,
+    Height,
+    Width,
+}
+
+fn parse_dual_args(
+    args: &mut impl Iterator<Item = String>,
+) -> Result<(usize, DualAlign, ApplyFlags), CliError> {
+    let mut primary = 0;
+    let mut align = DualAlign::None;
+    let mut flags = ApplyFlags::default();
+
+    while let Some(arg) = args.next() {
+        if flags.accept(&arg, args)? {
+            continue;
+        }
+        if arg == "--align" {
+            let value = expect_arg(args, "align")?;
+            align = match value.as_str() {
+                "height" => DualAlign::Height,
+                "width" => DualAlign::Width,
+                "none" => DualAlign::None,
+                _ => {
+                    return Err(
+                        format!("invalid align: {value} (expected height, width or none)").into(),
+                    )
+                }
+            };
+            continue;
+        }
+        if arg != "--primary" {
+            return Err(CliError::Usage(format!("unknown option: {arg}")));
+        }
+        let value = args
+            .next()
+            .ok_or_else(|| CliError::Usage("missing value for --primary".to_string()))?;
+        primary = match value.as_str() {
+            "left" => 0,
+            "right" => 1,
+            _ => return Err(format!("invalid primary: {value} (expected left or right)").into()),
+        };
+    }
+    Ok((primary, align, flags))
+}
+
+fn align_dual_scales(
+    flags: &mut ApplyFlags,
+    displays: [&str; 2],
+    primary: usize,
+    align: DualAlign,
+    sections: &[DisplaySection],
+    backend: Backend,
+) -> Result<(), CliError> {
+    if align == DualAlign::None {
+        return Ok(());
+    }
+    let size = |display: &str| {
+        let section =
+            find_section(sections, display).ok_or_else(|| display_not_found(sections, display))?;
+        let (width, height) = row_mode_size(section, backend)?;
+        Ok::<u32, CliError>(match align {
+            DualAlign::Width => width,
+            _ => height,
+        })
+    };
+    let secondary = displays[1 - primary];
+    let (target, own) = (size(displays[primary])?, size(secondary)?);
+    let base = flags.scale.unwrap_or(1.0);
+    let scale = match backend {
+        Backend::Xrandr => base * f64::from(target) / f64::from(own),
+        Backend::Wlr => base * f64::from(own) / f64::from(target),
+    };
+    flags
+        .display_scales
+        .push((secondary.to_string(), (scale * 10000.0).round() / 10000.0));
+    Ok(())
+}
+
+fn parse_row_args(
+    args: &mut impl Iterator<Item = String>,
+) -> Result<(Vec<String>, usize, ApplyFlags), CliError> {
+    let mut displays = Vec::new();
+    let mut primary = 0;
+    let mut flags = ApplyFlags::default();
+
+    while let Some(arg) = args.next() {
+        if flags.accept(&arg, args)? {
+            continue;
+        }
+        if arg == "--primary" {
+            let value = args
+                .next()
+                .ok_or_else(|| CliError::Usage("missing value for --primary".to_string()))?;
+            primary = match value.parse::<usize>() {
+                Ok(index) if index > 0 => index - 1,
+                _ => return Err(format!("invalid primary index: {value}").into()),
+            };
+        } else if arg.starts_with("--") {
+            return Err(CliError::Usage(format!("unknown option: {arg}")));
+        } else {
+            displays.push(arg);
+        }
+    }
+
+    Ok((displays, primary, flags))
+}
+
+#[derive(Default)]
+struct LayoutOutput {
+    name: String,
+    identity: Option<String>,
+    mode: Option<String>,
+    resolution: Option<String>,
+    rate: Option<String>,
+    position: Option<String>,
+    rotation: Option<String>,
+    scale: Option<String>,
+    primary: bool,
+    off: bool,
+}
+
+struct Layout {
+    outputs: Vec<LayoutOutput>,
+    off_others: bool,
+}
+
+fn parse_layout(text: &str) -> Result<Layout, CliError> {
+    parse_layout_entries(parse_config(text)?)
+}
+
+fn parse_layout_entries(entries: Vec<ConfigEntry>) -> Result<Layout, CliError> {
+    let mut layout = Layout {
+        outputs: Vec::new(),
+        off_others: true,
+    };
+
+    for entry in entries {
+        if entry.table.is_empty() {
+            match entry.key.as_str() {
+                "off_others" => layout.off_others = parse_config_bool(&entry)?,
+                _ => return Err(format!("unknown layout key: {}", entry.key).into()),
+            }
+            continue;
+        }
+
+        let name = entry
+            .table
+            .strip_prefix("output.")
+            .ok_or_else(|| format!("unknown layout table: {}", entry.table))?;
+        let index = match layout.outputs.iter().position(|output| output.name == name) {
+            Some(index) => index,
+            None => {
+                layout.outputs.push(LayoutOutput {
+                    name: name.to_string(),
+                    ..LayoutOutput::default()
+                });
+                layout.outputs.len() - 1
+            }
+        };
+        let output = &mut layout.outputs[index];
+        match entry.key.as_str() {
+            "mode" => output.mode = Some(entry.value.clone()).filter(|mode| mode != "auto"),
+            "resolution" => output.resolution = Some(entry.value.clone()),
+            "identity" => output.identity = Some(entry.value.clone()),
+            "rate" => output.rate = Some(entry.value.clone()),
+            "position" => output.position = Some(entry.value.clone()),
+            "rotation" => output.rotation = Some(entry.value.clone()),
+            "scale" => output.scale = Some(entry.value.clone()),
+            "primary" => output.primary = parse_config_bool(&entry)?,
+            "off" => output.off = parse_config_bool(&entry)?,
+            _ => return Err(format!("unknown output key: {}", entry.key).into()),
+        }
+    }
+
+    if let Some(output) = layout
+        .outputs
+        .iter()
+        .find(|output| output.mode.is_some() && output.resolution.is_some())
+    {
+        return Err(format!("output {} sets both mode and resolution", output.name).into());
+    }
+    if layout
+        .outputs
+        .iter()
+        .filter(|output| output.primary)
+        .count()
+        > 1
+    {
+        return Err("layout marks more than one output as primary"
+            .to_string()
+            .into());
+    }
+
+    Ok(layout)
+}
+
+fn load_layout(path: &str) -> Result<Layout, CliError> {
+    let text = std::fs::read_to_string(path)
+        .map_err(|err| format!("failed to read layout file {path}: {err}"))?;
+    parse_layout(&text)
+}
+
+fn layout_toml(layout: &Layout) -> String {
+    let mut text = String::new();
+    if !layout.off_others {
+        text.push_str("off_others = false\n\n");
+    }
+    for output in &layout.outputs {
+        text.push_str(&format!("[output.{}]\n", json_string(&output.name)));
+        let fields = [
+            ("identity", &output.identity),
+            ("mode", &output.mode),
+            ("resolution", &output.resolution),
+            ("rate", &output.rate),
+            ("position", &output.position),
+            ("rotation", &output.rotation),
+            ("scale", &output.scale),
+        ];
+        for (key, value) in fields {
+            if let Some(value) = value {
+                text.push_str(&format!("{key} = {}\n", json_string(value)));
+            }
+        }
+        if output.primary {
+            text.push_str("primary = true\n");
+        }
+        if output.off {
+            text.push_str("off = true\n");
+        }
+        text.push('\n');
+    }
+    text
+}
+
+fn autorandr_profile_dir(profile: &str) -> Result<PathBuf, CliError> {
+    if profile.contains('/') {
+        return Ok(PathBuf::from(profile));
+    }
+    Ok(xdg_dir("XDG_CONFIG_HOME", ".config")?
+        .join("autorandr")
+        .join(profile))
+}
+
+fn import_autorandr(dir: &Path) -> Result<Layout, CliError> {
+    let path = dir.join("config");
+    let config = std::fs::read_to_string(&path)
+        .map_err(|err| format!("failed to read {}: {err}", path.display()))?;
+    let mut layout = Layout {
+        outputs: Vec::new(),
+        off_others: true,
+    };
+    for (index, line) in config.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (key, value) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        let value = value.trim();
+        if key == "output" {
+            layout.outputs.push(LayoutOutput {
+                name: value.to_string(),
+                ..LayoutOutput::default()
+            });
+            continue;
+        }
+        let output = layout.outputs.last_mut().ok_or_else(|| {
+            format!(
+                "{} line {}: {key} before any output",
+                path.display(),
+                index + 1
+            )
+        })?;
+        match key {
+            "off" => output.off = true,
+            "primary" => output.primary = true,
+            "mode" => output.mode = Some(value.to_string()),
+            "pos" => output.position = Some(value.to_string()),
+            "rate" => output.rate = Some(value.to_string()),
+            "rotate" => output.rotation = Some(value.to_string()),
+            "scale" | "transform" => match autorandr_scale(key, value) {
+                Some(scale) if scale == "1" => {}
+                Some(scale) => output.scale = Some(scale),
+                None => eprintln!("warning: {}: unsupported {key} {value}", output.name),
+            },
+            "crtc" => {}
+            _ => eprintln!("warning: {}: skipping unsupported {key}", output.name),
+        }
+    }
+
+    let setup = std::fs::read_to_string(dir.join("setup")).unwrap_or_default();
+    for line in setup.lines() {
+        if let Some((name, edid)) = line.trim().split_once(char::is_whitespace) {
+            if let Some(output) = layout.outputs.iter_mut().find(|output| output.name == name) {
+                output.identity = Some(edid_hash(edid.trim()));
+            }
+        }
+    }
+    if layout
+        .outputs
+        .iter()
+        .filter(|output| output.primary)
+        .count()
+        > 1
+    {
+        return Err("autorandr profile marks more than one output as primary"
+            .to_string()
+            .into());
+    }
+    Ok(layout)
+}
+
+fn autorandr_scale(key: &str, value: &str) -> Option<String> {
+    let factors: Vec<f64> = if key == "scale" {
+        let (x, y) = value.split_once('x')?;
+        vec![x.parse().ok()?, y.parse().ok()?]
+    } else {
+        let matrix: Vec<f64> = value
+            .split(',')
+            .map(|cell| cell.trim().parse().ok())
+            .collect::<Option<_>>()?;
+        let diagonal_only = matrix.len() == 9
+            && matrix[8] == 1.0
+            && [1, 2, 3, 5, 6, 7].iter().all(|index| matrix[*index] == 0.0);
+        if !diagonal_only {
+            return None;
+        }
+        vec![matrix[0], matrix[4]]
+    };
+    (factors[0] == factors[1] && factors[0] > 0.0).then(|| factors[0].to_string())
+}
+
+fn export_autorandr(
+    mut layout: Layout,
+    sections: &[DisplaySection],
+    backend: Backend,
+    dir: &Path,
+    force: bool,
+) -> Result<(), CliError> {
+    if !force {
+        if let Some(path) = ["config", "setup"]
+            .iter()
+            .map(|name| dir.join(name))
+            .find(|path| path.exists())
+        {
+            return Err(format!(
+                "{} already exists (use --force to overwrite)",
+                path.display()
+            )
+            .into());
+        }
+    }
+    resolve_layout_identities(&mut layout, sections, Substitution::Never)?;
+    let mut config = String::new();
+    for output in &layout.outputs {
+        let section = find_section(sections, &output.name)
+            .ok_or_else(|| display_not_found(sections, &output.name))?;
+        config.push_str(&format!("output {}\n", output.name));
+        if output.off {
+            config.push_str("off\n");
+            continue;
+        }
+        let (width, height) = match (&output.resolution, &output.mode) {
+            (Some(spec), _) => resolve_layout_resolution(section, backend, spec)?,
+            (None, Some(mode)) => mode
+                .split_once('x')
+                .and_then(|(width, height)| Some((width.parse().ok()?, height.parse().ok()?)))
+                .ok_or_else(|| format!("invalid mode for {}: {mode}", output.name))?,
+            (None, None) => row_mode_size(section, backend)?,
+        };
+        let (x, y) = match &output.position {
+            Some(value) => parse_position(value)
+                .ok_or_else(|| format!("invalid position for {}: {value}", output.name))?,
+            None => section
+                .geometry
+                .as_deref()
+                .and_then(parse_geometry)
+                .map_or((0, 0), |geometry| (geometry.x, geometry.y)),
+        };
+        config.push_str(&format!("mode {width}x{height}\npos {x}x{y}\n"));
+        if output.primary {
+            config.push_str("primary\n");
+        }
+        let rate = match output.rate.as_deref() {
+            Some("max") => select_mode_rate(section, width, height, Some(&RateChoice::Max)),
+            Some(value) => Some(
+                value
+                    .parse::<f64>()
+                    .map_err(|_| format!("invalid rate for {}: {value}", output.name))?,
+            ),
+            None => None,
+        };
+        if let Some(rate) = rate {
+            config.push_str(&format!("rate {rate:.2}\n"));
+        }
+        config.push_str(&format!(
+            "rotate {}\n",
+            output.rotation.as_deref().unwrap_or("normal")
+        ));
+        if let Some(scale) = &output.scale {
+            config.push_str(&format!("scale {scale}x{scale}\n"));
+        }
+    }
+    if layout.off_others {
+        for section in sections {
+            if !layout
+                .outputs
+                .iter()
+                .any(|output| output.name == section.name)
+            {
+                config.push_str(&format!("output {}\noff\n", section.name));
+            }
+        }
+    }
+    let setup: String = layout
+        .outputs
+        .iter()
+        .filter_map(|output| {
+            let edid = extract_edid_hex(find_section(sections, &output.name)?)?;
+            Some(format!("{} {edid}\n", output.name))
+        })
+        .collect();
+
+    std::fs::create_dir_all(dir)
+        .map_err(|err| format!("failed to create {}: {err}", dir.display()))?;
+    for (name, contents) in [("config", config), ("setup", setup)] {
+        let path = dir.join(name);
+        std::fs::write(&path, contents)
+            .map_err(|err| format!("failed to write {}: {err}", path.display()))?;
+    }
+    Ok(())
+}
+
+fn run_layout_apply(
+    layout: Layout,
+    sections: &[DisplaySection],
+    backend: Backend,
+    flags: &ApplyFlags,
+    substitution: Substitution,
+) -> Result<Outcome, CliError> {
+    if flags.position.is_some() || flags.valign.is_some() || !flags.bezel.is_empty() {
+        return Err(CliError::Usage(
+            "--position, --valign and --bezel only apply to row layouts; set position in the layout file"
+                .to_string(),
+        ));
+    }
+    let (args, before, after) = plan_layout_apply(layout, sections, backend, flags, substitution)?;
+    finish_apply(sections, &before, &after, args, backend, flags)
+}
+
+type LayoutPlan = (Vec<String>, Vec<OutputState>, Vec<OutputState>);
+
+fn plan_layout_apply(
+    mut layout: Layout,
+    sections: &[DisplaySection],
+    backend: Backend,
+    flags: &ApplyFlags,
+    substitution: Substitution,
+) -> Result<LayoutPlan, CliError> {
+    resolve_layout_identities(&mut layout, sections, substitution)?;
+
+    let mut args = Vec::new();
+    let mut placed = HashMap::new();
+    let mut listed = HashSet::new();
+
+    for output in &layout.outputs {
+        let section = find_section(sections, &output.name)
+            .ok_or_else(|| display_not_found(sections, &output.name))?;
+        listed.insert(output.name.clone());
+        args.push("--output".to_string());
+        args.push(output.name.clone());
+
+        if output.off {
+            args.push("--off".to_string());
+            continue;
+        }
+
+        let rotation = output
+            .rotation
+            .as_deref()
+            .or(flags.rotation_for(&output.name))
+            .unwrap_or("normal");
+        if !is_rotation(rotation) {
+            return Err(format!("invalid rotation for {}: {rotation}", output.name).into());
+        }
+        let position = match &output.position {
+            Some(value) => Some(
+                parse_position(value)
+                    .ok_or_else(|| format!("invalid position for {}: {value}", output.name))?,
+            ),
+            None => None,
+        };
+        let scale = match &output.scale {
+            Some(value) => value
+                .parse::<f64>()
+                .ok()
+                .filter(|scale| *scale > 0.0)
+                .ok_or_else(|| format!("invalid scale for {}: {value}", output.name))?,
+            None => flags.scale_for(&output.name).unwrap_or(1.0),
+        };
+
+        let mode = match &output.resolution {
+            Some(spec) => {
+                let (width, height) = resolve_layout_resolution(section, backend, spec)?;
+                Some(format!("{width}x{height}"))
+            }
+            None => output.mode.clone(),
+        };
+        let size = match &mode {
+            Some(mode) => mode
+                .split_once('x')
+                .and_then(|(width, height)| Some((width.parse().ok()?, height.parse().ok()?))),
+            None => match backend {
+                Backend::Xrandr => preferred_mode_size(section),
+                Backend::Wlr => wlr_preferred_size(section),
+            },
+        }
+        .ok_or_else(|| format!("cannot determine mode for display: {}", output.name))?;
+        let (width, height) = if matches!(rotation, "left" | "right") {
+            (size.1, size.0)
+        } else {
+            size
+        };
+        let (width, height) = match backend {
+            Backend::Xrandr => (
+                (f64::from(width) * scale).round() as u32,
+                (f64::from(height) * scale).round() as u32,
+            ),
+            Backend::Wlr => (
+                (f64::from(width) / scale).round() as u32,
+                (f64::from(height) / scale).round() as u32,
+            ),
+        };
+        let current = section.geometry.as_deref().and_then(parse_geometry);
+        let (x, y) = position
+            .or(current.map(|geometry| (geometry.x, geometry.y)))
+            .unwrap_or((0, 0));
+        let rate_choice = match output.rate.as_deref() {
+            Some("max") => Some(RateChoice::Max),
+            Some(value) => {
+                Some(RateChoice::Fixed(value.parse::<f64>().map_err(|_| {
+                    format!("invalid rate for {}: {value}", output.name)
+                })?))
+            }
+            None => flags.rate.clone(),
+        };
+        let mode_rate = match &mode {
+            Some(_) => Some(size),
+            None => preferred_mode_size(section),
+        }
+        .and_then(|(width, height)| select_mode_rate(section, width, height, rate_choice.as_ref()))
+        .map(|rate| format!("{rate:.2}"));
+        let rate = match (&rate_choice, &output.rate) {
+            (Some(RateChoice::Fixed(_)), Some(rate)) => Some(rate.clone()),
+            (Some(_), _) => Some(mode_rate.clone().ok_or_else(|| {
+                format!("no refresh rates available for display: {}", output.name)
+            })?),
+            (None, _) => None,
+        };
+        placed.insert(
+            output.name.clone(),
+            (
+                format!("{width}x{height}{x:+}{y:+}"),
+                mode_rate,
+                rotation.to_string(),
+                format_scale(scale),
+            ),
+        );
+
+        match backend {
+            Backend::Xrandr => {
+                match &mode {
+                    Some(mode) => {
+                        args.push("--mode".to_string());
+                        args.push(mode.clone());
+                    }
+                    None => args.push("--auto".to_string()),
+                }
+                if let Some(rate) = &rate {
+                    args.push("--rate".to_string());
+                    args.push(rate.clone());
+                }
+                if position.is_some() {
+                    args.push("--pos".to_string());
+                    args.push(format!("{x}x{y}"));
+                }
+                args.push("--rotate".to_string());
+                args.push(rotation.to_string());
+                args.push("--scale".to_string());
+                args.push(format!("{scale}x{scale}"));
+                if output.primary {
+                    args.push("--primary".to_string());
+                }
+            }
+            Backend::Wlr => {
+                args.push("--on".to_string());
+                match (&mode, &rate) {
+                    (Some(mode), Some(rate)) => {
+                        args.push("--mode".to_string());
+                        args.push(format!("{mode}@{rate}"));
+                    }
+                    (Some(mode), None) => {
+                        args.push("--mode".to_string());
+                        args.push(mode.clone());
+                    }
+                    (None, _) => args.push("--preferred".to_string()),
+                }
+                if position.is_some() {
+                    args.push("--pos".to_string());
+                    args.push(format!("{x},{y}"));
+                }
+                args.push("--transform".to_string());
+                args.push(rotation_wlr_transform(rotation).to_string());
+                args.push("--scale".to_string());
+                args.push(scale.to_string());
+            }
+        }
+    }
+
+    if layout.off_others {
+        let off_targets = filtered_display_names(sections, &listed);
+        args.extend(build_off_args(&off_targets));
+    }
+
+    let before = current_output_states(sections);
+    let primary = layout
+        .outputs
+        .iter()
+        .find(|output| output.primary && !output.off)
+        .map(|output| output.name.as_str());
+    let after: Vec<OutputState> = before
+        .iter()
+        .map(|state| {
+            let primary = match primary {
+                Some(name) => name == state.name,
+                None => state.primary,
+            };
+            if !listed.contains(&state.name) && !layout.off_others {
+                return OutputState {
+                    primary: primary && state.geometry.is_some() && backend == Backend::Xrandr,
+                    ..state.clone()
+                };
+            }
+            match placed.get(&state.name) {
+                Some((geometry, rate, rotation, scale)) => OutputState {
+                    name: state.name.clone(),
+                    geometry: Some(geometry.clone()),
+                    rate: rate.clone(),
+                    primary: primary && backend == Backend::Xrandr,
+                    rotation: Some(rotation.clone()),
+                    scale: Some(scale.clone()),
+                },
+                None => OutputState {
+                    name: state.name.clone(),
+                    geometry: None,
+                    rate: None,
+                    primary: false,
+                    rotation: None,
+                    scale: None,
+                },
+            }
+        })
+        .collect();
+
+    Ok((args, before, after))
+}
+
+fn run_bench(args: &mut impl Iterator<Item = String>) -> Result<(), CliError> {
+    let mut iterations = 10;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--iterations" => {
+                let value = expect_arg(args, "iterations")?;
+                iterations = value
+                    .parse::<usize>()
+                    .ok()
+                    .filter(|count| *count > 0)
+                    .ok_or_else(|| format!("invalid iterations: {value}"))?;
+            }
+            other => return Err(CliError::Usage(format!("unknown option: {other}"))),
+        }
+    }
+    for backend in [Backend::Xrandr, Backend::Wlr] {
+        let name = backend.name();
+        let text = match query_backend_text(backend) {
+            Ok(text) => text,
+            Err(err) => {
+                println!("{name} unavailable ({err})");
+                continue;
+            }
+        };
+        let sections = parse_backend_sections(backend, &text);
+        let operations: [(&str, &dyn Fn()); 5] = [
+            ("query", &|| {
+                let _ = query_backend_text(backend);
+            }),
+            ("parse", &|| {
+                parse_backend_sections(backend, &text);
+            }),
+            ("modes", &|| {
+                sections.iter().for_each(|section| {
+                    parse_modes(section);
+                })
+            }),
+            ("identity", &|| {
+                sections.iter().for_each(|section| {
+                    section_identity(section);
+                })
+            }),
+            ("state", &|| {
+                current_output_states(&sections);
+            }),
+        ];
+        for (operation, run) in operations {
+            let mut samples: Vec<Duration> = (0..iterations)
+                .map(|_| {
+                    let started = Instant::now();
+                    run();
+                    started.elapsed()
+                })
+                .collect();
+            samples.sort();
+            let millis = |duration: Duration| duration.as_secs_f64() * 1000.0;
+            println!(
+                "{name} {operation} min={:.3}ms median={:.3}ms max={:.3}ms",
+                millis(samples[0]),
+                millis(samples[samples.len() / 2]),
+                millis(samples[samples.len() - 1])
+            );
+        }
+    }
+    Ok(())
+}
+
+fn run_serve(args: &mut impl Iterator<Item = String>, backend: Backend) -> Result<(), CliError> {
+    let mut socket = None;
+    let mut dbus = false;
+    let mut stdio = false;
+    let mut interval = Duration::from_secs(2);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--socket" => socket = Some(PathBuf::from(expect_arg(args, "socket path")?)),
+            "--dbus" => dbus = true,
+            "--stdio" => stdio = true,
+            "--interval" => {
+                interval = parse_seconds(&expect_arg(args, "interval seconds")?, "interval")?;
+                if interval.is_zero() {
+                    return Err("invalid interval: must be greater than zero"
+                        .to_string()
+                        .into());
+                }
+            }
+            other => return Err(CliError::Usage(format!("unknown option: {other}"))),
+        }
+    }
+    if [socket.is_some(), dbus, stdio]
+        .iter()
+        .filter(|set| **set)
+        .count()
+        > 1
+    {
+        return Err(CliError::Usage(
+            "--socket, --dbus and --stdio are mutually exclusive".to_string(),
+        ));
+    }
+    if dbus {
+        return run_dbus_service(backend, interval);
+    }
+    if stdio {
+        // One connection handed over by a socket unit with Accept=yes.
+        let cache = RwLock::new(ServeCache::query(backend)?);
+        return serve_connection(io::stdin().lock(), io::stdout(), &cache, backend);
+    }
+    let socket = match socket {
+        Some(path) => path,
+        None => env::var_os("XDG_RUNTIME_DIR")
+            .map(|dir| PathBuf::from(dir).join("xrandr-utils.sock"))
+            .ok_or_else(|| "XDG_RUNTIME_DIR is not set; pass --socket <path>".to_string())?,
+    };
+    if let Ok(metadata) = std::fs::symlink_metadata(&socket) {
+        if !metadata.file_type().is_socket() {
+            return Err(format!("{} exists and is not a socket", socket.display()).into());
+        }
+        if UnixStream::connect(&socket).is_ok() {
+            return Err(format!("already serving on {}", socket.display()).into());
+        }
+        std::fs::remove_file(&socket)
+            .map_err(|err| format!("failed to remove {}: {err}", socket.display()))?;
+    }
+    let listener = UnixListener::bind(&socket)
+        .map_err(|err| format!("failed to bind {}: {err}", socket.display()))?;
+    std::fs::set_permissions(&socket, std::fs::Permissions::from_mode(0o600))
+        .map_err(|err| format!("failed to restrict {}: {err}", socket.display()))?;
+
+    let initial = ServeCache::query(backend)?;
+    let mut uptime = start_uptime_tracking(&initial.sections, "serve");
+    let cache = Arc::new(RwLock::new(initial));
+    // Polled, not driven by RandR events: a refresh every --interval.
+    let refresh = Arc::clone(&cache);
+    thread::spawn(move || loop {
+        thread::sleep(interval);
+        match ServeCache::query(backend) {
+            Ok(fresh) => {
+                track_uptime(&mut uptime, &fresh.sections, "serve");
+                *refresh.write().unwrap_or_else(|err| err.into_inner()) = fresh;
+            }
+            Err(err) => eprintln!("serve: {err}"),
+        }
+    });
+
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(err) => {
+                eprintln!("serve: {err}");
+                continue;
+            }
+        };
+        let cache = Arc::clone(&cache);
+        thread::spawn(move || {
+            let result = stream
+                .try_clone()
+                .map_err(|err| format!("failed to clone socket: {err}").into())
+                .and_then(|writer| {
+                    serve_connection(io::BufReader::new(stream), writer, &cache, backend)
+                });
+            if let Err(err) = result {
+                eprintln!("serve: {err}");
+            }
+        });
+    }
+    Ok(())
+}
+
+/// The last backend query, kept both as text (for verbose-only commands) and parsed.
+struct ServeCache {
+    verbose: String,
+    sections: Vec<DisplaySection>,
+}
+
+impl ServeCache {
+    fn query(backend: Backend) -> Result<ServeCache, CliError> {
+        let verbose = query_backend_text(backend)?;
+        check_current_seat()?;
+        let sections = parse_backend_sections(backend, &verbose);
+        Ok(ServeCache { verbose, sections })
+    }
+
+    /// Runs a query command against the cached state and captures what it prints.
+    fn run_query(
+        &self,
+        words: Vec<String>,
+        backend: Backend,
+    ) -> (Result<Outcome, CliError>, String) {
+        let mut words = words.into_iter();
+        let command = match words.next() {
+            Some(command) => command,
+            None => {
+                return (
+                    Err(CliError::Usage("empty command".to_string())),
+                    String::new(),
+                )
+            }
+        };
+        if let Err(err) = check_query_command(&command, "serve") {
+            return (Err(err), String::new());
+        }
+        let sections = self.sections.clone();
+        capture_output(|| run_command(&command, words.peekable(), sections, backend, &self.verbose))
+    }
+
+    /// Like `run_query`, as the exit code, output and error message of a response.
+    fn answer(&self, words: Vec<String>, backend: Backend) -> (i32, String, String) {
+        match self.run_query(words, backend) {
+            (Ok(outcome), output) => (outcome.exit_code(), output, String::new()),
+            (Err(err), output) => (err.exit_code(), output, err.to_string()),
+        }
+    }
+}
+
+fn serve_connection(
+    reader: impl BufRead,
+    mut writer: impl Write,
+    cache: &RwLock<ServeCache>,
+    backend: Backend,
+) -> Result<(), CliError> {
+    for line in reader.lines() {
+        let line = line.map_err(|err| format!("failed to read request: {err}"))?;
+        let request = line.trim();
+        if request.is_empty() {
+            continue;
+        }
+        let json = request.starts_with('{');
+        let words = if json {
+            json_string_field(request, "command")
+                .ok_or_else(|| "request is missing a \"command\" string".to_string().into())
+                .and_then(|command| split_command_words(&command))
+        } else {
+            split_command_words(request)
+        };
+        let (code, output, error) = match words {
+            Ok(words) => cache
+                .read()
+                .unwrap_or_else(|err| err.into_inner())
+                .answer(words, backend),
+            Err(err) => (err.exit_code(), String::new(), err.to_string()),
+        };
+        let response = if json {
+            format!(
+                "{{\"ok\": {}, \"exit_code\": {code}, \"output\": {}, \"error\": {}}}\n",
+                code == 0,
+                json_string(&output),
+                if error.is_empty() {
+                    "null".to_string()
+                } else {
+                    json_string(&error)
+                }
+            )
+        } else if code == 0 {
+            format!("{output}\n")
+        } else {
+            format!("{output}error: {error}\n\n")
+        };
+        writer
+            .write_all(response.as_bytes())
+            .and_then(|()| writer.flush())
+            .map_err(|err| format!("failed to write response: {err}"))?;
+    }
+    Ok(())
+}
+
+const DBUS_SERVICE_NAME: &str = "org.xrandrutils.Displays";
+const DBUS_OBJECT_PATH: &str = "/org/xrandrutils/Displays";
+const DBUS_INTROSPECTION: &str = r#"<!DOCTYPE node PUBLIC "-//freedesktop//DTD D-BUS Object Introspection 1.0//EN"
+ "http://www.freedesktop.org/standards/dbus/1.0/introspect.dtd">
+<node>
+  <interface name="org.xrandrutils.Displays">
+    <method name="ListDisplays"><arg name="names" type="as" direction="out"/></method>
+    <method name="GetUptimeMap"><arg name="entries" type="as" direction="out"/></method>
+    <method name="GetGeometry">
+      <arg name="name" type="s" direction="in"/>
+      <arg name="geometry" type="s" direction="out"/>
+    </method>
+    <method name="Query">
+      <arg name="command" type="s" direction="in"/>
+      <arg name="output" type="s" direction="out"/>
+    </method>
+    <method name="ApplyProfile"><arg name="name" type="s" direction="in"/></method>
+    <signal name="DisplaysChanged">
+      <annotation name="org.freedesktop.DBus.DocString" value="Emitted when polling the layout every serve --interval seconds (2 by default) finds a change; not driven by RandR events"/>
+    </signal>
+  </interface>
+  <interface name="org.freedesktop.DBus.Introspectable">
+    <method name="Introspect"><arg name="xml" type="s" direction="out"/></method>
+  </interface>
+  <interface name="org.freedesktop.DBus.Peer">
+    <method name="Ping"/>
+  </interface>
+</node>
+"#;
+
+/// `serve --dbus`: exposes the cached layout as `org.xrandrutils.Displays` on the
+/// session bus and emits `DisplaysChanged` when a poll finds a difference.
+fn run_dbus_service(backend: Backend, interval: Duration) -> Result<(), CliError> {
+    let mut cache = ServeCache::query(backend)?;
+    let mut uptime = start_uptime_tracking(&cache.sections, "serve");
+    let mut connection = dbus::Connection::session(DBUS_SERVICE_NAME)?;
+    let mut next_refresh = Instant::now() + interval;
+    loop {
+        let wait = next_refresh.saturating_duration_since(Instant::now());
+        let message = match connection.incoming.recv_timeout(wait) {
+            Ok(message) => Some(message),
+            Err(mpsc::RecvTimeoutError::Timeout) => None,
+            Err(mpsc::RecvTimeoutError::Disconnected) => {
+                return Err("session bus closed the connection".to_string().into())
+            }
+        };
+        let mut refresh = message.is_none();
+        if let Some(call) = message.filter(|message| {
+            message.kind == dbus::MessageType::MethodCall as u8
+                && message.path.as_deref() == Some(DBUS_OBJECT_PATH)
+        }) {
+            refresh = handle_dbus_call(&mut connection, &call, &cache, backend)?;
+        }
+        if refresh {
+            match ServeCache::query(backend) {
+                Ok(fresh) => {
+                    track_uptime(&mut uptime, &fresh.sections, "serve");
+                    let changed = !watch_events(
+                        &watch_snapshot(&cache.sections, &[]),
+                        &watch_snapshot(&fresh.sections, &[]),
+                        unix_timestamp(),
+                    )
+                    .is_empty();
+                    cache = fresh;
+                    if changed {
+                        connection.emit(DBUS_OBJECT_PATH, DBUS_SERVICE_NAME, "DisplaysChanged")?;
+                    }
+                }
+                Err(err) => eprintln!("serve: {err}"),
+            }
+            next_refresh = Instant::now() + interval;
+        }
+    }
+}
+
+/// Answers one method call; returns whether it changed the layout.
+fn handle_dbus_call(
+    connection: &mut dbus::Connection,
+    call: &dbus::Message,
+    cache: &ServeCache,
+    backend: Backend,
+) -> Result<bool, CliError> {
+    let invalid_args = |connection: &mut dbus::Connection, expected: &str| {
+        connection.reply_error(
+            call,
+            "org.freedesktop.DBus.Error.InvalidArgs",
+            &format!("expected arguments ({expected})"),
+        )
+    };
+    let query = |words: Vec<String>| match cache.run_query(words, backend) {
+        (Ok(_), output) => Ok(output),
+        (Err(err), _) => Err(err),
+    };
+    let result = if call.is("org.freedesktop.DBus.Introspectable", "Introspect") {
+        let mut body = dbus::Writer::default();
+        body.string(DBUS_INTROSPECTION);
+        return connection.reply(call, "s", &body.buf).map(|()| false);
+    } else if call.is("org.freedesktop.DBus.Peer", "Ping") {
+        return connection.reply(call, "", &[]).map(|()| false);
+    } else if call.is(DBUS_SERVICE_NAME, "ListDisplays") {
+        query(vec!["display_names".to_string()]).map(|output| {
+            let names: Vec<String> = output.lines().map(str::to_string).collect();
+            let mut body = dbus::Writer::default();
+            body.string_array(&names);
+            ("as", body.buf)
+        })
+    } else if call.is(DBUS_SERVICE_NAME, "GetUptimeMap") {
+        query(vec!["display_uptime_map".to_string()]).map(|output| {
+            let entries: Vec<String> = output.lines().map(str::to_string).collect();
+            let mut body = dbus::Writer::default();
+            body.string_array(&entries);
+            ("as", body.buf)
+        })
+    } else if call.is(DBUS_SERVICE_NAME, "GetGeometry") {
+        let Some(name) = call.string_arg() else {
+            return invalid_args(connection, "s").map(|()| false);
+        };
+        query(vec!["display_geometry".to_string(), name]).map(|output| {
+            let mut body = dbus::Writer::default();
+            body.string(output.trim_end());
+            ("s", body.buf)
+        })
+    } else if call.is(DBUS_SERVICE_NAME, "Query") {
+        let Some(command) = call.string_arg() else {
+            return invalid_args(connection, "s").map(|()| false);
+        };
+        split_command_words(&command).and_then(query).map(|output| {
+            let mut body = dbus::Writer::default();
+            body.string(&output);
+            ("s", body.buf)
+        })
+    } else if call.is(DBUS_SERVICE_NAME, "ApplyProfile") {
+        let Some(name) = call.string_arg() else {
+            return invalid_args(connection, "s").map(|()| false);
+        };
+        // Only layout presets: a command preset could run anything, and it
+        // would run on the bus loop that every other caller is waiting on.
+        let preset = read_user_config()
+            .and_then(|config| parse_config(&config))
+            .and_then(|entries| find_preset(entries, &name));
+        let result = match preset {
+            Ok(Preset::Layout(layout)) => {
+                let flags = ApplyFlags::default();
+                capture_output(|| {
+                    run_layout_apply(
+                        layout,
+                        &cache.sections,
+                        backend,
+                        &flags,
+                        Substitution::Never,
+                    )
+                })
+                .0
+            }
+            Ok(Preset::Command(_)) => Err(CliError::Usage(format!(
+                "preset {name} is not a layout preset"
+            ))),
+            Err(err) => Err(err),
+        };
+        return match result {
+            Ok(_) => connection.reply(call, "", &[]).map(|()| true),
+            Err(err) => connection
+                .reply_error(call, dbus_error_name(&err), err.message())
+                .map(|()| false),
+        };
+    } else {
+        let member = call.member.as_deref().unwrap_or_default();
+        return connection
+            .reply_error(
+                call,
+                "org.freedesktop.DBus.Error.UnknownMethod",
+                &format!("unknown method: {member}"),
+            )
+            .map(|()| false);
+    };
+    match result {
+        Ok((signature, body)) => connection.reply(call, signature, &body)?,
+        Err(err) => connection.reply_error(call, dbus_error_name(&err), err.message())?,
+    }
+    Ok(false)
+}
+
+fn dbus_error_name(err: &CliError) -> &'static str {
+    match err {
+        CliError::NotFound(_) => "org.xrandrutils.Displays.Error.NotFound",
+        CliError::Usage(_) => "org.freedesktop.DBus.Error.InvalidArgs",
+        _ => "org.freedesktop.DBus.Error.Failed",
+    }
+}
+
+fn json_string_field(text: &str, key: &str) -> Option<String> {
+    let start = text.find(&json_string(key))? + json_string(key).len();
+    let rest = text[start..].trim_start().strip_prefix(':')?.trim_start();
+    let mut chars = rest.strip_prefix('"')?.chars();
+    let mut value = String::new();
+    while let Some(ch) = chars.next() {
+        match ch {
+            '"' => return Some(value),
+            '\\' => match chars.next()? {
+                'n' => value.push('\n'),
+                't' => value.push('\t'),
+                'r' => value.push('\r'),
+                'b' => value.push('\u{8}'),
+                'f' => value.push('\u{c}'),
+                'u' => {
+                    let hex_unit = |chars: &mut std::str::Chars| {
+                        let code: String = chars.take(4).collect();
+                        u32::from_str_radix(&code, 16).ok()
+                    };
+                    let high = hex_unit(&mut chars)?;
+                    let code = if (0xd800..0xdc00).contains(&high) {
+                        if chars.next()? != '\\' || chars.next()? != 'u' {
+                            return None;
+                        }
+                        let low =
+                            hex_unit(&mut chars).filter(|low| (0xdc00..0xe000).contains(low))?;
+                        0x10000 + ((high - 0xd800) << 10) + (low - 0xdc00)
+                    } else {
+                        high
+                    };
+                    value.push(char::from_u32(code)?);
+                }
+                other @ ('"' | '\\' | '/') => value.push(other),
+                _ => return None,
+            },
+            _ => value.push(ch),
+        }
+    }
+    None
+}
+
+fn run_simulate_command(
+    args: &mut impl Iterator<Item = String>,
+    backend: Backend,
+) -> Result<Outcome, CliError> {
+    let path = expect_arg(args, "layout file")?;
+    let mut snapshot = None;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--snapshot" => snapshot = Some(expect_arg(args, "snapshot file")?),
+            other => return Err(CliError::Usage(format!("unknown option: {other}"))),
+        }
+    }
+    let text = match snapshot {
+        Some(snapshot) => {
+            REPLAYED_INPUT.store(true, Ordering::Relaxed);
+            std::fs::read_to_string(&snapshot)
+                .map_err(|err| format!("failed to read snapshot {snapshot}: {err}"))?
+        }
+        None => get_verbose_text(backend)?,
+    };
+    check_current_seat()?;
+    let sections = parse_backend_sections(backend, &text);
+    let (args, before, after) = plan_layout_apply(
+        load_layout(&path)?,
+        &sections,
+        backend,
+        &ApplyFlags::default(),
+        Substitution::Never,
+    )?;
+
+    let program = match backend {
+        Backend::Xrandr => "xrandr",
+        Backend::Wlr => "wlr-randr",
+    };
+    print_dry_run(program, &args);
+    for (old, new) in before.iter().zip(&after) {
+        if old != new {
+            println!("- {}", old.describe());
+            println!("+ {}", new.describe());
+        }
+    }
+    let issues = simulation_issues(&text, &sections, &before, &after);
+    for issue in &issues {
+        println!("error: {issue}");
+    }
+    if !issues.is_empty() {
+        return Err(format!("layout failed validation with {} issue(s)", issues.len()).into());
+    }
+    Ok(Outcome::Done)
+}
+
+fn simulation_issues(
+    text: &str,
+    sections: &[DisplaySection],
+    before: &[OutputState],
+    after: &[OutputState],
+) -> Vec<String> {
+    let mut issues = layout_offset_issues(after);
+    let active: Vec<(&str, Geometry)> = after
+        .iter()
+        .filter_map(|state| {
+            let geometry = parse_geometry(state.geometry.as_deref()?)?;
+            Some((state.name.as_str(), geometry))
+        })
+        .collect();
+    if active.is_empty() && before.iter().any(|state| state.geometry.is_some()) {
+        issues.push(LAST_ACTIVE_DISPLAY_ERROR.to_string());
+    }
+
+    for (index, (name, geometry)) in active.iter().enumerate() {
+        for (other_name, other) in &active[index + 1..] {
+            let mirrored = (geometry.x, geometry.y, geometry.width, geometry.height)
+                == (other.x, other.y, other.width, other.height);
+            let overlaps = i64::from(geometry.x) < i64::from(other.x) + i64::from(other.width)
+                && i64::from(other.x) < i64::from(geometry.x) + i64::from(geometry.width)
+                && i64::from(geometry.y) < i64::from(other.y) + i64::from(other.height)
+                && i64::from(other.y) < i64::from(geometry.y) + i64::from(geometry.height);
+            if overlaps && !mirrored {
+                issues.push(format!("{name} overlaps {other_name}"));
+            }
+        }
+    }
+
+    for state in after {
+        let Some(section) = find_section(sections, &state.name) else {
+            continue;
+        };
+        let Some(clock) = planned_pixel_clock(section, state) else {
+            continue;
+        };
+        let Some((capacity, label)) = section_link_capacity(section) else {
+            continue;
+        };
+        let required = clock * f64::from(section_bits_per_component(section) * 3) / 1000.0;
+        if required > capacity {
+            issues.push(format!(
+                "{} needs {required:.2} Gbit/s but its link carries about {capacity:.2} Gbit/s ({label})",
+                state.name
+            ));
+        }
+    }
+
+    let mut crtcs = HashSet::new();
+    for section in sections {
+        if let Some(list) = section_field(section, "CRTCs") {
+            crtcs.extend(list.split_whitespace().map(str::to_string));
+        }
+    }
+    if !crtcs.is_empty() && active.len() > crtcs.len() {
+        issues.push(format!(
+            "{} active outputs but only {} CRTCs available",
+            active.len(),
+            crtcs.len()
+        ));
+    }
+
+    if let Some((max_width, max_height)) = screen_maximum_size(text) {
+        let right = active
+            .iter()
+            .map(|(_, geometry)| i64::from(geometry.x) + i64::from(geometry.width))
+            .max()
+            .unwrap_or(0);
+        let bottom = active
+            .iter()
+            .map(|(_, geometry)| i64::from(geometry.y) + i64::from(geometry.height))
+            .max()
+            .unwrap_or(0);
+        if right > max_width || bottom > max_height {
+            issues.push(format!(
+                "framebuffer {right}x{bottom} exceeds screen maximum {max_width}x{max_height}"
+            ));
+        }
+    }
+    issues
+}
+
+/// Pixel clock of the mode a planned output state would use, undoing the rotation and
+/// scale that `plan_layout_apply` applied to its geometry.
+fn planned_pixel_clock(section: &DisplaySection, state: &OutputState) -> Option<f64> {
+    let geometry = parse_geometry(state.geometry.as_deref()?)?;
+    let scale = state
+        .scale
+        .as_deref()
+        .and_then(|scale| scale.parse::<f64>().ok())
+        .filter(|scale| *scale > 0.0)
+        .unwrap_or(1.0);
+    let unscale = |value: u32| (f64::from(value) / scale).round() as u32;
+    let (width, height) = (unscale(geometry.width), unscale(geometry.height));
+    let (width, height) = match state.rotation.as_deref() {
+        Some("left" | "right") => (height, width),
+        _ => (width, height),
+    };
+    let rate = state
+        .rate
+        .as_deref()
+        .and_then(|rate| rate.parse::<f64>().ok());
+    mode_pixel_clock(section, width, height, rate)
+}
+
+/// Pixel clock in MHz of the `--verbose` mode with this size and, if given, refresh
+/// rate; without a rate the preferred mode of that size wins.
+fn mode_pixel_clock(
+    section: &DisplaySection,
+    width: u32,
+    height: u32,
+    rate: Option<f64>,
+) -> Option<f64> {
+    let mut found = None;
+    let mut pending: Option<(f64, bool, u32)> = None;
+    for line in section.lines.iter().skip(1) {
+        let trimmed = line.trim();
+        let mut parts = trimmed.split_whitespace();
+        match parts.next().unwrap_or_default() {
+            "h:" => {
+                if let Some((_, _, mode_width)) = pending.as_mut() {
+                    *mode_width = field_after(trimmed, "width").unwrap_or(0);
+                }
+            }
+            "v:" => {
+                let Some((clock, preferred, mode_width)) = pending.take() else {
+                    continue;
+                };
+                let refresh = trimmed
+                    .rsplit("clock")
+                    .next()
+                    .and_then(|value| value.trim().trim_end_matches("Hz").parse::<f64>().ok());
+                let size_matches =
+                    mode_width == width && field_after(trimmed, "height") == Some(height);
+                let rate_matches = match (rate, refresh) {
+                    (Some(rate), Some(refresh)) => (rate - refresh).abs() < 0.01,
+                    (Some(_), None) => false,
+                    (None, _) => true,
+                };
+                if size_matches && rate_matches && (found.is_none() || preferred) {
+                    found = Some(clock);
+                }
+            }
+            _ => {
+                pending = parts
+                    .next()
+                    .filter(|id| id.starts_with("(0x"))
+                    .and_then(|_| parts.next()?.strip_suffix("MHz")?.parse::<f64>().ok())
+                    .map(|clock| (clock, trimmed.contains("+preferred"), 0));
+            }
+        }
+    }
+    found
+}
+
+fn screen_maximum_size(text: &str) -> Option<(i64, i64)> {
+    let line = text.lines().find(|line| line.starts_with("Screen "))?;
+    let maximum = line.split_once("maximum")?.1;
+    let (width, height) = maximum.split_once('x')?;
+    Some((width.trim().parse().ok()?, height.trim().parse().ok()?))
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Substitution {
+    Never,
+    Offer,
+    Auto,
+}
+
+fn identity_matches(section: &DisplaySection, wanted: &str) -> bool {
+    section_identity(section)
+        .is_some_and(|identity| identity.describe() == wanted || identity.edid_hash == wanted)
+}
+
+type IdentityField = fn(&Identity) -> String;
+
+fn identity_selector(selector: &str) -> Result<(IdentityField, &str), CliError> {
+    let (kind, wanted) = selector.split_once(':').ok_or_else(|| {
+        format!("invalid identity selector: {selector} (expected serial:, edid: or model:)")
+    })?;
+    let field: IdentityField = match kind {
+        "serial" => |identity| identity.serial.clone(),
+        "edid" => |identity| identity.edid_hash.clone(),
+        "model" => |identity| identity.model.clone(),
+        _ => {
+            return Err(format!(
+                "unknown identity selector: {kind} (expected serial, edid or model)"
+            )
+            .into())
+        }
+    };
+    Ok((field, wanted))
+}
+
+fn display_name_for(sections: &[DisplaySection], selector: &str) -> Result<String, CliError> {
+    let (field, wanted) = identity_selector(selector)?;
+    let mut names: Vec<String> = sections
+        .iter()
+        .filter(|section| section.state == DisplayState::Connected)
+        .filter(|section| {
+            section_identity(section).is_some_and(|identity| field(&identity) == wanted)
+        })
+        .map(|section| section.name.clone())
+        .collect();
+    match names.len() {
+        0 => Err(CliError::NotFound(format!(
+            "no connected display matches {selector}"
+        ))),
+        1 => Ok(names.remove(0)),
+        _ => Err(CliError::NotFound(format!(
+            "{selector} matches multiple displays: {}",
+            names.join(", ")
+        ))),
+    }
+}
+
+fn resolve_layout_identities(
+    layout: &mut Layout,
+    sections: &[DisplaySection],
+    substitution: Substitution,
+) -> Result<(), CliError> {
+    let mut assigned: HashSet<String> = layout
+        .outputs
+        .iter()
+        .filter(|output| output.identity.is_none())
+        .map(|output| output.name.clone())
+        .collect();
+    let mut missing = Vec::new();
+
+    for (index, output) in layout.outputs.iter_mut().enumerate() {
+        let wanted = match &output.identity {
+            Some(wanted) => wanted,
+            None => continue,
+        };
+        let found = sections.iter().find(|section| {
+            section.state == DisplayState::Connected
+                && !assigned.contains(&section.name)
+                && identity_matches(section, wanted)
+        });
+        match found {
+            Some(section) => {
+                output.name = section.name.clone();
+                assigned.insert(section.name.clone());
+            }
+            None => missing.push(index),
+        }
+    }
+
+    for index in missing {
+        let output = &mut layout.outputs[index];
+        let wanted = output.identity.as_deref().unwrap_or_default();
+        let candidate = sections
+            .iter()
+            .filter(|section| {
+                section.state == DisplayState::Connected && !assigned.contains(&section.name)
+            })
+            .min_by_key(|section| is_internal_panel(&section.name));
+        match (candidate, substitution) {
+            (Some(section), Substitution::Auto) => {
+                eprintln!(
+                    "substituting {} for missing display {} ({wanted})",
+                    section.name, output.name
+                );
+                output.name = section.name.clone();
+                assigned.insert(section.name.clone());
+            }
+            (Some(section), Substitution::Offer) => {
+                return Err(format!(
+                    "display {} ({wanted}) not found; {} is connected and unassigned, rerun with --auto-substitute to use it",
+                    output.name, section.name
+                ).into());
+            }
+            _ => {
+                return Err(format!("display {} ({wanted}) not found", output.name).into());
+            }
+        }
+    }
+    Ok(())
+}
+
+fn resolve_layout_resolution(
+    section: &DisplaySection,
+    backend: Backend,
+    spec: &str,
+) -> Result<(u32, u32), CliError> {
+    let name = &section.name;
+    let spec = spec.trim();
+    if spec == "native" {
+        return match backend {
+            Backend::Xrandr => preferred_mode_size(section),
+            Backend::Wlr => wlr_preferred_size(section),
+        }
+        .ok_or_else(|| format!("native resolution not available for display: {name}").into());
+    }
+
+    let (bound, at_most) = match spec.strip_prefix("<=") {
+        Some(bound) => (bound.trim(), true),
+        None => (spec, false),
+    };
+    let (width, height) = bound
+        .split_once('x')
+        .and_then(|(width, height)| Some((width.parse::<u32>().ok()?, height.parse::<u32>().ok()?)))
+        .ok_or_else(|| format!("invalid resolution for {name}: {spec}"))?;
+    let modes = parse_modes(section);
+    let found = if at_most {
+        modes
+            .iter()
+            .filter(|mode| mode.width <= width && mode.height <= height)
+            .max_by_key(|mode| (mode.width * mode.height, mode.width))
+    } else {
+        modes
+            .iter()
+            .find(|mode| (mode.width, mode.height) == (width, height))
+    };
+    found
+        .map(|mode| (mode.width, mode.height))
+        .ok_or_else(|| format!("no mode matching {spec} for display: {name}").into())
+}
+
+fn parse_position(value: &str) -> Option<(i32, i32)> {
+    let (x, y) = value.split_once(['x', ','])?;
+    Some((x.trim().parse().ok()?, y.trim().parse().ok()?))
+}
+
+fn run_display_rotate(
+    display: &str,
+    rotation: &str,
+    sections: &[DisplaySection],
+    backend: Backend,
+) -> Result<(), CliError> {
+    let section =
+        find_section(sections, display).ok_or_else(|| display_not_found(sections, display))?;
+    if !is_rotation(rotation) {
+        return Err(format!(
+            "invalid rotation: {rotation} (expected normal, left, right or inverted)"
+        )
+        .into());
+    }
+    if section.geometry.is_none() {
+        return Err(format!("display not active: {display}").into());
+    }
+
+    let args = match backend {
+        Backend::Xrandr => vec![
+            "--output".to_string(),
+            display.to_string(),
+            "--rotate".to_string(),
+            rotation.to_string(),
+        ],
+        Backend::Wlr => vec![
+            "--output".to_string(),
+            display.to_string(),
+            "--transform".to_string(),
+            rotation_wlr_transform(rotation).to_string(),
+        ],
+    };
+    run_layout_change(sections, backend, args)
+}
+
+fn section_brightness(section: &DisplaySection) -> Option<f64> {
+    section_field(section, "Brightness")?.parse().ok()
+}
+
+fn run_display_brightness_set(
+    display: &str,
+    value: &str,
+    sections: &[DisplaySection],
+    backend: Backend,
+) -> Result<(), CliError> {
+    if backend == Backend::Wlr {
+        return Err("brightness is not supported by the wlr backend"
+            .to_string()
+            .into());
+    }
+    let section =
+        find_section(sections, display).ok_or_else(|| display_not_found(sections, display))?;
+    if section.geometry.is_none() {
+        return Err(format!("display not active: {display}").into());
+    }
+
+    let invalid = || format!("invalid brightness: {value} (expected 0.0 to 1.0 or +/-step)");
+    let brightness = if value.starts_with('+') || value.starts_with('-') {
+        let step: f64 = value.parse().map_err(|_| invalid())?;
+        let current = section_brightness(section)
+            .ok_or_else(|| format!("brightness not available for display: {display}"))?;
+        (current + step).clamp(0.0, 1.0)
+    } else {
+        let brightness: f64 = value.parse().map_err(|_| invalid())?;
+        if !(0.0..=1.0).contains(&brightness) {
+            return Err(invalid().into());
+        }
+        brightness
+    };
+
+    run_xrandr_with_args(vec![
+        "--output".to_string(),
+        display.to_string(),
+        "--brightness".to_string(),
+        format!("{brightness:.2}"),
+    ])
+}
+
+enum BacklightSource {
+    Property(String),
+    Sysfs(PathBuf),
+}
+
+struct BacklightLevel {
+    source: BacklightSource,
+    value: u32,
+    min: u32,
+    max: u32,
+}
+
+impl BacklightLevel {
+    fn percent(&self) -> u32 {
+        let span = self.max.saturating_sub(self.min).max(1);
+        let value = self.value.clamp(self.min, self.max) - self.min;
+        (f64::from(value) * 100.0 / f64::from(span)).round() as u32
+    }
+
+    fn raw_for_percent(&self, percent: u32) -> u32 {
+        let span = self.max.saturating_sub(self.min);
+        self.min + (f64::from(span) * f64::from(percent.min(100)) / 100.0).round() as u32
+    }
+}
+
+fn find_backlight(section: &DisplaySection) -> Result<BacklightLevel, CliError> {
+    if let Some(level) = randr_backlight(section) {
+        return Ok(level);
+    }
+    if is_internal_panel(&section.name) {
+        let connector = section_drm_connector(section).and_then(|drm| drm.path.canonicalize().ok());
+        if let Some(level) = sysfs_backlight(connector.as_deref()) {
+            return Ok(level);
+        }
+    }
+    Err(format!("backlight not available for display: {}", section.name).into())
+}
+
+fn is_internal_panel(name: &str) -> bool {
+    ["eDP", "LVDS", "DSI"]
+        .iter()
+        .any(|prefix| name.starts_with(prefix))
+}
+
+fn randr_backlight(section: &DisplaySection) -> Option<BacklightLevel> {
+    let mut lines = section.lines.iter().skip(1).map(|line| line.trim());
+    while let Some(line) = lines.next() {
+        let (key, value) = match line.split_once(':') {
+            Some(pair) => pair,
+            None => continue,
+        };
+        let key = key.trim();
+        if !key.eq_ignore_ascii_case("backlight") {
+            continue;
+        }
+        let value = value.trim().parse().ok()?;
+        let range = lines.next()?.strip_prefix("range:")?.trim();
+        let (min, max) = range
+            .trim_start_matches('(')
+            .trim_end_matches(')')
+            .split_once(',')?;
+        return Some(BacklightLevel {
+            source: BacklightSource::Property(key.to_string()),
+            value,
+            min: min.trim().parse().ok()?,
+            max: max.trim().parse().ok()?,
+        });
+    }
+    None
+}
+
+fn sysfs_backlight(connector: Option<&Path>) -> Option<BacklightLevel> {
+    let read_number =
+        |path: PathBuf| -> Option<u32> { std::fs::read_to_string(path).ok()?.trim().parse().ok() };
+    let mut devices: Vec<(usize, PathBuf)> = std::fs::read_dir("/sys/class/backlight")
+        .ok()?
+        .flatten()
+        .map(|entry| {
+            let path = entry.path();
+            let kind = std::fs::read_to_string(path.join("type")).unwrap_or_default();
+            let linked = connector.is_some_and(|connector| {
+                path.join("device").canonicalize().ok().as_deref() == Some(connector)
+            });
+            let rank = match kind.trim() {
+                _ if linked => 0,
+                "firmware" => 1,
+                "platform" => 2,
+                _ => 3,
+            };
+            (rank, path)
+        })
+        .collect();
+    devices.sort();
+    devices.into_iter().find_map(|(_, path)| {
+        let value = read_number(path.join("brightness"))?;
+        let max = read_number(path.join("max_brightness"))?;
+        Some(BacklightLevel {
+            source: BacklightSource::Sysfs(path),
+            value,
+            min: 0,
+            max,
+        })
+    })
+}
+
+fn run_dpms(
+    action: &str,
+    output: Option<&str>,
+    sections: &[DisplaySection],
+    backend: Backend,
+) -> Result<(), CliError> {
+    if !matches!(action, "on" | "off" | "standby" | "status") {
+        return Err(CliError::Usage(format!(
+            "unknown dpms action: {action} (expected on, off, standby or status)"
+        )));
+    }
+
+    let targets: Vec<&DisplaySection> =
+        match output {
+            Some(display) => vec![find_section(sections, display)
+                .ok_or_else(|| display_not_found(sections, display))?],
+            None if backend == Backend::Wlr => sections
+                .iter()
+                .filter(|section| section.state == DisplayState::Connected)
+                .collect(),
+            None => {
+                if action == "status" {
+                    println!("{}", query_xset_dpms()?);
+                    return Ok(());
+                }
+                return run_xset_with_args(vec![
+                    "dpms".to_string(),
+                    "force".to_string(),
+                    action.to_string(),
+                ]);
+            }
+        };
+
+    if action == "status" {
+        for section in &targets {
+            let state = if section.geometry.is_some() {
+                "on"
+            } else {
+                "off"
+            };
+            if output.is_some() {
+                println!("{state}");
+            } else {
+                println!("{}={state}", section.name);
+            }
+        }
+        return Ok(());
+    }
+    if action == "standby" {
+        return Err("dpms standby is only supported for all outputs on xrandr"
+            .to_string()
+            .into());
+    }
+
+    let mut args = Vec::new();
+    for section in targets {
+        args.push("--output".to_string());
+        args.push(section.name.clone());
+        args.push(match (action, backend) {
+            ("off", _) => "--off".to_string(),
+            (_, Backend::Wlr) => "--on".to_string(),
+            _ => "--auto".to_string(),
+        });
+    }
+    if args.is_empty() {
+        return Err("no connected displays".to_string().into());
+    }
+    run_layout_change(sections, backend, args)
+}
+
+fn query_xset_dpms() -> Result<&'static str, CliError> {
+    let output = Command::new("xset")
+        .arg("q")
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .output()
+        .map_err(|err| format!("failed to run xset q: {err}"))?;
+    if !output.status.success() {
+        return Err("xset q exited with failure".to_string().into());
+    }
+    let text = String::from_utf8_lossy(&output.stdout);
+    let state = text
+        .lines()
+        .find_map(|line| line.trim().strip_prefix("Monitor is "))
+        .ok_or_else(|| "dpms state not reported by xset".to_string())?;
+    match state.trim() {
+        "On" => Ok("on"),
+        "Off" => Ok("off"),
+        "Standby" => Ok("standby"),
+        "Suspend" => Ok("suspend"),
+        other => Err(format!("unknown dpms state: {other}").into()),
+    }
+}
+
+fn run_xset_with_args(args: Vec<String>) -> Result<(), CliError> {
+    run_program_with_args("xset", args)
+}
+
+fn run_backlight_set(
+    display: &str,
+    level: &BacklightLevel,
+    value: &str,
+    backend: Backend,
+    install_rule: bool,
+) -> Result<(), CliError> {
+    let invalid = || format!("invalid backlight percent: {value} (expected 0-100 or +/-step)");
+    let percent = if value.starts_with('+') || value.starts_with('-') {
+        let step: i64 = value.parse().map_err(|_| invalid())?;
+        (i64::from(level.percent()) + step).clamp(0, 100) as u32
+    } else {
+        let percent: u32 = value.parse().map_err(|_| invalid())?;
+        if percent > 100 {
+            return Err(invalid().into());
+        }
+        percent
+    };
+    let raw = level.raw_for_percent(percent);
+
+    match &level.source {
+        BacklightSource::Property(name) => {
+            if backend == Backend::Wlr {
+                return Err("backlight property is not supported by the wlr backend"
+                    .to_string()
+                    .into());
+            }
+            run_xrandr_with_args(vec![
+                "--output".to_string(),
+                display.to_string(),
+                "--set".to_string(),
+                name.clone(),
+                raw.to_string(),
+            ])
+        }
+        BacklightSource::Sysfs(path) => {
+            let path = path.join("brightness");
+            if is_dry_run() {
+                println!("echo {raw} > {}", shell_quote(&path.to_string_lossy()));
+                return Ok(());
+            }
+            match std::fs::write(&path, raw.to_string()) {
+                Ok(()) => Ok(()),
+                Err(err) if err.kind() == io::ErrorKind::PermissionDenied => {
+                    Err(permission_error(&BACKLIGHT_PERMISSION_FIX, install_rule).into())
+                }
+                Err(err) => Err(format!("failed to write {}: {err}", path.display()).into()),
+            }
+        }
+    }
+}
+
+fn run_display_property_set(
+    display: &str,
+    name: &str,
+    value: &str,
+    sections: &[DisplaySection],
+    backend: Backend,
+) -> Result<(), CliError> {
+    if backend == Backend::Wlr {
+        return Err("output properties are not supported by the wlr backend"
+            .to_string()
+            .into());
+    }
+    let section =
+        find_section(sections, display).ok_or_else(|| display_not_found(sections, display))?;
+    let property = section_properties(section)
+        .into_iter()
+        .find(|property| property.name.eq_ignore_ascii_case(name))
+        .ok_or_else(|| format!("property not found for display {display}: {name}"))?;
+
+    match property.constraint.as_deref() {
+        Some(constraint) if constraint.starts_with("supported:") => {
+            let supported: Vec<&str> = constraint["supported:".len()..]
+                .split(',')
+                .map(str::trim)
+                .collect();
+            if !supported.contains(&value) {
+                return Err(format!(
+                    "invalid value for {}: {value} (supported: {})",
+                    property.name,
+                    supported.join(", ")
+                )
+                .into());
+            }
+        }
+        Some(constraint) if constraint.starts_with("range:") => {
+            let range = constraint["range:".len()..]
+                .trim()
+                .trim_start_matches('(')
+                .trim_end_matches(')');
+            let bounds = range.split_once(',').and_then(|(min, max)| {
+                Some((
+                    min.trim().parse::<i64>().ok()?,
+                    max.trim().parse::<i64>().ok()?,
+                ))
+            });
+            if let Some((min, max)) = bounds {
+                let number: i64 = value.parse().map_err(|_| {
+                    format!(
+                        "invalid value for {}: {value} (expected an integer)",
+                        property.name
+                    )
+                })?;
+                if !(min..=max).contains(&number) {
+                    return Err(format!(
+                        "invalid value for {}: {value} (range {min} to {max})",
+                        property.name
+                    )
+                    .into());
+                }
+            }
+        }
+        _ => {}
+    }
+
+    run_xrandr_with_args(vec![
+        "--output".to_string(),
+        display.to_string(),
+        "--set".to_string(),
+        property.name,
+        value.to_string(),
+    ])
+}
+
+fn section_transform(section: &DisplaySection) -> Option<Vec<String>> {
+    let start = section
+        .lines
+        .iter()
+        .position(|line| line.trim_start().starts_with("Transform:"))?;
+    let first = section.lines[start]
+        .trim_start()
+        .strip_prefix("Transform:")?;
+    let values: Vec<String> = std::iter::once(first)
+        .chain(
+            section
+                .lines
+                .iter()
+                .skip(start + 1)
+                .take(2)
+                .map(String::as_str),
+        )
+        .flat_map(str::split_whitespace)
+        .take(9)
+        .map(str::to_string)
+        .collect();
+    if values.len() == 9 && values.iter().all(|value| value.parse::<f64>().is_ok()) {
+        Some(values)
+    } else {
+        None
+    }
+}
+
+fn parse_scale_factor(value: &str) -> Result<(f64, f64), CliError> {
+    let (x, y) = value.split_once('x').unwrap_or((value, value));
+    let parse = |part: &str| part.parse::<f64>().ok().filter(|scale| *scale > 0.0);
+    match (parse(x), parse(y)) {
+        (Some(x), Some(y)) => Ok((x, y)),
+        _ => Err(format!("invalid scale: {value}").into()),
+    }
+}
+
+fn run_display_scale(
+    display: &str,
+    factor: &str,
+    sections: &[DisplaySection],
+    backend: Backend,
+) -> Result<(), CliError> {
+    let section =
+        find_section(sections, display).ok_or_else(|| display_not_found(sections, display))?;
+    if section.geometry.is_none() {
+        return Err(format!("display not active: {display}").into());
+    }
+    let (x, y) = parse_scale_factor(factor)?;
+    let scale = match backend {
+        Backend::Xrandr => format!("{x}x{y}"),
+        Backend::Wlr if x == y => x.to_string(),
+        Backend::Wlr => {
+            return Err("wlr backend only supports uniform scale factors"
+                .to_string()
+                .into())
+        }
+    };
+    run_layout_change(
+        sections,
+        backend,
+        vec![
+            "--output".to_string(),
+            display.to_string(),
+            "--scale".to_string(),
+            scale,
+        ],
+    )
+}
+
+/// The gamma as `display_gamma_set` takes it. `xrandr --verbose` reports the
+/// reciprocal of each channel that was set, so the readers invert it here.
+fn section_gamma(section: &DisplaySection) -> Option<String> {
+    section_field(section, "Gamma")?
+        .split(':')
+        .map(|channel| {
+            let value = channel.trim().parse::<f64>().ok().filter(|v| *v > 0.0)?;
+            Some(format!("{:.2}", 1.0 / value))
+        })
+        .collect::<Option<Vec<_>>>()
+        .map(|channels| channels.join(":"))
+}
+
+fn run_display_gamma_set(
+    display: &str,
+    gamma: &str,
+    sections: &[DisplaySection],
+    backend: Backend,
+) -> Result<(), CliError> {
+    if backend == Backend::Wlr {
+        return Err("gamma is not supported by the wlr backend"
+            .to_string()
+            .into());
+    }
+    let section =
+        find_section(sections, display).ok_or_else(|| display_not_found(sections, display))?;
+    if section.geometry.is_none() {
+        return Err(format!("display not active: {display}").into());
+    }
+
+    let channels: Vec<f64> = gamma
+        .split(':')
+        .map(|channel| channel.parse::<f64>().ok().filter(|value| *value > 0.0))
+        .collect::<Option<_>>()
+        .filter(|channels: &Vec<f64>| channels.len() == 3)
+        .ok_or_else(|| format!("invalid gamma: {gamma} (expected r:g:b)"))?;
+    let gamma = channels
+        .iter()
+        .map(|value| format!("{value:.2}"))
+        .collect::<Vec<_>>()
+        .join(":");
+    run_xrandr_with_args(vec![
+        "--output".to_string(),
+        display.to_string(),
+        "--gamma".to_string(),
+        gamma,
+    ])
+}
+
+fn run_set_primary(
+    display: &str,
+    sections: &[DisplaySection],
+    backend: Backend,
+) -> Result<(), CliError> {
+    if backend == Backend::Wlr {
+        return Err("set_primary is not supported by the wlr backend"
+            .to_string()
+            .into());
+    }
+    let section =
+        find_section(sections, display).ok_or_else(|| display_not_found(sections, display))?;
+    if section.geometry.is_none() {
+        return Err(format!("display not active: {display}").into());
+    }
+    if section.primary {
+        return Ok(());
+    }
+    run_layout_change(
+        sections,
+        backend,
+        vec![
+            "--output".to_string(),
+            display.to_string(),
+            "--primary".to_string(),
+        ],
+    )
+}
+
+struct Modeline {
+    name: String,
+    clock: f64,
+    horizontal: [u32; 4],
+    vertical: [u32; 4],
+    flags: [&'static str; 2],
+}
+
+impl Modeline {
+    fn args(&self) -> Vec<String> {
+        let mut args = vec![self.name.clone(), format!("{:.2}", self.clock)];
+        args.extend(self.horizontal.iter().map(u32::to_string));
+        args.extend(self.vertical.iter().map(u32::to_string));
+        args.extend(self.flags.iter().map(|flag| flag.to_string()));
+        args
+    }
+}
+
+fn cvt_modeline(width: u32, height: u32, rate: f64, reduced: bool) -> Modeline {
+    let width = width - width % 8;
+    let vsync = match (width * 3 == height * 4, width * 9 == height * 16) {
+        (true, _) => 4,
+        (_, true) => 5,
+        _ if width * 10 == height * 16 => 6,
+        _ if width * 4 == height * 5 || width * 9 == height * 15 => 7,
+        _ => 10,
+    };
+    let field_period = 1_000_000.0 / rate;
+
+    let (total_width, total_height, clock_khz, hsync, vsync_start, flags) = if reduced {
+        let period = (field_period - 460.0) / f64::from(height);
+        let blank_lines = ((460.0 / period) as u32 + 1).max(3 + vsync + 6);
+        let total_width = width + 160;
+        let total_height = height + blank_lines;
+        let clock = (rate * f64::from(total_height) * f64::from(total_width) / 1000.0) as u32;
+        let hsync_end = width + 80;
+        (
+            total_width,
+            total_height,
+            clock - clock % 250,
+            (hsync_end - 32, hsync_end),
+            height + 3,
+            ["+hsync", "-vsync"],
+        )
+    } else {
+        let period = (field_period - 550.0) / f64::from(height + 3);
+        let sync_and_back_porch = ((550.0 / period) as u32 + 1).max(vsync + 6);
+        let total_height = height + sync_and_back_porch + 3;
+        let blank_percent = (30.0 - 300.0 * period / 1000.0).max(20.0);
+        let blank = (f64::from(width) * blank_percent / (100.0 - blank_percent)) as u32;
+        let blank = blank - blank % 16;
+        let total_width = width + blank;
+        let clock = (f64::from(total_width) * 1000.0 / period) as u32;
+        let hsync_width = total_width * 8 / 100;
+        let hsync_end = width + blank / 2;
+        (
+            total_width,
+            total_height,
+            clock - clock % 250,
+            (hsync_end - (hsync_width - hsync_width % 8), hsync_end),
+            height + 3,
+            ["-hsync", "+vsync"],
+        )
+    };
+
+    let suffix = if reduced { "R" } else { "" };
+    Modeline {
+        name: format!("{width}x{height}{suffix}_{rate:.2}"),
+        clock: f64::from(clock_khz) / 1000.0,
+        horizontal: [width, hsync.0, hsync.1, total_width],
+        vertical: [height, vsync_start, vsync_start + vsync, total_height],
+        flags,
+    }
+}
+
+fn run_mode_add(
+    display: &str,
+    spec: &str,
+    reduced: bool,
+    switch: bool,
+    sections: &[DisplaySection],
+    backend: Backend,
+) -> Result<(), CliError> {
+    if find_section(sections, display).is_none() {
+        return Err(display_not_found(sections, display));
+    }
+    let (width, height, rate) = parse_custom_mode(spec)?;
+
+    if backend == Backend::Wlr {
+        if !switch {
+            return Err("wlr backend can only add a custom mode with --switch"
+                .to_string()
+                .into());
+        }
+        return run_layout_change(
+            sections,
+            backend,
+            vec![
+                "--output".to_string(),
+                display.to_string(),
+                "--custom-mode".to_string(),
+                format!("{width}x{height}@{rate}"),
+            ],
+        );
+    }
+
+    let modeline = cvt_modeline(width, height, rate, reduced);
+    if switch {
+        // Before the mode is created, so a refused switch leaves nothing behind.
+        check_remote_session(false)?;
+    }
+    add_user_mode(display, &modeline, sections)?;
+    if switch {
+        apply_layout_change(
+            sections,
+            backend,
+            vec![
+                "--output".to_string(),
+                display.to_string(),
+                "--mode".to_string(),
+                modeline.name.clone(),
+            ],
+        )?;
+    }
+    Ok(())
+}
+
+fn parse_custom_mode(spec: &str) -> Result<(u32, u32, f64), CliError> {
+    let invalid = || format!("invalid mode: {spec} (expected WxH[@Hz])");
+    let (size, rate) = match spec.split_once('@') {
+        Some((size, rate)) => (
+            size,
+            rate.parse::<f64>()
+                .ok()
+                .filter(|rate| *rate > 0.0)
+                .ok_or_else(invalid)?,
+        ),
+        None => (spec, 60.0),
+    };
+    let (width, height) = size
+        .split_once('x')
+        .and_then(|(width, height)| Some((width.parse::<u32>().ok()?, height.parse::<u32>().ok()?)))
+        .filter(|(width, height)| *width >= 8 && *height > 0)
+        .ok_or_else(invalid)?;
+    Ok((width, height, rate))
+}
+
+fn add_user_mode(
+    display: &str,
+    modeline: &Modeline,
+    sections: &[DisplaySection],
+) -> Result<(), CliError> {
+    let listed = |section: &DisplaySection| {
+        section_mode_names(section)
+            .iter()
+            .any(|(name, _)| *name == modeline.name)
+    };
+    if !sections.iter().any(listed) {
+        let mut args = vec!["--newmode".to_string()];
+        args.extend(modeline.args());
+        run_xrandr_with_args(args)?;
+    }
+    if !find_section(sections, display).is_some_and(listed) {
+        run_xrandr_with_args(vec![
+            "--addmode".to_string(),
+            display.to_string(),
+            modeline.name.clone(),
+        ])?;
+    }
+    Ok(())
+}
+
+fn run_virtual_enable(
+    spec: &str,
+    output: Option<&str>,
+    sections: &[DisplaySection],
+    backend: Backend,
+) -> Result<(), CliError> {
+    if backend == Backend::Wlr {
+        return Err("virtual displays are not supported by the wlr backend"
+            .to_string()
+            .into());
+    }
+    let (width, height, rate) = parse_custom_mode(spec)?;
+    let unused = |section: &&DisplaySection| {
+        section.state != DisplayState::Connected && section.geometry.is_none()
+    };
+    let display = match output {
+        Some(name) => {
+            let section =
+                find_section(sections, name).ok_or_else(|| display_not_found(sections, name))?;
+            if section.geometry.is_some() {
+                return Err(format!("display already active: {name}").into());
+            }
+            section.name.clone()
+        }
+        None => sections
+            .iter()
+            .filter(unused)
+            .find(|section| section.name.starts_with("VIRTUAL") || section.name.starts_with("None"))
+            .or_else(|| sections.iter().find(unused))
+            .map(|section| section.name.clone())
+            .ok_or_else(|| "no unused output available for a virtual display".to_string())?,
+    };
+
+    let right = sections
+        .iter()
+        .filter_map(|section| section.geometry.as_deref().and_then(parse_geometry))
+        .map(|geometry| geometry.x + geometry.width as i32)
+        .max()
+        .unwrap_or(0);
+    let modeline = cvt_modeline(width, height, rate, false);
+    check_remote_session(false)?;
+    add_user_mode(&display, &modeline, sections)?;
+    apply_layout_change(
+        sections,
+        backend,
+        vec![
+            "--output".to_string(),
+            display.clone(),
+            "--mode".to_string(),
+            modeline.name.clone(),
+            "--pos".to_string(),
+            format!("{right}x0"),
+        ],
+    )?;
+    println!("{display}");
+    Ok(())
+}
+
+fn section_mode_names(section: &DisplaySection) -> Vec<(String, bool)> {
+    section
+        .lines
+        .iter()
+        .filter_map(|line| {
+            let mut parts = line.split_whitespace();
+            let name = parts.next()?;
+            parts
+                .next()
+                .filter(|id| id.starts_with("(0x") && id.ends_with(')'))?;
+            Some((name.to_string(), line.contains("*current")))
+        })
+        .collect()
+}
+
+fn is_user_mode_name(name: &str) -> bool {
+    let size = name.strip_suffix('i').unwrap_or(name);
+    !size.split_once('x').is_some_and(|(width, height)| {
+        width.parse::<u32>().is_ok() && height.parse::<u32>().is_ok()
+    })
+}
+
+fn run_mode_delete(
+    display: &str,
+    name: &str,
+    sections: &[DisplaySection],
+    backend: Backend,
+) -> Result<(), CliError> {
+    if backend == Backend::Wlr {
+        return Err("mode_delete is not supported by the wlr backend"
+            .to_string()
+            .into());
+    }
+    let section =
+        find_section(sections, display).ok_or_else(|| display_not_found(sections, display))?;
+    let modes = section_mode_names(section);
+    let (_, current) = modes
+        .iter()
+        .find(|(mode, _)| mode == name)
+        .ok_or_else(|| format!("mode not found for display {display}: {name}"))?;
+    if *current {
+        return Err(format!("mode in use by display {display}: {name}").into());
+    }
+
+    run_xrandr_with_args(vec![
+        "--delmode".to_string(),
+        display.to_string(),
+        name.to_string(),
+    ])?;
+    let shared = sections
+        .iter()
+        .filter(|other| other.name != display)
+        .any(|other| {
+            section_mode_names(other)
+                .iter()
+                .any(|(mode, _)| mode == name)
+        });
+    if !shared {
+        run_xrandr_with_args(vec!["--rmmode".to_string(), name.to_string()])?;
+    }
+    Ok(())
+}
+
+/// Plain `xrandr` lists each output's modes in the compact form without ids and then
+/// prints every mode not attached to any output in the verbose `name (0xid)` form.
+fn query_xrandr_modes() -> Result<String, CliError> {
+    let output = Command::new("xrandr")
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .output()
+        .map_err(|err| CliError::Backend(format!("failed to run xrandr: {err}")))?;
+    if !output.status.success() {
+        return Err(CliError::Backend("xrandr exited with failure".to_string()));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Names from the trailing block of modes that follows the last output and belongs
+/// to no output.
+fn unattached_mode_names(text: &str) -> Vec<String> {
+    let lines: Vec<&str> = text.lines().collect();
+    let start = lines
+        .iter()
+        .rposition(|line| parse_header(line).is_some())
+        .map_or(0, |index| index + 1);
+    let block = DisplaySection {
+        name: String::new(),
+        state: DisplayState::Unknown,
+        primary: false,
+        geometry: None,
+        rotation: None,
+        lines: lines[start..].iter().map(|line| line.to_string()).collect(),
+    };
+    section_mode_names(&block)
+        .into_iter()
+        .map(|(name, _)| name)
+        .collect()
+}
+
+fn run_mode_gc(backend: Backend) -> Result<(), CliError> {
+    if backend == Backend::Wlr {
+        return Err("mode_gc is not supported by the wlr backend"
+            .to_string()
+            .into());
+    }
+    let mut removed = HashSet::new();
+    for name in unattached_mode_names(&query_xrandr_modes()?) {
+        if !is_user_mode_name(&name) {
+            continue;
+        }
+        if !removed.insert(name.clone()) {
+            continue;
+        }
+        match run_xrandr_with_args(vec!["--rmmode".to_string(), name.clone()]) {
+            Ok(()) => println!("{name}"),
+            Err(err) => eprintln!("mode_gc: skipping {name}: {err}"),
+        }
+    }
+    Ok(())
+}
+
+fn run_mirror_display_output(
+    source: &str,
+    target: &str,
+    sections: &[DisplaySection],
+    backend: Backend,
+) -> Result<(), CliError> {
+    if backend == Backend::Wlr {
+        return Err("mirror_display_output is not supported by the wlr backend"
+            .to_string()
+            .into());
+    }
+    if source == target {
+        return Err("source and target displays must be different"
+            .to_string()
+            .into());
+    }
+
+    let source_section =
+        find_section(sections, source).ok_or_else(|| display_not_found(sections, source))?;
+    let target_section =
+        find_section(sections, target).ok_or_else(|| display_not_found(sections, target))?;
+
+    let source_modes = parse_modes(source_section);
+    let target_modes = parse_modes(target_section);
+    if source_modes.is_empty() {
+        return Err(format!("no modes available for display: {source}").into());
+    }
+    if target_modes.is_empty() {
+        return Err(format!("no modes available for display: {target}").into());
+    }
+
+    let common = source_modes
+        .iter()
+        .filter(|mode| {
+            target_modes
+                .iter()
+                .any(|other| (other.width, other.height) == (mode.width, mode.height))
+        })
+        .max_by_key(|mode| mode.width as u64 * mode.height as u64);
+
+    let args = match common {
+        Some(mode) => {
+            let size = format!("{}x{}", mode.width, mode.height);
+            vec![
+                "--output".to_string(),
+                source.to_string(),
+                "--mode".to_string(),
+                size.clone(),
+                "--output".to_string(),
+                target.to_string(),
+                "--mode".to_string(),
+                size,
+                "--same-as".to_string(),
+                source.to_string(),
+            ]
+        }
+        None => {
+            let mode = source_modes
+                .iter()
+                .find(|mode| mode.preferred)
+                .unwrap_or(&source_modes[0]);
+            vec![
+                "--output".to_string(),
+                source.to_string(),
+                "--mode".to_string(),
+                format!("{}x{}", mode.width, mode.height),
+                "--output".to_string(),
+                target.to_string(),
+                "--auto".to_string(),
+                "--same-as".to_string(),
+                source.to_string(),
+                "--scale-from".to_string(),
+                format!("{}x{}", mode.width, mode.height),
+            ]
+        }
+    };
+
+    run_layout_change(sections, backend, args)
+}
+
+fn run_monitor_power(
+    display: &str,
+    state: &str,
+    sections: &[DisplaySection],
+    install_rule: bool,
+) -> Result<(), CliError> {
+    let section =
+        find_section(sections, display).ok_or_else(|| display_not_found(sections, display))?;
+    if section.state != DisplayState::Connected {
+        return Err(format!("display not connected: {display}").into());
+    }
+
+    // VCP feature 0xD6 (power mode): 01 = on, 04 = standby, 05 = off via power button.
+    let value = match state {
+        "on" => "01",
+        "standby" => "04",
+        "off" => "05",
+        _ => {
+            return Err(
+                format!("invalid power state: {state} (expected on, off or standby)").into(),
+            )
+        }
+    };
+
+    let mut args = match section_drm_connector(section).and_then(|drm| drm.ddc_bus()) {
+        Some(bus) => vec!["--bus".to_string(), bus],
+        None => {
+            let edid = extract_edid_hex(section).ok_or_else(|| {
+                CliError::EdidMissing(format!("edid data not available for display: {display}"))
+            })?;
+            if edid.len() < 256 {
+                return Err(format!("edid data too short for display: {display}").into());
+            }
+            vec!["--edid".to_string(), edid[..256].to_string()]
+        }
+    };
+    args.extend(["setvcp".to_string(), "d6".to_string(), value.to_string()]);
+    run_ddcutil_with_args(args).map_err(|err| {
+        if is_permission_error(err.message()) {
+            permission_error(&DDC_PERMISSION_FIX, install_rule).into()
+        } else {
+            err
+        }
+    })
+}
+
+struct PermissionFix {
+    subsystem: &'static str,
+    group: &'static str,
+    rule_path: &'static str,
+    rule: &'static str,
+}
+
+const DDC_PERMISSION_FIX: PermissionFix = PermissionFix {
+    subsystem: "ddc/ci (/dev/i2c-*)",
+    group: "i2c",
+    rule_path: "/etc/udev/rules.d/60-xrandr-utils-i2c.rules",
+    rule: "KERNEL==\"i2c-[0-9]*\", GROUP=\"i2c\", MODE=\"0660\"\n",
+};
+
+const BACKLIGHT_PERMISSION_FIX: PermissionFix = PermissionFix {
+    subsystem: "backlight (/sys/class/backlight)",
+    group: "video",
+    rule_path: "/etc/udev/rules.d/60-xrandr-utils-backlight.rules",
+    rule: "ACTION==\"add\", SUBSYSTEM==\"backlight\", RUN+=\"/bin/chgrp video /sys/class/backlight/%k/brightness\", RUN+=\"/bin/chmod g+w /sys/class/backlight/%k/brightness\"\n",
+};
+
+fn is_permission_error(message: &str) -> bool {
+    let lower = message.to_ascii_lowercase();
+    lower.contains("permission denied") || lower.contains("eacces")
+}
+
+fn permission_error(fix: &PermissionFix, install: bool) -> String {
+    let steps = format!(
+        "add your user to the {group} group (sudo usermod -aG {group} $USER), then log out and back in",
+        group = fix.group
+    );
+    if !install {
+        return format!(
+            "permission denied accessing {}; {steps}, and install this udev rule at {} (or rerun with --install-udev-rule):\n{}",
+            fix.subsystem,
+            fix.rule_path,
+            fix.rule.trim_end()
+        );
+    }
+    match std::fs::write(fix.rule_path, fix.rule) {
+        Ok(()) => format!(
+            "permission denied accessing {}; installed udev rule at {}, run `sudo udevadm control --reload && sudo udevadm trigger` and {steps}",
+            fix.subsystem, fix.rule_path
+        ),
+        Err(err) if err.kind() == io::ErrorKind::PermissionDenied => format!(
+            "permission denied accessing {}; failed to write {}: {err} (rerun with sudo)",
+            fix.subsystem, fix.rule_path
+        ),
+        Err(err) => format!(
+            "permission denied accessing {}; failed to write {}: {err}",
+            fix.subsystem, fix.rule_path
+        ),
+    }
+}
+
+struct WatchFlags {
+    interval: Duration,
+    json_stream: bool,
+    events: Option<HashSet<String>>,
+    matches: Vec<String>,
+    hook: Option<String>,
+    debounce: Duration,
+    rate_limit: Duration,
+    properties: Vec<String>,
+    retrain_link: bool,
+    color: Option<ColorSchedule>,
+}
+
+struct ColorSchedule {
+    default: Option<(u32, u32)>,
+    outputs: Vec<(String, u32, u32)>,
+    window: NightWindow,
+    transition: Duration,
+}
+
+enum NightWindow {
+    Fixed { start: u32, end: u32 },
+    Location { latitude: f64, longitude: f64 },
+}
+
+impl ColorSchedule {
+    fn night_fraction(&self, now: u64, utc_offset: i64) -> f64 {
+        let now = now as f64;
+        let (rise, set) = match self.window {
+            NightWindow::Fixed { start, end } => {
+                let midnight =
+                    (now + utc_offset as f64).div_euclid(86400.0) * 86400.0 - utc_offset as f64;
+                (
+                    midnight + f64::from(end) * 60.0,
+                    midnight + f64::from(start) * 60.0,
+                )
+            }
+            NightWindow::Location {
+                latitude,
+                longitude,
+            } => sun_times(now, latitude, longitude),
+        };
+        let offset = |edge: f64| (now - edge + 43200.0).rem_euclid(86400.0) - 43200.0;
+        let (from_rise, from_set) = (offset(rise), offset(set));
+        let half = (self.transition.as_secs_f64() / 2.0).max(1.0);
+        if from_rise.abs() < half && from_rise.abs() <= from_set.abs() {
+            0.5 - from_rise / (2.0 * half)
+        } else if from_set.abs() < half {
+            0.5 + from_set / (2.0 * half)
+        } else if from_set.rem_euclid(86400.0) < from_rise.rem_euclid(86400.0) {
+            1.0
+        } else {
+            0.0
+        }
+    }
+
+    fn temperatures(&self, sections: &[DisplaySection], night: f64) -> Vec<(String, u32)> {
+        sections
+            .iter()
+            .filter(|section| section.geometry.is_some())
+            .filter_map(|section| {
+                let (day_kelvin, night_kelvin) = self
+                    .outputs
+                    .iter()
+                    .find(|(name, _, _)| name == &section.name)
+                    .map(|(_, day, night)| (*day, *night))
+                    .or(self.default)?;
+                let kelvin = f64::from(day_kelvin)
+                    + (f64::from(night_kelvin) - f64::from(day_kelvin)) * night;
+                Some((section.name.clone(), kelvin.round() as u32 / 50 * 50))
+            })
+            .collect()
+    }
+}
+
+fn sun_times(now: f64, latitude: f64, longitude: f64) -> (f64, f64) {
+    let julian_day = now / 86400.0 + 2440587.5;
+    let cycle = (julian_day - 2451545.0 + 0.0008 + longitude / 360.0).round();
+    let noon = cycle - longitude / 360.0;
+    let anomaly = (357.5291 + 0.98560028 * noon)
+        .rem_euclid(360.0)
+        .to_radians();
+    let center =
+        1.9148 * anomaly.sin() + 0.02 * (2.0 * anomaly).sin() + 0.0003 * (3.0 * anomaly).sin();
+    let ecliptic = (anomaly.to_degrees() + center + 282.9372)
+        .rem_euclid(360.0)
+        .to_radians();
+    let transit = 2451545.0 + noon + 0.0053 * anomaly.sin() - 0.0069 * (2.0 * ecliptic).sin();
+    let declination = (ecliptic.sin() * 23.4397_f64.to_radians().sin()).asin();
+    let latitude = latitude.to_radians();
+    let hour_angle = ((-0.833_f64.to_radians().sin() - latitude.sin() * declination.sin())
+        / (latitude.cos() * declination.cos()))
+    .clamp(-1.0, 1.0)
+    .acos()
+    .to_degrees();
+    let to_unix = |julian: f64| (julian - 2440587.5) * 86400.0;
+    (
+        to_unix(transit - hour_angle / 360.0),
+        to_unix(transit + hour_angle / 360.0),
+    )
+}
+
+fn temperature_gamma(kelvin: u32) -> String {
+    let t = f64::from(kelvin) / 100.0;
+    let red = if t <= 66.0 {
+        255.0
+    } else {
+        329.698727446 * (t - 60.0).powf(-0.1332047592)
+    };
+    let green = if t <= 66.0 {
+        99.4708025861 * t.ln() - 161.1195681661
+    } else {
+        288.1221695283 * (t - 60.0).powf(-0.0755148492)
+    };
+    let blue = if t >= 66.0 {
+        255.0
+    } else if t <= 19.0 {
+        0.0
+    } else {
+        138.5177312231 * (t - 10.0).ln() - 305.0447927307
+    };
+    [red, green, blue]
+        .iter()
+        .map(|channel| format!("{:.2}", (channel / 255.0).clamp(0.1, 1.0)))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+fn parse_clock_time(value: &str) -> Option<u32> {
+    let (hours, minutes) = value.split_once(':')?;
+    let hours: u32 = hours.parse().ok()?;
+    let minutes: u32 = minutes.parse().ok()?;
+    (hours < 24 && minutes < 60).then_some(hours * 60 + minutes)
+}
+
+fn local_utc_offset() -> i64 {
+    let output = match Command::new("date").arg("+%z").output() {
+        Ok(output) if output.status.success() => output,
+        _ => return 0,
+    };
+    let text = String::from_utf8_lossy(&output.stdout);
+    let text = text.trim();
+    let (sign, digits) = match text.strip_prefix('-') {
+        Some(digits) => (-1, digits),
+        None => (1, text.trim_start_matches('+')),
+    };
+    match (digits.get(..2), digits.get(2..4)) {
+        (Some(hours), Some(minutes)) => match (hours.parse::<i64>(), minutes.parse::<i64>()) {
+            (Ok(hours), Ok(minutes)) => sign * (hours * 3600 + minutes * 60),
+            _ => 0,
+        },
+        _ => 0,
+    }
+}
+
+fn apply_color_temperature(temperatures: &[(String, u32)]) -> Result<(), CliError> {
+    let mut args = Vec::new();
+    for (name, kelvin) in temperatures {
+        args.extend([
+            "--output".to_string(),
+            name.clone(),
+            "--gamma".to_string(),
+            temperature_gamma(*kelvin),
+        ]);
+    }
+    if args.is_empty() {
+        return Ok(());
+    }
+    run_xrandr_with_args(args)
+}
+
+impl WatchFlags {
+    fn accepts(&self, event: &WatchEvent) -> bool {
+        let category = watch_event_category(event.kind);
+        self.events
+            .as_ref()
+            .is_none_or(|events| events.contains(category))
+            && (self.matches.is_empty() || self.matches.contains(&event.display))
+    }
+}
+
+const WATCH_EVENT_CATEGORIES: [&str; 5] = ["connect", "disconnect", "mode", "primary", "property"];
+
+fn watch_event_category(kind: &str) -> &'static str {
+    match kind {
+        "connected" => "connect",
+        "disconnected" => "disconnect",
+        "primary" => "primary",
+        "property" => "property",
+        _ => "mode",
+    }
+}
+
+fn parse_watch_flags(args: &mut impl Iterator<Item = String>) -> Result<WatchFlags, CliError> {
+    let mut flags = WatchFlags {
+        interval: Duration::from_secs(2),
+        json_stream: false,
+        events: None,
+        matches: Vec::new(),
+        hook: None,
+        debounce: Duration::ZERO,
+        rate_limit: Duration::ZERO,
+        properties: Vec::new(),
+        retrain_link: false,
+        color: None,
+    };
+    let mut temperatures = None;
+    let mut output_temperatures = Vec::new();
+    let mut window = None;
+    let mut transition = Duration::from_secs(1800);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--json-stream" => flags.json_stream = true,
+            "--events" => {
+                let value = expect_arg(args, "event list")?;
+                let mut events = HashSet::new();
+                for event in value
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|event| !event.is_empty())
+                {
+                    if !WATCH_EVENT_CATEGORIES.contains(&event) {
+                        return Err(format!(
+                            "unknown event: {event} (expected one of {})",
+                            WATCH_EVENT_CATEGORIES.join(", ")
+                        )
+                        .into());
+                    }
+                    events.insert(event.to_string());
+                }
+                flags.events = Some(events);
+            }
+            "--match" => flags.matches.push(expect_arg(args, "display selector")?),
+            "--interval" => {
+                flags.interval = parse_seconds(&expect_arg(args, "interval seconds")?, "interval")?;
+                if flags.interval.is_zero() {
+                    return Err("invalid interval: must be greater than zero"
+                        .to_string()
+                        .into());
+                }
+            }
+            "--hook" => flags.hook = Some(expect_arg(args, "hook command")?),
+            "--debounce" => {
+                flags.debounce = parse_seconds(&expect_arg(args, "debounce seconds")?, "debounce")?;
+            }
+            "--rate-limit" => {
+                flags.rate_limit =
+                    parse_seconds(&expect_arg(args, "rate limit seconds")?, "rate limit")?;
+            }
+            "--property" => flags.properties.push(expect_arg(args, "property name")?),
+            "--retrain-link" => flags.retrain_link = true,
+            "--color-temp" => {
+                let value = expect_arg(args, "color temperatures")?;
+                let (output, kelvins) = match value.split_once('=') {
+                    Some((output, kelvins)) => (Some(output.to_string()), kelvins),
+                    None => (None, value.as_str()),
+                };
+                let parsed = kelvins.split_once(':').and_then(|(day, night)| {
+                    let day = day
+                        .parse::<u32>()
+                        .ok()
+                        .filter(|k| (1000..=25000).contains(k))?;
+                    let night = night
+                        .parse::<u32>()
+                        .ok()
+                        .filter(|k| (1000..=25000).contains(k))?;
+                    Some((day, night))
+                });
+                let (day, night) = parsed.ok_or_else(|| {
+                    format!(
+                        "invalid color temperatures: {value} (expected [<display>=]<day>:<night> in kelvin)"
+                    )
+                })?;
+                match output {
+                    Some(output) => output_temperatures.push((output, day, night)),
+                    None => temperatures = Some((day, night)),
+                }
+            }
+            "--night" => {
+                let value = expect_arg(args, "night window")?;
+                let parsed = value.split_once('-').and_then(|(start, end)| {
+                    Some(NightWindow::Fixed {
+                        start: parse_clock_time(start)?,
+                        end: parse_clock_time(end)?,
+                    })
+                });
+                window = Some(parsed.ok_or_else(|| {
+                    format!("invalid night window: {value} (expected HH:MM-HH:MM)")
+                })?);
+            }
+            "--location" => {
+                let value = expect_arg(args, "location")?;
+                let parsed = value.split_once(',').and_then(|(latitude, longitude)| {
+                    let latitude = latitude
+                        .trim()
+                        .parse::<f64>()
+                        .ok()
+                        .filter(|v| v.abs() <= 90.0)?;
+                    let longitude = longitude
+                        .trim()
+                        .parse::<f64>()
+                        .ok()
+                        .filter(|v| v.abs() <= 180.0)?;
+                    Some(NightWindow::Location {
+                        latitude,
+                        longitude,
+                    })
+                });
+                window =
+                    Some(parsed.ok_or_else(|| {
+                        format!("invalid location: {value} (expected <lat>,<lon>)")
+                    })?);
+            }
+            "--transition" => {
+                transition = parse_seconds(&expect_arg(args, "transition seconds")?, "transition")?;
+            }
+            other => return Err(CliError::Usage(format!("unknown option: {other}"))),
+        }
+    }
+    let scheduled = temperatures.is_some() || !output_temperatures.is_empty();
+    flags.color = match (scheduled, window) {
+        (true, Some(window)) => Some(ColorSchedule {
+            default: temperatures,
+            outputs: output_temperatures,
+            window,
+            transition,
+        }),
+        (true, None) => {
+            return Err("--color-temp requires --night or --location"
+                .to_string()
+                .into());
+        }
+        (false, Some(_)) => {
+            return Err("--night and --location require --color-temp"
+                .to_string()
+                .into());
+        }
+        (false, None) => None,
+    };
+    if flags.retrain_link
+        && !flags
+            .properties
+            .iter()
+            .any(|name| name.eq_ignore_ascii_case("link-status"))
+    {
+        flags.properties.push("link-status".to_string());
+    }
+    Ok(flags)
+}
+
+fn parse_seconds(value: &str, name: &str) -> Result<Duration, CliError> {
+    value
+        .parse::<f64>()
+        .ok()
+        .filter(|secs| secs.is_finite() && *secs >= 0.0)
+        .map(Duration::from_secs_f64)
+        .ok_or_else(|| CliError::Usage(format!("invalid {name}: {value}")))
+}
+
+struct WatchOutput {
+    name: String,
+    state: DisplayState,
+    primary: bool,
+    geometry: Option<String>,
+    identity: String,
+    properties: Vec<(String, String)>,
+}
+
+struct PropertyChange {
+    name: String,
+    old: Option<String>,
+    new: String,
+}
+
+struct WatchEvent {
+    kind: &'static str,
+    display: String,
+    identity: String,
+    old_geometry: Option<String>,
+    new_geometry: Option<String>,
+    property: Option<PropertyChange>,
+    timestamp: u64,
+}
+
+impl WatchEvent {
+    fn json(&self) -> String {
+        let geometry = |value: &Option<String>| {
+            value
+                .as_deref()
+                .map(json_string)
+                .unwrap_or_else(|| "null".to_string())
+        };
+        let property = match &self.property {
+            Some(change) => format!(
+                ", \"property\": {{\"name\": {}, \"old\": {}, \"new\": {}}}",
+                json_string(&change.name),
+                geometry(&change.old),
+                json_string(&change.new)
+            ),
+            None => String::new(),
+        };
+        format!(
+            "{{\"type\": {}, \"display\": {}, \"identity\": {}, \"old_geometry\": {}, \"new_geometry\": {}{property}, \"timestamp\": {}}}",
+            json_string(self.kind),
+            json_string(&self.display),
+            json_string(&self.identity),
+            geometry(&self.old_geometry),
+            geometry(&self.new_geometry),
+            self.timestamp
+        )
+    }
+
+    fn line(&self) -> String {
+        if let Some(change) = &self.property {
+            return format!(
+                "{} {} {} {} {} -> {}",
+                self.timestamp,
+                self.kind,
+                self.display,
+                change.name,
+                change.old.as_deref().unwrap_or("-"),
+                change.new
+            );
+        }
+        format!(
+            "{} {} {} {} -> {}",
+            self.timestamp,
+            self.kind,
+            self.display,
+            self.old_geometry.as_deref().unwrap_or("-"),
+            self.new_geometry.as_deref().unwrap_or("-")
+        )
+    }
+}
+
+fn watch_snapshot(sections: &[DisplaySection], properties: &[String]) -> Vec<WatchOutput> {
+    sections
+        .iter()
+        .map(|section| WatchOutput {
+            name: section.name.clone(),
+            state: section.state,
+            primary: section.primary,
+            geometry: section.geometry.clone(),
+            identity: section_identity(section)
+                .map(|identity| identity.describe())
+                .unwrap_or_default(),
+            properties: section_properties(section)
+                .into_iter()
+                .filter(|property| {
+                    properties
+                        .iter()
+                        .any(|name| name.eq_ignore_ascii_case(&property.name))
+                })
+                .map(|property| (property.name, property.value))
+                .collect(),
+        })
+        .collect()
+}
+
+fn watch_events(before: &[WatchOutput], after: &[WatchOutput], timestamp: u64) -> Vec<WatchEvent> {
+    let mut events = Vec::new();
+    let mut push = |kind,
+                    output: &WatchOutput,
+                    old: &Option<String>,
+                    new: &Option<String>,
+                    property: Option<PropertyChange>| {
+        events.push(WatchEvent {
+            kind,
+            display: output.name.clone(),
+            identity: output.identity.clone(),
+            old_geometry: old.clone(),
+            new_geometry: new.clone(),
+            property,
+            timestamp,
+        });
+    };
+    for output in after {
+        let previous = before.iter().find(|prev| prev.name == output.name);
+        let old_state = previous.map(|prev| prev.state);
+        let old_geometry = previous.and_then(|prev| prev.geometry.clone());
+        if output.state != DisplayState::Connected && old_state == Some(DisplayState::Connected) {
+            let identity = previous.map(|prev| prev.identity.as_str()).unwrap_or("");
+            let gone = WatchOutput {
+                name: output.name.clone(),
+                state: output.state,
+                primary: false,
+                geometry: None,
+                identity: identity.to_string(),
+                properties: Vec::new(),
+            };
+            push("disconnected", &gone, &old_geometry, &output.geometry, None);
+            continue;
+        }
+        if output.state == DisplayState::Connected && old_state != Some(DisplayState::Connected) {
+            push("connected", output, &old_geometry, &output.geometry, None);
+            continue;
+        }
+        let kind = match (&old_geometry, &output.geometry) {
+            (None, Some(_)) => Some("enabled"),
+            (Some(_), None) => Some("disabled"),
+            (Some(old), Some(new)) if old != new => Some("geometry"),
+            _ => None,
+        };
+        if let Some(kind) = kind {
+            push(kind, output, &old_geometry, &output.geometry, None);
+        }
+        if output.primary && !previous.is_some_and(|prev| prev.primary) {
+            push("primary", output, &old_geometry, &output.geometry, None);
+        }
+        for (name, value) in &output.properties {
+            let old = previous.and_then(|prev| {
+                prev.properties
+                    .iter()
+                    .find(|(prev_name, _)| prev_name == name)
+                    .map(|(_, value)| value.clone())
+            });
+            if previous.is_some() && old.as_ref() != Some(value) {
+                let change = PropertyChange {
+                    name: name.clone(),
+                    old,
+                    new: value.clone(),
+                };
+                push(
+                    "property",
+                    output,
+                    &old_geometry,
+                    &output.geometry,
+                    Some(change),
+                );
+            }
+        }
+    }
+    for output in before {
+        if output.state == DisplayState::Connected
+            && !after.iter().any(|next| next.name == output.name)
+        {
+            push("disconnected", output, &output.geometry, &None, None);
+        }
+    }
+    events
+}
+
+fn unix_timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or(0)
+}
+
+fn run_watch(
+    sections: &[DisplaySection],
+    backend: Backend,
+    flags: &WatchFlags,
+) -> Result<(), CliError> {
+    if flags.retrain_link && backend == Backend::Wlr {
+        return Err("--retrain-link is not supported by the wlr backend"
+            .to_string()
+            .into());
+    }
+    if flags.color.is_some() && backend == Backend::Wlr {
+        return Err("--color-temp is not supported by the wlr backend"
+            .to_string()
+            .into());
+    }
+    let mut applied_temperatures = None;
+    let mut apply_color = |sections: &[DisplaySection], layout_changed: bool| {
+        let schedule = match &flags.color {
+            Some(schedule) => schedule,
+            None => return,
+        };
+        // Re-read the offset every tick so a DST switch moves a fixed night window.
+        let night = schedule.night_fraction(unix_timestamp(), local_utc_offset());
+        let temperatures = schedule.temperatures(sections, night);
+        if layout_changed || applied_temperatures.as_ref() != Some(&temperatures) {
+            if let Err(err) = apply_color_temperature(&temperatures) {
+                eprintln!("watch: {err}");
+            }
+            applied_temperatures = Some(temperatures);
+        }
+    };
+    apply_color(sections, false);
+    let mut uptime = start_uptime_tracking(sections, "watch");
+    let mut previous = watch_snapshot(sections, &flags.properties);
+    let mut stdout = io::stdout();
+    let mut pending: Vec<WatchEvent> = Vec::new();
+    let mut last_change = Instant::now();
+    let mut last_hook: Option<Instant> = None;
+    loop {
+        thread::sleep(flags.interval);
+        if let Some(hook) = &flags.hook {
+            let settled = last_change.elapsed() >= flags.debounce;
+            let allowed = last_hook.is_none_or(|at| at.elapsed() >= flags.rate_limit);
+            if !pending.is_empty() && settled && allowed {
+                run_watch_hook(hook, &pending);
+                pending.clear();
+                last_hook = Some(Instant::now());
+            }
+        }
+        let text = match query_backend_text(backend) {
+            Ok(text) => text,
+            Err(err) => {
+                eprintln!("watch: {err}");
+                continue;
+            }
+        };
+        let current_sections = parse_backend_sections(backend, &text);
+        track_uptime(&mut uptime, &current_sections, "watch");
+        let current = watch_snapshot(&current_sections, &flags.properties);
+        let events = watch_events(&previous, &current, unix_timestamp());
+        apply_color(&current_sections, !events.is_empty());
+        let events: Vec<WatchEvent> = events
+            .into_iter()
+            .filter(|event| flags.accepts(event))
+            .collect();
+        if !events.is_empty() {
+            last_change = Instant::now();
+        }
+        for event in &events {
+            let rendered = if flags.json_stream {
+                event.json()
+            } else {
+                event.line()
+            };
+            writeln!(stdout, "{rendered}")
+                .map_err(|err| format!("failed to write event: {err}"))?;
+            stdout
+                .flush()
+                .map_err(|err| format!("failed to write event: {err}"))?;
+            if flags.retrain_link {
+                retrain_degraded_link(event);
+            }
+        }
+        previous = current;
+        if flags.hook.is_some() {
+            pending.extend(events);
+        }
+    }
+}
+
+struct UptimeRecord {
+    name: String,
+    state: String,
+    since: u64,
+    changes: u64,
+}
+
+fn read_uptime_records() -> Result<Vec<UptimeRecord>, CliError> {
+    read_state("uptime")?
+        .unwrap_or_default()
+        .lines()
+        .map(|line| {
+            parse_uptime_record(line).ok_or_else(|| format!("invalid uptime record: {line}").into())
+        })
+        .collect()
+}
+
+fn parse_uptime_record(line: &str) -> Option<UptimeRecord> {
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    match fields[..] {
+        [name, state, since, changes] => Some(UptimeRecord {
+            name: name.to_string(),
+            state: state.to_string(),
+            since: since.parse().ok()?,
+            changes: changes.parse().ok()?,
+        }),
+        _ => None,
+    }
+}
+
+/// Loads the saved records and brings them up to date with `sections`; `context`
+/// prefixes the warnings of the long-running command doing the tracking.
+fn start_uptime_tracking(sections: &[DisplaySection], context: &str) -> Vec<UptimeRecord> {
+    let mut records = read_uptime_records().unwrap_or_else(|err| {
+        eprintln!("{context}: {err}");
+        Vec::new()
+    });
+    track_uptime(&mut records, sections, context);
+    records
+}
+
+fn track_uptime(records: &mut Vec<UptimeRecord>, sections: &[DisplaySection], context: &str) {
+    let now = unix_timestamp();
+    let mut changed = false;
+    for section in sections {
+        let state = section_activity(section);
+        match records
+            .iter_mut()
+            .find(|record| record.name == section.name)
+        {
+            Some(record) if record.state == state => {}
+            Some(record) => {
+                record.state = state.to_string();
+                record.since = now;
+                record.changes += 1;
+                changed = true;
+            }
+            None => {
+                records.push(UptimeRecord {
+                    name: section.name.clone(),
+                    state: state.to_string(),
+                    since: now,
+                    changes: 0,
+                });
+                changed = true;
+            }
+        }
+    }
+    if !changed || is_dry_run() {
+        return;
+    }
+    let saved: String = records
+        .iter()
+        .map(|record| {
+            format!(
+                "{} {} {} {}\n",
+                record.name, record.state, record.since, record.changes
+            )
+        })
+        .collect();
+    if let Err(err) = write_state("uptime", &saved) {
+        eprintln!("{context}: {err}");
+    }
+}
+
+/// Uptime records in the Prometheus text format, for a node_exporter textfile
+/// collector or a scrape through `serve`.
+fn uptime_metrics(records: &[UptimeRecord]) -> String {
+    let label = |value: &str| value.replace('\\', "\\\\").replace('"', "\\\"");
+    let mut text = String::from(concat!(
+        "# HELP xrandr_utils_display_state_since_seconds Unix time of the display's last state change.\n",
+        "# TYPE xrandr_utils_display_state_since_seconds gauge\n",
+    ));
+    for record in records {
+        text.push_str(&format!(
+            "xrandr_utils_display_state_since_seconds{{display=\"{}\",state=\"{}\"}} {}\n",
+            label(&record.name),
+            label(&record.state),
+            record.since
+        ));
+    }
+    text.push_str(concat!(
+        "# HELP xrandr_utils_display_state_changes_total State changes seen since tracking began.\n",
+        "# TYPE xrandr_utils_display_state_changes_total counter\n",
+    ));
+    for record in records {
+        text.push_str(&format!(
+            "xrandr_utils_display_state_changes_total{{display=\"{}\"}} {}\n",
+            label(&record.name),
+            record.changes
+        ));
+    }
+    text
+}
+
+fn retrain_degraded_link(event: &WatchEvent) {
+    let degraded = event.new_geometry.is_some()
+        && event.property.as_ref().is_some_and(|change| {
+            change.name.eq_ignore_ascii_case("link-status") && change.new == "Bad"
+        });
+    if !degraded {
+        return;
+    }
+    eprintln!("watch: retraining link on {}", event.display);
+    if let Err(err) = run_xrandr_with_args(vec![
+        "--output".to_string(),
+        event.display.clone(),
+        "--set".to_string(),
+        "link-status".to_string(),
+        "Good".to_string(),
+    ]) {
+        eprintln!("watch: {err}");
+    }
+}
+
+/// Writes systemd user units for `watch`, or a socket unit plus a per-connection
+/// service for `serve --stdio`; with `enable` they are also enabled and started.
+fn run_install_service(
+    watch_args: &[String],
+    serve: bool,
+    print_only: bool,
+    enable: bool,
+) -> Result<(), CliError> {
+    let exe = env::current_exe().map_err(|err| format!("failed to locate binary: {err}"))?;
+    let command_line = |words: &[String]| {
+        std::iter::once(exe.to_string_lossy().into_owned())
+            .chain(words.iter().cloned())
+            .map(|arg| systemd_quote(&arg))
+            .collect::<Vec<_>>()
+            .join(" ")
+    };
+    let (units, start) = if serve {
+        let exec = command_line(&["serve".to_string(), "--stdio".to_string()]);
+        let socket = "[Unit]\n\
+Description=xrandr-utils query socket\n\
+PartOf=graphical-session.target\n\
+After=graphical-session.target\n\
+\n\
+[Socket]\n\
+ListenStream=%t/xrandr-utils.sock\n\
+SocketMode=0600\n\
+Accept=yes\n\
+\n\
+[Install]\n\
+WantedBy=graphical-session.target\n"
+            .to_string();
+        let service = format!(
+            "[Unit]\n\
+Description=xrandr-utils query connection\n\
+\n\
+[Service]\n\
+ExecStart={exec}\n\
+StandardInput=socket\n\
+StandardOutput=socket\n\
+StandardError=journal\n"
+        );
+        (
+            vec![
+                ("xrandr-utils-serve.socket", socket),
+                ("xrandr-utils-serve@.service", service),
+            ],
+            "xrandr-utils-serve.socket",
+        )
+    } else {
+        parse_watch_flags(&mut watch_args.iter().cloned())?;
+        let mut words = vec!["watch".to_string()];
+        words.extend(watch_args.iter().cloned());
+        let exec = command_line(&words);
+        let service = format!(
+            "[Unit]\n\
+Description=xrandr-utils output watcher\n\
+PartOf=graphical-session.target\n\
+After=graphical-session.target\n\
+\n\
+[Service]\n\
+ExecStart={exec}\n\
+Restart=on-failure\n\
+\n\
+[Install]\n\
+WantedBy=graphical-session.target\n"
+        );
+        (
+            vec![("xrandr-utils.service", service)],
+            "xrandr-utils.service",
+        )
+    };
+    if print_only {
+        for (index, (name, unit)) in units.iter().enumerate() {
+            if units.len() > 1 {
+                if index > 0 {
+                    println!();
+                }
+                println!("# {name}");
+            }
+            print!("{unit}");
+        }
+        return Ok(());
+    }
+
+    let unit_dir = xdg_dir("XDG_CONFIG_HOME", ".config")?
+        .join("systemd")
+        .join("user");
+    std::fs::create_dir_all(&unit_dir)
+        .map_err(|err| format!("failed to create {}: {err}", unit_dir.display()))?;
+    for (name, unit) in &units {
+        let path = unit_dir.join(name);
+        std::fs::write(&path, unit)
+            .map_err(|err| format!("failed to write {}: {err}", path.display()))?;
+        println!("{}", path.display());
+    }
+    if enable {
+        run_systemctl_user(&["daemon-reload"])?;
+        run_systemctl_user(&["enable", "--now", start])?;
+    }
+    Ok(())
+}
+
+fn run_systemctl_user(args: &[&str]) -> Result<(), CliError> {
+    let mut words = vec!["--user".to_string()];
+    words.extend(args.iter().map(|arg| arg.to_string()));
+    if is_dry_run() {
+        print_dry_run("systemctl", &words);
+        return Ok(());
+    }
+    let status = Command::new("systemctl")
+        .args(&words)
+        .status()
+        .map_err(|err| format!("failed to run systemctl: {err}"))?;
+    if !status.success() {
+        return Err(format!("systemctl {} failed: {status}", words.join(" ")).into());
+    }
+    Ok(())
+}
+
+fn xdg_dir(variable: &str, fallback: &str) -> Result<PathBuf, CliError> {
+    match env::var(variable) {
+        Ok(dir) if !dir.is_empty() => Ok(PathBuf::from(dir)),
+        _ => {
+            let home = env::var("HOME").map_err(|_| "HOME is not set".to_string())?;
+            Ok(PathBuf::from(home).join(fallback))
+        }
+    }
+}
+
+fn state_dir() -> Result<PathBuf, CliError> {
+    Ok(xdg_dir("XDG_STATE_HOME", ".local/state")?.join("xrandr-utils"))
+}
+
+/// 1: atomic writes under `lock`. 2: saved display positions moved from one
+/// `position_<display>` file each, named after untrusted output names, into `positions`.
+const STATE_VERSION: u32 = 2;
+
+// The lock is per open file, so a holder must not call back into run_backend_with_args.
+struct StateDir {
+    dir: PathBuf,
+    _lock: std::fs::File,
+}
+
+impl StateDir {
+    fn lock() -> Result<StateDir, CliError> {
+        StateDir::lock_at(state_dir()?)
+    }
+
+    fn lock_at(dir: PathBuf) -> Result<StateDir, CliError> {
+        std::fs::create_dir_all(&dir)
+            .map_err(|err| format!("failed to create {}: {err}", dir.display()))?;
+        let path = dir.join("lock");
+        let lock = std::fs::OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&path)
+            .map_err(|err| format!("failed to open {}: {err}", path.display()))?;
+        lock.lock()
+            .map_err(|err| format!("failed to lock {}: {err}", path.display()))?;
+        let state = StateDir { dir, _lock: lock };
+        state.migrate()?;
+        Ok(state)
+    }
+
+    fn migrate(&self) -> Result<(), CliError> {
+        let version = match self.read("version")? {
+            Some(text) => text
+                .trim()
+                .parse::<u32>()
+                .map_err(|_| format!("invalid state version in {}", self.dir.display()))?,
+            None => 0,
+        };
+        if version > STATE_VERSION {
+            return Err(format!(
+                "{} was written by a newer xrandr-utils (state version {version})",
+                self.dir.display()
+            )
+            .into());
+        }
+        if version == STATE_VERSION {
+            return Ok(());
+        }
+        let mut names: Vec<String> = std::fs::read_dir(&self.dir)
+            .map_err(|err| format!("failed to read {}: {err}", self.dir.display()))?
+            .flatten()
+            .map(|entry| entry.file_name().to_string_lossy().into_owned())
+            .collect();
+        names.sort();
+        if version < 1 {
+            for name in names.iter().filter(|name| name.ends_with(".tmp")) {
+                self.remove(name)?;
+            }
+        }
+        if version < 2 {
+            let mut positions = parse_saved_positions(&self.read("positions")?.unwrap_or_default());
+            let old: Vec<&String> = names
+                .iter()
+                .filter(|name| name.starts_with("position_") && !name.ends_with(".tmp"))
+                .collect();
+            for name in &old {
+                let display = &name["position_".len()..];
+                match self
+                    .read(name)?
+                    .as_deref()
+                    .map(str::trim)
+                    .and_then(parse_position)
+                {
+                    Some(position) => set_saved_position(&mut positions, display, position),
+                    None => eprintln!("warning: dropping invalid saved position for {display}"),
+                }
+            }
+            self.write("positions", &saved_positions_text(&positions))?;
+            for name in old {
+                self.remove(name)?;
+            }
+        }
+        self.write("version", &format!("{STATE_VERSION}\n"))
+    }
+
+    fn read(&self, name: &str) -> Result<Option<String>, CliError> {
+        let path = self.dir.join(name);
+        match std::fs::read_to_string(&path) {
+            Ok(text) => Ok(Some(text)),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(format!("failed to read {}: {err}", path.display()).into()),
+        }
+    }
+
+    fn write(&self, name: &str, contents: &str) -> Result<(), CliError> {
+        let path = self.dir.join(name);
+        let temp = self.dir.join(format!("{name}.tmp"));
+        let mut file = std::fs::File::create(&temp)
+            .map_err(|err| format!("failed to write {}: {err}", temp.display()))?;
+        file.write_all(contents.as_bytes())
+            .and_then(|_| file.sync_all())
+            .map_err(|err| format!("failed to write {}: {err}", temp.display()))?;
+        std::fs::rename(&temp, &path)
+            .map_err(|err| format!("failed to write {}: {err}", path.display()).into())
+    }
+
+    fn remove(&self, name: &str) -> Result<(), CliError> {
+        let path = self.dir.join(name);
+        match std::fs::remove_file(&path) {
+            Ok(()) => Ok(()),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+            Err(err) => Err(format!("failed to remove {}: {err}", path.display()).into()),
+        }
+    }
+}
+
+fn read_state(name: &str) -> Result<Option<String>, CliError> {
+    if !state_dir()?.exists() {
+        return Ok(None);
+    }
+    StateDir::lock()?.read(name)
+}
+
+fn write_state(name: &str, contents: &str) -> Result<(), CliError> {
+    StateDir::lock()?.write(name, contents)
+}
+
+/// Positions saved by `display_off` for `display_on`, one `<display> <x>x<y>` line each.
+fn parse_saved_positions(saved: &str) -> Vec<(String, (i32, i32))> {
+    saved
+        .lines()
+        .filter_map(|line| {
+            let (display, position) = line.split_once(' ')?;
+            Some((display.to_string(), parse_position(position)?))
+        })
+        .collect()
+}
+
+fn set_saved_position(
+    positions: &mut Vec<(String, (i32, i32))>,
+    display: &str,
+    position: (i32, i32),
+) {
+    positions.retain(|(name, _)| name != display);
+    positions.push((display.to_string(), position));
+}
+
+fn saved_positions_text(positions: &[(String, (i32, i32))]) -> String {
+    positions
+        .iter()
+        .map(|(display, (x, y))| format!("{display} {x}x{y}\n"))
+        .collect()
+}
+
+fn write_saved_state(
+    name: &str,
+    sections: &[DisplaySection],
+    backend: Backend,
+    extra_args: &[String],
+) -> Result<(), CliError> {
+    let state = StateDir::lock()?;
+    if state.read(name)?.is_some() {
+        return Ok(());
+    }
+    let mut saved = String::new();
+    if let Some((display, level)) = internal_backlight(sections) {
+        saved.push_str(&format!("backlight {display} {}\n", level.percent()));
+    }
+    for arg in restore_args(sections, backend).iter().chain(extra_args) {
+        saved.push_str(&format!("arg {arg}\n"));
+    }
+    state.write(name, &saved)
+}
+
+fn restore_saved_state(
+    name: &str,
+    sections: &[DisplaySection],
+    backend: Backend,
+) -> Result<(), CliError> {
+    let saved = read_state(name)?.ok_or_else(|| format!("{} is not on", name.replace('_', " ")))?;
+
+    let mut args = Vec::new();
+    let mut backlight = None;
+    for line in saved.lines() {
+        match line.split_once(' ') {
+            Some(("arg", value)) => args.push(value.to_string()),
+            Some(("backlight", value)) => backlight = value.split_once(' '),
+            _ => return Err(format!("invalid saved state for {name}").into()),
+        }
+    }
+    run_layout_change(sections, backend, args)?;
+    if let Some((display, percent)) = backlight {
+        if let Some(section) = find_section(sections, display) {
+            let level = find_backlight(section)?;
+            run_backlight_set(display, &level, percent, backend, false)?;
+        }
+    }
+    if !is_dry_run() {
+        StateDir::lock()?.remove(name)?;
+    }
+    Ok(())
+}
+
+fn toggle_steps(
+    sections: &[DisplaySection],
+    presets: &[String],
+) -> Result<Vec<(String, Vec<String>)>, CliError> {
+    if !presets.is_empty() {
+        return Ok(presets
+            .iter()
+            .map(|name| (name.clone(), vec!["preset".to_string(), name.clone()]))
+            .collect());
+    }
+    let connected = |internal: bool| {
+        sections
+            .iter()
+            .find(|section| {
+                section.state == DisplayState::Connected
+                    && is_internal_panel(&section.name) == internal
+            })
+            .map(|section| section.name.clone())
+    };
+    let (Some(internal), Some(external)) = (connected(true), connected(false)) else {
+        return Err("toggle needs a connected internal and external display"
+            .to_string()
+            .into());
+    };
+    let single = |name: &str| vec!["single_display_output".to_string(), name.to_string()];
+    Ok(vec![
+        ("internal".to_string(), single(&internal)),
+        ("external".to_string(), single(&external)),
+        (
+            "dual".to_string(),
+            vec!["dual_display_output".to_string(), internal, external],
+        ),
+    ])
+}
+
+fn internal_backlight(sections: &[DisplaySection]) -> Option<(String, BacklightLevel)> {
+    sections
+        .iter()
+        .filter(|section| {
+            section.state == DisplayState::Connected && is_internal_panel(&section.name)
+        })
+        .find_map(|section| Some((section.name.clone(), find_backlight(section).ok()?)))
+}
+
+fn run_battery_mode_on(
+    sections: &[DisplaySection],
+    backend: Backend,
+    brightness: u32,
+    disable: &[String],
+    flags: &ApplyFlags,
+) -> Result<Outcome, CliError> {
+    let before = current_output_states(sections);
+    let mut args = Vec::new();
+    let mut after = Vec::new();
+    for (section, state) in sections.iter().zip(&before) {
+        let mut rate = state.rate.clone();
+        let mut geometry = state.geometry.clone();
+        if disable.contains(&section.name) {
+            args.extend(build_off_args(std::slice::from_ref(&section.name)));
+            geometry = None;
+        } else if geometry.is_some() && !is_internal_panel(&section.name) {
+            let current = parse_modes(section).into_iter().find(|mode| mode.current);
+            if let Some(mode) = current {
+                let target = select_mode_rate(
+                    section,
+                    mode.width,
+                    mode.height,
+                    Some(&RateChoice::Fixed(60.0)),
+                )
+                .unwrap_or(mode.refresh);
+                if format!("{target:.2}") != format!("{:.2}", mode.refresh) {
+                    args.push("--output".to_string());
+                    args.push(section.name.clone());
+                    args.push("--mode".to_string());
+                    match backend {
+                        Backend::Xrandr => {
+                            args.push(format!("{}x{}", mode.width, mode.height));
+                            args.push("--rate".to_string());
+                            args.push(format!("{target:.2}"));
+                        }
+                        Backend::Wlr => {
+                            args.push(format!("{}x{}@{target:.3}", mode.width, mode.height));
+                        }
+                    }
+                    rate = Some(format!("{target:.2}"));
+                }
+            }
+        }
+        after.push(OutputState {
+            name: state.name.clone(),
+            primary: state.primary && geometry.is_some(),
+            rate: rate.filter(|_| geometry.is_some()),
+            rotation: state.rotation.clone().filter(|_| geometry.is_some()),
+            scale: state.scale.clone().filter(|_| geometry.is_some()),
+            geometry,
+        });
+    }
+
+    // Only remember the previous state once the change has actually been applied,
+    // so a failed or reverted apply does not leave battery mode looking "on".
+    let outcome = finish_apply(sections, &before, &after, args, backend, flags)?;
+    if !flags.check && !is_dry_run() {
+        write_saved_state("battery_mode", sections, backend, &[])?;
+    }
+    if !flags.check {
+        if let Some((name, level)) = internal_backlight(sections) {
+            run_backlight_set(&name, &level, &brightness.to_string(), backend, false)?;
+        }
+    }
+    Ok(outcome)
+}
+
+fn run_game_mode_on(
+    display: &str,
+    vrr: bool,
+    sections: &[DisplaySection],
+    backend: Backend,
+    flags: &ApplyFlags,
+) -> Result<Outcome, CliError> {
+    let section =
+        find_section(sections, display).ok_or_else(|| display_not_found(sections, display))?;
+    if section.state != DisplayState::Connected {
+        return Err(format!("display not connected: {display}").into());
+    }
+    if vrr && backend == Backend::Xrandr {
+        return Err("--vrr is not supported by the xrandr backend"
+            .to_string()
+            .into());
+    }
+    let (width, height) = parse_modes(section)
+        .into_iter()
+        .find(|mode| mode.current)
+        .map(|mode| (mode.width, mode.height))
+        .or_else(|| match backend {
+            Backend::Xrandr => preferred_mode_size(section),
+            Backend::Wlr => wlr_preferred_size(section),
+        })
+        .ok_or_else(|| format!("preferred mode not available for display: {display}"))?;
+    let rate = select_mode_rate(section, width, height, Some(&RateChoice::Max))
+        .ok_or_else(|| format!("no refresh rates available for display: {display}"))?;
+
+    let mut args = vec!["--output".to_string(), display.to_string()];
+    let mut saved_args = Vec::new();
+    match backend {
+        Backend::Xrandr => {
+            args.extend([
+                "--mode".to_string(),
+                format!("{width}x{height}"),
+                "--rate".to_string(),
+                format!("{rate:.2}"),
+                "--pos".to_string(),
+                "0x0".to_string(),
+                "--primary".to_string(),
+                "--brightness".to_string(),
+                "1".to_string(),
+                "--gamma".to_string(),
+                "1:1:1".to_string(),
+            ]);
+            saved_args.extend(["--output".to_string(), display.to_string()]);
+            saved_args.extend(xrandr_color_args(section));
+        }
+        Backend::Wlr => {
+            args.extend([
+                "--on".to_string(),
+                "--mode".to_string(),
+                format!("{width}x{height}@{rate:.3}"),
+                "--pos".to_string(),
+                "0,0".to_string(),
+            ]);
+            if vrr {
+                args.extend(["--adaptive-sync".to_string(), "enabled".to_string()]);
+                saved_args.extend([
+                    "--output".to_string(),
+                    display.to_string(),
+                    "--adaptive-sync".to_string(),
+                    "disabled".to_string(),
+                ]);
+            }
+        }
+    }
+    let others: Vec<String> = sections
+        .iter()
+        .filter(|other| other.name != display)
+        .map(|other| other.name.clone())
+        .collect();
+    args.extend(build_off_args(&others));
+
+    let before = current_output_states(sections);
+    let after: Vec<OutputState> = before
+        .iter()
+        .map(|state| {
+            let selected = state.name == display;
+            OutputState {
+                name: state.name.clone(),
+                geometry: selected.then(|| format!("{width}x{height}+0+0")),
+                rate: selected.then(|| format!("{rate:.2}")),
+                primary: selected && backend == Backend::Xrandr,
+                rotation: state.rotation.clone().filter(|_| selected),
+                scale: state.scale.clone().filter(|_| selected),
+            }
+        })
+        .collect();
+
+    let outcome = finish_apply(sections, &before, &after, args, backend, flags)?;
+    if !flags.check && !is_dry_run() {
+        write_saved_state("game_mode", sections, backend, &saved_args)?;
+    }
+    Ok(outcome)
+}
+
+fn systemd_quote(arg: &str) -> String {
+    let plain = !arg.is_empty()
+        && arg
+            .chars()
+            .all(|ch| ch.is_ascii_alphanumeric() || "-_./:,=+@".contains(ch));
+    if plain {
+        return arg.to_string();
+    }
+    let escaped = arg
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('%', "%%")
+        .replace('$', "$$");
+    format!("\"{escaped}\"")
+}
+
+fn run_on_change(
+    sections: &[DisplaySection],
+    backend: Backend,
+    interval: Duration,
+    timeout: Option<Duration>,
+    command: &[String],
+) -> Result<Outcome, CliError> {
+    let started = Instant::now();
+    let previous = watch_snapshot(sections, &[]);
+    loop {
+        if timeout.is_some_and(|limit| started.elapsed() >= limit) {
+            return Err("timed out waiting for output change".to_string().into());
+        }
+        thread::sleep(interval);
+        let text = match query_backend_text(backend) {
+            Ok(text) => text,
+            Err(err) => {
+                eprintln!("on_change: {err}");
+                continue;
+            }
+        };
+        let current = watch_snapshot(&parse_backend_sections(backend, &text), &[]);
+        if !watch_events(&previous, &current, unix_timestamp()).is_empty() {
+            break;
+        }
+    }
+
+    let status = Command::new(&command[0])
+        .args(&command[1..])
+        .status()
+        .map_err(|err| format!("failed to run {}: {err}", command[0]))?;
+    if status.success() {
+        Ok(Outcome::Done)
+    } else {
+        Ok(Outcome::Exited(status.code().unwrap_or(1)))
+    }
+}
+
+fn run_watch_hook(hook: &str, events: &[WatchEvent]) {
+    let payload = events
+        .iter()
+        .map(WatchEvent::json)
+        .collect::<Vec<_>>()
+        .join("\n");
+    let status = Command::new("sh")
+        .arg("-c")
+        .arg(hook)
+        .env("XRANDR_UTILS_EVENTS", payload)
+        .status();
+    match status {
+        Ok(status) if !status.success() => eprintln!("watch: hook exited with {status}"),
+        Ok(_) => {}
+        Err(err) => eprintln!("watch: failed to run hook: {err}"),
+    }
+}
+
+fn filtered_display_names(sections: &[DisplaySection], exclude: &HashSet<String>) -> Vec<String> {
+    sections
+        .iter()
+        .map(|section| section.name.as_str())
+        .filter(|name| !exclude.contains(*name))
+        .map(|name| name.to_string())
+        .collect()
+}
+
+fn build_off_args(displays: &[String]) -> Vec<String> {
+    let mut args = Vec::new();
+    for display in displays {
+        args.push("--output".to_string());
+        args.push(display.clone());
+        args.push("--off".to_string());
+    }
+    args
+}
+
+fn is_dry_run() -> bool {
+    DRY_RUN.load(Ordering::Relaxed)
+}
+
+fn print_dry_run(program: &str, args: &[String]) {
+    let mut words = vec![shell_quote(program)];
+    words.extend(args.iter().map(|arg| shell_quote(arg)));
+    println!("{}", words.join(" "));
+}
+
+fn shell_quote(arg: &str) -> String {
+    let plain = !arg.is_empty()
+        && arg
+            .chars()
+            .all(|ch| ch.is_ascii_alphanumeric() || "-_./:,=+@%".contains(ch));
+    if plain {
+        arg.to_string()
+    } else {
+        format!("'{}'", arg.replace('\'', "'\\''"))
+    }
+}
+
+fn run_program_with_args(program: &str, args: Vec<String>) -> Result<(), CliError> {
+    if is_dry_run() {
+        print_dry_run(program, &args);
+        return Ok(());
+    }
+    let status = Command::new(program)
+        .args(&args)
+        .status()
+        .map_err(|err| CliError::Backend(format!("failed to run {program}: {err}")))?;
+
+    if !status.success() {
+        return Err(CliError::Backend(format!(
+            "{program} command failed: {status}"
+        )));
+    }
+
+    Ok(())
+}
+
+fn run_xrandr_with_args(args: Vec<String>) -> Result<(), CliError> {
+    run_program_with_args("xrandr", args)
+}
+
+fn run_backend_with_args(backend: Backend, args: Vec<String>) -> Result<(), CliError> {
+    match backend {
+        Backend::Xrandr => run_xrandr_with_args(args),
+        Backend::Wlr => run_program_with_args("wlr-randr", args),
+    }
+}
+
+/// Runs a user-requested layout change, saving the layout it replaces (from the
+/// already-parsed `sections`) as the undo state and appending the result to history.
+fn run_layout_change(
+    sections: &[DisplaySection],
+    backend: Backend,
+    args: Vec<String>,
+) -> Result<(), CliError> {
+    check_remote_session(false)?;
+    apply_layout_change(sections, backend, args)
+}
+
+/// `run_layout_change` for callers that have already done the remote-session check.
+fn apply_layout_change(
+    sections: &[DisplaySection],
+    backend: Backend,
+    args: Vec<String>,
+) -> Result<(), CliError> {
+    if is_dry_run() {
+        return run_backend_with_args(backend, args);
+    }
+    let saved = layout_snapshot_text(
+        backend,
+        &current_output_states(sections),
+        &restore_args(sections, backend),
+    );
+    run_backend_with_args(backend, args)?;
+    if let Err(err) = write_state("undo", &saved) {
+        eprintln!("warning: failed to save undo state: {err}");
+    }
+    if let Err(err) = record_history(backend) {
+        eprintln!("warning: failed to record history: {err}");
+    }
+    Ok(())
+}
+
+fn layout_snapshot_text(backend: Backend, states: &[OutputState], args: &[String]) -> String {
+    let states: Vec<String> = states.iter().map(snapshot_state_fields).collect();
+    snapshot_text(backend.name(), &states, args)
+}
+
+fn snapshot_text(backend: &str, states: &[String], args: &[String]) -> String {
+    let mut saved = format!("backend {backend}\n");
+    for state in states {
+        saved.push_str(&format!("state {state}\n"));
+    }
+    for arg in args {
+        saved.push_str(&format!("arg {arg}\n"));
+    }
+    saved
+}
+
+/// One output's `state` line in a snapshot, without the keyword.
+fn snapshot_state_fields(state: &OutputState) -> String {
+    format!(
+        "{} {} {} {} {} {}",
+        state.name,
+        state.geometry.as_deref().unwrap_or("-"),
+        state.rate.as_deref().unwrap_or("-"),
+        u8::from(state.primary),
+        state.rotation.as_deref().unwrap_or("-"),
+        state.scale.as_deref().unwrap_or("-")
+    )
+}
+
+fn apply_layout_snapshot(
+    saved: &str,
+    origin: &str,
+    sections: &[DisplaySection],
+    backend: Backend,
+    flags: &ApplyFlags,
+) -> Result<Outcome, CliError> {
+    let invalid = || format!("invalid saved state in {origin}");
+    let mut args = Vec::new();
+    let mut saved_states = Vec::new();
+    for line in saved.lines() {
+        match line.split_once(' ') {
+            Some(("backend", name)) if name == backend.name() => {}
+            Some(("backend", name)) => {
+                return Err(format!("{origin} was recorded with the {name} backend").into())
+            }
+            Some(("state", value)) => {
+                let fields: Vec<&str> = value.split(' ').collect();
+                // Snapshots written before rotation and scale were tracked have four fields.
+                let (name, geometry, rate, primary, rotation, scale) = match fields[..] {
+                    [name, geometry, rate, primary, rotation, scale] => {
+                        (name, geometry, rate, primary, Some(rotation), Some(scale))
+                    }
+                    [name, geometry, rate, primary] => (name, geometry, rate, primary, None, None),
+                    _ => return Err(invalid().into()),
+                };
+                let optional = |value: &str| (value != "-").then(|| value.to_string());
+                let current = || {
+                    current_output_states(sections)
+                        .into_iter()
+                        .find(|state| state.name == name)
+                };
+                let geometry = optional(geometry);
+                saved_states.push(OutputState {
+                    name: name.to_string(),
+                    rate: optional(rate),
+                    primary: primary == "1",
+                    rotation: match rotation {
+                        Some(rotation) => optional(rotation),
+                        None => current()
+                            .and_then(|state| state.rotation)
+                            .filter(|_| geometry.is_some()),
+                    },
+                    scale: match scale {
+                        Some(scale) => optional(scale),
+                        None => current()
+                            .and_then(|state| state.scale)
+                            .filter(|_| geometry.is_some()),
+                    },
+                    geometry,
+                });
+            }
+            Some(("arg", value)) => args.push(value.to_string()),
+            _ => return Err(invalid().into()),
+        }
+    }
+    let before = current_output_states(sections);
+    let after: Vec<OutputState> = before
+        .iter()
+        .map(|state| {
+            saved_states
+                .iter()
+                .find(|saved| saved.name == state.name)
+                .cloned()
+                .unwrap_or_else(|| OutputState {
+                    name: state.name.clone(),
+                    geometry: None,
+                    rate: None,
+                    primary: false,
+                    rotation: None,
+                    scale: None,
+                })
+        })
+        .collect();
+    let changed = before != after
+        || (args != restore_args(sections, backend) && args != full_state_args(sections, backend));
+    finish_apply_change(sections, &before, &after, changed, args, backend, flags)
+}
+
+fn run_undo(
+    sections: &[DisplaySection],
+    backend: Backend,
+    flags: &ApplyFlags,
+) -> Result<Outcome, CliError> {
+    let saved = read_state("undo")?.ok_or_else(|| "nothing to undo".to_string())?;
+    apply_layout_snapshot(&saved, "undo state", sections, backend, flags)
+}
+
+fn xrandr_color_args(section: &DisplaySection) -> Vec<String> {
+    let mut args = Vec::new();
+    if let Some(brightness) = section_brightness(section) {
+        args.extend(["--brightness".to_string(), format!("{brightness:.2}")]);
+    }
+    if let Some(gamma) = section_gamma(section) {
+        args.extend(["--gamma".to_string(), gamma]);
+    }
+    args
+}
+
+fn full_state_args(sections: &[DisplaySection], backend: Backend) -> Vec<String> {
+    let mut args = restore_args(sections, backend);
+    for section in sections.iter().filter(|section| section.geometry.is_some()) {
+        let mut extra = Vec::new();
+        match backend {
+            Backend::Xrandr => {
+                extra.extend(xrandr_color_args(section));
+                if let Some(matrix) = section_transform(section) {
+                    extra.extend(["--transform".to_string(), matrix.join(",")]);
+                }
+            }
+            Backend::Wlr => {
+                if let Some(scale) = wlr_field(section, "Scale:") {
+                    extra.extend(["--scale".to_string(), scale.to_string()]);
+                }
+            }
+        }
+        if !extra.is_empty() {
+            args.extend(["--output".to_string(), section.name.clone()]);
+            args.extend(extra);
+        }
+    }
+    args
+}
+
+const REDACTED_CONFIG_KEYS: [&str; 5] = ["token", "secret", "password", "passwd", "credential"];
+const BUNDLE_HISTORY_ENTRIES: usize = 20;
+
+fn write_support_bundle(
+    path: &str,
+    sections: &[DisplaySection],
+    backend: Backend,
+    verbose: &str,
+) -> Result<(), CliError> {
+    let archive = env::current_dir()
+        .map_err(|err| format!("failed to resolve {path}: {err}"))?
+        .join(path);
+    let staging = create_bundle_staging_dir()?;
+    let root = staging.join("xrandr-utils-bundle");
+    let result = stage_support_bundle(&root, sections, backend, verbose).and_then(|()| {
+        let status = Command::new("tar")
+            .arg("-czf")
+            .arg(&archive)
+            .arg("-C")
+            .arg(&staging)
+            .arg("xrandr-utils-bundle")
+            .status()
+            .map_err(|err| format!("failed to run tar: {err}"))?;
+        if !status.success() {
+            return Err(format!("tar command failed: {status}").into());
+        }
+        Ok(())
+    });
+    let _ = std::fs::remove_dir_all(&staging);
+    result
+}
+
+/// Creates a fresh private (0700) directory to stage the bundle in. It lives under
+/// $XDG_RUNTIME_DIR or the state directory rather than a shared, guessable /tmp path,
+/// and creation fails instead of reusing a directory that already exists.
+fn create_bundle_staging_dir() -> Result<PathBuf, CliError> {
+    use std::os::unix::fs::DirBuilderExt;
+    let base = match env::var_os("XDG_RUNTIME_DIR").filter(|dir| !dir.is_empty()) {
+        Some(dir) => PathBuf::from(dir),
+        None => {
+            let dir = state_dir()?;
+            std::fs::create_dir_all(&dir)
+                .map_err(|err| format!("failed to create {}: {err}", dir.display()))?;
+            dir
+        }
+    };
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.subsec_nanos())
+        .unwrap_or_default();
+    let staging = base.join(format!(
+        "xrandr-utils-bundle-{}-{nanos:08x}",
+        std::process::id()
+    ));
+    std::fs::DirBuilder::new()
+        .mode(0o700)
+        .create(&staging)
+        .map_err(|err| format!("failed to create {}: {err}", staging.display()))?;
+    Ok(staging)
+}
+
+fn stage_support_bundle(
+    root: &Path,
+    sections: &[DisplaySection],
+    backend: Backend,
+    verbose: &str,
+) -> Result<(), CliError> {
+    std::fs::create_dir_all(root.join("edid"))
+        .map_err(|err| format!("failed to create {}: {err}", root.display()))?;
+    let or_error =
+        |result: Result<String, CliError>| result.unwrap_or_else(|err| format!("{err}\n"));
+    let mut files = vec![
+        (
+            "version.txt".to_string(),
+            format!(
+                "xrandr-utils {}\nbackend {}\n",
+                env!("CARGO_PKG_VERSION"),
+                backend.name()
+            )
+            .into_bytes(),
+        ),
+        ("verbose.txt".to_string(), verbose.as_bytes().to_vec()),
+        (
+            "config.toml".to_string(),
+            or_error(read_optional_user_config().map(|config| {
+                redact_config(&config.unwrap_or_else(|| "# no config file\n".to_string()))
+            }))
+            .into_bytes(),
+        ),
+        (
+            "history.txt".to_string(),
+            or_error(read_state("history").map(|history| {
+                let history = history.unwrap_or_default();
+                let lines: Vec<&str> = history.lines().collect();
+                let recent = &lines[lines.len().saturating_sub(BUNDLE_HISTORY_ENTRIES)..];
+                recent.iter().map(|line| format!("{line}\n")).collect()
+            }))
+            .into_bytes(),
+        ),
+    ];
+    if backend == Backend::Xrandr {
+        files.push((
+            "listmonitors.txt".to_string(),
+            or_error(resolve_monitors_text(None)).into_bytes(),
+        ));
+        files.push((
+            "providers.txt".to_string(),
+            or_error(query_providers().map(|providers| {
+                providers
+                    .iter()
+                    .map(|provider| format!("{}\n", provider.line()))
+                    .collect()
+            }))
+            .into_bytes(),
+        ));
+    }
+    for section in sections {
+        if let Some(bytes) = extract_edid_hex(section).and_then(|hex| hex_to_bytes(&hex).ok()) {
+            files.push((format!("edid/{}.bin", section.name), bytes));
+        }
+    }
+    for (name, contents) in files {
+        let path = root.join(name);
+        std::fs::write(&path, contents)
+            .map_err(|err| format!("failed to write {}: {err}", path.display()))?;
+    }
+    Ok(())
+}
+
+fn redact_config(text: &str) -> String {
+    let is_secret = |key: &str| {
+        let key = key.trim().to_ascii_lowercase();
+        REDACTED_CONFIG_KEYS.iter().any(|word| key.contains(word))
+    };
+    text.lines()
+        .map(|line| match line.split_once('=') {
+            Some((key, _)) if is_secret(key) => format!("{key}= \"<redacted>\"\n"),
+            _ => format!("{line}\n"),
+        })
+        .collect()
+}
+
+/// Output properties that describe user-facing configuration and are safe to
+/// replay; everything else (link state, connector ids, driver internals) is left out.
+const EXPORT_PROPERTIES: [&str; 5] = [
+    "Broadcast RGB",
+    "max bpc",
+    "scaling mode",
+    "Colorspace",
+    "TearFree",
+];
+
+fn export_script(sections: &[DisplaySection], backend: Backend, full: bool) -> String {
+    if !full {
+        return shell_script(backend, restore_args(sections, backend));
+    }
+    let mut args = full_state_args(sections, backend);
+    if backend == Backend::Xrandr {
+        for section in sections.iter().filter(|section| section.geometry.is_some()) {
+            let settable: Vec<OutputProperty> = section_properties(section)
+                .into_iter()
+                .filter(|property| EXPORT_PROPERTIES.contains(&property.name.as_str()))
+                .collect();
+            if settable.is_empty() {
+                continue;
+            }
+            args.extend(["--output".to_string(), section.name.clone()]);
+            for property in settable {
+                args.extend(["--set".to_string(), property.name, property.value]);
+            }
+        }
+    }
+    shell_script(backend, args)
+}
+
+fn shell_script(backend: Backend, args: Vec<String>) -> String {
+    let program = match backend {
+        Backend::Xrandr => "xrandr",
+        Backend::Wlr => "wlr-randr",
+    };
+    let mut script = format!("#!/bin/sh\n# Generated by xrandr-utils\n{program}");
+    for arg in args {
+        if arg == "--output" {
+            script.push_str(" \\\n ");
+        }
+        script.push(' ');
+        script.push_str(&shell_quote(&arg));
+    }
+    script.push('\n');
+    script
+}
+
+fn run_state(
+    action: &str,
+    args: &mut Peekable<impl Iterator<Item = String>>,
+    sections: &[DisplaySection],
+    backend: Backend,
+) -> Result<Outcome, CliError> {
+    let path = expect_arg(args, "state file")?;
+    match action {
+        "save" => {
+            if let Some(other) = args.next() {
+                return Err(CliError::Usage(format!("unknown option: {other}")));
+            }
+            let saved = layout_snapshot_text(
+                backend,
+                &current_output_states(sections),
+                &full_state_args(sections, backend),
+            );
+            std::fs::write(&path, saved).map_err(|err| format!("failed to write {path}: {err}"))?;
+            Ok(Outcome::Done)
+        }
+        "restore" => {
+            let flags = parse_apply_flags(args)?;
+            let saved = std::fs::read_to_string(&path)
+                .map_err(|err| format!("failed to read {path}: {err}"))?;
+            let result = apply_layout_snapshot(&saved, &path, sections, backend, &flags);
+            guard_json_failure(result, &flags)
+        }
+        _ => Err(CliError::Usage(format!(
+            "unknown state action: {action} (expected save or restore)"
+        ))),
+    }
+}
+
+const HISTORY_LIMIT: usize = 100;
+
+struct HistoryEntry {
+    timestamp: u64,
+    backend: String,
+    summary: String,
+    args: Vec<String>,
+    /// Snapshot `state` fields for each output; empty for entries recorded before
+    /// history kept them.
+    states: Vec<String>,
+}
+
+fn parse_history(saved: &str) -> Result<Vec<HistoryEntry>, CliError> {
+    saved
+        .lines()
+        .map(|line| {
+            let invalid = || CliError::from(format!("invalid history entry: {line}"));
+            let mut fields = line.splitn(5, '\t');
+            let timestamp = fields.next().and_then(|value| value.parse().ok());
+            match (timestamp, fields.next(), fields.next(), fields.next()) {
+                (Some(timestamp), Some(backend), Some(summary), Some(args)) => Ok(HistoryEntry {
+                    timestamp,
+                    backend: backend.to_string(),
+                    summary: summary.to_string(),
+                    args: split_command_words(args).map_err(|_| invalid())?,
+                    states: fields
+                        .next()
+                        .map(|states| states.split(';').map(str::to_string).collect())
+                        .unwrap_or_default(),
+                }),
+                _ => Err(invalid()),
+            }
+        })
+        .collect()
+}
+
+fn record_history(backend: Backend) -> Result<(), CliError> {
+    let sections = parse_backend_sections(backend, &query_backend_text(backend)?);
+    let args = restore_args(&sections, backend);
+    let state = StateDir::lock()?;
+    let mut history = parse_history(&state.read("history")?.unwrap_or_default())?;
+    if history.last().is_some_and(|last| last.args == args) {
+        return Ok(());
+    }
+    let active: Vec<String> = sections
+        .iter()
+        .filter_map(|section| Some(format!("{} {}", section.name, section.geometry.as_ref()?)))
+        .collect();
+    history.push(HistoryEntry {
+        timestamp: unix_timestamp(),
+        backend: backend.name().to_string(),
+        summary: if active.is_empty() {
+            "all off".to_string()
+        } else {
+            active.join(", ")
+        },
+        args,
+        states: current_output_states(&sections)
+            .iter()
+            .map(snapshot_state_fields)
+            .collect(),
+    });
+    let skip = history.len().saturating_sub(HISTORY_LIMIT);
+    state.write("history", &history_text(&history[skip..]))
+}
+
+fn history_text(history: &[HistoryEntry]) -> String {
+    history
+        .iter()
+        .map(|entry| {
+            let args: Vec<String> = entry.args.iter().map(|arg| shell_quote(arg)).collect();
+            let mut line = format!(
+                "{}\t{}\t{}\t{}",
+                entry.timestamp,
+                entry.backend,
+                entry.summary,
+                args.join(" ")
+            );
+            if !entry.states.is_empty() {
+                line.push('\t');
+                line.push_str(&entry.states.join(";"));
+            }
+            line + "\n"
+        })
+        .collect()
+}
+
+fn format_timestamp(timestamp: u64, utc_offset: i64) -> String {
+    let local = timestamp as i64 + utc_offset;
+    let (days, seconds) = (local.div_euclid(86400), local.rem_euclid(86400));
+    let era = (days + 719468).div_euclid(146097);
+    let day_of_era = (days + 719468).rem_euclid(146097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    format!(
+        "{year:04}-{month:02}-{day:02} {:02}:{:02}",
+        seconds / 3600,
+        seconds % 3600 / 60
+    )
+}
+
+fn run_history(
+    action: &str,
+    args: &mut impl Iterator<Item = String>,
+    sections: &[DisplaySection],
+    backend: Backend,
+) -> Result<Outcome, CliError> {
+    let history = parse_history(&read_state("history")?.unwrap_or_default())?;
+    match action {
+        "list" => {
+            let utc_offset = local_utc_offset();
+            for (index, entry) in history.iter().enumerate() {
+                println!(
+                    "{}  {}  {}",
+                    index + 1,
+                    format_timestamp(entry.timestamp, utc_offset),
+                    entry.summary
+                );
+            }
+            Ok(Outcome::Done)
+        }
+        "apply" => {
+            let value = expect_arg(args, "history entry")?;
+            let entry = value
+                .parse::<usize>()
+                .ok()
+                .and_then(|index| history.get(index.checked_sub(1)?))
+                .ok_or_else(|| format!("history entry not found: {value}"))?;
+            if entry.backend != backend.name() {
+                return Err(format!(
+                    "history entry {value} was recorded with the {} backend",
+                    entry.backend
+                )
+                .into());
+            }
+            let flags = parse_apply_flags(args)?;
+            let result = if entry.states.is_empty() {
+                // Older entries only kept the arguments, so there is no plan or diff.
+                let before = current_output_states(sections);
+                let changed = entry.args != restore_args(sections, backend);
+                let args = entry.args.clone();
+                finish_apply_change(sections, &before, &before, changed, args, backend, &flags)
+            } else {
+                let saved = snapshot_text(&entry.backend, &entry.states, &entry.args);
+                let origin = format!("history entry {value}");
+                apply_layout_snapshot(&saved, &origin, sections, backend, &flags)
+            };
+            guard_json_failure(result, &flags)
+        }
+        _ => Err(CliError::Usage(format!(
+            "unknown history action: {action} (expected list or apply)"
+        ))),
+    }
+}
+
+fn run_ddcutil_with_args(args: Vec<String>) -> Result<(), CliError> {
+    if is_dry_run() {
+        print_dry_run("ddcutil", &args);
+        return Ok(());
+    }
+    let output = Command::new("ddcutil")
+        .args(&args)
+        .stdout(Stdio::inherit())
+        .stderr(Stdio::piped())
+        .output()
+        .map_err(|err| format!("failed to run ddcutil: {err}"))?;
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    eprint!("{stderr}");
+
+    if !output.status.success() {
+        return Err(format!(
+            "ddcutil command failed: {}: {}",
+            output.status,
+            stderr.trim()
+        )
+        .into());
+    }
+
+    Ok(())
+}
+
+fn expect_arg(args: &mut impl Iterator<Item = String>, name: &str) -> Result<String, CliError> {
+    args.next()
+        .ok_or_else(|| CliError::Usage(format!("missing argument: {name}")))
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Backend {
+    Xrandr,
+    Wlr,
+}
+
+impl Backend {
+    fn name(self) -> &'static str {
+        match self {
+            Backend::Xrandr => "xrandr",
+            Backend::Wlr => "wlr",
+        }
+    }
+
+    fn detect() -> Result<Backend, CliError> {
+        if let Ok(value) = env::var("XRANDR_UTILS_BACKEND") {
+            return match value.as_str() {
+                "xrandr" => Ok(Backend::Xrandr),
+                "wlr" | "wlr-randr" => Ok(Backend::Wlr),
+                _ => Err(format!("unknown backend: {value}").into()),
+            };
+        }
+        if env::var_os("WAYLAND_DISPLAY").is_some() {
+            Ok(Backend::Wlr)
+        } else {
+            Ok(Backend::Xrandr)
+        }
+    }
+}
+
+fn get_verbose_text(backend: Backend) -> Result<String, CliError> {
+    let mut stdin = io::stdin();
+    match INPUT_PATH.get().map(String::as_str) {
+        Some("-") => {}
+        Some(path) => {
+            let text = std::fs::read_to_string(path)
+                .map_err(|err| format!("failed to read input {path}: {err}"))?;
+            if text.trim().is_empty() {
+                return Err(format!("input {path} is empty").into());
+            }
+            REPLAYED_INPUT.store(true, Ordering::Relaxed);
+            return Ok(text);
+        }
+        None if stdin.is_terminal() || stdin_is_null() => return query_backend_text(backend),
+        None => {}
+    }
+    let mut buf = String::new();
+    stdin
+        .read_to_string(&mut buf)
+        .map_err(|err| format!("failed to read stdin: {err}"))?;
+    if buf.trim().is_empty() {
+        return Err("stdin supplied but empty".to_string().into());
+    }
+    REPLAYED_INPUT.store(true, Ordering::Relaxed);
+    Ok(buf)
+}
+
+fn stdin_is_null() -> bool {
+    match (
+        std::fs::metadata("/proc/self/fd/0"),
+        std::fs::metadata("/dev/null"),
+    ) {
+        (Ok(stdin), Ok(null)) => stdin.dev() == null.dev() && stdin.ino() == null.ino(),
+        _ => false,
+    }
+}
+
+fn query_backend_text(backend: Backend) -> Result<String, CliError> {
+    if backend == Backend::Wlr {
+        let output = Command::new("wlr-randr")
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .output()
+            .map_err(|err| CliError::Backend(format!("failed to run wlr-randr: {err}")))?;
+        if !output.status.success() {
+            return Err(CliError::Backend(
+                "wlr-randr exited with failure".to_string(),
+            ));
+        }
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+    } else {
+        let output = Command::new("xrandr")
+            .arg("--verbose")
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .output()
+            .map_err(|err| CliError::Backend(format!("failed to run xrandr --verbose: {err}")))?;
+        if !output.status.success() {
+            return Err(CliError::Backend(
+                "xrandr --verbose exited with failure".to_string(),
+            ));
+        }
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum DisplayState {
+    Connected,
+    Disconnected,
+    Unknown,
+}
+
+impl DisplayState {
+    fn as_str(self) -> &'static str {
+        match self {
+            DisplayState::Connected => "connected",
+            DisplayState::Disconnected => "disconnected",
+            DisplayState::Unknown => "unknown",
+        }
+    }
+}
+
+#[derive(Clone)]
+struct DisplaySection {
+    name: String,
+    state: DisplayState,
+    primary: bool,
+    geometry: Option<String>,
+    rotation: Option<String>,
+    lines: Vec<String>,
+}
+
+fn parse_sections(verbose: &str) -> Vec<DisplaySection> {
+    let mut sections = Vec::new();
+    let mut current: Option<DisplaySection> = None;
+
+    for line in verbose.lines() {
+        if let Some(header) = parse_header(line) {
+            if let Some(section) = current.take() {
+                sections.push(section);
+            }
+            current = Some(DisplaySection {
+                name: header.name,
+                state: header.state,
+                primary: header.primary,
+                geometry: header.geometry,
+                rotation: header.rotation,
+                lines: vec![line.to_string()],
+            });
+        } else if let Some(section) = current.as_mut() {
+            section.lines.push(line.to_string());
+        }
+    }
+
+    if let Some(section) = current {
+        sections.push(section);
+    }
+
+    sections
+}
+
+fn classify_line(line: &str, backend: Backend, in_section: bool, in_edid: bool) -> &'static str {
+    let trimmed = line.trim();
+    if trimmed.is_empty() {
+        return "blank";
+    }
+    if backend == Backend::Wlr {
+        return if !line.starts_with(char::is_whitespace) {
+            "header"
+        } else if trimmed.contains(" px,") {
+            "mode"
+        } else if trimmed.contains(':') {
+            "field"
+        } else {
+            "unknown"
+        };
+    }
+
+    if trimmed.starts_with("Screen ") {
+        return "screen";
+    }
+    if parse_header(line).is_some() {
+        return "header";
+    }
+    if !in_section {
+        return "ignored";
+    }
+    if in_edid && trimmed.chars().all(|ch| ch.is_ascii_hexdigit()) {
+        return "edid-data";
+    }
+
+    let mut parts = trimmed.split_whitespace();
+    let first = parts.next().unwrap_or_default();
+    if matches!(first, "h:" | "v:") {
+        return "mode-timing";
+    }
+    if parts.next().is_some_and(|id| id.starts_with("(0x")) {
+        return "mode";
+    }
+    if parse_compact_modes(trimmed, &mut Vec::new()) {
+        return "mode";
+    }
+    if trimmed.starts_with("filter:") {
+        return "transform-filter";
+    }
+    if trimmed
+        .split_whitespace()
+        .all(|part| part.parse::<f64>().is_ok())
+    {
+        return "transform-row";
+    }
+    if line.starts_with("\t\t") || line.starts_with("        ") {
+        return "property-detail";
+    }
+    if trimmed.contains(':') {
+        return "property";
+    }
+    "unknown"
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum InputFormat {
+    Verbose,
+    Props,
+    Plain,
+    Wlr,
+}
+
+impl InputFormat {
+    fn detect(backend: Backend, text: &str) -> InputFormat {
+        if backend == Backend::Wlr {
+            return InputFormat::Wlr;
+        }
+        let verbose = text.lines().any(|line| {
+            let trimmed = line.trim();
+            trimmed.starts_with("Identifier:")
+                || trimmed
+                    .split_whitespace()
+                    .nth(1)
+                    .is_some_and(|token| token.starts_with("(0x"))
+        });
+        if verbose {
+            InputFormat::Verbose
+        } else if text.lines().any(|line| line.starts_with('\t')) {
+            InputFormat::Props
+        } else {
+            InputFormat::Plain
+        }
+    }
+
+    fn as_str(self) -> &'static str {
+        match self {
+            InputFormat::Verbose => "xrandr --verbose",
+            InputFormat::Props => "xrandr --props",
+            InputFormat::Plain => "xrandr",
+            InputFormat::Wlr => "wlr-randr",
+        }
+    }
+}
+
+fn require_verbose(backend: Backend, text: &str, command: &str) -> Result<(), CliError> {
+    let format = InputFormat::detect(backend, text);
+    if format != InputFormat::Verbose {
+        return Err(format!(
+            "{command} requires xrandr --verbose input (got {})",
+            format.as_str()
+        )
+        .into());
+    }
+    Ok(())
+}
+
+fn require_properties(backend: Backend, text: &str, command: &str) -> Result<(), CliError> {
+    let format = InputFormat::detect(backend, text);
+    if format == InputFormat::Plain {
+        return Err(format!(
+            "{command} requires xrandr --verbose or --props input (got {})",
+            format.as_str()
+        )
+        .into());
+    }
+    Ok(())
+}
+
+fn print_parse_debug(backend: Backend, text: &str) {
+    eprintln!(
+        "input format: {}",
+        InputFormat::detect(backend, text).as_str()
+    );
+    let mut in_section = false;
+    let mut in_edid = false;
+    for (index, line) in text.lines().enumerate() {
+        let kind = classify_line(line, backend, in_section, in_edid);
+        match kind {
+            "header" => in_section = true,
+            "property" => {
+                let trimmed = line.trim();
+                in_edid = trimmed.starts_with("EDID:") || trimmed.starts_with("EDID_DATA:");
+            }
+            "edid-data" => {}
+            _ => in_edid = false,
+        }
+        eprintln!("{:>5} {kind:<17} {line}", index + 1);
+    }
+}
+
+fn parse_backend_sections(backend: Backend, text: &str) -> Vec<DisplaySection> {
+    let mut sections = match backend {
+        Backend::Xrandr => parse_sections(text),
+        Backend::Wlr => parse_wlr_sections(text),
+    };
+    if !REPLAYED_INPUT.load(Ordering::Relaxed) {
+        for section in &mut sections {
+            let missing = match backend {
+                Backend::Xrandr => {
+                    section.state == DisplayState::Connected && extract_edid_hex(section).is_none()
+                }
+                Backend::Wlr => true,
+            };
+            if !missing {
+                continue;
+            }
+            let connector = extract_connector_id(section);
+            if let Some(edid) = read_sysfs_edid(&section.name, connector.as_deref()) {
+                push_edid_lines(section, &edid);
+            }
+        }
+    }
+    if let Some(ignored) = IGNORED_DISPLAYS.get() {
+        sections.retain(|section| {
+            !ignored
+                .iter()
+                .any(|pattern| pattern.is_match(&section.name))
+        });
+    }
+    if CURRENT_SEAT_ONLY.load(Ordering::Relaxed) {
+        // Fail closed: without seat data, or for an output whose seat is unknown,
+        // nothing is considered to be on the current seat.
+        let current = current_seat().ok();
+        sections.retain(|section| {
+            current.is_some_and(|current| {
+                section_seat(section).ok().flatten().as_deref() == Some(current)
+            })
+        });
+    }
+    sections
+}
+
+/// Makes `--current-seat` an error when the seat data it filters on is unavailable,
+/// instead of silently keeping outputs from other seats.
+fn check_current_seat() -> Result<(), CliError> {
+    if !CURRENT_SEAT_ONLY.load(Ordering::Relaxed) {
+        return Ok(());
+    }
+    if REPLAYED_INPUT.load(Ordering::Relaxed) {
+        return Err(CliError::Usage(
+            "--current-seat needs live display data, not replayed input".to_string(),
+        ));
+    }
+    current_seat()?;
+    seat_cards()?;
+    Ok(())
+}
+
+fn loginctl_output(args: &[&str]) -> Result<String, CliError> {
+    let output = Command::new("loginctl")
+        .args(args)
+        .stderr(Stdio::null())
+        .output()
+        .map_err(|err| format!("failed to run loginctl: {err}"))?;
+    if !output.status.success() {
+        return Err(format!("loginctl {} exited with failure", args.join(" ")).into());
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+fn seat_cards() -> Result<&'static HashMap<String, String>, CliError> {
+    SEAT_CARDS
+        .get_or_init(|| {
+            let mut cards = HashMap::new();
+            for line in loginctl_output(&["list-seats", "--no-legend"])?.lines() {
+                let Some(seat) = line.split_whitespace().next() else {
+                    continue;
+                };
+                let status = loginctl_output(&["seat-status", "--full", "--no-pager", seat])?;
+                let mut remaining = status.as_str();
+                while let Some(index) = remaining.find("/drm/card") {
+                    remaining = &remaining[index + "/drm/".len()..];
+                    let digits = remaining["card".len()..]
+                        .chars()
+                        .take_while(char::is_ascii_digit)
+                        .count();
+                    if digits > 0 {
+                        cards
+                            .entry(remaining[.."card".len() + digits].to_string())
+                            .or_insert_with(|| seat.to_string());
+                    }
+                }
+            }
+            Ok(cards)
+        })
+        .as_ref()
+        .map_err(Clone::clone)
+}
+
+fn current_seat() -> Result<&'static str, CliError> {
+    CURRENT_SEAT
+        .get_or_init(|| {
+            if let Ok(seat) = env::var("XDG_SEAT") {
+                return Ok(seat);
+            }
+            let session = env::var("XDG_SESSION_ID").unwrap_or_else(|_| "self".to_string());
+            let seat = loginctl_output(&["show-session", &session, "-p", "Seat", "--value"])?;
+            match seat.trim() {
+                "" => Err(format!("session {session} is not attached to a seat").into()),
+                seat => Ok(seat.to_string()),
+            }
+        })
+        .as_ref()
+        .map(String::as_str)
+        .map_err(Clone::clone)
+}
+
+fn section_seat(section: &DisplaySection) -> Result<Option<String>, CliError> {
+    if REPLAYED_INPUT.load(Ordering::Relaxed) {
+        return Err("seat data is not available for replayed input"
+            .to_string()
+            .into());
+    }
+    let cards = seat_cards()?;
+    Ok(section_drm_connector(section).and_then(|drm| {
+        let card = drm.name.split('-').next()?;
+        cards.get(card).cloned()
+    }))
+}
+
+fn parse_wlr_sections(text: &str) -> Vec<DisplaySection> {
+    let mut sections = Vec::new();
+    let mut current: Option<DisplaySection> = None;
+
+    for line in text.lines() {
+        let is_header = !line.trim().is_empty() && !line.starts_with(char::is_whitespace);
+        if is_header {
+            if let Some(section) = current.take() {
+                sections.push(section);
+            }
+            let name = line.split_whitespace().next().unwrap_or_default();
+            current = Some(DisplaySection {
+                name: name.to_string(),
+                state: DisplayState::Connected,
+                primary: false,
+                geometry: None,
+                rotation: None,
+                lines: vec![line.to_string()],
+            });
+        } else if let Some(section) = current.as_mut() {
+            section.lines.push(line.to_string());
+        }
+    }
+
+    if let Some(section) = current {
+        sections.push(section);
+    }
+
+    for section in &mut sections {
+        section.geometry = wlr_geometry(section);
+        if section.geometry.is_some() {
+            section.rotation = wlr_field(section, "Transform:")
+                .and_then(wlr_transform_rotation)
+                .map(|rotation| rotation.to_string());
+        }
+    }
+
+    sections
+}
+
+fn push_edid_lines(section: &mut DisplaySection, edid: &str) {
+    section.lines.push("\tEDID:".to_string());
+    for chunk in edid.as_bytes().chunks(32) {
+        section
+            .lines
+            .push(format!("\t\t{}", String::from_utf8_lossy(chunk)));
+    }
+}
+
+fn wlr_transform_rotation(transform: &str) -> Option<&'static str> {
+    match transform.strip_prefix("flipped-").unwrap_or(transform) {
+        "normal" | "flipped" => Some("normal"),
+        "90" => Some("left"),
+        "180" => Some("inverted"),
+        "270" => Some("right"),
+        _ => None,
+    }
+}
+
+fn rotation_wlr_transform(rotation: &str) -> &'static str {
+    match rotation {
+        "left" => "90",
+        "inverted" => "180",
+        "right" => "270",
+        _ => "normal",
+    }
+}
+
+fn wlr_field<'a>(section: &'a DisplaySection, label: &str) -> Option<&'a str> {
+    section
+        .lines
+        .iter()
+        .find_map(|line| line.trim().strip_prefix(label))
+        .map(|value| value.trim())
+}
+
+fn wlr_mode_size(section: &DisplaySection, marker: &str) -> Option<(u32, u32)> {
+    let line = section
+        .lines
+        .iter()
+        .map(|line| line.trim())
+        .find(|line| line.contains(" px,") && line.contains(marker))?;
+    let size = line.split_whitespace().next()?;
+    let (width, height) = size.split_once('x')?;
+    Some((width.parse().ok()?, height.parse().ok()?))
+}
+
+fn wlr_logical_size(section: &DisplaySection, width: u32, height: u32) -> (u32, u32) {
+    let rotated = matches!(
+        wlr_field(section, "Transform:"),
+        Some("90" | "270" | "flipped-90" | "flipped-270")
+    );
+    let (width, height) = if rotated {
+        (height, width)
+    } else {
+        (width, height)
+    };
+    let scale = wlr_field(section, "Scale:")
+        .and_then(|value| value.parse::<f64>().ok())
+        .filter(|value| *value > 0.0)
+        .unwrap_or(1.0);
+    (
+        (f64::from(width) / scale).round() as u32,
+        (f64::from(height) / scale).round() as u32,
+    )
+}
+
+fn wlr_geometry(section: &DisplaySection) -> Option<String> {
+    if wlr_field(section, "Enabled:") != Some("yes") {
+        return None;
+    }
+    let (width, height) = wlr_mode_size(section, "current")?;
+    let (width, height) = wlr_logical_size(section, width, height);
+    let (x, y) = wlr_field(section, "Position:")?.split_once(',')?;
+    let x: i32 = x.trim().parse().ok()?;
+    let y: i32 = y.trim().parse().ok()?;
+    Some(format!("{width}x{height}{x:+}{y:+}"))
+}
+
+fn wlr_preferred_size(section: &DisplaySection) -> Option<(u32, u32)> {
+    let (width, height) = wlr_mode_size(section, "preferred")?;
+    Some(wlr_logical_size(section, width, height))
+}
+
+fn read_sysfs_edid(name: &str, connector_id: Option<&str>) -> Option<String> {
+    let connector = find_drm_connector(name, connector_id)?;
+    let bytes = std::fs::read(connector.path.join("edid")).ok()?;
+    if bytes.is_empty() {
+        return None;
+    }
+    Some(bytes.iter().map(|byte| format!("{byte:02x}")).collect())
+}
+
+struct DrmConnector {
+    name: String,
+    connector: String,
+    path: PathBuf,
+    connector_id: Option<String>,
+}
+
+impl DrmConnector {
+    fn ddc_bus(&self) -> Option<String> {
+        let target = std::fs::read_link(self.path.join("ddc")).ok()?;
+        let name = target.file_name()?.to_string_lossy().into_owned();
+        name.strip_prefix("i2c-").map(str::to_string)
+    }
+}
+
+fn drm_connectors() -> Vec<DrmConnector> {
+    let entries = match std::fs::read_dir("/sys/class/drm") {
+        Ok(entries) => entries,
+        Err(_) => return Vec::new(),
+    };
+    let mut connectors: Vec<DrmConnector> = entries
+        .flatten()
+        .filter_map(|entry| {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            let connector = match name.split_once('-') {
+                Some((card, connector)) if card.starts_with("card") => connector.to_string(),
+                _ => return None,
+            };
+            let path = entry.path();
+            let connector_id = std::fs::read_to_string(path.join("connector_id"))
+                .ok()
+                .map(|id| id.trim().to_string());
+            Some(DrmConnector {
+                name,
+                connector,
+                path,
+                connector_id,
+            })
+        })
+        .collect();
+    connectors.sort_by(|a, b| a.name.cmp(&b.name));
+    connectors
+}
+
+/// Matches a display to its DRM connector by connector id, then by exact name, then by
+/// alias. Each step must identify a single card-and-connector pair; a name present on
+/// several cards is ambiguous and yields no match rather than the wrong card's data.
+fn find_drm_connector(name: &str, connector_id: Option<&str>) -> Option<DrmConnector> {
+    let mut connectors = drm_connectors();
+    let unique = |matches: &dyn Fn(&DrmConnector) -> bool| {
+        let mut found = (0..connectors.len()).filter(|&index| matches(&connectors[index]));
+        found.next().filter(|_| found.next().is_none())
+    };
+    let index = connector_id
+        .and_then(|id| unique(&|drm| drm.connector_id.as_deref() == Some(id)))
+        .or_else(|| unique(&|drm| drm.connector == name))
+        .or_else(|| unique(&|drm| connector_alias(&drm.connector) == connector_alias(name)))?;
+    Some(connectors.swap_remove(index))
+}
+
+fn section_drm_connector(section: &DisplaySection) -> Option<DrmConnector> {
+    find_drm_connector(&section.name, extract_connector_id(section).as_deref())
+}
+
+/// Normalises xrandr and kernel connector names to one spelling ("HDMI-A-1", "HDMI1"
+/// and "HDMI-1" all become "HDMI-1") while keeping every numeric segment, so "DP-1-1"
+/// and "DP-11" stay distinct.
+fn connector_alias(name: &str) -> String {
+    let name = name.replace("HDMI-A-", "HDMI-");
+    match name.find(|ch: char| ch.is_ascii_digit()) {
+        Some(index) => format!("{}-{}", name[..index].trim_end_matches('-'), &name[index..]),
+        None => name,
+    }
+}
+
+#[derive(Default)]
+struct MapFlags {
+    filtered: bool,
+    keys: bool,
+    values: bool,
+    count: bool,
+    strict_errors: bool,
+    transforms: Vec<ValueTransform>,
+}
+
+enum ValueTransform {
+    Lower,
+    Upper,
+    Trim,
+    Replace(String, String),
+}
+
+impl ValueTransform {
+    fn parse(spec: &str) -> Result<ValueTransform, CliError> {
+        if let Some(rest) = spec.strip_prefix("replace:") {
+            let (from, to) = rest.split_once(',').ok_or_else(|| {
+                format!("invalid replace transform: {spec} (expected replace:<from>,<to>)")
+            })?;
+            if from.is_empty() {
+                return Err(format!("replace transform needs a search string: {spec}").into());
+            }
+            return Ok(ValueTransform::Replace(from.to_string(), to.to_string()));
+        }
+        match spec {
+            "lower" => Ok(ValueTransform::Lower),
+            "upper" => Ok(ValueTransform::Upper),
+            "trim" => Ok(ValueTransform::Trim),
+            _ => Err(format!("unknown transform: {spec}").into()),
+        }
+    }
+
+    fn apply(&self, value: &str) -> String {
+        match self {
+            ValueTransform::Lower => value.to_lowercase(),
+            ValueTransform::Upper => value.to_uppercase(),
+            ValueTransform::Trim => value.trim().to_string(),
+            ValueTransform::Replace(from, to) => value.replace(from.as_str(), to),
+        }
+    }
+}
+
+#[derive(Default)]
+struct DisplayNamesFlags {
+    connected: bool,
+    disconnected: bool,
+    active: bool,
+    inactive: bool,
+    count: bool,
+}
+
+impl DisplayNamesFlags {
+    fn accepts(&self, section: &DisplaySection) -> bool {
+        let connected = section.state == DisplayState::Connected;
+        let active = section.geometry.is_some();
+        (!self.connected || connected)
+            && (!self.disconnected || section.state == DisplayState::Disconnected)
+            && (!self.active || active)
+            && (!self.inactive || (connected && !active))
+    }
+}
+
+fn parse_display_names_flags(
+    args: &mut impl Iterator<Item = String>,
+) -> Result<DisplayNamesFlags, CliError> {
+    let mut flags = DisplayNamesFlags::default();
+    for arg in args {
+        match arg.as_str() {
+            "--connected" => flags.connected = true,
+            "--disconnected" => flags.disconnected = true,
+            "--active" => flags.active = true,
+            "--inactive" => flags.inactive = true,
+            "--count" => flags.count = true,
+            _ => return Err(CliError::Usage(format!("unknown option: {arg}"))),
+        }
+    }
+    Ok(flags)
+}
+
+fn parse_monitor_serial_args(
+    args: &mut impl Iterator<Item = String>,
+) -> Result<(String, bool), CliError> {
+    let mut serial: Option<String> = None;
+    let mut connected_only = false;
+
+    for arg in args {
+        if arg == "--connected" {
+            connected_only = true;
+            continue;
+        }
+        if serial.is_none() {
+            serial = Some(arg);
+        } else {
+            return Err(format!("unexpected argument: {arg}").into());
+        }
+    }
+
+    let serial = serial.ok_or_else(|| CliError::Usage("missing argument: serial".to_string()))?;
+    Ok((serial, connected_only))
+}
+
+fn parse_map_flags<I>(
+    args: &mut std::iter::Peekable<I>,
+    _allow_transposed: bool,
+) -> Result<MapFlags, CliError>
+where
+    I: Iterator<Item = String>,
+{
+    let mut flags = MapFlags::default();
+    while let Some(arg) = args.peek() {
+        if !arg.starts_with("--") {
+            break;
+        }
+        let arg = args.next().expect("peeked value must exist");
+        match arg.as_str() {
+            "--filtered" => flags.filtered = true,
+            "--count" => flags.count = true,
+            "--strict-errors" => flags.strict_errors = true,
+            "--keys" => {
+                if flags.values {
+                    return Err("cannot combine --keys with --values".to_string().into());
+                }
+                flags.keys = true;
+            }
+            "--values" => {
+                if flags.keys {
+                    return Err("cannot combine --keys with --values".to_string().into());
+                }
+                flags.values = true;
+            }
+            "--transform" => {
+                let spec = args
+                    .next()
+                    .ok_or_else(|| CliError::Usage("missing value for --transform".to_string()))?;
+                flags.transforms.push(ValueTransform::parse(&spec)?);
+            }
+            _ => return Err(CliError::Usage(format!("unknown option: {arg}"))),
+        }
+    }
+    Ok(flags)
+}
+
+fn should_skip_map_value(value: &str, flags: &MapFlags) -> bool {
+    if !(flags.filtered || flags.values) {
+        return false;
+    }
+    value.trim().is_empty()
+}
+
+struct MapOutput {
+    flags: MapFlags,
+    seen_values: HashSet<String>,
+    count: usize,
+}
+
+impl MapOutput {
+    fn new(flags: MapFlags) -> MapOutput {
+        MapOutput {
+            flags,
+            seen_values: HashSet::new(),
+            count: 0,
+        }
+    }
+
+    fn entry(&mut self, name: &str, value: &str) {
+        let mut value = value.to_string();
+        for transform in &self.flags.transforms {
+            value = transform.apply(&value);
+        }
+        let value = value.as_str();
+
+        if should_skip_map_value(value, &self.flags) {
+            return;
+        }
+
+        if self.flags.values {
+            if self.seen_values.insert(value.to_string()) {
+                self.count += 1;
+                if !self.flags.count {
+                    println!("{value}");
+                }
+            }
+            return;
+        }
+
+        self.count += 1;
+        if self.flags.count {
+            return;
+        }
+        if self.flags.keys {
+            println!("{name}");
+        } else {
+            println!("{name}={value}");
+        }
+    }
+
+    fn try_entry<F>(&mut self, name: &str, compute: F) -> Result<(), CliError>
+    where
+        F: FnOnce() -> Result<Option<String>, CliError>,
+    {
+        match compute() {
+            Ok(value) => {
+                self.entry(name, value.as_deref().unwrap_or(""));
+                Ok(())
+            }
+            Err(err) if self.flags.strict_errors => Err(format!("{name}: {err}").into()),
+            Err(err) => {
+                eprintln!("warning: {name}: {err}");
+                self.entry(name, "");
+                Ok(())
+            }
+        }
+    }
+
+    fn finish(&self) {
+        if self.flags.count {
+            println!("{}", self.count);
+        }
+    }
+}
+
+struct HeaderInfo {
+    name: String,
+    state: DisplayState,
+    primary: bool,
+    geometry: Option<String>,
+    rotation: Option<String>,
+}
+
+fn parse_header(line: &str) -> Option<HeaderInfo> {
+    let mut parts = line.split_whitespace();
+    let name = parts.next()?;
+    let state_word = parts.next()?;
+
+    let state = match state_word {
+        "connected" => DisplayState::Connected,
+        "disconnected" => DisplayState::Disconnected,
+        "unknown" if parts.next() == Some("connection") => DisplayState::Unknown,
+        _ => return None,
+    };
+
+    let mut primary = false;
+    let mut geometry = None;
+    let mut rotation = None;
+
+    for token in parts {
+        if token.starts_with('(') && !token.starts_with("(0x") {
+            break;
+        }
+        if token == "primary" {
+            primary = true;
+        } else if geometry.is_none() && is_geometry_token(token) {
+            geometry = Some(token.to_string());
+        } else if rotation.is_none() && is_rotation(token) {
+            rotation = Some(token.to_string());
+        }
+    }
+
+    if geometry.is_some() && rotation.is_none() {
+        rotation = Some("normal".to_string());
+    }
+
+    Some(HeaderInfo {
+        name: name.to_string(),
+        state,
+        primary,
+        geometry,
+        rotation,
+    })
+}
+
+fn section_reflection(section: &DisplaySection) -> Option<&'static str> {
+    if let Some(transform) = wlr_field(section, "Transform:") {
+        if wlr_transform_rotation(transform).is_some() {
+            return transform.starts_with("flipped").then_some("x");
+        }
+    }
+    let header = section.lines.first()?;
+    let header = header.split(" (normal").next().unwrap_or(header);
+    if header.contains("X and Y axis") {
+        Some("xy")
+    } else if header.contains("X axis") {
+        Some("x")
+    } else if header.contains("Y axis") {
+        Some("y")
+    } else {
+        None
+    }
+}
+
+fn is_rotation(token: &str) -> bool {
+    matches!(token, "normal" | "left" | "inverted" | "right")
+}
+
+fn is_geometry_token(token: &str) -> bool {
+    let bytes = token.as_bytes();
+    let len = bytes.len();
+    if len == 0 {
+        return false;
+    }
+
+    let mut index = 0;
+    match consume_digits(bytes, index) {
+        Some(next) => index = next,
+        None => return false,
+    }
+    if index >= len || bytes[index] != b'x' {
+        return false;
+    }
+    index += 1;
+    match consume_digits(bytes, index) {
+        Some(next) => index = next,
+        None => return false,
+    }
+
+    match consume_signed_number(bytes, index) {
+        Some(next) => index = next,
+        None => return false,
+    }
+    match consume_signed_number(bytes, index) {
+        Some(next) => index = next,
+        None => return false,
+    }
+
+    index == len
+}
+
+struct Geometry {
+    width: u32,
+    height: u32,
+    x: i32,
+    y: i32,
+}
+
+fn parse_geometry(token: &str) -> Option<Geometry> {
+    if !is_geometry_token(token) {
+        return None;
+    }
+    let (width, rest) = token.split_once('x')?;
+    let (height, offsets) = rest.split_at(rest.find(['+', '-'])?);
+    let (x, y) = offsets.split_at(offsets[1..].find(['+', '-'])? + 1);
+    Some(Geometry {
+        width: width.parse().ok()?,
+        height: height.parse().ok()?,
+        x: x.parse().ok()?,
+        y: y.parse().ok()?,
+    })
+}
+
+fn consume_digits(bytes: &[u8], mut index: usize) -> Option<usize> {
+    if index >= bytes.len() || !bytes[index].is_ascii_digit() {
+        return None;
+    }
+    while index < bytes.len() && bytes[index].is_ascii_digit() {
+        index += 1;
+    }
+    Some(index)
+}
+
+fn consume_signed_number(bytes: &[u8], mut index: usize) -> Option<usize> {
+    if index >= bytes.len() {
+        return None;
+    }
+
+    let sign = bytes[index];
+    if sign != b'+' && sign != b'-' {
+        return None;
+    }
+    index += 1;
+
+    if index >= bytes.len() || !bytes[index].is_ascii_digit() {
+        return None;
+    }
+
+    while index < bytes.len() && bytes[index].is_ascii_digit() {
+        index += 1;
+    }
+
+    Some(index)
+}
+
+fn expect_display(
+    args: &mut impl Iterator<Item = String>,
+    name: &str,
+    sections: &[DisplaySection],
+) -> Result<String, CliError> {
+    let arg = expect_arg(args, name)?;
+    resolve_display_name(sections, &arg)
+}
+
+fn is_display_pattern(arg: &str) -> bool {
+    (arg.len() > 1 && arg.starts_with('/') && arg.ends_with('/')) || arg.contains(['*', '?', '['])
+}
+
+fn display_name_matcher(pattern: &str) -> Result<NamePattern, CliError> {
+    match pattern
+        .strip_prefix('/')
+        .and_then(|rest| rest.strip_suffix('/'))
+    {
+        Some(regex) => NamePattern::regex(regex),
+        None => NamePattern::glob(pattern),
+    }
+}
+
+fn matching_display_names(
+    sections: &[DisplaySection],
+    pattern: &str,
+) -> Result<Vec<String>, CliError> {
+    let matcher = display_name_matcher(pattern)?;
+    Ok(sections
+        .iter()
+        .filter(|section| matcher.is_match(&section.name))
+        .map(|section| section.name.clone())
+        .collect())
+}
+
+fn resolve_display_name(sections: &[DisplaySection], arg: &str) -> Result<String, CliError> {
+    if find_section(sections, arg).is_some() {
+        return Ok(arg.to_string());
+    }
+    if !is_display_pattern(arg) {
+        return Ok(fuzzy_display_name(sections, arg).unwrap_or_else(|| arg.to_string()));
+    }
+    let mut names = matching_display_names(sections, arg)?;
+    match names.len() {
+        0 => Err(CliError::NotFound(format!(
+            "no display matches pattern: {arg}"
+        ))),
+        1 => Ok(names.remove(0)),
+        _ => Err(CliError::NotFound(format!(
+            "pattern {arg} matches multiple displays: {}",
+            names.join(", ")
+        ))),
+    }
+}
+
+fn fuzzy_display_name(sections: &[DisplaySection], arg: &str) -> Option<String> {
+    if !FUZZY_NAMES.load(Ordering::Relaxed) {
+        return None;
+    }
+    let normalize = |text: &str| {
+        text.chars()
+            .filter(|ch| ch.is_ascii_alphanumeric())
+            .map(|ch| ch.to_ascii_lowercase())
+            .collect::<String>()
+    };
+    let wanted = normalize(arg);
+    let unique = |matches: Vec<&DisplaySection>| match matches[..] {
+        [section] => Some(section.name.clone()),
+        _ => None,
+    };
+    unique(
+        sections
+            .iter()
+            .filter(|section| section.name.eq_ignore_ascii_case(arg))
+            .collect(),
+    )
+    .or_else(|| {
+        unique(
+            sections
+                .iter()
+                .filter(|section| {
+                    !wanted.is_empty() && normalize(&section.name).starts_with(&wanted)
+                })
+                .collect(),
+        )
+    })
+}
+
+fn expand_display_patterns(
+    sections: &[DisplaySection],
+    args: &[String],
+) -> Result<Vec<String>, CliError> {
+    let mut displays = Vec::new();
+    for arg in args {
+        if find_section(sections, arg).is_some() || !is_display_pattern(arg) {
+            displays.push(resolve_display_name(sections, arg)?);
+            continue;
+        }
+        let names = matching_display_names(sections, arg)?;
+        if names.is_empty() {
+            return Err(CliError::NotFound(format!(
+                "no display matches pattern: {arg}"
+            )));
+        }
+        displays.extend(names);
+    }
+    Ok(displays)
+}
+
+enum PatternAtom {
+    Char(char),
+    Any,
+    Class(Vec<(char, char)>, bool),
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum PatternRepeat {
+    One,
+    Optional,
+    ZeroOrMore,
+    OneOrMore,
+}
+
+struct PatternBranch {
+    items: Vec<(PatternAtom, PatternRepeat)>,
+    anchored_start: bool,
+    anchored_end: bool,
+}
+
+struct NamePattern {
+    branches: Vec<PatternBranch>,
+}
+
+impl NamePattern {
+    fn glob(pattern: &str) -> Result<NamePattern, CliError> {
+        let mut items = Vec::new();
+        let mut chars = pattern.chars().peekable();
+        while let Some(ch) = chars.next() {
+            let item = match ch {
+                '*' => (PatternAtom::Any, PatternRepeat::ZeroOrMore),
+                '?' => (PatternAtom::Any, PatternRepeat::One),
+                '[' => (parse_pattern_class(&mut chars, '!')?, PatternRepeat::One),
+                _ => (PatternAtom::Char(ch), PatternRepeat::One),
+            };
+            items.push(item);
+        }
+        Ok(NamePattern {
+            branches: vec![PatternBranch {
+                items,
+                anchored_start: true,
+                anchored_end: true,
+            }],
+        })
+    }
+
+    fn regex(pattern: &str) -> Result<NamePattern, CliError> {
+        let mut branches = Vec::new();
+        for branch in split_regex_branches(pattern) {
+            let mut items: Vec<(PatternAtom, PatternRepeat)> = Vec::new();
+            let mut anchored_start = false;
+            let mut anchored_end = false;
+            let mut chars = branch.chars().peekable();
+            while let Some(ch) = chars.next() {
+                let atom = match ch {
+                    '^' if items.is_empty() => {
+                        anchored_start = true;
+                        continue;
+                    }
+                    '$' if chars.peek().is_none() => {
+                        anchored_end = true;
+                        continue;
+                    }
+                    '.' => PatternAtom::Any,
+                    '[' => parse_pattern_class(&mut chars, '^')?,
+                    '\\' => match chars.next() {
+                        Some('d') => PatternAtom::Class(vec![('0', '9')], false),
+                        Some('w') => PatternAtom::Class(
+                            vec![('a', 'z'), ('A', 'Z'), ('0', '9'), ('_', '_')],
+                            false,
+                        ),
+                        Some(escaped) => PatternAtom::Char(escaped),
+                        None => return Err(format!("invalid regex: /{pattern}/").into()),
+                    },
+                    '*' | '+' | '?' => {
+                        let repeat = match ch {
+                            '*' => PatternRepeat::ZeroOrMore,
+                            '+' => PatternRepeat::OneOrMore,
+                            _ => PatternRepeat::Optional,
+                        };
+                        match items.last_mut() {
+                            Some(last) if last.1 == PatternRepeat::One => last.1 = repeat,
+                            _ => return Err(format!("invalid regex: /{pattern}/").into()),
+                        }
+                        continue;
+                    }
+                    '(' | ')' | '{' | '}' => {
+                        return Err(format!("unsupported regex syntax '{ch}' in /{pattern}/").into())
+                    }
+                    _ => PatternAtom::Char(ch),
+                };
+                items.push((atom, PatternRepeat::One));
+            }
+            branches.push(PatternBranch {
+                items,
+                anchored_start,
+                anchored_end,
+            });
+        }
+        Ok(NamePattern { branches })
+    }
+
+    fn is_match(&self, text: &str) -> bool {
+        let chars: Vec<char> = text.chars().collect();
+        self.branches.iter().any(|branch| {
+            let last_start = if branch.anchored_start {
+                0
+            } else {
+                chars.len()
+            };
+            (0..=last_start).any(|start| {
+                match_pattern_items(&branch.items, &chars[start..], branch.anchored_end)
+            })
+        })
+    }
+}
+
+fn split_regex_branches(pattern: &str) -> Vec<String> {
+    let mut branches = vec![String::new()];
+    let mut in_class = false;
+    let mut escaped = false;
+    for ch in pattern.chars() {
+        if !escaped && !in_class && ch == '|' {
+            branches.push(String::new());
+            continue;
+        }
+        if !escaped {
+            match ch {
+                '[' => in_class = true,
+                ']' => in_class = false,
+                _ => {}
+            }
+        }
+        escaped = !escaped && ch == '\\';
+        branches.last_mut().expect("branch exists").push(ch);
+    }
+    branches
+}
+
+fn parse_pattern_class(
+    chars: &mut std::iter::Peekable<std::str::Chars>,
+    negate: char,
+) -> Result<PatternAtom, CliError> {
+    let negated = chars.next_if_eq(&negate).is_some();
+    let mut ranges = Vec::new();
+    loop {
+        let start = match chars.next() {
+            Some(']') if !ranges.is_empty() => break,
+            Some(ch) => ch,
+            None => return Err("unterminated character class".to_string().into()),
+        };
+        if chars.peek() == Some(&'-') {
+            chars.next();
+            match chars.next() {
+                Some(']') => {
+                    ranges.push((start, start));
+                    ranges.push(('-', '-'));
+                    break;
+                }
+                Some(end) => ranges.push((start, end)),
+                None => return Err("unterminated character class".to_string().into()),
+            }
+        } else {
+            ranges.push((start, start));
+        }
+    }
+    Ok(PatternAtom::Class(ranges, negated))
+}
+
+fn pattern_atom_matches(atom: &PatternAtom, ch: char) -> bool {
+    match atom {
+        PatternAtom::Char(expected) => *expected == ch,
+        PatternAtom::Any => true,
+        PatternAtom::Class(ranges, negated) => {
+            ranges
+                .iter()
+                .any(|(start, end)| (*start..=*end).contains(&ch))
+                != *negated
+        }
+    }
+}
+
+fn match_pattern_items(
+    items: &[(PatternAtom, PatternRepeat)],
+    text: &[char],
+    anchored_end: bool,
+) -> bool {
+    let ((atom, repeat), rest) = match items.split_first() {
+        Some(split) => split,
+        None => return !anchored_end || text.is_empty(),
+    };
+    let (min, max) = match repeat {
+        PatternRepeat::One => (1, 1),
+        PatternRepeat::Optional => (0, 1),
+        PatternRepeat::ZeroOrMore => (0, usize::MAX),
+        PatternRepeat::OneOrMore => (1, usize::MAX),
+    };
+    let available = text
+        .iter()
+        .take(max)
+        .take_while(|ch| pattern_atom_matches(atom, **ch))
+        .count();
+    if available < min {
+        return false;
+    }
+    (min..=available)
+        .rev()
+        .any(|count| match_pattern_items(rest, &text[count..], anchored_end))
+}
+
+fn find_section<'a>(sections: &'a [DisplaySection], name: &str) -> Option<&'a DisplaySection> {
+    sections.iter().find(|section| section.name == name)
+}
+
+fn display_not_found(sections: &[DisplaySection], name: &str) -> CliError {
+    let suggestions = similar_display_names(sections, name);
+    CliError::NotFound(match suggestions.split_last() {
+        None => format!("display not found: {name}"),
+        Some((last, [])) => format!("display not found: {name}, did you mean {last}?"),
+        Some((last, rest)) => format!(
+            "display not found: {name}, did you mean {} or {last}?",
+            rest.join(", ")
+        ),
+    })
+}
+
+fn similar_display_names(sections: &[DisplaySection], name: &str) -> Vec<String> {
+    let normalize = |text: &str| {
+        text.chars()
+            .filter(|ch| ch.is_ascii_alphanumeric())
+            .map(|ch| ch.to_ascii_lowercase())
+            .collect::<Vec<char>>()
+    };
+    let wanted = normalize(name);
+    if wanted.is_empty() {
+        return Vec::new();
+    }
+    let limit = (wanted.len() / 3).max(1);
+    let mut scored: Vec<(usize, &str)> = sections
+        .iter()
+        .filter_map(|section| {
+            let candidate = normalize(&section.name);
+            let prefix = candidate.starts_with(&wanted) || wanted.starts_with(&candidate);
+            let distance = edit_distance(&wanted, &candidate);
+            (prefix || distance <= limit).then_some((distance, section.name.as_str()))
+        })
+        .collect();
+    scored.sort();
+    scored
+        .into_iter()
+        .take(3)
+        .map(|(_, name)| name.to_string())
+        .collect()
+}
+
+fn edit_distance(a: &[char], b: &[char]) -> usize {
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, left) in a.iter().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, right) in b.iter().enumerate() {
+            let substitute = previous[j] + usize::from(left != right);
+            current.push(substitute.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+struct Mode {
+    width: u32,
+    height: u32,
+    refresh: f64,
+    current: bool,
+    preferred: bool,
+}
+
+fn parse_modes(section: &DisplaySection) -> Vec<Mode> {
+    let mut modes: Vec<Mode> = Vec::new();
+
+    for line in section.lines.iter().skip(1) {
+        let trimmed = line.trim();
+        if let Some((size, rest)) = trimmed.split_once(" px, ") {
+            if let Some((width, height)) = size.split_once('x') {
+                modes.push(Mode {
+                    width: width.parse().unwrap_or(0),
+                    height: height.parse().unwrap_or(0),
+                    refresh: rest
+                        .split_whitespace()
+                        .next()
+                        .and_then(|rate| rate.parse().ok())
+                        .unwrap_or(0.0),
+                    current: rest.contains("current"),
+                    preferred: rest.contains("preferred"),
+                });
+            }
+            continue;
+        }
+        if parse_compact_modes(trimmed, &mut modes) {
+            continue;
+        }
+        let mut parts = trimmed.split_whitespace();
+        let first = parts.next().unwrap_or_default();
+        if first == "h:" {
+            if let Some(mode) = modes.last_mut() {
+                mode.width = field_after(trimmed, "width").unwrap_or(mode.width);
+            }
+            continue;
+        }
+        if first == "v:" {
+            if let Some(mode) = modes.last_mut() {
+                mode.height = field_after(trimmed, "height").unwrap_or(mode.height);
+                if let Some(clock) = trimmed.rsplit("clock").next() {
+                    let clock = clock.trim().trim_end_matches("Hz");
+                    mode.refresh = clock.trim().parse().unwrap_or(0.0);
+                }
+            }
+            continue;
+        }
+        if !parts.next().is_some_and(|id| id.starts_with("(0x")) {
+            continue;
+        }
+        let (width, height) = first
+            .split_once('x')
+            .map(|(width, height)| {
+                let height = height.trim_end_matches(|ch: char| !ch.is_ascii_digit());
+                (width.parse().unwrap_or(0), height.parse().unwrap_or(0))
+            })
+            .unwrap_or((0, 0));
+        modes.push(Mode {
+            width,
+            height,
+            refresh: 0.0,
+            current: trimmed.contains("*current"),
+            preferred: trimmed.contains("+preferred"),
+        });
+    }
+
+    modes
+}
+
+fn parse_native_check_args(
+    args: &mut impl Iterator<Item = String>,
+) -> Result<(Option<String>, bool), CliError> {
+    let mut display = None;
+    let mut exit_code = false;
+    for arg in args {
+        if arg == "--exit-code" {
+            exit_code = true;
+        } else if arg.starts_with("--") {
+            return Err(CliError::Usage(format!("unknown option: {arg}")));
+        } else if display.is_none() {
+            display = Some(arg);
+        } else {
+            return Err(format!("unexpected argument: {arg}").into());
+        }
+    }
+    Ok((display, exit_code))
+}
+
+fn native_mismatches(
+    sections: &[DisplaySection],
+    display: Option<&str>,
+) -> Result<Vec<(String, String, String)>, CliError> {
+    let targets: Vec<&DisplaySection> = match display {
+        Some(name) => {
+            let section =
+                find_section(sections, name).ok_or_else(|| display_not_found(sections, name))?;
+            if !parse_modes(section).iter().any(|mode| mode.current) {
+                return Err(format!("current mode not available for display: {name}").into());
+            }
+            vec![section]
+        }
+        None => sections.iter().collect(),
+    };
+
+    let mut mismatches = Vec::new();
+    for section in targets {
+        let modes = parse_modes(section);
+        let current = modes.iter().find(|mode| mode.current);
+        let preferred = modes.iter().find(|mode| mode.preferred);
+        if let (Some(current), Some(preferred)) = (current, preferred) {
+            if (current.width, current.height) != (preferred.width, preferred.height) {
+                mismatches.push((
+                    section.name.clone(),
+                    format!("{}x{}", current.width, current.height),
+                    format!("{}x{}", preferred.width, preferred.height),
+                ));
+            }
+        }
+    }
+    Ok(mismatches)
+}
+
+fn mode_label(mode: &Mode) -> String {
+    format!("{}x{}@{:.2}", mode.width, mode.height, mode.refresh)
+}
+
+fn format_mode(mode: &Mode) -> String {
+    let mut text = mode_label(mode);
+    if mode.current {
+        text.push('*');
+    }
+    if mode.preferred {
+        text.push('+');
+    }
+    text
+}
+
+fn current_resolution_rates(section: &DisplaySection) -> Vec<u32> {
+    let modes = parse_modes(section);
+    let current = match modes.iter().find(|mode| mode.current) {
+        Some(mode) => mode,
+        None => return Vec::new(),
+    };
+    let mut rates: Vec<u32> = modes
+        .iter()
+        .filter(|mode| (mode.width, mode.height) == (current.width, current.height))
+        .map(|mode| mode.refresh.round() as u32)
+        .collect();
+    rates.sort_unstable_by(|a, b| b.cmp(a));
+    rates.dedup();
+    rates
+}
+
+fn parse_compact_modes(line: &str, modes: &mut Vec<Mode>) -> bool {
+    let mut parts = line.split_whitespace();
+    let size = match parts.next() {
+        Some(size) => size,
+        None => return false,
+    };
+    let (width, height) = match size.split_once('x') {
+        Some((width, height)) => (
+            width.parse::<u32>(),
+            height
+                .trim_end_matches(|ch: char| !ch.is_ascii_digit())
+                .parse::<u32>(),
+        ),
+        None => return false,
+    };
+    let (width, height) = match (width, height) {
+        (Ok(width), Ok(height)) => (width, height),
+        _ => return false,
+    };
+    let rates: Vec<&str> = parts.collect();
+    let is_rate = |token: &&str| token.trim_end_matches(['*', '+']).parse::<f64>().is_ok();
+    if rates.is_empty() || !is_rate(&rates[0]) {
+        return false;
+    }
+
+    for token in rates {
+        if token == "+" {
+            if let Some(mode) = modes.last_mut() {
+                mode.preferred = true;
+            }
+            continue;
+        }
+        let refresh = match token.trim_end_matches(['*', '+']).parse::<f64>() {
+            Ok(refresh) => refresh,
+            Err(_) => continue,
+        };
+        modes.push(Mode {
+            width,
+            height,
+            refresh,
+            current: token.contains('*'),
+            preferred: token.contains('+'),
+        });
+    }
+    true
+}
+
+fn field_after(line: &str, label: &str) -> Option<u32> {
+    let mut parts = line.split_whitespace();
+    parts.find(|part| *part == label)?;
+    parts.next()?.parse().ok()
+}
+
+fn preferred_mode_size(section: &DisplaySection) -> Option<(u32, u32)> {
+    parse_modes(section)
+        .iter()
+        .find(|mode| mode.preferred)
+        .map(|mode| (mode.width, mode.height))
+}
+
+fn json_string(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len() + 2);
+    escaped.push('"');
+    for ch in text.chars() {
+        match ch {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            ch if ch.is_control() => escaped.push_str(&format!("\\u{:04x}", ch as u32)),
+            ch => escaped.push(ch),
+        }
+    }
+    escaped.push('"');
+    escaped
+}
+
+fn escape_multiline(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('\n', "\\n")
+}
+
+fn extract_edid_hex(section: &DisplaySection) -> Option<String> {
+    let mut capture = false;
+    let mut hex = String::new();
+
+    for line in &section.lines {
+        let trimmed = line.trim();
+        if trimmed.starts_with("EDID:") || trimmed.starts_with("EDID_DATA:") {
+            capture = true;
+            continue;
+        }
+        if capture {
+            if trimmed.is_empty() {
+                break;
+            }
+            if trimmed
+                .chars()
+                .all(|ch| ch.is_ascii_hexdigit() || ch.is_ascii_whitespace())
+            {
+                for ch in trimmed.chars() {
+                    if ch.is_ascii_hexdigit() {
+                        hex.push(ch);
+                    }
+                }
+            } else {
+                break;
+            }
+        }
+    }
+
+    if hex.is_empty() {
+        None
+    } else {
+        Some(hex)
+    }
+}
+
+fn group_hex(hex: &str, size: usize) -> String {
+    let separator = if size == 2 { " " } else { "\n" };
+    hex.as_bytes()
+        .chunks(size)
+        .map(|chunk| String::from_utf8_lossy(chunk).into_owned())
+        .collect::<Vec<_>>()
+        .join(separator)
+}
+
+fn edid_physical_size_mm(hex: &str) -> Option<(u32, u32)> {
+    let bytes = hex_to_bytes(hex).ok()?;
+    if bytes.len() < 128 {
+        return None;
+    }
+
+    let timing = &bytes[54..72];
+    if timing[0] != 0 || timing[1] != 0 {
+        let width = u32::from(timing[12]) | (u32::from(timing[14] >> 4) << 8);
+        let height = u32::from(timing[13]) | (u32::from(timing[14] & 0x0f) << 8);
+        if width > 0 && height > 0 {
+            return Some((width, height));
+        }
+    }
+
+    let width = u32::from(bytes[21]) * 10;
+    let height = u32::from(bytes[22]) * 10;
+    if width > 0 && height > 0 {
+        Some((width, height))
+    } else {
+        None
+    }
+}
+
+fn edid_manufacturer_id(hex: &str) -> Option<String> {
+    let bytes = hex_to_bytes(hex).ok()?;
+    if bytes.len() < 10 {
+        return None;
+    }
+    let value = (u16::from(bytes[8]) << 8) | u16::from(bytes[9]);
+    let id: String = [10, 5, 0]
+        .iter()
+        .map(|shift| (((value >> shift) & 0x1f) as u8 + b'@') as char)
+        .collect();
+    if id.chars().all(|ch| ch.is_ascii_uppercase()) {
+        Some(id)
+    } else {
+        None
+    }
+}
+
+fn edid_header_fields(hex: &str) -> Result<Vec<(&'static str, String)>, CliError> {
+    let bytes = hex_to_bytes(hex)?;
+    if bytes.len() < 128 {
+        return Err("edid data is shorter than one block".to_string().into());
+    }
+    if bytes[..8] != [0x00, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x00] {
+        return Err("edid header magic is invalid".to_string().into());
+    }
+
+    let mut fields = vec![
+        (
+            "manufacturer",
+            edid_manufacturer_id(hex).unwrap_or_default(),
+        ),
+        (
+            "product_code",
+            u16::from_le_bytes([bytes[10], bytes[11]]).to_string(),
+        ),
+        (
+            "serial_number",
+            u32::from_le_bytes([bytes[12], bytes[13], bytes[14], bytes[15]]).to_string(),
+        ),
+    ];
+    let year = (1990 + u32::from(bytes[17])).to_string();
+    match bytes[16] {
+        0xff => fields.push(("model_year", year)),
+        week => {
+            fields.push(("week", week.to_string()));
+            fields.push(("year", year));
+        }
+    }
+    fields.push(("edid_version", format!("{}.{}", bytes[18], bytes[19])));
+    fields.push(("extension_blocks", bytes[126].to_string()));
+    Ok(fields)
+}
+
+fn edid_validation_issues(hex: &str) -> Vec<String> {
+    let bytes = match hex_to_bytes(hex) {
+        Ok(bytes) => bytes,
+        Err(err) => return vec![err.to_string()],
+    };
+    if bytes.len() < 128 {
+        return vec![format!("truncated edid: {} bytes", bytes.len())];
+    }
+
+    let mut issues = Vec::new();
+    if bytes[..8] != [0x00, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x00] {
+        issues.push("invalid header magic".to_string());
+    }
+    if bytes.len() % 128 != 0 {
+        issues.push(format!(
+            "length {} is not a multiple of 128 bytes",
+            bytes.len()
+        ));
+    }
+    for (index, block) in bytes.chunks_exact(128).enumerate() {
+        let sum = block.iter().fold(0u8, |acc, byte| acc.wrapping_add(*byte));
+        if sum != 0 {
+            issues.push(format!("block {index} checksum mismatch"));
+        }
+    }
+    let declared = usize::from(bytes[126]);
+    let present = bytes.len() / 128 - 1;
+    if declared != present {
+        issues.push(format!(
+            "extension count {declared} does not match {present} extension blocks present"
+        ));
+    }
+    issues
+}
+
+fn edid_descriptor_text(hex: &str, tag: u8) -> Option<String> {
+    let bytes = hex_to_bytes(hex).ok()?;
+    if bytes.len() < 128 {
+        return None;
+    }
+    for offset in [54, 72, 90, 108] {
+        let descriptor = &bytes[offset..offset + 18];
+        if descriptor[0] != 0 || descriptor[1] != 0 || descriptor[3] != tag {
+            continue;
+        }
+        let text: String = descriptor[5..]
+            .iter()
+            .take_while(|byte| **byte != 0x0a)
+            .map(|byte| *byte as char)
+            .collect();
+        let text = text.trim();
+        if !text.is_empty() {
+            return Some(text.to_string());
+        }
+    }
+    None
+}
+
+fn edid_model_name(hex: &str) -> Option<String> {
+    edid_descriptor_text(hex, 0xfc)
+}
+
+fn edid_serial(hex: &str) -> Option<String> {
+    if let Some(serial) = edid_descriptor_text(hex, 0xff) {
+        return Some(serial);
+    }
+    let bytes = hex_to_bytes(hex).ok()?;
+    if bytes.len() >= 16 {
+        let numeric = u32::from_le_bytes([bytes[12], bytes[13], bytes[14], bytes[15]]);
+        if numeric != 0 {
+            return Some(numeric.to_string());
+        }
+    }
+    edid_descriptor_text(hex, 0xfe)
+}
+
+fn edid_hash(hex: &str) -> String {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for byte in hex_to_bytes(hex).unwrap_or_default() {
+        hash ^= u64::from(byte);
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    format!("{hash:016x}")
+}
+
+#[derive(Default)]
+struct Identity {
+    vendor: String,
+    model: String,
+    serial: String,
+    edid_hash: String,
+}
+
+impl Identity {
+    fn describe(&self) -> String {
+        format!(
+            "{}|{}|{}|{}",
+            self.vendor, self.model, self.serial, self.edid_hash
+        )
+    }
+}
+
+fn section_identity(section: &DisplaySection) -> Option<Identity> {
+    let edid = extract_edid_hex(section)?;
+    let vendor = edid_manufacturer_id(&edid)
+        .map(|id| pnp_vendor_name(&id).map(str::to_string).unwrap_or(id))
+        .unwrap_or_default();
+    Some(Identity {
+        vendor,
+        model: edid_model_name(&edid).unwrap_or_default(),
+        serial: edid_serial(&edid).unwrap_or_default(),
+        edid_hash: edid_hash(&edid),
+    })
+}
+
+#[derive(Default)]
+struct EdidFeatures {
+    hdr: bool,
+    vrr: bool,
+    audio: bool,
+    deep_color: bool,
+    hdmi: bool,
+    max_tmds_mhz: Option<u32>,
+}
+
+impl EdidFeatures {
+    fn raise_tmds(&mut self, rate: Option<&u8>) {
+        let Some(mhz) = rate.map(|rate| u32::from(*rate) * 5).filter(|mhz| *mhz > 0) else {
+            return;
+        };
+        self.max_tmds_mhz = Some(self.max_tmds_mhz.map_or(mhz, |current| current.max(mhz)));
+    }
+}
+
+fn edid_features(hex: &str) -> Option<EdidFeatures> {
+    let bytes = hex_to_bytes(hex).ok()?;
+    if bytes.len() < 128 {
+        return None;
+    }
+    let mut features = EdidFeatures {
+        deep_color: edid_color_depth(&bytes).is_some_and(|depth| depth >= 10),
+        ..EdidFeatures::default()
+    };
+    for block in bytes[128..].chunks_exact(128) {
+        if block[0] != 0x02 {
+            continue;
+        }
+        features.audio |= block[3] & 0x40 != 0;
+        let end = usize::from(block[2]).clamp(4, 127);
+        let mut offset = 4;
+        while offset < end {
+            let tag = block[offset] >> 5;
+            let len = usize::from(block[offset] & 0x1f);
+            let payload = &block[(offset + 1).min(end)..(offset + 1 + len).min(end)];
+            match (tag, payload) {
+                (1, _) => features.audio = true,
+                (3, [0x03, 0x0c, 0x00, rest @ ..]) => {
+                    features.hdmi = true;
+                    features.deep_color |= rest.get(2).is_some_and(|flags| flags & 0x10 != 0);
+                    features.raise_tmds(rest.get(3));
+                }
+                (3, [0x1a, 0x00, 0x00, ..]) => features.vrr = true,
+                (3, [0xd8, 0x5d, 0xc4, rest @ ..]) => {
+                    features.vrr |= rest.get(5).is_some_and(|vrr_min| vrr_min & 0x3f != 0);
+                    features.deep_color |= rest.get(3).is_some_and(|flags| flags & 0x01 != 0);
+                    features.raise_tmds(rest.get(1));
+                }
+                (7, [0x06, ..]) => features.hdr = true,
+                _ => {}
+            }
+            offset += 1 + len;
+        }
+    }
+    Some(features)
+}
+
+struct Capabilities {
+    max_resolution: Option<(u32, u32)>,
+    max_native_rate: Option<f64>,
+    features: Option<EdidFeatures>,
+    vrr_property: bool,
+}
+
+fn section_capabilities(section: &DisplaySection) -> Capabilities {
+    let modes = parse_modes(section);
+    let max_resolution = modes
+        .iter()
+        .map(|mode| (mode.width, mode.height))
+        .max_by_key(|(width, height)| u64::from(*width) * u64::from(*height));
+    let native = preferred_mode_size(section).or(max_resolution);
+    let max_native_rate = modes
+        .iter()
+        .filter(|mode| Some((mode.width, mode.height)) == native)
+        .map(|mode| mode.refresh)
+        .reduce(f64::max);
+    let vrr_property = section_properties(section)
+        .iter()
+        .any(|property| property.name == "vrr_capable" && property.value == "1");
+    Capabilities {
+        max_resolution,
+        max_native_rate,
+        features: extract_edid_hex(section).and_then(|edid| edid_features(&edid)),
+        vrr_property,
+    }
+}
+
+impl Capabilities {
+    fn flag(&self, pick: impl Fn(&EdidFeatures) -> bool) -> Option<bool> {
+        self.features.as_ref().map(pick)
+    }
+
+    fn columns(&self) -> [(&'static str, Option<String>); 6] {
+        let yes_no =
+            |value: Option<bool>| value.map(|value| (if value { "yes" } else { "no" }).to_string());
+        let vrr = if self.vrr_property {
+            Some(true)
+        } else {
+            self.flag(|features| features.vrr)
+        };
+        [
+            (
+                "max_resolution",
+                self.max_resolution
+                    .map(|(width, height)| format!("{width}x{height}")),
+            ),
+            (
+                "max_refresh",
+                self.max_native_rate.map(|rate| format!("{rate:.2}")),
+            ),
+            ("hdr", yes_no(self.flag(|features| features.hdr))),
+            ("vrr", yes_no(vrr)),
+            ("audio", yes_no(self.flag(|features| features.audio))),
+            ("10bit", yes_no(self.flag(|features| features.deep_color))),
+        ]
+    }
+}
+
+fn run_capabilities(sections: &[DisplaySection], json: bool) {
+    let connected: Vec<&DisplaySection> = sections
+        .iter()
+        .filter(|section| section.state == DisplayState::Connected)
+        .collect();
+    if json {
+        for section in connected {
+            let fields: Vec<String> = section_capabilities(section)
+                .columns()
+                .into_iter()
+                .map(|(key, value)| {
+                    let value = match (key, value) {
+                        (_, None) => "null".to_string(),
+                        ("max_resolution", Some(value)) => json_string(&value),
+                        ("max_refresh", Some(value)) => value,
+                        (_, Some(value)) => (value == "yes").to_string(),
+                    };
+                    format!("\"{key}\": {value}")
+                })
+                .collect();
+            println!(
+                "{{\"name\": {}, {}}}",
+                json_string(&section.name),
+                fields.join(", ")
+            );
+        }
+        return;
+    }
+    let mut rows = vec![[
+        "display",
+        "max_resolution",
+        "max_refresh",
+        "hdr",
+        "vrr",
+        "audio",
+        "10bit",
+    ]
+    .map(str::to_string)
+    .to_vec()];
+    for section in connected {
+        let mut row = vec![section.name.clone()];
+        row.extend(
+            section_capabilities(section)
+                .columns()
+                .into_iter()
+                .map(|(_, value)| value.unwrap_or_else(|| "-".to_string())),
+        );
+        rows.push(row);
+    }
+    let widths: Vec<usize> = (0..rows[0].len())
+        .map(|column| rows.iter().map(|row| row[column].len()).max().unwrap_or(0))
+        .collect();
+    for row in rows {
+        let cells: Vec<String> = row
+            .iter()
+            .zip(&widths)
+            .map(|(cell, width)| format!("{cell:<width$}"))
+            .collect();
+        println!("{}", cells.join("  ").trim_end());
+    }
+}
+
+fn pnp_vendor_name(id: &str) -> Option<&'static str> {
+    PNP_VENDORS
+        .iter()
+        .find(|(vendor_id, _)| *vendor_id == id)
+        .map(|(_, name)| *name)
+}
+
+const PNP_VENDORS: &[(&str, &str)] = &[
+    ("AAC", "AcerView"),
+    ("ACI", "ASUS"),
+    ("ACR", "Acer"),
+    ("AIC", "AG Neovo"),
+    ("AOC", "AOC"),
+    ("APP", "Apple"),
+    ("AUO", "AU Optronics"),
+    ("AUS", "ASUS"),
+    ("BNQ", "BenQ"),
+    ("BOE", "BOE"),
+    ("CMN", "Chimei Innolux"),
+    ("CMO", "Chi Mei Optoelectronics"),
+    ("CPQ", "Compaq"),
+    ("DEL", "Dell"),
+    ("DON", "Denon"),
+    ("ECS", "Elitegroup"),
+    ("ELO", "Elo Touch Solutions"),
+    ("EIZ", "EIZO"),
+    ("ENC", "EIZO"),
+    ("EPI", "Envision Peripherals"),
+    ("FUS", "Fujitsu Siemens"),
+    ("GBT", "Gigabyte"),
+    ("GSM", "LG Electronics"),
+    ("GWY", "Gateway"),
+    ("HEI", "Hyundai"),
+    ("HIQ", "Hyundai ImageQuest"),
+    ("HIT", "Hitachi"),
+    ("HPN", "HP"),
+    ("HSD", "HannStar"),
+    ("HSL", "Hansol"),
+    ("HTC", "Hitachi"),
+    ("HWP", "HP"),
+    ("IBM", "IBM"),
+    ("ICL", "Fujitsu ICL"),
+    ("IVM", "Iiyama"),
+    ("IVO", "InfoVision Optoelectronics"),
+    ("KDS", "KDS"),
+    ("LEN", "Lenovo"),
+    ("LGD", "LG Display"),
+    ("LPL", "LG Philips"),
+    ("MAX", "Belinea"),
+    ("MEI", "Panasonic"),
+    ("MEL", "Mitsubishi"),
+    ("MSI", "MSI"),
+    ("NEC", "NEC"),
+    ("NOK", "Nokia"),
+    ("NVD", "NVIDIA"),
+    ("OQI", "Optiquest"),
+    ("PHL", "Philips"),
+    ("PIO", "Pioneer"),
+    ("PNR", "Planar"),
+    ("QDS", "Quanta Display"),
+    ("RAT", "Rent-A-Tech"),
+    ("REL", "Relisys"),
+    ("SAM", "Samsung"),
+    ("SAN", "Sanyo"),
+    ("SDC", "Samsung Display"),
+    ("SEC", "Seiko Epson"),
+    ("SGI", "SGI"),
+    ("SHP", "Sharp"),
+    ("SNY", "Sony"),
+    ("SPT", "Sceptre"),
+    ("SRC", "Shamrock"),
+    ("STN", "Samtron"),
+    ("TAT", "Tatung"),
+    ("TOS", "Toshiba"),
+    ("TRL", "Royal Information"),
+    ("TSB", "Toshiba"),
+    ("UNK", "Unknown"),
+    ("VIZ", "Vizio"),
+    ("VSC", "ViewSonic"),
+    ("WAC", "Wacom"),
+    ("XMI", "Xiaomi"),
+    ("ZCM", "Zenith"),
+];
+
+fn section_physical_size(section: &DisplaySection) -> Option<(u32, u32)> {
+    let header = section
+        .lines
+        .first()
+        .map(String::as_str)
+        .unwrap_or_default();
+    let tokens: Vec<&str> = header.split_whitespace().collect();
+    for window in tokens.windows(3) {
+        if let [width, "x", height] = window {
+            let width = width
+                .strip_suffix("mm")
+                .and_then(|value| value.parse().ok());
+            let height = height
+                .strip_suffix("mm")
+                .and_then(|value| value.parse().ok());
+            if let (Some(width), Some(height)) = (width, height) {
+                if width > 0 && height > 0 {
+                    return Some((width, height));
+                }
+            }
+        }
+    }
+
+    if let Some(value) = section_field(section, "Physical size") {
+        let size = value.trim_end_matches("mm").trim();
+        if let Some((width, height)) = size.split_once('x') {
+            if let (Ok(width), Ok(height)) = (width.parse::<u32>(), height.parse::<u32>()) {
+                if width > 0 && height > 0 {
+                    return Some((width, height));
+                }
+            }
+        }
+    }
+
+    extract_edid_hex(section).and_then(|edid| edid_physical_size_mm(&edid))
+}
+
+fn section_dpi(section: &DisplaySection) -> Result<u32, CliError> {
+    let name = &section.name;
+    let mode = parse_modes(section)
+        .into_iter()
+        .find(|mode| mode.current)
+        .ok_or_else(|| format!("current mode not available for display: {name}"))?;
+    let (width_mm, height_mm) = section_physical_size(section)
+        .ok_or_else(|| format!("physical size not available for display: {name}"))?;
+
+    let diagonal_px = f64::from(mode.width).hypot(f64::from(mode.height));
+    let diagonal_in = f64::from(width_mm).hypot(f64::from(height_mm)) / 25.4;
+    Ok((diagonal_px / diagonal_in).round() as u32)
+}
+
+struct OutputProperty {
+    name: String,
+    value: String,
+    constraint: Option<String>,
+}
+
+const FIXED_OUTPUT_FIELDS: [&str; 13] = [
+    "Identifier",
+    "Timestamp",
+    "Subpixel",
+    "Gamma",
+    "Brightness",
+    "Clones",
+    "CRTC",
+    "CRTCs",
+    "Panning",
+    "Tracking",
+    "Border",
+    "Transform",
+    "filter",
+];
+
+fn section_properties(section: &DisplaySection) -> Vec<OutputProperty> {
+    let mut properties: Vec<OutputProperty> = Vec::new();
+    let mut in_property = false;
+    for line in section.lines.iter().skip(1) {
+        if line.starts_with("  ") {
+            break;
+        }
+        let body = match line.strip_prefix('\t') {
+            Some(body) => body,
+            None => continue,
+        };
+        if body.starts_with(char::is_whitespace) {
+            if !in_property {
+                continue;
+            }
+            let property = properties.last_mut().expect("property in progress");
+            let trimmed = body.trim();
+            if trimmed.starts_with("supported:") || trimmed.starts_with("range:") {
+                property.constraint = Some(trimmed.to_string());
+            } else {
+                property.value.push_str(trimmed);
+            }
+            continue;
+        }
+        let (name, value) = match body.split_once(':') {
+            Some(pair) => pair,
+            None => continue,
+        };
+        let name = name.trim();
+        in_property = !FIXED_OUTPUT_FIELDS.contains(&name);
+        if in_property {
+            properties.push(OutputProperty {
+                name: name.to_string(),
+                value: value.trim().to_string(),
+                constraint: None,
+            });
+        }
+    }
+    properties
+}
+
+fn extract_connector_id(section: &DisplaySection) -> Option<String> {
+    section_field(section, "CONNECTOR_ID").map(|value| value.to_string())
+}
+
+fn section_field<'a>(section: &'a DisplaySection, label: &str) -> Option<&'a str> {
+    for line in section.lines.iter().skip(1) {
+        let trimmed = line.trim();
+        let (key, value) = match trimmed.split_once(':') {
+            Some(pair) => pair,
+            None => continue,
+        };
+        if key.trim().eq_ignore_ascii_case(label) {
+            let value = value.trim();
+            if !value.is_empty() {
+                return Some(value);
+            }
+        }
+    }
+    None
+}
+
+fn resolve_monitors_text(provided: Option<String>) -> Result<String, CliError> {
+    if let Some(text) = provided {
+        if text.trim().is_empty() {
+            return Err("monitor text argument is empty".to_string().into());
+        }
+        return Ok(text);
+    }
+
+    let output = Command::new("xrandr")
+        .arg("--listmonitors")
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .output()
+        .map_err(|err| CliError::Backend(format!("failed to run xrandr --listmonitors: {err}")))?;
+
+    if !output.status.success() {
+        return Err(CliError::Backend(
+            "xrandr --listmonitors exited with failure".to_string(),
+        ));
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+struct Provider {
+    index: u32,
+    id: String,
+    name: String,
+    capabilities: Vec<String>,
+    crtcs: u32,
+    outputs: u32,
+}
+
+impl Provider {
+    fn has(&self, capability: &str) -> bool {
+        self.capabilities.iter().any(|cap| cap == capability)
+    }
+
+    fn line(&self) -> String {
+        format!(
+            "{} id={} index={} crtcs={} outputs={} caps={}",
+            self.name,
+            self.id,
+            self.index,
+            self.crtcs,
+            self.outputs,
+            self.capabilities.join(",")
+        )
+    }
+
+    fn json(&self) -> String {
+        let capabilities = self
+            .capabilities
+            .iter()
+            .map(|cap| json_string(cap))
+            .collect::<Vec<_>>()
+            .join(", ");
+        format!(
+            "{{\"name\": {}, \"id\": {}, \"index\": {}, \"crtcs\": {}, \"outputs\": {}, \"capabilities\": [{capabilities}]}}",
+            json_string(&self.name),
+            json_string(&self.id),
+            self.index,
+            self.crtcs,
+            self.outputs
+        )
+    }
+}
+
+fn query_providers() -> Result<Vec<Provider>, CliError> {
+    let output = Command::new("xrandr")
+        .arg("--listproviders")
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .output()
+        .map_err(|err| CliError::Backend(format!("failed to run xrandr --listproviders: {err}")))?;
+    if !output.status.success() {
+        return Err(CliError::Backend(
+            "xrandr --listproviders exited with failure".to_string(),
+        ));
+    }
+    Ok(parse_providers(&String::from_utf8_lossy(&output.stdout)))
+}
+
+fn parse_providers(text: &str) -> Vec<Provider> {
+    text.lines().filter_map(parse_provider_line).collect()
+}
+
+fn parse_provider_line(line: &str) -> Option<Provider> {
+    let rest = line.trim().strip_prefix("Provider ")?;
+    let (index, rest) = rest.split_once(':')?;
+    let rest = rest.trim().strip_prefix("id:")?.trim();
+    let (id, rest) = rest.split_once(' ')?;
+    let rest = rest.trim().strip_prefix("cap:")?.trim();
+    let (caps, rest) = rest.split_once("crtcs:")?;
+    let capabilities = caps
+        .split(',')
+        .skip(1)
+        .map(|cap| cap.trim().to_ascii_lowercase().replace(' ', "-"))
+        .filter(|cap| !cap.is_empty())
+        .collect();
+    let (crtcs, rest) = rest.trim().split_once(' ')?;
+    let rest = rest.trim().strip_prefix("outputs:")?.trim();
+    let (outputs, rest) = rest.split_once(' ')?;
+    let name = rest.split_once("name:")?.1.trim();
+    Some(Provider {
+        index: index.trim().parse().ok()?,
+        id: id.to_string(),
+        name: name.to_string(),
+        capabilities,
+        crtcs: crtcs.parse().ok()?,
+        outputs: outputs.parse().ok()?,
+    })
+}
+
+fn find_provider<'a>(providers: &'a [Provider], selector: &str) -> Result<&'a Provider, CliError> {
+    providers
+        .iter()
+        .find(|provider| {
+            provider.name == selector
+                || provider.id.eq_ignore_ascii_case(selector)
+                || provider.index.to_string() == selector
+        })
+        .ok_or_else(|| format!("provider not found: {selector}").into())
+}
+
+fn run_provider_link(option: &str, provider: &str, other: &str) -> Result<(), CliError> {
+    let providers = query_providers()?;
+    let (needs, other_needs) = match option {
+        "--setprovideroffloadsink" => ("source-offload", "sink-offload"),
+        _ => ("sink-output", "source-output"),
+    };
+    let first = find_provider(&providers, provider)?;
+    if !first.has(needs) {
+        return Err(format!("provider {} does not support {needs}", first.name).into());
+    }
+    let other = if matches!(other, "none" | "0x0" | "NULL") {
+        "0x0".to_string()
+    } else {
+        let second = find_provider(&providers, other)?;
+        if !second.has(other_needs) {
+            return Err(format!("provider {} does not support {other_needs}", second.name).into());
+        }
+        second.id.clone()
+    };
+    run_xrandr_with_args(vec![option.to_string(), first.id.clone(), other])
+}
+
+fn parse_monitor_map(text: &str) -> HashMap<String, String> {
+    let mut lines = text.lines();
+    let mut map = HashMap::new();
+
+    if let Some(first) = lines.next() {
+        if !first.starts_with("Monitors:") {
+            insert_monitor_line(&mut map, first);
+        }
+        for line in lines {
+            insert_monitor_line(&mut map, line);
+        }
+    }
+
+    map
+}
+
+fn insert_monitor_line(map: &mut HashMap<String, String>, line: &str) {
+    let trimmed = line.trim_end();
+    if trimmed.is_empty() {
+        return;
+    }
+    let mut parts = trimmed.split_whitespace();
+    let index_part = match parts.next() {
+        Some(value) => value,
+        None => return,
+    };
+    if let Some(name) = parts.last() {
+        let index_value = index_part.split(':').next().unwrap_or(index_part);
+        map.insert(name.to_string(), index_value.to_string());
+    }
+}
+
+fn section_serial(section: &DisplaySection) -> Result<Option<String>, CliError> {
+    let edid = match checked_edid(section)? {
+        Some(edid) => edid,
+        None => return Ok(None),
+    };
+    let decoded = decode_edid(&edid)?;
+    Ok(find_serial_slice(&decoded).map(|value| value.to_string()))
+}
+
+fn checked_edid(section: &DisplaySection) -> Result<Option<String>, CliError> {
+    let edid = match extract_edid_hex(section) {
+        Some(edid) => edid,
+        None => return Ok(None),
+    };
+    let bytes = hex_to_bytes(&edid)?;
+    if bytes.len() < 128 {
+        return Err(format!("truncated edid: {} bytes", bytes.len()).into());
+    }
+    Ok(Some(edid))
+}
+
+fn section_serial_matches(section: &DisplaySection, serial: &str) -> bool {
+    let edid = match extract_edid_hex(section) {
+        Some(value) => value,
+        None => return false,
+    };
+    let decoded = match decode_edid(&edid) {
+        Ok(value) => value,
+        Err(_) => return false,
+    };
+    find_serial_slice(&decoded) == Some(serial)
+}
+
+fn find_serial_slice(decoded: &str) -> Option<&str> {
+    find_between_quotes(decoded, "Display Product Serial Number:")
+        .or_else(|| find_after_colon(decoded, "Serial Number:"))
+        .or_else(|| find_between_quotes(decoded, "Alphanumeric Data String:"))
+}
+
+fn find_between_quotes<'a>(decoded: &'a str, label: &str) -> Option<&'a str> {
+    for line in decoded.lines() {
+        if let Some(value) = extract_between_quotes(line, label) {
+            if !value.is_empty() {
+                return Some(value);
+            }
+        }
+    }
+    None
+}
+
+fn find_after_colon<'a>(decoded: &'a str, label: &str) -> Option<&'a str> {
+    for line in decoded.lines() {
+        if let Some(value) = extract_after_colon(line, label) {
+            if !value.is_empty() {
+                return Some(value);
+            }
+        }
+    }
+    None
+}
+
+fn monitor_serial_has_displays(
+    sections: &[DisplaySection],
+    serial: &str,
+    connected_only: bool,
+) -> bool {
+    sections.iter().any(|section| {
+        if connected_only && section.state != DisplayState::Connected {
+            return false;
+        }
+        section_serial_matches(section, serial)
+    })
+}
+
+fn monitor_serial_get_displays(
+    sections: &[DisplaySection],
+    serial: &str,
+    connected_only: bool,
+) -> Vec<String> {
+    let mut matches = Vec::new();
+    for section in sections {
+        if connected_only && section.state != DisplayState::Connected {
+            continue;
+        }
+        if section_serial_matches(section, serial) {
+            matches.push(section.name.clone());
+        }
+    }
+    matches
+}
+
+fn decode_edid(hex: &str) -> Result<String, CliError> {
+    let bytes = hex_to_bytes(hex)?;
+    let mut child = Command::new("edid-decode")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .map_err(|err| format!("failed to run edid-decode: {err}"))?;
+
+    if let Some(stdin) = child.stdin.as_mut() {
+        stdin
+            .write_all(&bytes)
+            .map_err(|err| format!("failed to write edid to decoder: {err}"))?;
+    } else {
+        return Err("failed to open edid-decode stdin".to_string().into());
+    }
+
+    let output = child
+        .wait_with_output()
+        .map_err(|err| format!("failed to read edid-decode output: {err}"))?;
+
+    if !output.status.success() {
+        return Err("edid-decode exited with failure".to_string().into());
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+fn hex_to_bytes(hex: &str) -> Result<Vec<u8>, CliError> {
+    let mut bytes = Vec::new();
+    let chars: Vec<char> = hex.chars().filter(|ch| !ch.is_ascii_whitespace()).collect();
+
+    if !chars.len().is_multiple_of(2) {
+        return Err("edid hex length is not even".to_string().into());
+    }
+
+    let mut index = 0;
+    while index < chars.len() {
+        let hi = chars[index];
+        let lo = chars[index + 1];
+        let value =
+            hex_pair_to_byte(hi, lo).ok_or_else(|| format!("invalid hex pair: {hi}{lo}"))?;
+        bytes.push(value);
+        index += 2;
+    }
+
+    Ok(bytes)
+}
+
+fn hex_pair_to_byte(hi: char, lo: char) -> Option<u8> {
+    let high = hi.to_digit(16)? as u8;
+    let low = lo.to_digit(16)? as u8;
+    Some((high << 4) | low)
+}
+
+fn extract_serial(decoded: &str) -> Option<String> {
+    find_serial_slice(decoded).map(|value| value.to_string())
+}
+
+fn extract_between_quotes<'a>(line: &'a str, label: &str) -> Option<&'a str> {
+    if !line.contains(label) {
+        return None;
+    }
+    let start = line.find('\'')?;
+    let end = line[start + 1..].find('\'')?;
+    Some(line[start + 1..start + 1 + end].trim())
+}
+
+fn extract_after_colon<'a>(line: &'a str, label: &str) -> Option<&'a str> {
+    if !line.contains(label) {
+        return None;
+    }
+    let idx = line.find(':')?;
+    Some(line[idx + 1..].trim())
+}
+
+enum Preset {
+    Command(Vec<String>),
+    Layout(Layout),
+}
+
+fn read_user_config() -> Result<String, CliError> {
+    let path = xdg_dir("XDG_CONFIG_HOME", ".config")?
+        .join("xrandr-utils")
+        .join("config.toml");
+    std::fs::read_to_string(&path)
+        .map_err(|err| format!("failed to read {}: {err}", path.display()).into())
+}
+
+fn read_optional_user_config() -> Result<Option<String>, CliError> {
+    let path = xdg_dir("XDG_CONFIG_HOME", ".config")?
+        .join("xrandr-utils")
+        .join("config.toml");
+    match std::fs::read_to_string(&path) {
+        Ok(text) => Ok(Some(text)),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(format!("failed to read {}: {err}", path.display()).into()),
+    }
+}
+
+#[derive(Default)]
+struct OutputOverride {
+    selector: String,
+    rotation: Option<String>,
+    primary: bool,
+    edge: Option<RowEdge>,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum RowEdge {
+    Left,
+    Right,
+}
+
+fn output_overrides(entries: &[ConfigEntry]) -> Result<Vec<OutputOverride>, CliError> {
+    let mut overrides: Vec<OutputOverride> = Vec::new();
+    for entry in entries {
+        let Some(selector) = entry.table.strip_prefix("outputs.") else {
+            continue;
+        };
+        identity_selector(selector)?;
+        let index = match overrides
+            .iter()
+            .position(|output| output.selector == selector)
+        {
+            Some(index) => index,
+            None => {
+                overrides.push(OutputOverride {
+                    selector: selector.to_string(),
+                    ..OutputOverride::default()
+                });
+                overrides.len() - 1
+            }
+        };
+        let output = &mut overrides[index];
+        match entry.key.as_str() {
+            "rotate" if is_rotation(&entry.value) => output.rotation = Some(entry.value.clone()),
+            "rotate" => {
+                return Err(format!(
+                "invalid rotation for {selector}: {} (expected normal, left, right or inverted)",
+                entry.value
+            )
+                .into())
+            }
+            "primary" => output.primary = parse_config_bool(entry)?,
+            "position" => {
+                output.edge = match entry.value.as_str() {
+                    "left" => Some(RowEdge::Left),
+                    "right" => Some(RowEdge::Right),
+                    other => {
+                        return Err(format!(
+                            "invalid position for {selector}: {other} (expected left or right)"
+                        )
+                        .into())
+                    }
+                }
+            }
+            other => return Err(format!("unknown key in [outputs.{selector}]: {other}").into()),
+        }
+    }
+    Ok(overrides)
+}
+
+fn apply_output_overrides(
+    overrides: &[OutputOverride],
+    sections: &[DisplaySection],
+    displays: &mut [String],
+    primary: &mut usize,
+    flags: &mut ApplyFlags,
+) -> Result<(), CliError> {
+    let selectors: Vec<(IdentityField, &str, &OutputOverride)> = overrides
+        .iter()
+        .map(|output| {
+            identity_selector(&output.selector).map(|(field, wanted)| (field, wanted, output))
+        })
+        .collect::<Result<_, _>>()?;
+    let mut matched: HashMap<String, &OutputOverride> = HashMap::new();
+    for display in displays.iter() {
+        let Some(identity) = find_section(sections, display).and_then(section_identity) else {
+            continue;
+        };
+        for (field, wanted, output) in &selectors {
+            if field(&identity) == *wanted {
+                matched.entry(display.clone()).or_insert(output);
+            }
+        }
+    }
+
+    let mut primary_name = displays[*primary].clone();
+    let pinned: Vec<&String> = displays
+        .iter()
+        .filter(|display| matched.get(*display).is_some_and(|output| output.primary))
+        .collect();
+    match pinned[..] {
+        [] => {}
+        [name] => primary_name = name.clone(),
+        _ => {
+            return Err(format!(
+                "config pins more than one primary display: {}",
+                pinned
+                    .iter()
+                    .map(|name| name.as_str())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            )
+            .into())
+        }
+    }
+    displays.sort_by_key(
+        |display| match matched.get(display).and_then(|output| output.edge) {
+            Some(RowEdge::Left) => 0,
+            None => 1,
+            Some(RowEdge::Right) => 2,
+        },
+    );
+    *primary = displays
+        .iter()
+        .position(|display| *display == primary_name)
+        .unwrap_or(0);
+    for (display, output) in matched {
+        if let Some(rotation) = &output.rotation {
+            flags.display_rotations.push((display, rotation.clone()));
+        }
+    }
+    Ok(())
+}
+
+fn preset_names(entries: &[ConfigEntry]) -> Vec<String> {
+    let mut names = Vec::new();
+    for entry in entries {
+        let name = if entry.table == "presets" {
+            entry.key.clone()
+        } else if let Some(rest) = entry.table.strip_prefix("presets.") {
+            rest.split('.').next().unwrap_or(rest).to_string()
+        } else {
+            continue;
+        };
+        if !names.contains(&name) {
+            names.push(name);
+        }
+    }
+    names
+}
+
+fn find_preset(entries: Vec<ConfigEntry>, name: &str) -> Result<Preset, CliError> {
+    let prefix = format!("presets.{name}");
+    let mut layout_entries = Vec::new();
+    for entry in entries {
+        if entry.table == "presets" && entry.key == name {
+            let words = split_command_words(&entry.value)?;
+            if words.is_empty() {
+                return Err(format!("preset {name} is empty").into());
+            }
+            return Ok(Preset::Command(words));
+        }
+        let table = if entry.table == prefix {
+            String::new()
+        } else if let Some(rest) = entry.table.strip_prefix(&format!("{prefix}.")) {
+            rest.to_string()
+        } else {
+            continue;
+        };
+        layout_entries.push(ConfigEntry { table, ..entry });
     }
-    args
+    if layout_entries.is_empty() {
+        return Err(format!("preset not found: {name}").into());
+    }
+    parse_layout_entries(layout_entries)
+        .map(Preset::Layout)
+        .map_err(|err| format!("preset {name}: {err}").into())
 }
 
-fn run_xrandr_with_args(args: Vec<String>) -> Result<(), String> {
-    let status = Command::new("xrandr")
-        .args(&args)
-        .status()
-        .map_err(|err| format!("failed to run xrandr: {err}"))?;
-
-    if !status.success() {
-        return Err(format!("xrandr command failed: {status}"));
+fn split_command_words(text: &str) -> Result<Vec<String>, CliError> {
+    let mut words = Vec::new();
+    let mut word = String::new();
+    let mut in_word = false;
+    let mut quote: Option<char> = None;
+    for ch in text.chars() {
+        match (quote, ch) {
+            (Some(open), _) if open == ch => quote = None,
+            (Some(_), _) => word.push(ch),
+            (None, '"' | '\'') => {
+                quote = Some(ch);
+                in_word = true;
+            }
+            (None, _) if ch.is_whitespace() => {
+                if in_word {
+                    words.push(std::mem::take(&mut word));
+                    in_word = false;
+                }
+            }
+            (None, _) => {
+                word.push(ch);
+                in_word = true;
+            }
+        }
     }
-
-    Ok(())
+    if quote.is_some() {
+        return Err(format!("unterminated quote in: {text}").into());
+    }
+    if in_word {
+        words.push(word);
+    }
+    Ok(words)
 }
 
-fn expect_arg(args: &mut impl Iterator<Item = String>, name: &str) -> Result<String, String> {
-    args.next()
-        .ok_or_else(|| format!("missing argument: {name}"))
+struct ConfigEntry {
+    table: String,
+    key: String,
+    value: String,
 }
 
-fn get_verbose_text() -> Result<String, String> {
-    let mut stdin = io::stdin();
-    if !stdin.is_terminal() {
-        let mut buf = String::new();
-        stdin
-            .read_to_string(&mut buf)
-            .map_err(|err| format!("failed to read stdin: {err}"))?;
-        if buf.trim().is_empty() {
-            return Err("stdin supplied but empty".to_string());
+fn parse_config(text: &str) -> Result<Vec<ConfigEntry>, CliError> {
+    let mut entries = Vec::new();
+    let mut table = String::new();
+
+    for (index, raw) in text.lines().enumerate() {
+        let line = strip_config_comment(raw).trim();
+        if line.is_empty() {
+            continue;
         }
-        Ok(buf)
-    } else {
-        let output = Command::new("xrandr")
-            .arg("--verbose")
-            .stdout(Stdio::piped())
-            .stderr(Stdio::null())
-            .output()
-            .map_err(|err| format!("failed to run xrandr --verbose: {err}"))?;
-        if !output.status.success() {
-            return Err("xrandr --verbose exited with failure".to_string());
+        if let Some(header) = line
+            .strip_prefix('[')
+            .and_then(|rest| rest.strip_suffix(']'))
+        {
+            table = header.trim().replace('"', "");
+            continue;
         }
-        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+        let (key, value) = line
+            .split_once('=')
+            .ok_or_else(|| format!("line {}: expected key = value", index + 1))?;
+        entries.push(ConfigEntry {
+            table: table.clone(),
+            key: unquote_config_value(key.trim()).to_string(),
+            value: unquote_config_value(value.trim()).to_string(),
+        });
     }
-}
 
-#[derive(Clone, Copy, PartialEq)]
-enum DisplayState {
-    Connected,
-    Disconnected,
+    Ok(entries)
 }
 
-impl DisplayState {
-    fn as_str(self) -> &'static str {
-        match self {
-            DisplayState::Connected => "connected",
-            DisplayState::Disconnected => "disconnected",
+fn strip_config_comment(line: &str) -> &str {
+    let mut quote: Option<char> = None;
+    for (index, ch) in line.char_indices() {
+        match (quote, ch) {
+            (None, '"' | '\'') => quote = Some(ch),
+            (Some(open), _) if open == ch => quote = None,
+            (None, '#') => return &line[..index],
+            _ => {}
         }
     }
+    line
 }
 
-struct DisplaySection {
-    name: String,
-    state: DisplayState,
-    primary: bool,
-    geometry: Option<String>,
-    lines: Vec<String>,
+fn unquote_config_value(value: &str) -> &str {
+    for quote in ['"', '\''] {
+        if let Some(inner) = value
+            .strip_prefix(quote)
+            .and_then(|rest| rest.strip_suffix(quote))
+        {
+            return inner;
+        }
+    }
+    value
 }
 
-fn parse_sections(verbose: &str) -> Vec<DisplaySection> {
-    let mut sections = Vec::new();
-    let mut current: Option<DisplaySection> = None;
+fn parse_config_bool(entry: &ConfigEntry) -> Result<bool, CliError> {
+    match entry.value.as_str() {
+        "true" => Ok(true),
+        "false" => Ok(false),
+        _ => Err(format!("expected true or false for {}: {}", entry.key, entry.value).into()),
+    }
+}
 
-    for line in verbose.lines() {
-        if let Some(header) = parse_header(line) {
-            if let Some(section) = current.take() {
-                sections.push(section);
-            }
-            current = Some(DisplaySection {
-                name: header.name,
-                state: header.state,
-                primary: header.primary,
-                geometry: header.geometry,
-                lines: vec![line.to_string()],
-            });
-        } else if let Some(section) = current.as_mut() {
-            section.lines.push(line.to_string());
+fn evaluate_assertion(expr: &str, sections: &[DisplaySection]) -> Result<bool, CliError> {
+    for op in ["==", "!=", ">=", "<=", ">", "<"] {
+        if let Some((lhs, rhs)) = expr.split_once(op) {
+            let lhs = resolve_assert_value(lhs.trim(), sections)?;
+            let rhs = resolve_assert_value(rhs.trim(), sections)?;
+            return compare_assert_values(&lhs, op, &rhs);
         }
     }
 
-    if let Some(section) = current {
-        sections.push(section);
+    match resolve_assert_value(expr.trim(), sections)?.as_str() {
+        "true" => Ok(true),
+        "false" => Ok(false),
+        _ => Err(CliError::Usage(format!(
+            "expression is not a predicate: {expr}"
+        ))),
     }
-
-    sections
-}
-
-#[derive(Default)]
-struct MapFlags {
-    filtered: bool,
-    keys: bool,
-    values: bool,
 }
 
-fn parse_display_names_flags(args: &mut impl Iterator<Item = String>) -> Result<bool, String> {
-    let mut connected_only = false;
-    for arg in args {
-        match arg.as_str() {
-            "--connected" => connected_only = true,
-            _ => return Err(format!("unknown option: {arg}")),
+fn select_display<'a>(
+    sections: &'a [DisplaySection],
+    selector: &str,
+) -> Result<&'a DisplaySection, CliError> {
+    match selector {
+        "primary" => sections
+            .iter()
+            .find(|section| section.primary)
+            .ok_or_else(|| CliError::NotFound("no primary display".to_string())),
+        "internal" => sections
+            .iter()
+            .find(|section| {
+                section.state == DisplayState::Connected && is_internal_panel(&section.name)
+            })
+            .ok_or_else(|| CliError::NotFound("no connected internal display".to_string())),
+        _ => {
+            let name = resolve_display_name(sections, selector)?;
+            find_section(sections, &name).ok_or_else(|| display_not_found(sections, &name))
         }
     }
-    Ok(connected_only)
 }
 
-fn parse_monitor_serial_args(
-    args: &mut impl Iterator<Item = String>,
-) -> Result<(String, bool), String> {
-    let mut serial: Option<String> = None;
-    let mut connected_only = false;
-
-    for arg in args {
-        if arg == "--connected" {
-            connected_only = true;
-            continue;
+fn describe_fields(section: &DisplaySection) -> Vec<(String, String)> {
+    let mut fields = Vec::new();
+    let mut push = |key: &str, value: String| fields.push((key.to_string(), value));
+    push("name", section.name.clone());
+    push("state", section.state.as_str().to_string());
+    push("primary", section.primary.to_string());
+    push("active", section.geometry.is_some().to_string());
+    if let Some(geometry) = &section.geometry {
+        push("geometry", geometry.clone());
+        if let Some(parsed) = parse_geometry(geometry) {
+            push("geometry.width", parsed.width.to_string());
+            push("geometry.height", parsed.height.to_string());
+            push("geometry.x", parsed.x.to_string());
+            push("geometry.y", parsed.y.to_string());
         }
-        if serial.is_none() {
-            serial = Some(arg);
-        } else {
-            return Err(format!("unexpected argument: {arg}"));
+    }
+    if let Some(rotation) = &section.rotation {
+        push("rotation", rotation.clone());
+    }
+    if let Some(reflection) = section_reflection(section) {
+        push("reflection", reflection.to_string());
+    }
+    let modes = parse_modes(section);
+    for (key, mode) in [
+        ("mode", modes.iter().find(|mode| mode.current)),
+        ("preferred", modes.iter().find(|mode| mode.preferred)),
+    ] {
+        if let Some(mode) = mode {
+            push(key, mode_label(mode));
+            push(&format!("{key}.width"), mode.width.to_string());
+            push(&format!("{key}.height"), mode.height.to_string());
+            push(&format!("{key}.refresh"), format!("{:.2}", mode.refresh));
         }
     }
-
-    let serial = serial.ok_or_else(|| "missing argument: serial".to_string())?;
-    Ok((serial, connected_only))
-}
-
-fn parse_map_flags<I>(
-    args: &mut std::iter::Peekable<I>,
-    _allow_transposed: bool,
-) -> Result<MapFlags, String>
-where
-    I: Iterator<Item = String>,
-{
-    let mut flags = MapFlags::default();
-    while let Some(arg) = args.peek() {
-        if !arg.starts_with("--") {
-            break;
+    if let Some((width, height)) = section_physical_size(section) {
+        push("physical_size", format!("{width}x{height}"));
+        push("physical_size.width_mm", width.to_string());
+        push("physical_size.height_mm", height.to_string());
+    }
+    if let Ok(dpi) = section_dpi(section) {
+        push("dpi", dpi.to_string());
+    }
+    if let Some(brightness) = section_brightness(section) {
+        push("brightness", format!("{brightness:.2}"));
+    }
+    if let Some(gamma) = section_gamma(section) {
+        push("gamma", gamma);
+    }
+    if let Some(crtc) = section_field(section, "CRTC") {
+        push("crtc", crtc.to_string());
+    }
+    if let Some(connector) = extract_connector_id(section) {
+        push("connector_id", connector);
+    }
+    if let Some(edid) = extract_edid_hex(section) {
+        if let Some(id) = edid_manufacturer_id(&edid) {
+            push(
+                "manufacturer",
+                pnp_vendor_name(&id)
+                    .map(str::to_string)
+                    .unwrap_or(id.clone()),
+            );
+            push("manufacturer_id", id);
         }
-        let arg = args.next().expect("peeked value must exist");
-        match arg.as_str() {
-            "--filtered" => flags.filtered = true,
-            "--keys" => {
-                if flags.values {
-                    return Err("cannot combine --keys with --values".to_string());
-                }
-                flags.keys = true;
-            }
-            "--values" => {
-                if flags.keys {
-                    return Err("cannot combine --keys with --values".to_string());
-                }
-                flags.values = true;
-            }
-            _ => return Err(format!("unknown option: {arg}")),
+        if let Some(model) = edid_model_name(&edid) {
+            push("model", model);
         }
+        if let Some(serial) = edid_serial(&edid) {
+            push("serial", serial);
+        }
+        push("edid_hash", edid_hash(&edid));
+        push("edid", edid);
     }
-    Ok(flags)
+    for property in section_properties(section) {
+        if property.name != "EDID" {
+            push(&format!("property.{}", property.name), property.value);
+        }
+    }
+    fields
 }
 
-fn should_skip_map_value(value: &str, flags: &MapFlags) -> bool {
-    if !(flags.filtered || flags.values) {
-        return false;
+fn resolve_get_path(
+    sections: &[DisplaySection],
+    path: &str,
+) -> Result<Vec<(Option<String>, String)>, CliError> {
+    let (selector, field) = match path.split_once('.') {
+        Some((selector, field)) => (selector, Some(field)),
+        None => (path, None),
+    };
+    let section = select_display(sections, selector)?;
+    let fields = describe_fields(section);
+    let field = match field {
+        Some(field) => field,
+        None => {
+            return Ok(fields
+                .into_iter()
+                .map(|(key, value)| (Some(key), value))
+                .collect())
+        }
+    };
+
+    if let Some((_, value)) = fields.iter().find(|(key, _)| key == field) {
+        return Ok(vec![(None, value.clone())]);
     }
-    value.trim().is_empty()
+    let prefix = format!("{field}.");
+    let children: Vec<(Option<String>, String)> = fields
+        .into_iter()
+        .filter_map(|(key, value)| {
+            key.strip_prefix(&prefix)
+                .map(|child| (Some(child.to_string()), value))
+        })
+        .collect();
+    if children.is_empty() {
+        return Err(format!("unknown field for display {}: {field}", section.name).into());
+    }
+    Ok(children)
 }
 
-fn output_map_entry(name: &str, value: &str, flags: &MapFlags, seen_values: &mut HashSet<String>) {
-    if should_skip_map_value(value, flags) {
-        return;
+fn run_set_path(
+    path: &str,
+    value: &str,
+    sections: &[DisplaySection],
+    backend: Backend,
+) -> Result<(), CliError> {
+    let (selector, field) = path
+        .split_once('.')
+        .ok_or_else(|| format!("missing field in path: {path}"))?;
+    let display = select_display(sections, selector)?.name.clone();
+    if let Some(name) = field.strip_prefix("property.") {
+        return run_display_property_set(&display, name, value, sections, backend);
     }
-
-    if flags.keys {
-        println!("{name}");
-    } else if flags.values {
-        if seen_values.insert(value.to_string()) {
-            println!("{value}");
-        }
-    } else {
-        println!("{name}={value}");
+    match field {
+        "primary" => match value {
+            "true" => run_set_primary(&display, sections, backend),
+            "false" => run_unset_primary(&display, sections, backend),
+            _ => Err(format!("invalid value for primary: {value} (expected true or false)").into()),
+        },
+        "rotation" => run_display_rotate(&display, value, sections, backend),
+        "brightness" => run_display_brightness_set(&display, value, sections, backend),
+        "gamma" => run_display_gamma_set(&display, value, sections, backend),
+        "mode" => run_display_mode_set(&display, value, sections, backend),
+        "position" => run_display_position_set(&display, value, sections, backend),
+        _ => Err(format!(
+            "field is not settable: {field} (expected primary, rotation, brightness, gamma, mode, position or property.<name>)"
+        ).into()),
     }
 }
 
-struct HeaderInfo {
-    name: String,
-    state: DisplayState,
-    primary: bool,
-    geometry: Option<String>,
+fn run_unset_primary(
+    display: &str,
+    sections: &[DisplaySection],
+    backend: Backend,
+) -> Result<(), CliError> {
+    if backend == Backend::Wlr {
+        return Err("primary is not supported by the wlr backend"
+            .to_string()
+            .into());
+    }
+    let section =
+        find_section(sections, display).ok_or_else(|| display_not_found(sections, display))?;
+    if !section.primary {
+        return Ok(());
+    }
+    run_layout_change(sections, backend, vec!["--noprimary".to_string()])
 }
 
-fn parse_header(line: &str) -> Option<HeaderInfo> {
-    let mut parts = line.split_whitespace();
-    let name = parts.next()?;
-    let state_word = parts.next()?;
-
-    let state = match state_word {
-        "connected" => DisplayState::Connected,
-        "disconnected" => DisplayState::Disconnected,
-        _ => return None,
+fn run_display_mode_set(
+    display: &str,
+    value: &str,
+    sections: &[DisplaySection],
+    backend: Backend,
+) -> Result<(), CliError> {
+    let section =
+        find_section(sections, display).ok_or_else(|| display_not_found(sections, display))?;
+    let (size, rate) = match value.split_once('@') {
+        Some((size, rate)) => {
+            let rate = rate
+                .parse::<f64>()
+                .ok()
+                .filter(|rate| *rate > 0.0)
+                .ok_or_else(|| format!("invalid rate: {rate}"))?;
+            (size, Some(rate))
+        }
+        None => (value, None),
+    };
+    if !size.contains('x') || size.starts_with("<=") {
+        return Err(format!("invalid mode: {value} (expected WxH[@Hz])").into());
+    }
+    let (width, height) = resolve_layout_resolution(section, backend, size)?;
+    let rate = match rate {
+        Some(rate) => Some(
+            select_mode_rate(section, width, height, Some(&RateChoice::Fixed(rate)))
+                .ok_or_else(|| format!("no refresh rates available for display: {display}"))?,
+        ),
+        None => None,
     };
 
-    let mut primary = false;
-    let mut geometry = None;
+    let mut args = vec![
+        "--output".to_string(),
+        display.to_string(),
+        "--mode".to_string(),
+    ];
+    match (backend, rate) {
+        (Backend::Xrandr, Some(rate)) => {
+            args.push(format!("{width}x{height}"));
+            args.push("--rate".to_string());
+            args.push(format!("{rate:.2}"));
+        }
+        (Backend::Wlr, Some(rate)) => args.push(format!("{width}x{height}@{rate:.3}")),
+        (_, None) => args.push(format!("{width}x{height}")),
+    }
+    run_layout_change(sections, backend, args)
+}
 
-    for token in parts {
-        if token == "primary" {
-            primary = true;
-        } else if geometry.is_none() && is_geometry_token(token) {
-            geometry = Some(token.to_string());
+fn run_reset(sections: &[DisplaySection], backend: Backend) -> Result<(), CliError> {
+    let mut args = Vec::new();
+    for section in sections {
+        args.push("--output".to_string());
+        args.push(section.name.clone());
+        if section.state != DisplayState::Connected {
+            args.push("--off".to_string());
+            continue;
         }
+        let reset: &[&str] = match backend {
+            Backend::Xrandr => &[
+                "--auto",
+                "--rotate",
+                "normal",
+                "--reflect",
+                "normal",
+                "--transform",
+                "none",
+                "--scale",
+                "1x1",
+            ],
+            Backend::Wlr => &["--on", "--transform", "normal", "--scale", "1"],
+        };
+        args.extend(reset.iter().map(|arg| arg.to_string()));
+    }
+    if !sections
+        .iter()
+        .any(|section| section.state == DisplayState::Connected)
+    {
+        return Err("no connected displays".to_string().into());
     }
+    run_layout_change(sections, backend, args)
+}
 
-    Some(HeaderInfo {
-        name: name.to_string(),
-        state,
-        primary,
-        geometry,
-    })
+fn run_display_off(
+    display: &str,
+    sections: &[DisplaySection],
+    backend: Backend,
+    force: bool,
+) -> Result<(), CliError> {
+    let section =
+        find_section(sections, display).ok_or_else(|| display_not_found(sections, display))?;
+    let Some(geometry) = section.geometry.as_deref().and_then(parse_geometry) else {
+        return Ok(());
+    };
+    if !force
+        && !sections
+            .iter()
+            .any(|other| other.name != display && other.geometry.is_some())
+    {
+        return Err(LAST_ACTIVE_DISPLAY_ERROR.to_string().into());
+    }
+    check_remote_session(force)?;
+    apply_layout_change(sections, backend, build_off_args(&[display.to_string()]))?;
+    if is_dry_run() {
+        return Ok(());
+    }
+    let state = StateDir::lock()?;
+    let mut positions = parse_saved_positions(&state.read("positions")?.unwrap_or_default());
+    set_saved_position(&mut positions, display, (geometry.x, geometry.y));
+    state.write("positions", &saved_positions_text(&positions))
 }
 
-fn is_geometry_token(token: &str) -> bool {
-    let bytes = token.as_bytes();
-    let len = bytes.len();
-    if len == 0 {
-        return false;
+fn run_display_on(
+    display: &str,
+    sections: &[DisplaySection],
+    backend: Backend,
+) -> Result<(), CliError> {
+    let section =
+        find_section(sections, display).ok_or_else(|| display_not_found(sections, display))?;
+    if section.state != DisplayState::Connected {
+        return Err(format!("display not connected: {display}").into());
     }
+    if section.geometry.is_some() {
+        return Ok(());
+    }
+    let saved = parse_saved_positions(&read_state("positions")?.unwrap_or_default())
+        .into_iter()
+        .find(|(name, _)| name == display)
+        .map(|(_, position)| position);
+    let (x, y) = saved.unwrap_or_else(|| {
+        let right_edge = sections
+            .iter()
+            .filter_map(|other| other.geometry.as_deref().and_then(parse_geometry))
+            .map(|geometry| geometry.x + geometry.width as i32)
+            .max()
+            .unwrap_or(0);
+        (right_edge, 0)
+    });
+    let (enable, position) = match backend {
+        Backend::Xrandr => ("--auto", format!("{x}x{y}")),
+        Backend::Wlr => ("--on", format!("{x},{y}")),
+    };
+    run_layout_change(
+        sections,
+        backend,
+        vec![
+            "--output".to_string(),
+            display.to_string(),
+            enable.to_string(),
+            "--pos".to_string(),
+            position,
+        ],
+    )?;
+    if saved.is_some() && !is_dry_run() {
+        let state = StateDir::lock()?;
+        let mut positions = parse_saved_positions(&state.read("positions")?.unwrap_or_default());
+        positions.retain(|(name, _)| name != display);
+        state.write("positions", &saved_positions_text(&positions))?;
+    }
+    Ok(())
+}
 
-    let mut index = 0;
-    match consume_digits(bytes, index) {
-        Some(next) => index = next,
-        None => return false,
+fn run_display_position_set(
+    display: &str,
+    value: &str,
+    sections: &[DisplaySection],
+    backend: Backend,
+) -> Result<(), CliError> {
+    let section =
+        find_section(sections, display).ok_or_else(|| display_not_found(sections, display))?;
+    if section.geometry.is_none() {
+        return Err(format!("display not active: {display}").into());
     }
-    if index >= len || bytes[index] != b'x' {
-        return false;
+    let (x, y) =
+        parse_position(value).ok_or_else(|| format!("invalid position: {value} (expected XxY)"))?;
+    let position = match backend {
+        Backend::Xrandr => format!("{x}x{y}"),
+        Backend::Wlr => format!("{x},{y}"),
+    };
+    run_layout_change(
+        sections,
+        backend,
+        vec![
+            "--output".to_string(),
+            display.to_string(),
+            "--pos".to_string(),
+            position,
+        ],
+    )
+}
+
+fn resolve_assert_value(term: &str, sections: &[DisplaySection]) -> Result<String, CliError> {
+    if term.is_empty() {
+        return Err(CliError::Usage("missing operand in expression".to_string()));
     }
-    index += 1;
-    match consume_digits(bytes, index) {
-        Some(next) => index = next,
-        None => return false,
+    if term == "primary" {
+        let primary = sections.iter().find(|section| section.primary);
+        return Ok(primary
+            .map(|section| section.name.clone())
+            .unwrap_or_default());
     }
 
-    match consume_signed_number(bytes, index) {
-        Some(next) => index = next,
-        None => return false,
+    let (function, arg) = match term.strip_suffix(')').and_then(|rest| rest.split_once('(')) {
+        Some((function, arg)) => (function.trim(), arg.trim()),
+        None => return Ok(term.to_string()),
+    };
+
+    let section = find_section(sections, arg);
+    let value = match function {
+        "connected" => section
+            .is_some_and(|section| section.state == DisplayState::Connected)
+            .to_string(),
+        "disconnected" => section
+            .is_some_and(|section| section.state == DisplayState::Disconnected)
+            .to_string(),
+        "state" => section
+            .map(|section| section.state.as_str().to_string())
+            .unwrap_or_default(),
+        "geometry" => section
+            .and_then(|section| section.geometry.clone())
+            .unwrap_or_default(),
+        "resolution" => section
+            .and_then(|section| section.geometry.as_deref())
+            .and_then(|geometry| geometry.split(['+', '-']).next())
+            .unwrap_or_default()
+            .to_string(),
+        "count" => {
+            let count = match arg {
+                "all" => sections.len(),
+                "connected" => sections
+                    .iter()
+                    .filter(|section| section.state == DisplayState::Connected)
+                    .count(),
+                "disconnected" => sections
+                    .iter()
+                    .filter(|section| section.state == DisplayState::Disconnected)
+                    .count(),
+                _ => return Err(CliError::Usage(format!("unknown count filter: {arg}"))),
+            };
+            count.to_string()
+        }
+        _ => return Err(CliError::Usage(format!("unknown function: {function}"))),
+    };
+    Ok(value)
+}
+
+fn compare_assert_values(lhs: &str, op: &str, rhs: &str) -> Result<bool, CliError> {
+    if let (Ok(left), Ok(right)) = (lhs.parse::<i64>(), rhs.parse::<i64>()) {
+        return Ok(match op {
+            "==" => left == right,
+            "!=" => left != right,
+            ">=" => left >= right,
+            "<=" => left <= right,
+            ">" => left > right,
+            _ => left < right,
+        });
     }
-    match consume_signed_number(bytes, index) {
-        Some(next) => index = next,
-        None => return false,
+
+    match op {
+        "==" => Ok(lhs == rhs),
+        "!=" => Ok(lhs != rhs),
+        _ => Err(CliError::Usage(format!(
+            "operator {op} requires numeric operands"
+        ))),
     }
+}
 
-    index == len
+fn print_usage() {
+    println!(
+        "Usage: xrandr-utils <command> [args]\n\n\
+Commands:\n  \
+display_connected <display>\n  \
+display_connected_map [map options]\n  \
+display_state <display>  (active, connected or disconnected)\n  \
+display_state_map [map options]\n  \
+display_uptime_map [map options]  (recorded by watch and serve)\n  \
+display_uptime_metrics  (the same records in the Prometheus text format)\n  \
+display_section <display>\n  \
+display_section_map [map options]\n  \
+display_edid <display> [--group 2|16|32] [--uppercase]\n  \
+display_edid_decoded <display>\n  \
+display_edid_info <display> [--json]\n  \
+install_service [--serve] [--systemd-user] [--print] [-- <watch options...>]  (write a user unit for watch, or with --serve a socket unit answering serve queries; --systemd-user also enables and starts it)\n  \
+on_change [--interval <seconds>] [--timeout <seconds>] -- <command...>\n  \
+display_edid_validate <display>\n  \
+display_edid_dump <display> <path|-> [--extensions-only]\n  \
+watch [--interval <seconds>] [--json-stream] [--events <list>] [--match <display>]\n    \
+      [--hook <command>] [--debounce <seconds>] [--rate-limit <seconds>]\n    \
+      [--property <name>]... [--retrain-link]\n    \
+      [--color-temp [<display>=]<day>:<night>... (--night HH:MM-HH:MM | --location <lat>,<lon>) [--transition <seconds>]]\n  \
+display_serial <display>\n  \
+display_serial_map [map options]\n  \
+monitor_serial_has_displays <serial> [--connected]\n  \
+monitor_serial_get_displays <serial> [--connected]\n  \
+display_rotation <display>\n  \
+display_rotation_map [map options]\n  \
+display_brightness <display>\n  \
+display_brightness_set <display> <value|+step|-step>\n  \
+dpms <on|off|standby|status> [--output <display>]\n  \
+undo [apply options]  (restore the layout before the last change)\n  \
+state save <file>\n  \
+state restore <file> [apply options]\n  \
+export script [--layout-only]  (print a standalone sh script reproducing the current layout, gamma and properties, or only the layout)\n  \
+support_bundle <file.tar.gz>  (archive dumps, EDIDs, redacted config and recent history)\n  \
+history list\n  \
+history apply <n> [apply options]\n  \
+reset  (re-enable every connected display with default transforms)\n  \
+display_off <display> [--force]  (--force allows turning off the last active display, and doing so over SSH)\n  \
+display_on <display>  (restores the position saved by display_off)\n  \
+backlight get <display>\n  \
+backlight set <display> <percent|+step|-step> [--install-udev-rule]\n  \
+connector_alias_map [map options] [--json]\n  \
+display_crtc <display>\n  \
+display_crtc_map [map options]\n  \
+display_transform <display>\n  \
+display_scale <display> <factor|XxY>\n  \
+display_properties <display>\n  \
+display_property <display> <name>\n  \
+display_property_set <display> <name> <value>\n  \
+display_gamma <display>\n  \
+display_gamma_map [map options]\n  \
+display_gamma_set <display> <r:g:b>\n  \
+display_primary\n  \
+display_is_primary <display>\n  \
+set_primary <display>\n  \
+display_rotate <display> <normal|left|right|inverted>\n  \
+mode_add <display> <WxH[@Hz]> [--reduced] [--switch]\n  \
+mode_delete <display> <mode name>\n  \
+virtual enable <WxH[@Hz]> [--output <display>]\n  \
+mode_gc\n  \
+display_manufacturer <display> [--id]\n  \
+display_manufacturer_map [map options]\n  \
+display_model <display>\n  \
+display_model_map [map options]\n  \
+display_identity_map [map options] [--json]\n  \
+seat_map [map options]  (logind seat of each output's DRM card)\n  \
+display_name_for <serial:...|edid:...|model:...>\n  \
+display_physical_size <display> [--json]\n  \
+display_physical_size_map [map options]\n  \
+display_dpi <display>\n  \
+display_bandwidth <display> [--link <gbit/s>]  (capacity from DP link properties or EDID; --link overrides)\n  \
+display_dpi_map [map options]\n  \
+capabilities [--json]\n  \
+display_connector <display>\n  \
+display_connector_map [map options]\n  \
+display_monitor <display>\n  \
+display_monitor_map [map options]\n  \
+providers list [--json]\n  \
+providers offload <source> <sink>\n  \
+providers output-source <sink> <source|none>\n  \
+display_names [--connected|--disconnected|--active|--inactive] [--count]\n  \
+display_geometry <display>\n  \
+display_geometry_map [map options] [--orientation]\n  \
+display_modes <display>\n  \
+display_modes_map [map options]\n  \
+display_rate <display>\n  \
+display_current_mode <display>\n  \
+display_current_mode_map [map options]\n  \
+display_preferred_mode <display>\n  \
+display_preferred_mode_map [map options]\n  \
+display_preferred_map [map options]\n  \
+display_native_check [display] [--exit-code]\n  \
+display_rates_map [map options]\n  \
+display_label_line <display>\n  \
+single_display_output <display> [apply options]\n  \
+dual_display_output <left> <right> [--primary left|right] [--align height|width|none]\n    \
+[apply options]\n  \
+triple_display_output <left> <middle> <right> [apply options]\n  \
+row_display_output <display>... [--primary N] [apply options]\n  \
+extend_all [--sort name|edid|position] [apply options]  (honors [outputs.<selector>] in config.toml)\n  \
+mirror_display_output <source> <target>\n  \
+layout_check\n  \
+normalize_layout [apply options]\n  \
+layout apply <file.toml> [apply options] [--substitute|--auto-substitute]\n  \
+simulate <file.toml> [--snapshot <file>]\n  \
+bench [--iterations <count>]  (query latency for each available backend)\n  \
+batch [--json] '<command> [args...]'...  (answer several query commands from one backend query)\n  \
+serve [--socket <path> | --dbus | --stdio] [--interval <seconds>]  (answer text or JSON query lines over a Unix socket, stdin/stdout, or org.xrandrutils.Displays on the session bus, from state cached by polling every --interval seconds (default 2); --dbus emits DisplaysChanged when a poll finds a change)\n  \
+preset <name> [args...]  (from ~/.config/xrandr-utils/config.toml)\n  \
+toggle [<preset>...] [apply options]  (internal -> external -> dual by default)\n  \
+preset --list\n  \
+profile apply <file.toml> [apply options] [--substitute|--auto-substitute]\n  \
+profile import-autorandr <name|dir>  (print an autorandr profile as layout TOML)\n  \
+profile export-autorandr <file.toml> <name|dir> [--force]  (write autorandr config and setup files; --force overwrites them)\n  \
+apply_layout <display>... [--primary N] [apply options]\n  \
+monitor_power <display> <on|off|standby> [--install-udev-rule]\n  \
+battery_mode [on [--brightness <percent>] [--disable <display>]... [apply options]|off]\n  \
+game_mode <display> [on|off] [--vrr] [apply options]\n  \
+get <display>[.<field>...]\n  \
+set <display>.<field> <value>  (primary, rotation, brightness, gamma, mode, position, property.<name>)\n  \
+assert <expr>  (exit 0 when true, 1 when false)\n\n\
+Map options:\n  \
+--filtered  skip entries with empty values\n  \
+--keys      print only display names\n  \
+--values    print only unique values\n  \
+--count     print the number of entries (unique values with --values)\n  \
+--strict-errors  fail on the first per-display error instead of warning\n  \
+--transform <lower|upper|trim|replace:<from>,<to>>  rewrite values (repeatable)\n\n\
+Apply options:\n  \
+--check  report whether the layout would change without applying it (exit 6 if it would)\n  \
+--exit-changed  exit 6 when the layout was changed\n  \
+--diff   print the per-output state before and after the change\n  \
+--json   emit the result as a JSON object ({{\"changed\": ..., \"diff\": ...}})\n  \
+--plan   print a summary of the per-output changes (old -> new) in the order they take effect\n  \
+--rate <hz>  use the preferred resolution at the closest refresh rate\n  \
+--max-rate   use the preferred resolution at its highest refresh rate\n  \
+--scale <factor>  scale each output (in layout files, outputs without their own scale)\n  \
+--position above|below|left-of|right-of  place each output relative to the previous one\n  \
+--valign top|center|bottom  vertically align outputs of different heights in a row\n  \
+--bezel <px>[,<px>...]  gap between adjacent outputs (one value for all, or one per pair)\n  \
+--force      allow a layout that leaves no display active, or a change from an SSH session\n  \
+--confirm-timeout <seconds>  revert the change unless confirmed on the terminal in time\n\n\
+Global options:\n  \
+--debug-parse  print how each input line was classified to stderr\n  \
+--dry-run      print the shell-quoted commands instead of running them\n  \
+--ignore <display>  skip a display everywhere (repeatable; accepts globs and /regex/)\n  \
+--fuzzy        match display names case-insensitively or by unambiguous prefix (edp -> eDP-1)\n  \
+--allow-remote  change the layout from an SSH session without --confirm-timeout or --force\n  \
+--current-seat  only consider outputs on this session's logind seat (fails without seat data)\n  \
+--error-format text|json  print errors as text or as a JSON object on stderr\n  \
+--input <path>  parse a saved xrandr --verbose or wlr-randr dump instead of querying (- for stdin)\n\n\
+Exit codes:\n  \
+1  general error\n  \
+2  display not found\n  \
+3  xrandr or wlr-randr failed\n  \
+4  EDID missing\n  \
+5  usage error\n  \
+6  the layout would change (--check) or was changed (--exit-changed)\n\n\
+Display arguments:\n  \
+<display> accepts an exact name, a glob (HDMI-*) or a /regex/ matching one output\n\n\
+Environment:\n  \
+XRANDR_UTILS_BACKEND=xrandr|wlr (defaults to wlr when WAYLAND_DISPLAY is set)\n  \
+XRANDR_UTILS_IGNORE=<display>[,<display>...]  displays to skip, as with --ignore\n  \
+XRANDR_UTILS_FUZZY=1  enable --fuzzy name matching\n\n\
+Config file (~/.config/xrandr-utils/config.toml):\n  \
+[presets] and [presets.<name>...]  commands or layouts for preset and toggle\n  \
+[outputs.\"<serial:...|edid:...|model:...>\"]  per-monitor overrides honored by extend_all:\n    \
+rotate = \"normal|left|right|inverted\"\n    \
+primary = true|false\n    \
+position = \"left|right\"  pin the output to the leftmost or rightmost slot\n"
+    );
 }
 
-fn consume_digits(bytes: &[u8], mut index: usize) -> Option<usize> {
-    if index >= bytes.len() || !bytes[index].is_ascii_digit() {
-        return None;
-    }
-    while index < bytes.len() && bytes[index].is_ascii_digit() {
-        index += 1;
-    }
-    Some(index)
-}
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-fn consume_signed_number(bytes: &[u8], mut index: usize) -> Option<usize> {
-    if index >= bytes.len() {
-        return None;
+    const DELL_BASE_EDID: &str = concat!(
+        "00ffffffffffff0010acc2a078563412161f0104a53c22783a00000000000000",
+        "00000000000000000000000000000000000000000000565e00a0a0a029500000",
+        "0000585421000000000000fc0044454c4c205532373230510a20000000ff0041",
+        "42433132330a202020202020000000fd0030901ea03c010a202020202020018b",
+    );
+
+    fn edid_with_extension(extension: &str) -> String {
+        format!("{DELL_BASE_EDID}{extension}")
     }
 
-    let sign = bytes[index];
-    if sign != b'+' && sign != b'-' {
-        return None;
+    /// A trimmed `xrandr --verbose` dump: eDP-1 (primary), DP-1 and a disconnected HDMI-1.
+    const VERBOSE: &str = concat!(
+        "Screen 0: minimum 320 x 200, current 4480 x 1440, maximum 16384 x 16384\n",
+        "eDP-1 connected primary 1920x1080+0+0 (0x46) normal (normal left inverted right x axis y axis) 344mm x 193mm\n",
+        "\tIdentifier: 0x42\n",
+        "\tTimestamp:  12345\n",
+        "\tSubpixel:   unknown\n",
+        "\tGamma:      1.0:1.0:1.0\n",
+        "\tBrightness: 1.0\n",
+        "\tClones:    \n",
+        "\tCRTC:       0\n",
+        "\tCRTCs:      0 1 2\n",
+        "\tTransform:  1.000000 0.000000 0.000000\n",
+        "\t            0.000000 1.000000 0.000000\n",
+        "\t            0.000000 0.000000 1.000000\n",
+        "\t           filter: \n",
+        "\tEDID: \n",
+        "\t\t00ffffffffffff0006af341200000000\n",
+        "\t\t0a1d0104a52213783a00000000000000\n",
+        "\t\t00000000000000000000000000000000\n",
+        "\t\t0000000000002e3680a070381f400000\n",
+        "\t\t000054be10000000000000fc0041554f\n",
+        "\t\t2050616e656c0a202020000000ff000a\n",
+        "\t\t202020202020202020202020000000fd\n",
+        "\t\t0030901ea03c010a2020202020200040\n",
+        "\tBacklight: 400 \n",
+        "\t\trange: (0, 937)\n",
+        "\tscaling mode: Full aspect \n",
+        "\t\tsupported: Full, Center, Full aspect\n",
+        "\tlink-status: Good \n",
+        "\t\tsupported: Good, Bad\n",
+        "\tCONNECTOR_ID: 95 \n",
+        "\t\tsupported: 95\n",
+        "\tnon-desktop: 0 \n",
+        "\t\trange: (0, 1)\n",
+        "  1920x1080 (0x46) 138.700MHz +HSync +VSync *current +preferred\n",
+        "        h: width  1920 start 1968 end 2000 total 2080 skew    0 clock  66.68KHz\n",
+        "        v: height 1080 start 1083 end 1088 total 1120           clock  60.02Hz\n",
+        "  1920x1080 (0x47) 110.960MHz +HSync +VSync\n",
+        "        h: width  1920 start 1968 end 2000 total 2080 skew    0 clock  53.35KHz\n",
+        "        v: height 1080 start 1083 end 1088 total 1120           clock  48.00Hz\n",
+        "DP-1 connected 2560x1440+1920+0 (0x4a) normal (normal left inverted right x axis y axis) 597mm x 336mm\n",
+        "\tIdentifier: 0x43\n",
+        "\tTimestamp:  12345\n",
+        "\tSubpixel:   unknown\n",
+        "\tGamma:      1.0:0.9:0.8\n",
+        "\tBrightness: 0.80\n",
+        "\tClones:    \n",
+        "\tCRTC:       1\n",
+        "\tCRTCs:      0 1 2\n",
+        "\tTransform:  1.000000 0.000000 0.000000\n",
+        "\t            0.000000 1.000000 0.000000\n",
+        "\t            0.000000 0.000000 1.000000\n",
+        "\t           filter: \n",
+        "\tEDID: \n",
+        "\t\t00ffffffffffff0010acc2a078563412\n",
+        "\t\t161f0104a53c22783a00000000000000\n",
+        "\t\t00000000000000000000000000000000\n",
+        "\t\t000000000000565e00a0a0a029500000\n",
+        "\t\t0000585421000000000000fc0044454c\n",
+        "\t\t4c205532373230510a20000000ff0041\n",
+        "\t\t42433132330a202020202020000000fd\n",
+        "\t\t0030901ea03c010a202020202020008c\n",
+        "\tBroadcast RGB: Automatic \n",
+        "\t\tsupported: Automatic, Full, Limited 16:235\n",
+        "\tlink-status: Good \n",
+        "\t\tsupported: Good, Bad\n",
+        "\tCONNECTOR_ID: 103 \n",
+        "\t\tsupported: 103\n",
+        "\tnon-desktop: 0 \n",
+        "\t\trange: (0, 1)\n",
+        "  2560x1440 (0x4a) 241.500MHz +HSync +VSync *current +preferred\n",
+        "        h: width  2560 start 2608 end 2640 total 2720 skew    0 clock  88.79KHz\n",
+        "        v: height 1440 start 1443 end 1448 total 1480           clock  59.95Hz\n",
+        "  2560x1440 (0x4b) 586.600MHz +HSync +VSync\n",
+        "        h: width  2560 start 2608 end 2640 total 2720 skew    0 clock  215.66KHz\n",
+        "        v: height 1440 start 1443 end 1448 total 1480           clock  143.91Hz\n",
+        "HDMI-1 disconnected (normal left inverted right x axis y axis)\n",
+        "\tIdentifier: 0x44\n",
+        "\tTimestamp:  12345\n",
+        "\tSubpixel:   unknown\n",
+        "\tClones:    \n",
+        "\tCRTCs:      0 1 2\n",
+        "\tTransform:  1.000000 0.000000 0.000000\n",
+        "\t            0.000000 1.000000 0.000000\n",
+        "\t            0.000000 0.000000 1.000000\n",
+        "\t           filter: \n",
+        "\tlink-status: Good \n",
+        "\t\tsupported: Good, Bad\n",
+        "\tCONNECTOR_ID: 110 \n",
+        "\t\tsupported: 110\n",
+        "\tnon-desktop: 0 \n",
+        "\t\trange: (0, 1)\n",
+    );
+
+    fn ok<T>(result: Result<T, CliError>) -> T {
+        result.unwrap_or_else(|err| panic!("{}", err.message()))
     }
-    index += 1;
 
-    if index >= bytes.len() || !bytes[index].is_ascii_digit() {
-        return None;
+    #[test]
+    fn edid_features_reads_hdmi_forum_blocks() {
+        // Basic audio, HDMI VSDB with DC_30bit, HF-VSDB with VRRmin 48 and an HDR block.
+        let edid = edid_with_extension(concat!(
+            "020328404490040301230907078301000067030c001000383c6ad85dc4017880",
+            "63023090e3060501000000000000000000000000000000000000000000000000",
+            "0000000000000000000000000000000000000000000000000000000000000000",
+            "000000000000000000000000000000000000000000000000000000000000008f",
+        ));
+        let features = edid_features(&edid).unwrap();
+        assert!(features.audio);
+        assert!(features.deep_color);
+        assert!(features.vrr);
+        assert!(features.hdr);
+        assert_eq!(features.max_tmds_mhz, Some(600));
     }
 
-    while index < bytes.len() && bytes[index].is_ascii_digit() {
-        index += 1;
+    #[test]
+    fn link_capacity_reads_dp_link_properties() {
+        let mut sections = parse_sections(VERBOSE);
+        let section = &mut sections[1];
+        section.lines.insert(1, "\tlink rate: 0x14 ".to_string());
+        section.lines.insert(2, "\tlane count: 4 ".to_string());
+        let (capacity, label) = section_link_capacity(section).unwrap();
+        assert!((capacity - 17.28).abs() < 0.001);
+        assert_eq!(label, "DisplayPort 4 x 5.40 Gbit/s, from link properties");
+        assert!((dp_lane_rate("810000").unwrap() - 8.1).abs() < 0.001);
+        assert!((dp_lane_rate("13.5 Gbps").unwrap() - 13.5).abs() < 0.001);
+        assert_eq!(dp_lane_rate("0x0"), None);
     }
 
-    Some(index)
-}
+    #[test]
+    fn edid_features_ignores_neighbouring_vsdb_bytes() {
+        // Max_TMDS_Clock 0x3c carries bit 0x10 and VRRmax[9:8] sets 0x40 with VRRmin 0.
+        let edid = edid_with_extension(concat!(
+            "02031b00449004030167030c001000003c69d85dc40178000002400000000000",
+            "0000000000000000000000000000000000000000000000000000000000000000",
+            "0000000000000000000000000000000000000000000000000000000000000000",
+            "0000000000000000000000000000000000000000000000000000000000000025",
+        ));
+        let features = edid_features(&edid).unwrap();
+        assert!(!features.audio);
+        assert!(!features.deep_color);
+        assert!(!features.vrr);
+        assert!(!features.hdr);
+    }
 
-fn find_section<'a>(sections: &'a [DisplaySection], name: &str) -> Option<&'a DisplaySection> {
-    sections.iter().find(|section| section.name == name)
-}
+    #[test]
+    fn edid_features_rejects_truncated_edid() {
+        assert!(edid_features(&DELL_BASE_EDID[..200]).is_none());
+    }
 
-fn escape_multiline(text: &str) -> String {
-    text.replace('\\', "\\\\").replace('\n', "\\n")
-}
+    #[test]
+    fn unattached_mode_names_reads_only_the_trailing_block() {
+        let text = concat!(
+            "Screen 0: minimum 8 x 8, current 1920 x 1080, maximum 32767 x 32767\n",
+            "eDP-1 connected primary 1920x1080+0+0 (normal left inverted right x axis y axis) 344mm x 194mm\n",
+            "   1920x1080     60.02*+\n",
+            "   1920x1080_60.00  59.96\n",
+            "HDMI-1 disconnected (normal left inverted right x axis y axis)\n",
+            "  1280x720_60.00 (0x1e5) 74.500MHz -HSync +VSync\n",
+            "        h: width  1280 start 1344 end 1472 total 1664 skew    0 clock  44.77KHz\n",
+            "        v: height  720 start  723 end  728 total  748           clock  59.86Hz\n",
+        );
+        assert_eq!(unattached_mode_names(text), ["1280x720_60.00"]);
+        assert!(unattached_mode_names(&text[..text.find("  1280x720").unwrap()]).is_empty());
+    }
 
-fn extract_edid_hex(section: &DisplaySection) -> Option<String> {
-    let mut capture = false;
-    let mut hex = String::new();
+    #[test]
+    fn connector_alias_keeps_numeric_segments() {
+        assert_eq!(connector_alias("HDMI-A-1"), "HDMI-1");
+        assert_eq!(connector_alias("HDMI1"), "HDMI-1");
+        assert_eq!(connector_alias("eDP-1"), connector_alias("eDP1"));
+        assert_eq!(connector_alias("DP1-1"), "DP-1-1");
+        assert_ne!(connector_alias("DP-1-1"), connector_alias("DP-11"));
+    }
 
-    for line in &section.lines {
-        let trimmed = line.trim();
-        if trimmed.starts_with("EDID:") {
-            capture = true;
-            continue;
-        }
-        if capture {
-            if trimmed.is_empty() {
-                break;
-            }
-            if trimmed
-                .chars()
-                .all(|ch| ch.is_ascii_hexdigit() || ch.is_ascii_whitespace())
-            {
-                for ch in trimmed.chars() {
-                    if ch.is_ascii_hexdigit() {
-                        hex.push(ch);
-                    }
-                }
-            } else {
-                break;
-            }
-        }
+    #[test]
+    fn parse_sections_reads_verbose_headers() {
+        let sections = parse_sections(VERBOSE);
+        let names: Vec<&str> = sections
+            .iter()
+            .map(|section| section.name.as_str())
+            .collect();
+        assert_eq!(names, ["eDP-1", "DP-1", "HDMI-1"]);
+        assert!(sections[0].primary);
+        assert!(!sections[1].primary);
+        assert_eq!(sections[1].geometry.as_deref(), Some("2560x1440+1920+0"));
+        assert!(sections[2].state == DisplayState::Disconnected);
+        assert_eq!(sections[2].geometry, None);
     }
 
-    if hex.is_empty() {
-        None
-    } else {
-        Some(hex)
+    #[test]
+    fn parse_modes_reads_verbose_timings() {
+        let modes = parse_modes(&parse_sections(VERBOSE)[1]);
+        assert_eq!(modes.len(), 2);
+        assert_eq!((modes[0].width, modes[0].height), (2560, 1440));
+        assert!(modes[0].current && modes[0].preferred);
+        assert!(!modes[1].current && !modes[1].preferred);
+        assert!((modes[1].refresh - 143.91).abs() < 0.001);
     }
-}
 
-fn extract_connector_id(section: &DisplaySection) -> Option<String> {
-    for line in &section.lines {
-        let trimmed = line.trim();
-        if let Some(rest) = trimmed.strip_prefix("CONNECTOR_ID:") {
-            let value = rest.trim();
-            if !value.is_empty() {
-                return Some(value.to_string());
-            }
-        }
+    #[test]
+    fn input_format_detection() {
+        let props = concat!(
+            "eDP-1 connected primary 1920x1080+0+0 (normal left inverted right x axis y axis) 344mm x 193mm\n",
+            "\tEDID: \n",
+            "\t\t00ffffffffffff00\n",
+            "   1920x1080     60.02*+  48.00  \n",
+        );
+        assert!(InputFormat::detect(Backend::Xrandr, VERBOSE) == InputFormat::Verbose);
+        let plain = props.lines().next().unwrap();
+        assert!(InputFormat::detect(Backend::Xrandr, props) == InputFormat::Props);
+        assert!(InputFormat::detect(Backend::Xrandr, plain) == InputFormat::Plain);
+        assert!(InputFormat::detect(Backend::Wlr, VERBOSE) == InputFormat::Wlr);
+        assert!(require_verbose(Backend::Xrandr, VERBOSE, "display_edid").is_ok());
+        assert!(require_verbose(Backend::Xrandr, props, "display_edid").is_err());
+        assert!(require_properties(Backend::Xrandr, props, "display_edid").is_ok());
+        assert!(require_properties(Backend::Xrandr, plain, "display_edid").is_err());
     }
-    None
-}
 
-fn resolve_monitors_text(provided: Option<String>) -> Result<String, String> {
-    if let Some(text) = provided {
-        if text.trim().is_empty() {
-            return Err("monitor text argument is empty".to_string());
-        }
-        return Ok(text);
+    #[test]
+    fn parse_compact_modes_reads_rates_and_markers() {
+        let mut modes = Vec::new();
+        assert!(parse_compact_modes(
+            "1920x1080     60.02*+  48.00",
+            &mut modes
+        ));
+        assert!(parse_compact_modes(
+            "1280x720i     60.00 +  50.00",
+            &mut modes
+        ));
+        assert!(!parse_compact_modes("Identifier: 0x42", &mut modes));
+        assert!(!parse_compact_modes(
+            "1920x1080 (0x46) 138.700MHz",
+            &mut modes
+        ));
+        assert_eq!(modes.len(), 4);
+        assert!(modes[0].current && modes[0].preferred);
+        assert!(!modes[1].current && !modes[1].preferred);
+        assert_eq!((modes[2].width, modes[2].height), (1280, 720));
+        assert!(modes[2].preferred);
+        assert!(!modes[3].preferred);
     }
 
-    let output = Command::new("xrandr")
-        .arg("--listmonitors")
-        .stdout(Stdio::piped())
-        .stderr(Stdio::null())
-        .output()
-        .map_err(|err| format!("failed to run xrandr --listmonitors: {err}"))?;
+    #[test]
+    fn parse_layout_reads_outputs_and_round_trips() {
+        let layout = ok(parse_layout(concat!(
+            "off_others = false\n",
+            "[output.eDP-1]\n",
+            "mode = \"1920x1080\"  # native\n",
+            "rate = \"60\"\n",
+            "position = \"0x0\"\n",
+            "primary = true\n",
+            "[output.\"DP-1\"]\n",
+            "rotation = \"left\"\n",
+            "scale = \"1.5\"\n",
+            "[output.HDMI-1]\n",
+            "off = true\n",
+        )));
+        assert!(!layout.off_others);
+        let names: Vec<&str> = layout
+            .outputs
+            .iter()
+            .map(|output| output.name.as_str())
+            .collect();
+        assert_eq!(names, ["eDP-1", "DP-1", "HDMI-1"]);
+        assert_eq!(layout.outputs[0].mode.as_deref(), Some("1920x1080"));
+        assert!(layout.outputs[0].primary);
+        assert_eq!(layout.outputs[1].rotation.as_deref(), Some("left"));
+        assert_eq!(layout.outputs[1].scale.as_deref(), Some("1.5"));
+        assert!(layout.outputs[2].off);
 
-    if !output.status.success() {
-        return Err("xrandr --listmonitors exited with failure".to_string());
+        let text = layout_toml(&layout);
+        assert_eq!(layout_toml(&ok(parse_layout(&text))), text);
     }
 
-    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
-}
+    #[test]
+    fn parse_layout_rejects_invalid_layouts() {
+        let two_primaries = "[output.a]\nprimary = true\n[output.b]\nprimary = true\n";
+        assert!(parse_layout(two_primaries).is_err());
+        let conflict = "[output.a]\nmode = \"1920x1080\"\nresolution = \"native\"\n";
+        assert!(parse_layout(conflict).is_err());
+        assert!(parse_layout("[output.a]\nbrightness = \"1\"\n").is_err());
+        assert!(parse_layout("[screen]\nsize = \"1x1\"\n").is_err());
+        assert!(parse_layout("[output.a]\nprimary\n").is_err());
+    }
 
-fn parse_monitor_map(text: &str) -> HashMap<String, String> {
-    let mut lines = text.lines();
-    let mut map = HashMap::new();
+    #[test]
+    fn section_gamma_inverts_the_reported_values() {
+        let sections = parse_sections(VERBOSE);
+        assert_eq!(
+            section_gamma(&sections[0]).as_deref(),
+            Some("1.00:1.00:1.00")
+        );
+        assert_eq!(
+            section_gamma(&sections[1]).as_deref(),
+            Some("1.00:1.11:1.25")
+        );
+        assert_eq!(section_gamma(&sections[2]), None);
+        assert_eq!(
+            xrandr_color_args(&sections[1]),
+            ["--brightness", "0.80", "--gamma", "1.00:1.11:1.25"]
+        );
+    }
 
-    if let Some(first) = lines.next() {
-        if !first.starts_with("Monitors:") {
-            insert_monitor_line(&mut map, first);
-        }
-        for line in lines {
-            insert_monitor_line(&mut map, line);
-        }
+    #[test]
+    fn name_patterns_match_globs_and_regexes() {
+        let glob = ok(display_name_matcher("DP-*"));
+        assert!(glob.is_match("DP-1") && glob.is_match("DP-1-1"));
+        assert!(!glob.is_match("eDP-1"));
+        let class = ok(display_name_matcher("HDMI-[!2]"));
+        assert!(class.is_match("HDMI-1") && !class.is_match("HDMI-2"));
+        let regex = ok(display_name_matcher("/^e?DP-\\d$/"));
+        assert!(regex.is_match("eDP-1") && regex.is_match("DP-2"));
+        assert!(!regex.is_match("DP-1-1"));
+        let alternation = ok(display_name_matcher("/HDMI|^eDP/"));
+        assert!(alternation.is_match("HDMI-A-1") && alternation.is_match("eDP-1"));
+        assert!(!alternation.is_match("DP-1"));
+        assert!(display_name_matcher("HDMI-[12").is_err());
     }
 
-    map
-}
+    #[test]
+    fn cvt_modeline_matches_reference_timings() {
+        // Reference values from `cvt 1920 1080 60` and `cvt -r 1920 1080 60`.
+        let modeline = cvt_modeline(1920, 1080, 60.0, false);
+        assert_eq!(modeline.name, "1920x1080_60.00");
+        assert!((modeline.clock - 173.0).abs() < 0.001);
+        assert_eq!(modeline.horizontal, [1920, 2048, 2248, 2576]);
+        assert_eq!(modeline.vertical, [1080, 1083, 1088, 1120]);
+        assert_eq!(modeline.flags, ["-hsync", "+vsync"]);
 
-fn insert_monitor_line(map: &mut HashMap<String, String>, line: &str) {
-    let trimmed = line.trim_end();
-    if trimmed.is_empty() {
-        return;
+        let reduced = cvt_modeline(1920, 1080, 60.0, true);
+        assert!((reduced.clock - 138.5).abs() < 0.001);
+        assert_eq!(reduced.horizontal, [1920, 1968, 2000, 2080]);
+        assert_eq!(reduced.vertical, [1080, 1083, 1088, 1111]);
+        assert_eq!(reduced.flags, ["+hsync", "-vsync"]);
     }
-    let mut parts = trimmed.split_whitespace();
-    let index_part = match parts.next() {
-        Some(value) => value,
-        None => return,
-    };
-    if let Some(name) = parts.last() {
-        let index_value = index_part.split(':').next().unwrap_or(index_part);
-        map.insert(name.to_string(), index_value.to_string());
+
+    #[test]
+    fn edid_validation_flags_corruption() {
+        let sections = parse_sections(VERBOSE);
+        let edid = ok(checked_edid(&sections[1])).unwrap();
+        assert!(edid_validation_issues(&edid).is_empty());
+
+        let corrupted = format!("{}ff{}", &edid[..40], &edid[42..]);
+        assert_eq!(
+            edid_validation_issues(&corrupted),
+            ["block 0 checksum mismatch"]
+        );
+        assert_eq!(
+            edid_validation_issues(&edid[..200]),
+            ["truncated edid: 100 bytes"]
+        );
+        assert!(ok(checked_edid(&sections[2])).is_none());
     }
-}
 
-fn section_serial(section: &DisplaySection) -> Option<String> {
-    let edid = extract_edid_hex(section)?;
-    let decoded = decode_edid(&edid).ok()?;
-    find_serial_slice(&decoded).map(|value| value.to_string())
-}
+    #[test]
+    fn watch_events_report_layout_changes() {
+        let before = watch_snapshot(&parse_sections(VERBOSE), &[]);
+        let changed = VERBOSE
+            .replace(
+                "DP-1 connected 2560x1440+1920+0 (0x4a)",
+                "DP-1 connected primary 2560x1440+0+0 (0x4a)",
+            )
+            .replace(
+                "eDP-1 connected primary 1920x1080+0+0 (0x46)",
+                "eDP-1 connected",
+            )
+            .replace("HDMI-1 disconnected", "HDMI-1 connected");
+        let after = watch_snapshot(&parse_sections(&changed), &[]);
+        let events: Vec<String> = watch_events(&before, &after, 7)
+            .iter()
+            .map(WatchEvent::line)
+            .collect();
+        assert_eq!(
+            events,
+            [
+                "7 disabled eDP-1 1920x1080+0+0 -> -",
+                "7 geometry DP-1 2560x1440+1920+0 -> 2560x1440+0+0",
+                "7 primary DP-1 2560x1440+1920+0 -> 2560x1440+0+0",
+                "7 connected HDMI-1 - -> -",
+            ]
+        );
+        assert!(watch_events(&before, &before, 7).is_empty());
+    }
 
-fn section_serial_matches(section: &DisplaySection, serial: &str) -> bool {
-    let edid = match extract_edid_hex(section) {
-        Some(value) => value,
-        None => return false,
-    };
-    let decoded = match decode_edid(&edid) {
-        Ok(value) => value,
-        Err(_) => return false,
-    };
-    find_serial_slice(&decoded).map_or(false, |value| value == serial)
-}
+    #[test]
+    fn uptime_metrics_label_each_display() {
+        let records: Vec<UptimeRecord> = ["eDP-1 active 1700000000 0", "DP\"1 off 1700000500 3"]
+            .iter()
+            .map(|line| parse_uptime_record(line).unwrap())
+            .collect();
+        let metrics = uptime_metrics(&records);
+        assert!(metrics.contains(
+            "xrandr_utils_display_state_since_seconds{display=\"eDP-1\",state=\"active\"} 1700000000\n"
+        ));
+        assert!(
+            metrics.contains("xrandr_utils_display_state_changes_total{display=\"DP\\\"1\"} 3\n")
+        );
+        assert_eq!(
+            metrics.lines().filter(|line| line.starts_with('#')).count(),
+            4
+        );
+    }
 
-fn find_serial_slice<'a>(decoded: &'a str) -> Option<&'a str> {
-    find_between_quotes(decoded, "Display Product Serial Number:")
-        .or_else(|| find_after_colon(decoded, "Serial Number:"))
-        .or_else(|| find_between_quotes(decoded, "Alphanumeric Data String:"))
-}
+    #[test]
+    fn format_timestamp_handles_offsets_and_leap_days() {
+        assert_eq!(format_timestamp(0, 0), "1970-01-01 00:00");
+        assert_eq!(format_timestamp(1_709_164_800, 0), "2024-02-29 00:00");
+        assert_eq!(format_timestamp(1_709_164_800, -3600), "2024-02-28 23:00");
+        assert_eq!(
+            format_timestamp(951_782_400 + 5_400, 19_800),
+            "2000-02-29 07:00"
+        );
+    }
 
-fn find_between_quotes<'a>(decoded: &'a str, label: &str) -> Option<&'a str> {
-    for line in decoded.lines() {
-        if let Some(value) = extract_between_quotes(line, label) {
-            if !value.is_empty() {
-                return Some(value);
-            }
-        }
+    #[test]
+    fn history_entries_round_trip() {
+        let saved = concat!(
+            "1700000000\txrandr\teDP-1 1920x1080+0+0\t--output eDP-1 --mode 1920x1080\n",
+            "1700000060\txrandr\tall off\t--output 'odd name' --off\t",
+            "eDP-1 - - 0 - -;odd - - 0 - -\n",
+        );
+        let history = ok(parse_history(saved));
+        assert_eq!(history.len(), 2);
+        assert!(history[0].states.is_empty());
+        assert_eq!(history[1].args, ["--output", "odd name", "--off"]);
+        assert_eq!(history[1].states, ["eDP-1 - - 0 - -", "odd - - 0 - -"]);
+        assert_eq!(history_text(&history), saved);
+        assert!(parse_history("1700000000\txrandr\tmissing args\n").is_err());
     }
-    None
-}
 
-fn find_after_colon<'a>(decoded: &'a str, label: &str) -> Option<&'a str> {
-    for line in decoded.lines() {
-        if let Some(value) = extract_after_colon(line, label) {
-            if !value.is_empty() {
-                return Some(value);
-            }
-        }
+    #[test]
+    fn undo_snapshots_round_trip() {
+        let sections = parse_sections(VERBOSE);
+        let saved = layout_snapshot_text(
+            Backend::Xrandr,
+            &current_output_states(&sections),
+            &restore_args(&sections, Backend::Xrandr),
+        );
+        assert!(
+            saved.starts_with("backend xrandr\nstate eDP-1 1920x1080+0+0 60.02 1 normal 1.00\n")
+        );
+        let flags = ApplyFlags {
+            check: true,
+            diff: true,
+            ..ApplyFlags::default()
+        };
+        let apply = |saved: &str| {
+            apply_layout_snapshot(saved, "undo state", &sections, Backend::Xrandr, &flags)
+        };
+        let check = |saved: &str| {
+            let (result, output) = capture_output(|| apply(saved));
+            (ok(result), output)
+        };
+        let (outcome, output) = check(&saved);
+        assert!(outcome == Outcome::Done);
+        assert_eq!(output, "unchanged\n");
+
+        // Snapshots written before rotation and scale were tracked still restore.
+        let legacy = saved.replace("DP-1 2560x1440+1920+0 59.95 0 normal 1.00", "DP-1 - - 0");
+        let (outcome, output) = check(&legacy);
+        assert!(outcome == Outcome::Changed);
+        assert_eq!(
+            output.lines().collect::<Vec<_>>(),
+            ["- DP-1 2560x1440+1920+0 59.95Hz", "+ DP-1 off", "changed"]
+        );
+        assert!(apply(&saved.replace("backend xrandr", "backend wlr")).is_err());
+        assert!(apply("state eDP-1").is_err());
     }
-    None
-}
 
-fn monitor_serial_has_displays(
-    sections: &[DisplaySection],
-    serial: &str,
-    connected_only: bool,
-) -> bool {
-    sections.iter().any(|section| {
-        if connected_only && section.state != DisplayState::Connected {
-            return false;
+    #[test]
+    fn state_dir_migrates_and_locks() {
+        let dir = env::temp_dir().join(format!("xrandr-utils-state-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        for (name, contents) in [
+            ("version", "1\n"),
+            ("position_HDMI-1", "1920x0\n"),
+            ("position_DP-1", "-1280x-200\n"),
+            ("toggle", "dual\n"),
+        ] {
+            std::fs::write(dir.join(name), contents).unwrap();
         }
-        section_serial_matches(section, serial)
-    })
-}
 
-fn monitor_serial_get_displays(
-    sections: &[DisplaySection],
-    serial: &str,
-    connected_only: bool,
-) -> Vec<String> {
-    let mut matches = Vec::new();
-    for section in sections {
-        if connected_only && section.state != DisplayState::Connected {
-            continue;
-        }
-        if section_serial_matches(section, serial) {
-            matches.push(section.name.clone());
-        }
-    }
-    matches
-}
+        let state = ok(StateDir::lock_at(dir.clone()));
+        assert_eq!(ok(state.read("version")).as_deref(), Some("2\n"));
+        assert_eq!(
+            ok(state.read("positions")).as_deref(),
+            Some("DP-1 -1280x-200\nHDMI-1 1920x0\n")
+        );
+        assert!(ok(state.read("position_HDMI-1")).is_none());
+        assert_eq!(ok(state.read("toggle")).as_deref(), Some("dual\n"));
 
-fn decode_edid(hex: &str) -> Result<String, String> {
-    let bytes = hex_to_bytes(hex)?;
-    let mut child = Command::new("edid-decode")
-        .stdin(Stdio::piped())
-        .stdout(Stdio::piped())
-        .stderr(Stdio::null())
-        .spawn()
-        .map_err(|err| format!("failed to run edid-decode: {err}"))?;
+        let lock = std::fs::File::open(dir.join("lock")).unwrap();
+        assert!(lock.try_lock().is_err());
+        drop(state);
+        assert!(lock.try_lock().is_ok());
+        drop(lock);
 
-    if let Some(stdin) = child.stdin.as_mut() {
-        stdin
-            .write_all(&bytes)
-            .map_err(|err| format!("failed to write edid to decoder: {err}"))?;
-    } else {
-        return Err("failed to open edid-decode stdin".to_string());
+        std::fs::write(dir.join("version"), "3\n").unwrap();
+        assert!(StateDir::lock_at(dir.clone()).is_err());
+        let _ = std::fs::remove_dir_all(&dir);
     }
 
-    let output = child
-        .wait_with_output()
-        .map_err(|err| format!("failed to read edid-decode output: {err}"))?;
-
-    if !output.status.success() {
-        return Err("edid-decode exited with failure".to_string());
+    #[test]
+    fn edit_distance_counts_edits() {
+        let chars = |text: &str| text.chars().collect::<Vec<_>>();
+        assert_eq!(edit_distance(&chars("kitten"), &chars("sitting")), 3);
+        assert_eq!(edit_distance(&chars("HDMI-1"), &chars("HDMI-1")), 0);
+        assert_eq!(edit_distance(&chars(""), &chars("DP-1")), 4);
+        assert_eq!(edit_distance(&chars("eDP1"), &chars("eDP-1")), 1);
     }
 
-    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
-}
+    #[test]
+    fn batch_frames_each_query() {
+        let batch = |args: &[&str]| {
+            let args = args.iter().map(|arg| arg.to_string());
+            capture_output(|| run_batch(args, Backend::Xrandr, VERBOSE))
+        };
+        let (result, output) = batch(&["display_names --connected", "display_primary"]);
+        ok(result);
+        assert_eq!(
+            output,
+            "==> display_names --connected <==\neDP-1\nDP-1\n\n==> display_primary <==\neDP-1\n"
+        );
 
-fn hex_to_bytes(hex: &str) -> Result<Vec<u8>, String> {
-    let mut bytes = Vec::new();
-    let chars: Vec<char> = hex.chars().filter(|ch| !ch.is_ascii_whitespace()).collect();
+        let (result, output) = batch(&["--json", "display_state DP-1", "display_state VGA-9"]);
+        ok(result);
+        assert_eq!(
+            output,
+            concat!(
+                "[{\"command\": \"display_state DP-1\", \"exit_code\": 0, \"output\": \"active\\n\", \"error\": null}, ",
+                "{\"command\": \"display_state VGA-9\", \"exit_code\": 2, \"output\": \"\", ",
+                "\"error\": \"display not found: VGA-9\"}]\n"
+            )
+        );
 
-    if chars.len() % 2 != 0 {
-        return Err("edid hex length is not even".to_string());
+        assert!(batch(&[]).0.is_err());
+        assert!(batch(&["display_off DP-1"]).0.is_err());
+        assert!(batch(&["display_names", "''"]).0.is_err());
     }
 
-    let mut index = 0;
-    while index < chars.len() {
-        let hi = chars[index];
-        let lo = chars[index + 1];
-        let value =
-            hex_pair_to_byte(hi, lo).ok_or_else(|| format!("invalid hex pair: {hi}{lo}"))?;
-        bytes.push(value);
-        index += 2;
+    #[test]
+    fn serve_answers_text_and_json_requests() {
+        let cache = RwLock::new(ServeCache {
+            verbose: VERBOSE.to_string(),
+            sections: parse_sections(VERBOSE),
+        });
+        let requests = concat!(
+            "display_geometry DP-1\n",
+            "\n",
+            "{\"command\": \"display_state \\\"eDP-1\\\"\"}\n",
+            "display_off DP-1\n",
+            "{\"id\": 1}\n",
+        );
+        let mut response = Vec::new();
+        ok(serve_connection(
+            requests.as_bytes(),
+            &mut response,
+            &cache,
+            Backend::Xrandr,
+        ));
+        let response = String::from_utf8(response).unwrap();
+        let mut lines = response.lines();
+        assert_eq!(lines.next(), Some("2560x1440+1920+0"));
+        assert_eq!(lines.next(), Some(""));
+        assert_eq!(
+            lines.next(),
+            Some("{\"ok\": true, \"exit_code\": 0, \"output\": \"active\\n\", \"error\": null}")
+        );
+        assert_eq!(
+            lines.next(),
+            Some(
+                "error: display_off cannot be used inside serve (only query commands are allowed)"
+            )
+        );
+        assert_eq!(lines.next(), Some(""));
+        assert!(lines
+            .next()
+            .is_some_and(|line| line.starts_with("{\"ok\": false, \"exit_code\": 1")));
+        assert_eq!(lines.next(), None);
     }
 
-    Ok(bytes)
-}
+    #[test]
+    fn json_string_field_decodes_escapes() {
+        let request = r#"{"id": 7, "command" : "display_state \"DP-1\"\t\u00e9\ud83d\ude00"}"#;
+        assert_eq!(
+            json_string_field(request, "command").as_deref(),
+            Some("display_state \"DP-1\"\t\u{e9}\u{1f600}")
+        );
+        assert_eq!(json_string_field(request, "id"), None);
+        assert_eq!(
+            json_string_field(r#"{"command": "unterminated}"#, "command"),
+            None
+        );
+        assert_eq!(
+            json_string_field(r#"{"command": "\ud83d"}"#, "command"),
+            None
+        );
+    }
 
-fn hex_pair_to_byte(hi: char, lo: char) -> Option<u8> {
-    let high = hi.to_digit(16)? as u8;
-    let low = lo.to_digit(16)? as u8;
-    Some((high << 4) | low)
-}
+    #[test]
+    fn output_overrides_reorder_and_rotate() {
+        let config = concat!(
+            "[outputs.\"serial:ABC123\"]\n",
+            "rotate = \"left\"\n",
+            "primary = true\n",
+            "position = \"left\"\n",
+            "[outputs.\"model:AUO Panel\"]\n",
+            "position = \"right\"\n",
+        );
+        let overrides = ok(output_overrides(&ok(parse_config(config))));
+        assert_eq!(overrides.len(), 2);
+        let sections = parse_sections(VERBOSE);
+        let mut displays = vec!["eDP-1".to_string(), "DP-1".to_string()];
+        let mut primary = 0;
+        let mut flags = ApplyFlags::default();
+        ok(apply_output_overrides(
+            &overrides,
+            &sections,
+            &mut displays,
+            &mut primary,
+            &mut flags,
+        ));
+        assert_eq!(displays, ["DP-1", "eDP-1"]);
+        assert_eq!(primary, 0);
+        assert_eq!(
+            flags.display_rotations,
+            [("DP-1".to_string(), "left".to_string())]
+        );
 
-fn extract_serial(decoded: &str) -> Option<String> {
-    find_serial_slice(decoded).map(|value| value.to_string())
-}
+        for config in [
+            "[outputs.\"serial:ABC123\"]\nrotate = sideways\n",
+            "[outputs.\"serial:ABC123\"]\nposition = above\n",
+            "[outputs.\"serial:ABC123\"]\nscale = 2\n",
+            "[outputs.\"port:DP-1\"]\nprimary = true\n",
+        ] {
+            assert!(output_overrides(&ok(parse_config(config))).is_err());
+        }
+    }
 
-fn extract_between_quotes<'a>(line: &'a str, label: &str) -> Option<&'a str> {
-    if !line.contains(label) {
-        return None;
+    #[test]
+    fn autorandr_scale_accepts_uniform_factors_only() {
+        assert_eq!(autorandr_scale("scale", "1.5x1.5").as_deref(), Some("1.5"));
+        assert_eq!(autorandr_scale("scale", "1x2"), None);
+        assert_eq!(
+            autorandr_scale("transform", "2,0,0,0,2,0,0,0,1").as_deref(),
+            Some("2")
+        );
+        assert_eq!(autorandr_scale("transform", "1,0.5,0,0,1,0,0,0,1"), None);
+        assert_eq!(autorandr_scale("transform", "1,0,0"), None);
     }
-    let start = line.find('\'')?;
-    let end = line[start + 1..].find('\'')?;
-    Some(line[start + 1..start + 1 + end].trim())
-}
 
-fn extract_after_colon<'a>(line: &'a str, label: &str) -> Option<&'a str> {
-    if !line.contains(label) {
-        return None;
+    #[test]
+    fn import_autorandr_reads_config_and_setup() {
+        let dir = env::temp_dir().join(format!(
+            "xrandr-utils-test-autorandr-{}",
+            std::process::id()
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(
+            dir.join("config"),
+            concat!(
+                "output eDP-1\n",
+                "off\n",
+                "output DP-1\n",
+                "crtc 0\n",
+                "mode 2560x1440\n",
+                "pos 0x0\n",
+                "primary\n",
+                "rate 59.95\n",
+                "rotate left\n",
+                "transform 1.25,0,0,0,1.25,0,0,0,1\n",
+            ),
+        )
+        .unwrap();
+        std::fs::write(dir.join("setup"), "DP-1 00ffffffffffff00\n").unwrap();
+        let layout = import_autorandr(&dir);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let layout = ok(layout);
+        assert!(layout.outputs[0].off);
+        let output = &layout.outputs[1];
+        assert_eq!(output.name, "DP-1");
+        assert!(output.primary);
+        assert_eq!(output.mode.as_deref(), Some("2560x1440"));
+        assert_eq!(output.position.as_deref(), Some("0x0"));
+        assert_eq!(output.rate.as_deref(), Some("59.95"));
+        assert_eq!(output.rotation.as_deref(), Some("left"));
+        assert_eq!(output.scale.as_deref(), Some("1.25"));
+        assert_eq!(output.identity, Some(edid_hash("00ffffffffffff00")));
     }
-    let idx = line.find(':')?;
-    Some(line[idx + 1..].trim())
-}
 
-fn print_usage() {
-    println!(
-        "Usage: xrandr-utils <command> [args]\n\n\
-Commands:\n  \
-display_connected <display>\n  \
-display_connected_map [--filtered] [--keys] [--values]\n  \
-display_section <display>\n  \
-display_section_map [--filtered] [--keys] [--values]\n  \
-display_edid <display>\n  \
-display_edid_decoded <display>\n  \
-display_serial <display>\n  \
-display_serial_map [--filtered] [--keys] [--values]\n  \
-monitor_serial_has_displays <serial> [--connected]\n  \
-monitor_serial_get_displays <serial> [--connected]\n  \
-display_connector <display>\n  \
-display_connector_map [--filtered] [--keys] [--values]\n  \
-display_monitor <display>\n  \
-display_monitor_map [--filtered] [--keys] [--values]\n  \
-display_names [--connected]\n  \
-display_geometry <display>\n  \
-display_geometry_map [--filtered] [--keys] [--values]\n  \
-display_label_line <display>\n  \
-single_display_output <display>\n  \
-dual_display_output <left> <right>\n"
-    );
+    #[test]
+    fn redact_config_hides_secret_values() {
+        let text = concat!(
+            "[preset.work]\n",
+            "command = \"single_display_output DP-1\"\n",
+            "api_token = \"abc123\"\n",
+            "  Password=hunter2\n",
+        );
+        assert_eq!(
+            redact_config(text),
+            concat!(
+                "[preset.work]\n",
+                "command = \"single_display_output DP-1\"\n",
+                "api_token = \"<redacted>\"\n",
+                "  Password= \"<redacted>\"\n",
+            )
+        );
+    }
+
+    #[test]
+    fn color_schedule_applies_per_output_temperatures() {
+        let args = [
+            "--color-temp",
+            "6500:3500",
+            "--color-temp",
+            "DP-1=6000:4500",
+            "--night",
+            "22:00-06:00",
+        ];
+        let mut args = args.iter().map(|arg| arg.to_string());
+        let flags = ok(parse_watch_flags(&mut args));
+        let schedule = flags.color.unwrap();
+        let sections = parse_sections(VERBOSE);
+        // Midnight UTC is inside the night window, noon is outside it.
+        assert!((schedule.night_fraction(86400 * 3, 0) - 1.0).abs() < f64::EPSILON);
+        assert!(schedule.night_fraction(86400 * 3 + 43200, 0).abs() < f64::EPSILON);
+        assert_eq!(
+            schedule.temperatures(&sections, 1.0),
+            [("eDP-1".to_string(), 3500), ("DP-1".to_string(), 4500)]
+        );
+        assert_eq!(
+            schedule.temperatures(&sections, 0.5),
+            [("eDP-1".to_string(), 5000), ("DP-1".to_string(), 5250)]
+        );
+    }
 }